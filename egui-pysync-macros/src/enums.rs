@@ -1,6 +1,6 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{self, parse_macro_input, Lit};
+use syn::{self, parse_macro_input, Data, DeriveInput, Lit};
 // use syn::{self, parse_macro_input, Data, DeriveInput, Expr, ExprLit, Lit};
 
 // pub(crate) fn enum_str_derive_impl(input: TokenStream) -> TokenStream {
@@ -105,6 +105,31 @@ use syn::{self, parse_macro_input, Lit};
 //     panic!("EnumInt can only be derived for enums");
 // }
 
+pub(crate) fn enum_names_derive_impl(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = input.ident;
+
+    if let Data::Enum(d) = input.data {
+        if d.variants.iter().any(|v| v.fields != syn::Fields::Unit) {
+            panic!("Enum variants must be unit variants");
+        }
+
+        let variants = d.variants.into_iter().map(|v| v.ident);
+
+        let out = quote!(
+            impl egui_pysync::EnumNames for #name {
+                const VARIANTS: &'static [(Self, &'static str)] = &[
+                    #( (Self::#variants, stringify!(#variants)) ),*
+                ];
+            }
+        );
+
+        return out.into();
+    }
+
+    panic!("EnumNames can only be derived for enums");
+}
+
 pub(crate) fn impl_pystruct(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as syn::ItemStruct);
 
@@ -182,7 +207,7 @@ pub(crate) fn impl_pyenum(input: TokenStream) -> TokenStream {
         panic!("Enums with generics are not supported");
     }
 
-    let variants = variants.clone().into_iter().map(|v| v);
+    let variants = variants.clone().into_iter();
     let mut names = Vec::new();
     let mut values = Vec::new();
     let mut actual = 0i64;
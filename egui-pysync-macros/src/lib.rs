@@ -17,6 +17,11 @@ mod enums;
 //     enums::enum_impl_derive_impl(input)
 // }
 
+#[proc_macro_derive(EnumNames)]
+pub fn enum_names_derive(input: TokenStream) -> TokenStream {
+    enums::enum_names_derive_impl(input)
+}
+
 #[proc_macro_attribute]
 pub fn pystruct(_: TokenStream, input: TokenStream) -> TokenStream {
     enums::impl_pystruct(input)
@@ -1,5 +1,7 @@
+import csv
 from collections.abc import Callable
 from types import ModuleType
+from typing import Any, Self
 
 from egui_pysync.signals import SignalsManager
 from egui_pysync.typing import SteteServerCoreBase
@@ -17,7 +19,12 @@ def _initialize_states(obj, server: SteteServerCoreBase, signals_manager: Signal
 
 
 class StateServer[T: _MainStatesBase]:
-    """The main class for the SteteServer for UI."""
+    """The main class for the SteteServer for UI.
+
+    The server keeps the actual content of all values. Values can be set at any time, also before the server
+    is started or when no client is connected. When a client connects, the messages waiting in the queue are
+    dropped and the client gets the actual content of all values instead, so nothing set before is lost.
+    """
 
     def __init__(
         self,
@@ -26,14 +33,40 @@ class StateServer[T: _MainStatesBase]:
         port: int,
         signals_workers: int = 3,
         error_handler: Callable[[Exception], None] | None = None,
-        ip_addr: tuple[int, int, int, int] | None = None,
+        ip_addr: tuple[int, int, int, int] | str | None = None,
         handshake: list[int] | None = None,
+        read_only: list[int] | None = None,
+        socket_path: str | None = None,
+        psk: bytes | None = None,
+        creator: str | None = None,
     ) -> None:
-        """Initialize the SteteServer."""
+        """Initialize the SteteServer.
+
+        Clients connecting with a hash from the read only list can only receive values, all their writes
+        are rejected. Read only hashes are allowed to connect also if they are not in the handshake list.
+        The handshake list can be replaced by the callback validating the auth token, see set_authenticator.
+        Independently of the hashes, the clients have to register the same values with the same types
+        as the core module, otherwise they are rejected.
+
+        If the socket path is set, the server listens on the Unix domain socket with this path instead
+        of the TCP port, the port and the ip address are ignored. Available only on Unix platforms. The stale
+        socket left at the path is replaced, the server fails to start if the path is used by another server
+        or it is not a socket.
+
+        The ip address can be also a string with the IPv4 or IPv6 address or the host name, which is resolved
+        when the server starts listening, for example "::" for all IPv6 interfaces or "my-instrument.local".
+
+        If the pre-shared key (32 bytes) is set, the whole connection is encrypted and only the clients with
+        the same key can connect. The core module has to be built with the encryption feature.
+
+        If the creator is set, the values are created by the function registered with this name in the core
+        module instead of the default one, the state class has to match it. Every server has its own values,
+        so more servers with different UIs can run in one process on different ports.
+        """
         core_server_class: type[SteteServerCoreBase] = getattr(core_module, "StateServerCore")
-        self._server = core_server_class(port, ip_addr, handshake)
+        self._server = core_server_class(port, ip_addr, handshake, read_only, socket_path, psk, creator)
         self._signals_manager = SignalsManager(self._server, signals_workers, error_handler)
-        self._states: T = state_class(self._server.update)
+        self._states: T = state_class(self._server.update, self._server.reserved_ids())
 
         _initialize_states(self._states, self._server, self._signals_manager)
         self.error = ErrorSignal(self._signals_manager)
@@ -61,6 +94,72 @@ class StateServer[T: _MainStatesBase]:
         """Stop the state server."""
         self._server.stop()
 
+    def shutdown(self, timeout: float | None = 5.0) -> bool:
+        """Shut down the state server and wait until all its connections are closed.
+
+        The listening socket is closed, the messages already sent to the UIs are written until the timeout
+        and then all UIs are disconnected. The server can not be started again. It can be registered to be
+        called at the exit of the interpreter, for example `atexit.register(server.shutdown)`.
+
+        Args:
+            timeout(float | None, optional): The timeout in seconds for writing of the messages. If None,
+                wait without limit. Defaults to 5 seconds.
+
+        Returns:
+            bool: False if the messages were not written before the timeout, True otherwise.
+        """
+        return self._server.shutdown(timeout)
+
+    def close(self, timeout: float | None = 5.0) -> bool:
+        """Shut down the state server and stop the signals threads.
+
+        All UIs are disconnected, the threads of the connections are joined and the port is released, so another
+        server can listen on it right away. The server is closed also at the end of the `with` block:
+
+            with StateServer(States, core, 8081) as server:
+                ...
+
+        Args:
+            timeout(float | None, optional): The timeout in seconds for writing of the messages. If None,
+                wait without limit. Defaults to 5 seconds.
+
+        Returns:
+            bool: False if the messages were not written before the timeout, True otherwise.
+        """
+        flushed = self._server.close(timeout)
+        self._signals_manager.stop_manager()
+        return flushed
+
+    def __enter__(self) -> Self:
+        """Start the state server."""
+        self._server.start()
+        return self
+
+    def __exit__(self, exc_type: Any, exc_value: Any, traceback: Any) -> None:
+        """Close the state server."""
+        self.close()
+
+    def set_address(
+        self, ip_addr: tuple[int, int, int, int] | str | None, port: int, socket_path: str | None = None
+    ) -> None:
+        """Change the address where the server listens.
+
+        It can be changed only while the server is stopped, the next start listens on the new address.
+        The address is handled in the same way as in the initialization. If the address is not available
+        at the start, for example the port is taken, the server is stopped and the error is sent as the error
+        signal, so another address can be set.
+
+        Args:
+            ip_addr(tuple[int, int, int, int] | str | None): The ip address or the host name. If None, localhost
+                is used.
+            port(int): The port of the server.
+            socket_path(str | None, optional): The path of the Unix domain socket used instead of the port.
+
+        Raises:
+            ValueError: If the server is running.
+        """
+        self._server.set_address(ip_addr, port, socket_path)
+
     def disconnect_client(self) -> None:
         """Disconnect actual client."""
         self._server.disconnect_client()
@@ -73,6 +172,400 @@ class StateServer[T: _MainStatesBase]:
         """If client is connected to the state server."""
         return self._server.is_connected()
 
+    def ping(self) -> None:
+        """Send the ping to the client to measure the round trip time."""
+        self._server.ping()
+
+    def last_rtt(self) -> float | None:
+        """Get the round trip time of the last ping in seconds. None if no pong was received yet."""
+        return self._server.last_rtt()
+
+    def latency_ms(self) -> float | None:
+        """Get the average round trip time of the last 8 pings in milliseconds.
+
+        It changes smoothly, so it can be shown as the quality of the connection. The pings are sent by
+        the ping method or by the heartbeat. None if no pong was received yet.
+        """
+        return self._server.latency_ms()
+
+    def set_heartbeat(self, interval: float | None) -> None:
+        """Send the ping to the UI if nothing was sent for the interval.
+
+        If nothing is received for three intervals, the UI is disconnected, so dead connections are
+        detected in seconds. It is applied to the next connection.
+
+        Args:
+            interval(float | None): The interval in seconds. If None, the heartbeat is disabled.
+        """
+        self._server.set_heartbeat(interval)
+
+    def set_ack_timeout(self, timeout: float | None) -> None:
+        """Accept the value from the UI if the acknowledge of the value sent to the UI did not come in time.
+
+        The UI acknowledges every received value and its writes are ignored until all its acknowledges come,
+        so the UI can not overwrite the newer value. If a message is lost, the writes of the value would be
+        ignored until the next connection. After the timeout the write is accepted and sent back to the UI,
+        so both sides have the same value again. The error signal is emitted for every timeout.
+
+        Args:
+            timeout(float | None): The timeout in seconds. If None, the writes wait for the acknowledges
+                without limit, which is the default.
+        """
+        self._server.set_ack_timeout(timeout)
+
+    def start_trace(self, path: str, payload: bool = False) -> None:
+        """Start recording of all messages sent and received by this process to the file.
+
+        Every message is one line with the time, direction, type, flags, id and size of the data.
+        The file is truncated.
+
+        Args:
+            path(str): Path of the trace file.
+            payload(bool): Include the hex dump of the data of the messages.
+        """
+        self._server.start_trace(path, payload)
+
+    def stop_trace(self) -> None:
+        """Stop recording of the messages and flush the trace file."""
+        self._server.stop_trace()
+
+    def set_tcp_options(
+        self,
+        nodelay: bool = False,
+        send_buffer: int | None = None,
+        recv_buffer: int | None = None,
+        keepalive: float | None = None,
+    ) -> None:
+        """Set the options of the TCP socket of the UI connection.
+
+        It is applied to the next connection and it is ignored for the Unix domain socket.
+
+        Args:
+            nodelay(bool): Disable the Nagle algorithm, so the small value updates are sent
+                immediately.
+            send_buffer(int | None): Size of the send buffer in bytes. If None, the system default
+                is used.
+            recv_buffer(int | None): Size of the receive buffer in bytes. If None, the system
+                default is used.
+            keepalive(float | None): Idle time in seconds before the keepalive probes are sent. If
+                None, the keepalive is disabled.
+        """
+        self._server.set_tcp_options(nodelay, send_buffer, recv_buffer, keepalive)
+
+    def set_max_message_size(self, size: int) -> None:
+        """Set the limit of the data size of one message received from the UI.
+
+        Larger messages are skipped without reading them to the memory, the error is reported to the error
+        signal and to the UI and the connection continues. It is applied to the next connection.
+
+        Args:
+            size(int): The limit in bytes, 256 MiB by default.
+        """
+        self._server.set_max_message_size(size)
+
+    def set_queue_limit(self, size: int) -> None:
+        """Set the limit of the queued data of the images and graphs waiting to be sent to the UI.
+
+        If the UI is slower than the updates, the image frames replaced by a newer frame are dropped first,
+        then all queued messages of the oldest image or graph, so the queue does not grow without the limit.
+        Such image or graph is sent again whole, when the queue is half empty. Values and commands are never
+        dropped. It is applied to the next connection.
+
+        Args:
+            size(int): The limit in bytes, 64 MiB by default.
+        """
+        self._server.set_queue_limit(size)
+
+    def set_broadcast(self, enabled: bool) -> None:
+        """Allow several UIs to be connected at the same time.
+
+        If enabled, the new UI does not disconnect the previous ones. All UIs get all values and the value
+        set by one UI is applied and sent to the other UIs. If disabled (default), the new UI disconnects
+        the previous one. It is applied to the next connection. The latency and the client info are
+        measured across all connected UIs.
+
+        Args:
+            enabled(bool): If the UIs are connected at the same time.
+        """
+        self._server.set_broadcast(enabled)
+
+    def on_disconnect(self, callback: Callable[[str | None, str | None], None] | None) -> None:
+        """Set the callback which is called when the UI disconnects.
+
+        It is called for clean disconnects and for connection errors from a separate thread. It can be
+        used to release the resources owned by the UI, for example to reset values to defaults.
+
+        Args:
+            callback(Callable[[str | None, str | None], None] | None): The callback with the address of
+                the UI and the last error, which is None for clean disconnects. If None, the callback is removed.
+        """
+        self._server.on_disconnect(callback)
+
+    def set_authenticator(self, callback: Callable[[int, str | None, str | None], bool] | None) -> None:
+        """Set the callback which decides if the UI is allowed to connect.
+
+        It replaces the handshake list, the read only hashes are still not allowed to write values. It is
+        called from the server thread after the version and the values of the UI are checked, so the next
+        UI waits until it returns. If it returns False or raises an exception, the UI is rejected.
+
+        Args:
+            callback(Callable[[int, str | None, str | None], bool] | None): The callback with the handshake
+                hash, the auth token and the address of the UI. If None, the handshake list is used again.
+        """
+        self._server.set_authenticator(callback)
+
+    def set_handshake(self, handshake: list[int] | None, read_only: list[int] | None = None) -> None:
+        """Replace the handshake and the read only lists given in the initialization.
+
+        It can be changed while the server runs, for example to rotate the hashes of the UI builds. The lists are
+        checked only when the UI connects, the connected UI is not disconnected.
+
+        Args:
+            handshake(list[int] | None): The allowed hashes. If None, all hashes are allowed.
+            read_only(list[int] | None, optional): The hashes which can only receive values. Defaults to None.
+        """
+        self._server.set_handshake(handshake, read_only)
+
+    def handshake_add(self, hash: int, read_only: bool = False) -> None:
+        """Allow the UI with the hash to connect.
+
+        If all hashes are allowed (the handshake list is None), the list is not changed, only the read only hash
+        is added.
+
+        Args:
+            hash(int): The handshake hash.
+            read_only(bool, optional): Add the hash to the read only list. Defaults to False.
+        """
+        self._server.handshake_add(hash, read_only)
+
+    def handshake_remove(self, hash: int) -> None:
+        """Remove the hash from the handshake and the read only lists.
+
+        The connected UI with the hash is not disconnected.
+
+        Args:
+            hash(int): The handshake hash.
+        """
+        self._server.handshake_remove(hash)
+
+    def handshake(self) -> tuple[list[int] | None, list[int]]:
+        """Get the current handshake and read only lists.
+
+        Returns:
+            tuple[list[int] | None, list[int]]: The allowed hashes, None if all hashes are allowed, and the read
+                only hashes.
+        """
+        return self._server.handshake()
+
+    def client_info(self) -> tuple[float, float, float] | None:
+        """Get the viewport of the connected UI.
+
+        The UI sends it after the connection and every time it changes.
+
+        Returns:
+            tuple[float, float, float] | None: The width and height in points and the pixels per point.
+                None if no UI is connected or it was not received yet.
+        """
+        return self._server.client_info()
+
+    def stats(self) -> dict[str, Any]:
+        """Get the counters of the messages sent to and received from all UIs since the server was created.
+
+        The bytes are counted as they are on the wire, with the head of the message and after the compression.
+
+        Returns:
+            dict[str, Any]: The "types" dict with a dict of messages_sent, bytes_sent, messages_received and
+                bytes_received for every message type, for example "value" or "image", the "queue_depth"
+                with the number of messages waiting in the writer to be written and the "dropped" with
+                the number of images and graphs dropped from the full queue.
+        """
+        return self._server.stats()
+
+    def errors(self, since: int = 0) -> list[dict[str, Any]]:
+        """Get the last errors of the server, the oldest first.
+
+        Every error is a dict with the "severity" ("warning" if the connection continues, "error" if it was
+        closed or could not be established), the "source" ("connection", "handshake", "transport", "value",
+        "callback" or "remote" for the errors reported by the UI), the "message", the "value_id" or None,
+        the "sequence" number and the unix "time". Only the last 100 errors are kept. The message of every
+        error is also sent as the error signal.
+
+        Args:
+            since(int, optional): Return only the errors with this sequence number or newer, for example
+                the last seen sequence number plus one. Defaults to 0.
+
+        Returns:
+            list[dict[str, Any]]: The errors.
+        """
+        return self._server.errors(since)
+
+    def on_error(self, callback: Callable[[dict[str, Any]], Any] | None) -> None:
+        """Set the callback which is called with every new error.
+
+        The callback gets the same dict as the errors method. It is called from a dedicated thread with
+        the GIL acquired for every call, the exception raised from the callback is only printed.
+
+        Args:
+            callback(Callable[[dict[str, Any]], Any] | None): The callback. If None, the callback is removed.
+        """
+        self._server.on_error(callback)
+
+    def set_audit(self, capacity: int | None) -> None:
+        """Enable the log of the value changes.
+
+        Every accepted change of a value is recorded, the values set from python and the values written
+        by the UI, only the last records up to the capacity are kept. Dicts, lists and other structures
+        are not recorded. The log is disabled by default.
+
+        Args:
+            capacity(int | None): The maximum number of kept records. If None, the log is disabled and all
+                records are dropped.
+        """
+        self._server.set_audit(capacity)
+
+    def audit_log(self, since: int = 0) -> list[dict[str, Any]]:
+        """Get the recorded value changes, the oldest first.
+
+        Every record is a dict with the "sequence" number, the unix "time", the "value_id", the "source"
+        ("server" for the values set from python, "client" for the values written by the UI) and the "old"
+        and the "new" value.
+
+        Args:
+            since(int, optional): Return only the records with this sequence number or newer. Defaults to 0.
+
+        Returns:
+            list[dict[str, Any]]: The records.
+        """
+        return self._server.audit_log(since)
+
+    def export_audit_csv(self, path: str, since: int = 0) -> None:
+        """Write the recorded value changes to the csv file with the header.
+
+        The columns are the same as the keys of the audit_log records, the values are written by their repr.
+
+        Args:
+            path(str): The path of the file, it is overwritten.
+            since(int, optional): Write only the records with this sequence number or newer. Defaults to 0.
+        """
+        columns = ("sequence", "time", "value_id", "source", "old", "new")
+        with open(path, "w", newline="") as file:
+            writer = csv.writer(file)
+            writer.writerow(columns)
+            for record in self._server.audit_log(since):
+                record["old"] = repr(record["old"])
+                record["new"] = repr(record["new"])
+                writer.writerow([record[column] for column in columns])
+
+    def health(self) -> dict[str, Any]:
+        """Get the state of the server for the monitoring, without parsing of the error signals.
+
+        The message rates are measured since the previous call, the first call measures them since the server
+        was created.
+
+        Returns:
+            dict[str, Any]: The "running" and "connected" flags, the "queue_depth" with the number of messages
+                waiting in the writer, the "last_error" with the last message of the error signal or None,
+                the "messages_sent_per_sec" and "messages_received_per_sec" rates and the "uptime" in seconds
+                since the start, None if the server is not running.
+        """
+        return self._server.health()
+
+    def wait_idle(self, timeout: float | None = None) -> bool:
+        """Wait until all messages sent to the UI so far are written.
+
+        It can be used to produce the next frame only when the previous one was sent instead of
+        flooding the queue. If no UI is connected, it returns immediately.
+
+        Args:
+            timeout(float | None, optional): The timeout in seconds. If None, wait without limit.
+
+        Returns:
+            bool: False if the timeout expired or the connection was lost, True otherwise.
+        """
+        return self._server.wait_idle(timeout)
+
+    def wire_type(self, value_id: int) -> tuple[int, str, str, str]:
+        """Get how the value with given id is sent on the wire.
+
+        Args:
+            value_id(int): The id of the value.
+
+        Returns:
+            tuple[int, str, str, str]: The type byte of the message head, the kind of the value, the rust type
+                of the value or of its elements and the encoding of the data ("postcard", "raw" or "bits").
+        """
+        return self._server.value_wire_type(value_id)
+
+    def group_ids(self, group: str) -> list[int]:
+        """Get the ids of all values in the group.
+
+        Groups are set on the rust side when the values are created.
+
+        Args:
+            group(str): The name of the group.
+
+        Returns:
+            list[int]: The ids in the order of creation.
+        """
+        return self._server.group_ids(group)
+
+    def reset_group(self, group: str, update: bool = False) -> None:
+        """Reset all values and static values in the group to their initial values.
+
+        Other members of the group are skipped. The values are set in the same way as from python,
+        but the signals are not emitted.
+
+        Args:
+            group(str): The name of the group.
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        self._server.group_reset(group, update)
+
+    def sync_group(self, group: str) -> None:
+        """Send the current content of all members of the group to the UI again.
+
+        Nothing is sent if no UI is connected, all values are synced after the connection.
+
+        Args:
+            group(str): The name of the group.
+        """
+        self._server.group_sync(group)
+
+    def dump_state_json(self) -> str:
+        """Dump the current content of all values to a json string.
+
+        Values are dumped by their ids. Images are dumped only by their size and graphs by their points
+        counts. It is intended for debugging and inspecting the server state.
+        """
+        return self._server.dump_state_json()
+
+    def save_state(self, path: str) -> None:
+        """Save the current content of all values, dicts and lists to the file.
+
+        The file is replaced only when it is completely written, so the previous state is kept if the process
+        crashes while saving. Static values, images, graphs and bitsets are not saved.
+
+        Args:
+            path(str): The path of the file.
+        """
+        self._server.save_state(path)
+
+    def load_state(self, path: str) -> None:
+        """Load the content of the values, dicts and lists saved by save_state.
+
+        The values are set in the same way as from python, including the properties and the allowed values,
+        and they are sent to the connected UI. It can be called also before the server is started, for example
+        to restore the UI after a crash. The file has to be saved by the server with the same values.
+
+        Args:
+            path(str): The path of the file.
+
+        Raises:
+            ValueError: If the file is not valid or it was saved with different values.
+            OSError: If the file can not be read.
+        """
+        self._server.load_state(path)
+
     def set_error_handler(self, error_handler: Callable[[Exception], None] | None) -> None:
         """Set the error handler.
 
@@ -84,6 +577,18 @@ class StateServer[T: _MainStatesBase]:
         """
         self._signals_manager.set_error_handler(error_handler)
 
+    def set_signals_workers(self, workers: int) -> None:
+        """Change the number of the threads calling the signal callbacks.
+
+        The callbacks of one value are always called in order by one thread at a time, the callbacks of
+        different values run concurrently, so a slow callback blocks only its own value. The removed threads
+        stop after their current callback.
+
+        Args:
+            workers(int): The number of the threads, at least one.
+        """
+        self._signals_manager.set_workers(workers)
+
     def check_workers(self) -> None:
         """Check all workers threads and restart them if they are stopped."""
         self._signals_manager.check_workers()
@@ -20,28 +20,65 @@ class SignalsManager:
         self._server = server
 
         self._workers_count = workers
-        self._workers: list[threading.Thread] = []
+        # ids of the retired workers are not reused, they can still finish their last callback
+        self._workers: dict[int, threading.Thread] = {}
+        self._next_id = 0
+        self._started = False
         self._error_handler = error_handler or self._default_error_handler
 
+    def _start_worker(self, thread_id: int) -> None:
+        worker = threading.Thread(
+            target=self._run, args=(thread_id,), daemon=True, name=f"signals_worker_{thread_id}"
+        )
+        self._workers[thread_id] = worker
+        worker.start()
+
     def start_manager(self) -> None:
         """Start the signals manager."""
-        for i in range(self._workers_count):
-            worker = threading.Thread(target=self._run, args=(i,), daemon=True, name=f"signals_worker_{i}")
-            self._workers.append(worker)
-            worker.start()
+        self._started = True
+        for _ in range(self._workers_count):
+            self._start_worker(self._next_id)
+            self._next_id += 1
+
+    def set_workers(self, workers: int) -> None:
+        """Change the number of the workers.
+
+        The removed workers stop after their current callback, the values with the same id continue on other
+        workers in order.
+        """
+        if workers < 1:
+            raise ValueError("At least one worker is required.")
+        self._workers_count = workers
+        if not self._started:
+            return
+
+        while len(self._workers) < workers:
+            self._start_worker(self._next_id)
+            self._next_id += 1
+        while len(self._workers) > workers:
+            thread_id = max(self._workers)
+            del self._workers[thread_id]
+            self._server.signals_retire_worker(thread_id)
+
+    def stop_manager(self) -> None:
+        """Stop all workers after their current callback, they can be started again by start_manager."""
+        self._started = False
+        for thread_id in list(self._workers):
+            del self._workers[thread_id]
+            self._server.signals_retire_worker(thread_id)
 
     def check_workers(self) -> None:
         """Check the workers. If a worker is not alive, restart it."""
-        for i, worker in enumerate(self._workers):
+        for thread_id, worker in list(self._workers.items()):
             if not worker.is_alive():
-                self._workers[i] = threading.Thread(
-                    target=self._run, args=(i,), daemon=True, name=f"signals_worker_{i}"
-                )
-                self._workers[i].start()
+                self._start_worker(thread_id)
 
     def _run(self, thread_id) -> None:
         while True:
-            ind, arg = self._server.value_get_signal(thread_id)
+            signal = self._server.value_get_signal(thread_id)
+            if signal is None:
+                return
+            ind, arg = signal
             callbacks = self._callbacks.get(ind, None)
             if callbacks:
                 for callback in callbacks:
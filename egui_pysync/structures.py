@@ -1,6 +1,6 @@
 # ruff: noqa: D107
 from abc import ABC, abstractmethod
-from collections.abc import Buffer, Callable
+from collections.abc import Buffer, Callable, Iterable
 from typing import Any
 
 import numpy as np
@@ -10,12 +10,24 @@ from egui_pysync.typing import SteteServerCoreBase
 
 
 class _Counter:
-    def __init__(self) -> None:
-        self._counter = 9  # first 10 values are reserved for system signals
+    def __init__(self, reserved: int = 10, namespace: int = 0) -> None:
+        if not 0 <= namespace <= 255:
+            raise ValueError("Namespace must be in the range 0 to 255.")
+        self._reserved = reserved
+        self._namespace = namespace << 24  # the highest byte of the id
+        self._counter = reserved - 1  # first values are reserved for system signals
 
     def get_id(self) -> int:
         self._counter += 1
-        return self._counter
+        return self._namespace | self._counter
+
+    def namespace(self, namespace: int) -> "_Counter":
+        """Get the counter of the namespace, it has to be the same as in the ServerValuesCreator.
+
+        The states created with the counter use their own ids, so the states of independent packages
+        can share one connection. Every call starts the namespace from the beginning.
+        """
+        return _Counter(self._reserved, namespace)
 
 
 class _StatesBase:
@@ -24,7 +36,7 @@ class _StatesBase:
 
 class _MainStatesBase(_StatesBase, ABC):
     @abstractmethod
-    def __init__(self, update: Callable[[float | None], None]) -> None:
+    def __init__(self, update: Callable[[float | None], None], reserved: int = 10) -> None:
         pass
 
 
@@ -97,6 +109,102 @@ class Value[T](_ValueBase):
         """
         return self._server.value_get(self._value_id)
 
+    def last_sync(self) -> float | None:
+        """Get the seconds since the value was last sent to the UI.
+
+        Available only if the library is built with the sync-time feature.
+
+        Returns:
+            float | None: The seconds since the last sync or None if the value was not sent yet.
+        """
+        return self._server.value_last_sync(self._value_id)
+
+    def set_allowed_values(self, values: list[T] | None) -> None:
+        """Set the values which are allowed for the value.
+
+        Setting a value outside the allowed values raises an error. If the UI sends a value outside the allowed
+        values, it is rejected and the current value is sent back to the UI.
+
+        Args:
+            values(list[T] | None): The allowed values. If None, all values are allowed.
+        """
+        self._server.value_set_allowed(self._value_id, values)
+
+    def set_thresholds(self, thresholds: list[float] | None, hysteresis: float = 0.0) -> None:
+        """Send the value to the UI only when it crosses one of the thresholds.
+
+        The value has to move over the threshold by the hysteresis to be sent, so noise around
+        the threshold is not sent. The server value is always updated, so the UI value lags behind
+        the server value between the thresholds. Only for numeric values.
+
+        Args:
+            thresholds(list[float] | None): The thresholds. If None, every set is sent to the UI.
+            hysteresis(float, optional): The hysteresis around the thresholds. Defaults to 0.0.
+        """
+        self._server.value_set_thresholds(self._value_id, thresholds, hysteresis)
+
+    def set_send_rate(self, rate: float | None) -> None:
+        """Limit how often the value is sent to the UI.
+
+        Values set faster than the rate are not sent, but the server value is always updated. The latest
+        value is sent at the end of the interval, so the UI ends with the same value as the server.
+
+        Args:
+            rate(float | None): The maximal number of sends per second. If None, every set is sent to the UI.
+        """
+        self._server.value_set_send_rate(self._value_id, rate)
+
+    def bind_property(
+        self, getter: Callable[[], T] | None, setter: Callable[[T], Any] | None = None
+    ) -> None:
+        """Bind the value to a python property.
+
+        The getter is called on every get and when the value is synchronized with the UI (on connection).
+        The setter is called on every set and when the UI changes the value. Changes of the python object
+        itself are not sent to the UI automatically, call set or the UI has to reconnect.
+
+        Args:
+            getter(Callable[[], T] | None): The getter of the property. If None, the value is not read from it.
+            setter(Callable[[T], Any] | None, optional): The setter of the property. Defaults to None.
+        """
+        self._server.value_set_property(self._value_id, getter, setter)
+
+    def set_inbound_transform(self, transform: Callable[[T], T] | None) -> None:
+        """Set the function which is applied to every value from the UI before it is stored.
+
+        It can be used to normalize the value (trim, round, clamp). The transformed value is stored
+        and emitted as the signal. If the transform changed the value, it is sent back to the UI.
+
+        Args:
+            transform(Callable[[T], T] | None): The transform function. If None, the value is stored as it is.
+        """
+        self._server.value_set_transform(self._value_id, transform)
+
+    def set_validator(self, validator: Callable[[T], str | None] | None) -> None:
+        """Set the validator of the values from the UI.
+
+        The validator rejects the value by returning the error message or by raising an exception.
+        The rejected value is not stored, the current value is sent back to the UI and the error
+        is sent as the error signal.
+
+        Args:
+            validator(Callable[[T], str | None] | None): The validator function. If None, all values are accepted.
+        """
+        self._server.value_set_validator(self._value_id, validator)
+
+    def on_change(self, callback: Callable[[T], Any] | None) -> None:
+        """Set the callback which is called when the UI changes the value.
+
+        The callback is called with the new value after it is applied, after the transform and the validator.
+        The values set from python do not call it. It is called from a dedicated thread with the GIL acquired
+        for every call, so it does not need the signals workers and the values are not polled. The exception
+        raised from the callback is sent as the error signal.
+
+        Args:
+            callback(Callable[[T], Any] | None): The callback with the new value. If None, the callback is removed.
+        """
+        self._server.value_on_change(self._value_id, callback)
+
     def connect(self, callback: Callable[[T], Any]) -> None:
         """Connect a callback to the value.
 
@@ -138,6 +246,16 @@ class ValueStatic[T](_StaticBase):
         """
         return self._server.static_get(self._value_id)
 
+    def last_sync(self) -> float | None:
+        """Get the seconds since the static value was last sent to the UI.
+
+        Available only if the library is built with the sync-time feature.
+
+        Returns:
+            float | None: The seconds since the last sync or None if the value was not sent yet.
+        """
+        return self._server.static_last_sync(self._value_id)
+
 
 class Signal[T](_ValueBase):
     """Signal from UI."""
@@ -242,9 +360,44 @@ class ValueImage(_StaticBase):
         """
         return self._server.image_size(self._value_id)
 
+    def set_preview(self, max_size: int | None) -> None:
+        """Set the preview of the image.
+
+        If set, a downscaled preview of the image is sent before every full image, so the UI can show it
+        until the full image is received. The preview is sent only if the image is bigger than the max size.
+
+        Args:
+            max_size(int | None): The maximal size of the longer side of the preview. None disables the preview.
+        """
+        self._server.image_set_preview(self._value_id, max_size)
+
+    def resend(self, update: bool = False) -> None:
+        """Send the current image to the UI again.
+
+        The last image is kept also when the UI is not connected and it is sent automatically after
+        the UI connects. This can be used to send it again, for example after it was skipped.
 
-class ValueDict[K, V](_StaticBase):
-    """Dict UI element."""
+        Args:
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        self._server.image_resend(self._value_id, update)
+
+    def set_max_fps(self, fps: float | None) -> None:
+        """Set the maximal frame rate of the image updates.
+
+        Images set faster than the frame rate are not sent to the UI. The last skipped image is sent
+        after the frame interval, so the UI always shows the latest image.
+
+        Args:
+            fps(float | None): The maximal number of images sent per second. None disables the limit.
+        """
+        self._server.image_set_max_fps(self._value_id, fps)
+
+
+class ValueDict[K, V](_ValueBase):
+    """Dict UI element. The UI can change the dict too if it is added by `add_dict_writable` on both sides,
+    the changes are kept in the server dict. The change which crosses the change of the server is dropped and
+    the UI gets the whole dict again."""
 
     def set(self, value: dict[K, V], update: bool = False) -> None:
         """Set the dict in the UI dict.
@@ -293,6 +446,22 @@ class ValueDict[K, V](_StaticBase):
         """
         self._server.dict_item_del(self._value_id, key, update)
 
+    def rename_item(self, old: K, new: K, overwrite: bool = False, update: bool = False) -> None:
+        """Rename the key of the item in the UI dict and keep its value.
+
+        The key is changed in a single message, so the item is never missing in the UI.
+
+        Args:
+            old(K): The current key of the item.
+            new(K): The new key of the item.
+            overwrite(bool, optional): Whether to replace the item with the new key if it exists. Defaults to False.
+            update(bool, optional): Whether to update the UI. Defaults to False.
+
+        Raises:
+            KeyError: If the old key is not in the dict or the new key exists and overwrite is False.
+        """
+        self._server.dict_item_rename(self._value_id, old, new, update, overwrite)
+
     def __getitem__(self, key: K) -> V:
         """Get the item in the UI dict."""
         return self.get_item(key)
@@ -305,9 +474,35 @@ class ValueDict[K, V](_StaticBase):
         """Remove the item from the UI dict."""
         self.remove_item(key, update=False)
 
+    def connect(self, callback: Callable[[tuple[str, Any]], Any]) -> None:
+        """Connect a callback to the changes of the dict made by the UI.
+
+        The callback gets the tuple of the action and the key, the actions are "all" with None, "set" and
+        "remove" with the key and "rename" with the tuple of the old and the new key. The callback is called
+        only if the UI sets the signal with the change.
+
+        Args:
+            callback(Callable[[tuple[str, Any]], Any]): The callback to connect.
+        """
+        self._signals_manager.add_callback(self._value_id, callback)
+
+    def disconnect(self, callback: Callable[[tuple[str, Any]], Any]) -> None:
+        """Disconnect a callback from the UI dict.
+
+        Args:
+            callback(Callable[[tuple[str, Any]], Any]): The callback to disconnect.
+        """
+        self._signals_manager.remove_callback(self._value_id, callback)
+
+    def disconnect_all(self) -> None:
+        """Disconnect all callbacks from the UI dict."""
+        self._signals_manager.clear_callbacks(self._value_id)
+
 
-class ValueList[T](_StaticBase):
-    """List UI element."""
+class ValueList[T](_ValueBase):
+    """List UI element. The UI can change the list too if it is added by `add_list_writable` on both sides,
+    the changes are kept in the server list. The change which crosses the change of the server is dropped and
+    the UI gets the whole list again."""
 
     def set(self, value: list[T], update: bool = False) -> None:
         """Set the list in the UI list.
@@ -365,6 +560,26 @@ class ValueList[T](_StaticBase):
         """
         self._server.list_item_add(self._value_id, value, update)
 
+    def move_item(self, from_idx: int, to_idx: int, update: bool = False) -> None:
+        """Move the item in the UI list. Only the indexes are sent, not the whole list.
+
+        Args:
+            from_idx(int): The current index of the item.
+            to_idx(int): The index of the item after the move.
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        self._server.list_item_move(self._value_id, from_idx, to_idx, update)
+
+    def swap(self, i: int, j: int, update: bool = False) -> None:
+        """Swap two items in the UI list. Only the indexes are sent, not the whole list.
+
+        Args:
+            i(int): The index of the first item.
+            j(int): The index of the second item.
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        self._server.list_swap(self._value_id, i, j, update)
+
     def __getitem__(self, idx: int) -> T:
         """Get the item in the UI list."""
         return self.get_item(idx)
@@ -373,6 +588,84 @@ class ValueList[T](_StaticBase):
         """Set the item in the UI list."""
         self.set_item(idx, value, update=False)
 
+    def connect(self, callback: Callable[[tuple[str, Any]], Any]) -> None:
+        """Connect a callback to the changes of the list made by the UI.
+
+        The callback gets the tuple of the action and the index, the actions are "all" with None, "set", "add"
+        and "remove" with the index and "move" and "swap" with the tuple of the two indexes. The callback is
+        called only if the UI sets the signal with the change.
+
+        Args:
+            callback(Callable[[tuple[str, Any]], Any]): The callback to connect.
+        """
+        self._signals_manager.add_callback(self._value_id, callback)
+
+    def disconnect(self, callback: Callable[[tuple[str, Any]], Any]) -> None:
+        """Disconnect a callback from the UI list.
+
+        Args:
+            callback(Callable[[tuple[str, Any]], Any]): The callback to disconnect.
+        """
+        self._signals_manager.remove_callback(self._value_id, callback)
+
+    def disconnect_all(self) -> None:
+        """Disconnect all callbacks from the UI list."""
+        self._signals_manager.clear_callbacks(self._value_id)
+
+
+class ValueBitset(_StaticBase):
+    """Bitset UI element. Bits are packed, so it is more compact than a list of bools."""
+
+    def set(self, values: Iterable[bool], update: bool = False) -> None:
+        """Set all bits in the UI bitset.
+
+        Args:
+            values(Iterable[bool]): The bits to set.
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        self._server.bitset_set(self._value_id, values, update)
+
+    def get(self) -> list[bool]:
+        """Get all bits in the UI bitset.
+
+        Returns:
+            list[bool]: The bits in the UI bitset.
+        """
+        return self._server.bitset_get(self._value_id)
+
+    def set_bit(self, idx: int, value: bool, update: bool = False) -> None:
+        """Set the bit in the UI bitset. Only the changed bit is sent to the UI.
+
+        Args:
+            idx(int): The index of the bit.
+            value(bool): The value of the bit.
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        self._server.bitset_bit_set(self._value_id, idx, value, update)
+
+    def get_bit(self, idx: int) -> bool:
+        """Get the bit in the UI bitset.
+
+        Args:
+            idx(int): The index of the bit.
+
+        Returns:
+            bool: The value of the bit.
+        """
+        return self._server.bitset_bit_get(self._value_id, idx)
+
+    def __len__(self) -> int:
+        """Get the number of bits in the UI bitset."""
+        return self._server.bitset_len(self._value_id)
+
+    def __getitem__(self, idx: int) -> bool:
+        """Get the bit in the UI bitset."""
+        return self.get_bit(idx)
+
+    def __setitem__(self, idx: int, value: bool) -> None:
+        """Set the bit in the UI bitset."""
+        self.set_bit(idx, value, update=False)
+
 
 class Graph:
     """Graph UI element."""
@@ -400,6 +693,18 @@ class Graph:
         """Check if the graph is linear -> only Y axis."""
         return self._server.graphs_is_linear(self._value_id, self._idx)
 
+    def set_compressed(self, compressed: bool) -> None:
+        """Set whether the graph data are sent compressed with the float codec.
+
+        The codec stores only the changed bytes of every value, so it works well for smooth data.
+        Noisy data can be even larger than uncompressed.
+
+        Args:
+            compressed(bool): Whether to compress the graph data.
+        """
+        self._check()
+        self._server.graphs_set_compressed(self._value_id, self._idx, compressed)
+
     def len(self) -> int:
         """Get the length of the graph.
 
@@ -419,6 +724,26 @@ class Graph:
         self._check()
         self._server.graphs_add_points(self._value_id, self._idx, points, update)
 
+    def attach_source(self, source: Iterable[Buffer], interval: float, update: bool = False) -> None:
+        """Attach a source of points to the graph.
+
+        The source is polled in a server thread. Every interval the next item is taken from the source and
+        added to the graph the same way as with add_points. Polling stops when the source is exhausted,
+        when the graph is removed, when the server is stopped or closed or when an error occurs (the error
+        is sent to the error signal).
+
+        Args:
+            source(Iterable[Buffer]): The source of the points, for example a generator.
+            interval(float): The interval between two polls in seconds, it has to be positive.
+            update(bool, optional): Whether to update the UI after each poll. Defaults to False.
+
+        Raises:
+            RuntimeError: If the server thread for the source can not be started.
+            ValueError: If the interval is not positive.
+        """
+        self._check()
+        self._server.graphs_attach_source(self._value_id, self._idx, source, interval, update)
+
     def set(self, graph: Buffer, update: bool = False) -> None:
         """Set the graph to the UI graphs.
 
@@ -508,6 +833,60 @@ class ValueGraphs(_StaticBase):
         self._graphs[idx] = graph_obj
         return graph_obj
 
+    def set_shared(self, graph: Buffer, x_idx: int, idx: int | None = None, update: bool = False) -> Graph:
+        """Set the graph which takes the x values from the shared x axis.
+
+        Only the y values with shape (N,) are sent. Adding points to the graph sends only the new
+        y values. If the graph and the axis have different lengths, only the common part is shown.
+
+        Args:
+            graph(Buffer): The y values of the graph. Has to implement the buffer protocol (numpy array).
+            x_idx(int): The index of the shared x axis. The axis has to be set by set_axis first.
+            idx(int, optional): The index of the graph. If None, smallest available index is used. Defaults to None.
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        if idx is None:
+            idx = 0
+            while idx in self._graphs:
+                idx += 1
+
+        self._server.graphs_set_shared(self._value_id, idx, x_idx, graph, update)
+        if idx in self._graphs:
+            return self._graphs[idx]
+
+        graph_obj = Graph(self._value_id, idx, self._server)
+        self._graphs[idx] = graph_obj
+        return graph_obj
+
+    def set_axis(self, x_idx: int, axis: Buffer, update: bool = False) -> None:
+        """Set the shared x axis. All graphs with the axis are updated.
+
+        Args:
+            x_idx(int): The index of the shared x axis.
+            axis(Buffer): The x values with shape (N,). Has to implement the buffer protocol (numpy array).
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        self._server.graphs_set_axis(self._value_id, x_idx, axis, update)
+
+    def add_axis_points(self, x_idx: int, points: Buffer, update: bool = False) -> None:
+        """Add the x values to the shared x axis.
+
+        Args:
+            x_idx(int): The index of the shared x axis.
+            points(Buffer): The x values to add with shape (N,).
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        self._server.graphs_add_axis_points(self._value_id, x_idx, points, update)
+
+    def remove_axis(self, x_idx: int, update: bool = False) -> None:
+        """Remove the shared x axis. The axis must not be used by any graph.
+
+        Args:
+            x_idx(int): The index of the shared x axis.
+            update(bool, optional): Whether to update the UI. Defaults to False.
+        """
+        self._server.graphs_remove_axis(self._value_id, x_idx, update)
+
     def remove(self, graph: Graph, update: bool = False) -> None:
         """Remove the graph from the UI graphs.
 
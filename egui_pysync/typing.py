@@ -1,30 +1,102 @@
 # ruff: noqa: D101, D102, D107
-from collections.abc import Buffer
+from collections.abc import Buffer, Callable, Iterable
 from enum import Enum
-from typing import Any
+from typing import Any, Self
 
 
 class SteteServerCoreBase:
     def __init__(
-        self, port: int, ip_addr: tuple[int, int, int, int] | None = None, handshake: list[int] | None = None
+        self,
+        port: int,
+        ip_addr: tuple[int, int, int, int] | str | None = None,
+        handshake: list[int] | None = None,
+        read_only: list[int] | None = None,
+        socket_path: str | None = None,
+        psk: bytes | None = None,
+        creator: str | None = None,
     ) -> None: ...
     def start(self) -> None: ...
     def stop(self) -> None: ...
+    def shutdown(self, timeout: float | None = None) -> bool: ...
+    def close(self, timeout: float | None = 5.0) -> bool: ...
+    def __enter__(self) -> Self: ...
+    def __exit__(self, exc_type: Any = None, exc_value: Any = None, traceback: Any = None) -> bool: ...
+    def set_address(
+        self, ip_addr: tuple[int, int, int, int] | str | None, port: int, socket_path: str | None = None
+    ) -> None: ...
     def is_running(self) -> bool: ...
     def is_connected(self) -> bool: ...
+    def ping(self) -> None: ...
+    def last_rtt(self) -> float | None: ...
+    def latency_ms(self) -> float | None: ...
+    def stats(self) -> dict[str, Any]: ...
+    def health(self) -> dict[str, Any]: ...
+    def errors(self, since: int = 0) -> list[dict[str, Any]]: ...
+    def set_audit(self, capacity: int | None = None) -> None: ...
+    def audit_log(self, since: int = 0) -> list[dict[str, Any]]: ...
+    def on_error(self, callback: Callable[[dict[str, Any]], Any] | None = None) -> None: ...
+    def on_disconnect(self, callback: Callable[[str | None, str | None], None] | None = None) -> None: ...
+    def set_authenticator(self, callback: Callable[[int, str | None, str | None], bool] | None = None) -> None: ...
+    def set_handshake(self, hashes: list[int] | None = None, read_only: list[int] | None = None) -> None: ...
+    def handshake_add(self, hash: int, read_only: bool = False) -> None: ...
+    def handshake_remove(self, hash: int) -> None: ...
+    def handshake(self) -> tuple[list[int] | None, list[int]]: ...
+    def set_heartbeat(self, interval: float | None = None) -> None: ...
+    def set_ack_timeout(self, timeout: float | None = None) -> None: ...
+    def set_max_message_size(self, size: int) -> None: ...
+    def set_queue_limit(self, size: int) -> None: ...
+    def set_broadcast(self, enabled: bool) -> None: ...
+    def start_trace(self, path: str, payload: bool = False) -> None: ...
+    def stop_trace(self) -> None: ...
+    def set_tcp_options(
+        self,
+        nodelay: bool = False,
+        send_buffer: int | None = None,
+        recv_buffer: int | None = None,
+        keepalive: float | None = None,
+    ) -> None: ...
+    def client_info(self) -> tuple[float, float, float] | None: ...
+    def wait_idle(self, timeout: float | None = None) -> bool: ...
+    def reserved_ids(self) -> int: ...
+    def value_wire_type(self, value_id: int) -> tuple[int, str, str, str]: ...
+    def dump_state_json(self) -> str: ...
+    def save_state(self, path: str) -> None: ...
+    def load_state(self, path: str) -> None: ...
     def disconnect_client(self) -> None: ...
     def update(self, duration: float | None = None) -> None: ...
 
+    # groups ----------------------------------------------------------------------
+    def group_ids(self, group: str) -> list[int]: ...
+    def group_reset(self, group: str, update: bool) -> None: ...
+    def group_sync(self, group: str) -> None: ...
+
     # values ----------------------------------------------------------------------
     def value_set(self, value_id: int, value: Any, set_signal: bool, update: bool) -> None: ...
     def value_get(self, value_id: int) -> Any: ...
+    def value_set_transform(self, value_id: int, transform: Callable[[Any], Any] | None = None) -> None: ...
+    def value_set_validator(self, value_id: int, validator: Callable[[Any], str | None] | None = None) -> None: ...
+    def value_on_change(self, value_id: int, callback: Callable[[Any], Any] | None = None) -> None: ...
+    def value_last_sync(self, value_id: int) -> float | None: ...
+    def value_set_allowed(self, value_id: int, allowed: list[Any] | None = None) -> None: ...
+    def value_set_send_rate(self, value_id: int, rate: float | None = None) -> None: ...
+    def value_set_thresholds(
+        self, value_id: int, thresholds: list[float] | None = None, hysteresis: float = 0.0
+    ) -> None: ...
+    def value_set_property(
+        self,
+        value_id: int,
+        getter: Callable[[], Any] | None = None,
+        setter: Callable[[Any], Any] | None = None,
+    ) -> None: ...
 
     # static ----------------------------------------------------------------------
     def static_set(self, value_id: int, value: Any, update: bool) -> None: ...
     def static_get(self, value_id: int) -> Any: ...
+    def static_last_sync(self, value_id: int) -> float | None: ...
 
     # signals ---------------------------------------------------------------------
-    def value_get_signal(self, thread_id) -> tuple[int, tuple[Any, ...]]: ...
+    def value_get_signal(self, thread_id) -> tuple[int, tuple[Any, ...]] | None: ...
+    def signals_retire_worker(self, thread_id: int) -> None: ...
     def value_set_register(self, value_id: int, register: bool) -> None: ...
     def signal_set(self, value_id: int, value: Any) -> None: ...
 
@@ -33,6 +105,9 @@ class SteteServerCoreBase:
         self, value_id: int, image: Buffer, update: bool, origin: list[int] | tuple[int, int] | None = None
     ) -> None: ...
     def image_get(self, value_id: int) -> tuple[bytearray, tuple[int, int]]: ...
+    def image_set_preview(self, value_id: int, max_size: int | None = None) -> None: ...
+    def image_resend(self, value_id: int, update: bool) -> None: ...
+    def image_set_max_fps(self, value_id: int, fps: float | None = None) -> None: ...
     def image_size(self, value_id: int) -> tuple[int, int]: ...
 
     # dict ------------------------------------------------------------------------
@@ -41,6 +116,7 @@ class SteteServerCoreBase:
     def dict_item_set(self, value_id: int, key: Any, value: Any, update: bool) -> None: ...
     def dict_item_get(self, value_id: int, key: Any) -> Any: ...
     def dict_item_del(self, value_id: int, key: Any, update: bool) -> None: ...
+    def dict_item_rename(self, value_id: int, old: Any, new: Any, update: bool, overwrite: bool = False) -> None: ...
     def dict_len(self, value_id: int) -> int: ...
 
     # list ------------------------------------------------------------------------
@@ -50,8 +126,17 @@ class SteteServerCoreBase:
     def list_item_get(self, value_id: int, idx: int) -> Any: ...
     def list_item_del(self, value_id: int, idx: int, update: bool) -> None: ...
     def list_item_add(self, value_id: int, value: Any, update: bool) -> None: ...
+    def list_item_move(self, value_id: int, from_idx: int, to_idx: int, update: bool) -> None: ...
+    def list_swap(self, value_id: int, i: int, j: int, update: bool) -> None: ...
     def list_len(self, value_id: int) -> int: ...
 
+    # bitsets ---------------------------------------------------------------------
+    def bitset_get(self, value_id: int) -> list[bool]: ...
+    def bitset_set(self, value_id: int, values: Iterable[bool], update: bool) -> None: ...
+    def bitset_bit_get(self, value_id: int, idx: int) -> bool: ...
+    def bitset_bit_set(self, value_id: int, idx: int, value: bool, update: bool) -> None: ...
+    def bitset_len(self, value_id: int) -> int: ...
+
     # graphs ----------------------------------------------------------------------
     def graphs_set(self, value_id: int, idx: int, graph: Buffer, update: bool) -> None: ...
     def graphs_get(self, value_id: int, idx: int) -> tuple[bytearray, tuple[int, ...]]: ...
@@ -62,11 +147,19 @@ class SteteServerCoreBase:
         points: Buffer,
         update: bool,
     ) -> None: ...
+    def graphs_attach_source(
+        self, value_id: int, idx: int, source: Iterable[Buffer], interval: float, update: bool = False
+    ) -> None: ...
     def graphs_len(self, value_id: int, idx: int) -> int: ...
     def graphs_remove(self, value_id: int, idx: int, update: bool) -> None: ...
     def graphs_count(self, value_id: int) -> int: ...
     def graphs_is_linear(self, value_id: int, idx: int) -> bool: ...
     def graphs_clear(self, value_id: int, update: bool) -> None: ...
+    def graphs_set_compressed(self, value_id: int, idx: int, compressed: bool) -> None: ...
+    def graphs_set_axis(self, value_id: int, x_idx: int, axis: Buffer, update: bool) -> None: ...
+    def graphs_add_axis_points(self, value_id: int, x_idx: int, points: Buffer, update: bool) -> None: ...
+    def graphs_remove_axis(self, value_id: int, x_idx: int, update: bool) -> None: ...
+    def graphs_set_shared(self, value_id: int, idx: int, x_idx: int, graph: Buffer, update: bool) -> None: ...
 
 
 class PySyncEnum(Enum):
@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::python_convert::ToPython;
+
+// who changed the value
+#[derive(Clone, Copy)]
+pub(crate) enum ChangeSource {
+    Client,
+    Server,
+}
+
+impl ChangeSource {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Client => "client",
+            Self::Server => "server",
+        }
+    }
+}
+
+// values are converted to python only when the log is read
+struct Change {
+    sequence: u64,
+    time: SystemTime,
+    id: u32,
+    source: ChangeSource,
+    old: Box<dyn ToPython>,
+    new: Box<dyn ToPython>,
+}
+
+struct Changes {
+    records: VecDeque<Change>,
+    capacity: usize,
+    next: u64,
+}
+
+// Bounded log of the accepted value changes, it is disabled by default. The values are cloned
+// only when the log is enabled.
+#[derive(Clone)]
+pub(crate) struct AuditLog {
+    enabled: Arc<AtomicBool>,
+    changes: Arc<Mutex<Changes>>,
+}
+
+impl AuditLog {
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: Arc::new(AtomicBool::new(false)),
+            changes: Arc::new(Mutex::new(Changes {
+                records: VecDeque::new(),
+                capacity: 0,
+                next: 0,
+            })),
+        }
+    }
+
+    // None disables the log and drops all records, the sequence numbers continue
+    pub(crate) fn set_capacity(&self, capacity: Option<usize>) {
+        let mut changes = self.changes.lock().unwrap();
+        let capacity = capacity.unwrap_or(0);
+        changes.capacity = capacity;
+        while changes.records.len() > capacity {
+            changes.records.pop_front();
+        }
+        self.enabled.store(capacity > 0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record<T>(&self, id: u32, source: ChangeSource, old: &T, new: &T)
+    where
+        T: ToPython + Clone + 'static,
+    {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut changes = self.changes.lock().unwrap();
+        if changes.capacity == 0 {
+            return;
+        }
+        let change = Change {
+            sequence: changes.next,
+            time: SystemTime::now(),
+            id,
+            source,
+            old: Box::new(old.clone()),
+            new: Box::new(new.clone()),
+        };
+        changes.next += 1;
+        if changes.records.len() == changes.capacity {
+            changes.records.pop_front();
+        }
+        changes.records.push_back(change);
+    }
+
+    // the kept records with the sequence number from the given one, the oldest first
+    pub(crate) fn to_py<'py>(
+        &self,
+        py: Python<'py>,
+        since: u64,
+    ) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        let changes = self.changes.lock().unwrap();
+        let mut result = Vec::new();
+        for change in changes.records.iter().filter(|c| c.sequence >= since) {
+            let time = change
+                .time
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0.0, |t| t.as_secs_f64());
+
+            let dict = PyDict::new(py);
+            dict.set_item("sequence", change.sequence)?;
+            dict.set_item("time", time)?;
+            dict.set_item("value_id", change.id)?;
+            dict.set_item("source", change.source.as_str())?;
+            dict.set_item("old", change.old.to_python(py))?;
+            dict.set_item("new", change.new.to_python(py))?;
+            result.push(dict);
+        }
+        Ok(result)
+    }
+}
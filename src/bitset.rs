@@ -0,0 +1,219 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+
+use crate::transport::{deserialize, MessageData};
+
+// bits are packed to bytes, first bit is the lowest bit of the first byte
+#[derive(Deserialize)]
+enum BitsetMessage {
+    All(usize, Vec<u8>),
+    Set(usize, bool),
+}
+
+#[inline]
+fn get_bit(data: &[u8], idx: usize) -> bool {
+    data[idx / 8] & (1 << (idx % 8)) != 0
+}
+
+#[inline]
+fn set_bit(data: &mut [u8], idx: usize, value: bool) {
+    match value {
+        true => data[idx / 8] |= 1 << (idx % 8),
+        false => data[idx / 8] &= !(1 << (idx % 8)),
+    }
+}
+
+pub(crate) trait BitsetUpdate: Sync + Send {
+    fn update_bitset(&self, data: MessageData) -> Result<(), String>;
+}
+
+pub struct ValueBitset {
+    id: u32,
+    bits: RwLock<(Vec<u8>, usize)>,
+    changed: AtomicBool,
+}
+
+impl ValueBitset {
+    pub(crate) fn new(id: u32) -> Arc<Self> {
+        Arc::new(Self {
+            id,
+            bits: RwLock::new((Vec::new(), 0)),
+            changed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn get(&self) -> Vec<bool> {
+        let b = self.bits.read().unwrap();
+        (0..b.1).map(|i| get_bit(&b.0, i)).collect()
+    }
+
+    pub fn get_bit(&self, idx: usize) -> Option<bool> {
+        let b = self.bits.read().unwrap();
+        if idx < b.1 {
+            Some(get_bit(&b.0, idx))
+        } else {
+            None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits.read().unwrap().1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.read().unwrap().1 == 0
+    }
+
+    /// Process the packed bits and the number of bits.
+    pub fn process<R>(&self, op: impl FnOnce(&[u8], usize) -> R) -> R {
+        let b = self.bits.read().unwrap();
+        op(&b.0, b.1)
+    }
+
+    /// If the bitset was changed by the server since the last call. The flag is shared by all
+    /// callers, so only one place in the UI should take it.
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl BitsetUpdate for ValueBitset {
+    fn update_bitset(&self, data: MessageData) -> Result<(), String> {
+        let message = deserialize(data)
+            .map_err(|e| format!("Error deserializing message {} with id {}", e, self.id))?;
+
+        match message {
+            BitsetMessage::All(len, data) => {
+                if data.len() != len.div_ceil(8) {
+                    return Err(format!(
+                        "Bitset data size does not match its length for id {}",
+                        self.id
+                    ));
+                }
+                *self.bits.write().unwrap() = (data, len);
+            }
+            BitsetMessage::Set(idx, value) => {
+                let mut b = self.bits.write().unwrap();
+                if idx < b.1 {
+                    set_bit(&mut b.0, idx, value);
+                }
+            }
+        }
+        self.changed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+// SERVER ---------------------------------------------------
+// ----------------------------------------------------------
+#[cfg(feature = "server")]
+pub(crate) mod server {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::Sender;
+
+    use pyo3::exceptions::PyIndexError;
+    use pyo3::prelude::*;
+    use pyo3::types::PyList;
+    use serde::Serialize;
+
+    use crate::server::{SyncTarget, SyncTrait};
+    use crate::transport::{serialize, WriteMessage};
+
+    #[derive(Serialize)]
+    enum BitsetMessageRef<'a> {
+        All(usize, &'a [u8]),
+        Set(usize, bool),
+    }
+
+    pub(crate) struct PyValueBitset {
+        id: u32,
+        bits: RwLock<(Vec<u8>, usize)>,
+        channel: Sender<WriteMessage>,
+        connected: Arc<AtomicBool>,
+    }
+
+    impl PyValueBitset {
+        pub(crate) fn new(
+            id: u32,
+            channel: Sender<WriteMessage>,
+            connected: Arc<AtomicBool>,
+        ) -> Arc<Self> {
+            Arc::new(Self {
+                id,
+                bits: RwLock::new((Vec::new(), 0)),
+                channel,
+                connected,
+            })
+        }
+
+        pub(crate) fn get_py<'py>(&self, py: Python<'py>) -> Bound<'py, PyList> {
+            let b = self.bits.read().unwrap();
+            PyList::new(py, (0..b.1).map(|i| get_bit(&b.0, i))).unwrap()
+        }
+
+        pub(crate) fn set_py(&self, values: &Bound<PyAny>, update: bool) -> PyResult<()> {
+            let mut data = Vec::new();
+            let mut len = 0;
+            for value in values.try_iter()? {
+                if len % 8 == 0 {
+                    data.push(0u8);
+                }
+                set_bit(&mut data, len, value?.extract()?);
+                len += 1;
+            }
+
+            let mut b = self.bits.write().unwrap();
+            if self.connected.load(Ordering::Relaxed) {
+                let message = serialize(BitsetMessageRef::All(len, &data));
+                self.channel
+                    .send(WriteMessage::Bitset(self.id, update, message))
+                    .unwrap();
+            }
+            *b = (data, len);
+
+            Ok(())
+        }
+
+        pub(crate) fn get_bit_py(&self, idx: usize) -> PyResult<bool> {
+            let b = self.bits.read().unwrap();
+            if idx >= b.1 {
+                return Err(PyIndexError::new_err("bitset index out of range"));
+            }
+            Ok(get_bit(&b.0, idx))
+        }
+
+        pub(crate) fn set_bit_py(&self, idx: usize, value: bool, update: bool) -> PyResult<()> {
+            let mut b = self.bits.write().unwrap();
+            if idx >= b.1 {
+                return Err(PyIndexError::new_err("bitset index out of range"));
+            }
+
+            if self.connected.load(Ordering::Relaxed) {
+                let message = serialize(BitsetMessageRef::Set(idx, value));
+                self.channel
+                    .send(WriteMessage::Bitset(self.id, update, message))
+                    .unwrap();
+            }
+            set_bit(&mut b.0, idx, value);
+
+            Ok(())
+        }
+
+        pub(crate) fn len_py(&self) -> usize {
+            self.bits.read().unwrap().1
+        }
+    }
+
+    impl SyncTrait for PyValueBitset {
+        fn sync(&self, target: SyncTarget) {
+            let b = self.bits.read().unwrap();
+            let message = serialize(BitsetMessageRef::All(b.1, &b.0));
+            let message = WriteMessage::Bitset(self.id, false, message);
+            self.channel.send(target.message(message)).unwrap();
+        }
+    }
+}
@@ -15,7 +15,7 @@ pub fn read_enums(file_path: impl ToString) -> Vec<EnumParse> {
         .collect();
 
     let mut result = Vec::new();
-    while lines.len() > 0 {
+    while !lines.is_empty() {
         let line = lines.pop_front().unwrap();
 
         if line.contains("pub enum") || line.contains("pub(crate) enum") {
@@ -72,7 +72,7 @@ pub fn read_structs(file_path: impl ToString) -> Vec<StructParse> {
         .collect();
 
     let mut result = Vec::new();
-    while lines.len() > 0 {
+    while !lines.is_empty() {
         let line = lines.pop_front().unwrap();
 
         if line.contains("pub struct") || line.contains("pub(crate) struct") {
@@ -128,6 +128,7 @@ enum ValueType {
     ValueDict,
     ValueList,
     ValueGraphs,
+    ValueBitset,
 }
 
 impl ValueType {
@@ -140,6 +141,7 @@ impl ValueType {
             ValueType::ValueDict => "add_dict",
             ValueType::ValueList => "add_list",
             ValueType::ValueGraphs => "add_graphs",
+            ValueType::ValueBitset => "add_bitset",
         }
     }
 }
@@ -148,6 +150,7 @@ struct Value {
     typ: ValueType,
     default: String,
     annotation: String,
+    writable: bool, // the dict or list is changed also by the client
 }
 
 impl Value {
@@ -156,6 +159,8 @@ impl Value {
             ValueType::ValueStatic
         } else if definition.contains("<ValueImage>") {
             ValueType::ValueImage
+        } else if definition.contains("<ValueBitset>") {
+            ValueType::ValueBitset
         } else if definition.contains("<Signal<") {
             ValueType::Signal
         } else if definition.contains("<ValueDict<") {
@@ -170,7 +175,7 @@ impl Value {
             panic!("Unknown value type: {}", definition);
         };
 
-        let annot = if let ValueType::ValueImage = typ {
+        let annot = if typ == ValueType::ValueImage || typ == ValueType::ValueBitset {
             "".to_string()
         } else {
             let annot = definition.split("<").collect::<Vec<&str>>()[2];
@@ -187,11 +192,21 @@ impl Value {
         } else {
             default
         };
+        let writable = declaration.contains("_writable(");
 
         Self {
             typ,
             default,
             annotation: annot,
+            writable,
+        }
+    }
+
+    // the server adds the dicts and the lists in the same way as the client
+    fn add_str(&self) -> String {
+        match self.writable {
+            true => format!("{}_writable", self.typ.as_add_str()),
+            false => self.typ.as_add_str().to_string(),
         }
     }
 }
@@ -206,6 +221,7 @@ fn test_if_value(line: &str) -> bool {
     line.contains("Arc<Value<")
         || line.contains("Arc<ValueStatic<")
         || line.contains("Arc<ValueImage>")
+        || line.contains("Arc<ValueBitset>")
         || line.contains("Arc<ValueGraphs<")
         || line.contains("Arc<Signal<")
         || line.contains("Arc<ValueDict<")
@@ -341,10 +357,13 @@ impl State {
         if root {
             file.write_all(format!("\n\nclass {}(sc._MainStatesBase):\n", self.name).as_bytes())
                 .unwrap();
-            file.write_all(b"    def __init__(self, update: Callable[[float | None], None]):\n")
-                .unwrap();
+            file.write_all(
+                b"    def __init__(self, update: Callable[[float | None], None], reserved: int = 10):\n",
+            )
+            .unwrap();
             file.write_all(b"        self._update = update\n").unwrap();
-            file.write_all(b"        c = sc._Counter()\n\n").unwrap();
+            file.write_all(b"        c = sc._Counter(reserved)\n\n")
+                .unwrap();
         } else if !written.contains(&self.name) {
             file.write_all(format!("\n\nclass {}(sc._StatesBase):\n", self.name).as_bytes())
                 .unwrap();
@@ -397,6 +416,9 @@ impl State {
                         ValueType::ValueGraphs => {
                             format!("        self.{} = sc.ValueGraphs(c)\n", name)
                         }
+                        ValueType::ValueBitset => {
+                            format!("        self.{} = sc.ValueBitset(c)\n", name)
+                        }
                     };
 
                     file.write_all(text.as_bytes()).unwrap();
@@ -484,7 +506,7 @@ pub fn parse_states_for_server(
         for item in items {
             match item {
                 Item::Value(_, value) => {
-                    let add_str = value.typ.as_add_str();
+                    let add_str = value.add_str();
 
                     let mut default = value.default.clone();
                     let mut annotation = value.annotation.clone();
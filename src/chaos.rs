@@ -0,0 +1,233 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Faults injected by the chaos stream. Rates are probabilities of every write.
+#[derive(Clone, Debug)]
+pub struct ChaosConfig {
+    /// Delay of every write.
+    pub latency: Duration,
+    /// Maximal random delay added to the latency.
+    pub jitter: Duration,
+    /// Probability that the written data are dropped.
+    pub drop_rate: f64,
+    /// Probability that one byte of the written data is flipped.
+    pub corrupt_rate: f64,
+    /// Probability that the write fails as if the connection was reset.
+    pub disconnect_rate: f64,
+    /// Seed of the random generator, 0 uses the current time.
+    pub seed: u64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            drop_rate: 0.0,
+            corrupt_rate: 0.0,
+            disconnect_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+// xorshift, it is enough for the fault injection and it needs no dependency
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        let seed = match seed {
+            0 => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_nanos() as u64)
+                .unwrap_or(1),
+            seed => seed,
+        };
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // uniform number in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Wrapper of a stream which injects latency, dropped and corrupted data and disconnects
+/// to the writes. Reads are passed through.
+pub struct ChaosStream<S> {
+    inner: S,
+    config: ChaosConfig,
+    random: Random,
+}
+
+impl<S> ChaosStream<S> {
+    pub fn new(inner: S, config: ChaosConfig) -> Self {
+        let random = Random::new(config.seed);
+        Self {
+            inner,
+            config,
+            random,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Read for ChaosStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for ChaosStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let jitter = self.config.jitter.mul_f64(self.random.unit());
+        let delay = self.config.latency + jitter;
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+
+        if self.random.unit() < self.config.disconnect_rate {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "Connection reset by the chaos stream",
+            ));
+        }
+
+        if buf.is_empty() || self.random.unit() < self.config.drop_rate {
+            return Ok(buf.len());
+        }
+
+        if self.random.unit() < self.config.corrupt_rate {
+            let mut data = buf.to_vec();
+            let idx = self.random.next() as usize % data.len();
+            data[idx] ^= 1 << (self.random.next() % 8);
+            self.inner.write_all(&data)?;
+        } else {
+            self.inner.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// TCP proxy between the client and the server, both directions are written through
+/// the chaos stream.
+pub struct ChaosProxy {
+    running: Arc<AtomicBool>,
+    addr: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ChaosProxy {
+    /// Listen on the address and forward every connection to the target address.
+    pub fn start(
+        addr: impl Into<SocketAddr>,
+        target: impl Into<SocketAddr>,
+        config: ChaosConfig,
+    ) -> io::Result<Self> {
+        let target = target.into();
+        let listener = TcpListener::bind(addr.into())?;
+        let addr = listener.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+
+        let th_running = running.clone();
+        let thread = thread::Builder::new()
+            .name("ChaosProxy".to_string())
+            .spawn(move || {
+                for (i, stream) in listener.incoming().enumerate() {
+                    if !th_running.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let client = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let server = match TcpStream::connect(target) {
+                        Ok(stream) => stream,
+                        Err(_) => {
+                            let _ = client.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                    };
+
+                    // every direction gets its own random sequence
+                    let mut config = config.clone();
+                    if config.seed != 0 {
+                        config.seed = config.seed.wrapping_add(2 * i as u64);
+                    }
+                    let _ = Self::pump(&client, &server, config.clone());
+                    config.seed = config.seed.wrapping_add(1);
+                    let _ = Self::pump(&server, &client, config);
+                }
+            })?;
+
+        Ok(Self {
+            running,
+            addr,
+            thread: Some(thread),
+        })
+    }
+
+    fn pump(from: &TcpStream, to: &TcpStream, config: ChaosConfig) -> io::Result<()> {
+        let mut from = from.try_clone()?;
+        let to = to.try_clone()?;
+        thread::Builder::new()
+            .name("ChaosPump".to_string())
+            .spawn(move || {
+                let mut to = ChaosStream::new(to, config);
+                let mut buffer = vec![0u8; CHUNK_SIZE];
+                loop {
+                    let res = match from.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(size) => to.write_all(&buffer[..size]),
+                    };
+                    if res.is_err() {
+                        break;
+                    }
+                }
+                let _ = from.shutdown(Shutdown::Both);
+                let _ = to.into_inner().shutdown(Shutdown::Both);
+            })?;
+        Ok(())
+    }
+
+    /// Address of the proxy, useful if it was started on the port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop accepting new connections. Already forwarded connections run until they are closed.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.running.store(false, Ordering::Relaxed);
+            // wake up the accept loop
+            let _ = TcpStream::connect(self.addr);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ChaosProxy {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -1,24 +1,61 @@
-use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::path::Path;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use egui::Context;
 
 use crate::client_state::{ConnectionState, UIState};
 use crate::commands::CommandMessage;
+use crate::errors::{ErrorSource, Severity};
+use crate::offline::OfflineQueue;
+use crate::schema::WireType;
+#[cfg(feature = "encryption")]
+use crate::secure::PresharedKey;
+use crate::socket::{Address, Socks5Proxy, Stream, TcpOptions};
 use crate::states_creator::{ValuesCreator, ValuesList};
-use crate::transport::{read_message, write_message, MessageData, ReadMessage, WriteMessage};
+use crate::transport::{
+    heartbeat_timeout, is_timeout, oversized, read_message, recv_message, write_message,
+    Extensions, MessageData, ReadMessage, WriteMessage, DEFAULT_MAX_MESSAGE_SIZE,
+};
+
+// the change from the server did not match, so some message was lost
+fn resync(ui_state: &UIState, id: u32, kind: &str) {
+    let message = format!(
+        "{} with id {} did not match the change from the server, it is synchronized again",
+        kind, id
+    );
+    ui_state.error(Severity::Warning, ErrorSource::Value, Some(id), message);
+    ui_state.resync(id);
+}
 
 fn handle_message(
     message: ReadMessage,
     vals: &ValuesList,
     ui_state: &UIState,
+    offline: Option<&OfflineQueue>,
 ) -> Result<(), String> {
     if let ReadMessage::Command(ref command) = message {
         match command {
-            CommandMessage::Update(t) => {
-                ui_state.update(*t);
+            CommandMessage::Update(t) => ui_state.update(*t),
+            CommandMessage::Ping(t) => ui_state.send_pong(*t),
+            CommandMessage::Pong(t) => ui_state.pong(*t),
+            CommandMessage::HandshakeAccepted(flag) => {
+                ui_state.set_extensions(Extensions::from_flag(*flag));
+            }
+            CommandMessage::SwapResult(id, success) => match vals.values.get(id) {
+                Some(value) => value.set_swap_result(*success),
+                None => return Err(format!("Value with id {} not found", id)),
+            },
+            CommandMessage::Error(error) => {
+                let message = format!("Server reported error: {}", error);
+                ui_state.error(Severity::Warning, ErrorSource::Remote, None, message);
             }
             _ => {}
         }
@@ -28,10 +65,17 @@ fn handle_message(
     let update = match message {
         ReadMessage::Value(id, updata, data) => match vals.values.get(&id) {
             Some(value) => {
-                match data {
-                    MessageData::Stack(data) => value.update_value(&data),
-                    MessageData::Heap(data) => value.update_value(&data),
-                }?;
+                match offline.filter(|queue| queue.contains(id)) {
+                    // the value written offline is kept, the server gets it after the acknowledge
+                    Some(queue) => {
+                        value.acknowledge();
+                        queue.replay(id);
+                    }
+                    None => match data {
+                        MessageData::Stack(data) => value.update_value(&data),
+                        MessageData::Heap(data) => value.update_value(&data),
+                    }?,
+                }
                 updata
             }
             None => return Err(format!("Value with id {} not found", id)),
@@ -50,18 +94,41 @@ fn handle_message(
 
         ReadMessage::Image(id, updata, data) => match vals.images.get(&id) {
             Some(value) => {
-                match data {
-                    MessageData::Stack(data) => value.update_image(&data),
-                    MessageData::Heap(data) => value.update_image(&data),
-                }?;
+                let res = value.update_image(data.as_slice());
+                data.recycle();
+                if res? {
+                    ui_state.resync(id);
+                }
                 updata
             }
             None => return Err(format!("Image with id {} not found", id)),
         },
 
+        #[cfg(feature = "shared-memory")]
+        ReadMessage::SharedImage(id, updata, data) => match vals.images.get(&id) {
+            Some(value) => {
+                if value.update_shared_image(data.as_slice())? {
+                    ui_state.resync(id);
+                }
+                updata
+            }
+            None => return Err(format!("Image with id {} not found", id)),
+        },
+
+        #[cfg(not(feature = "shared-memory"))]
+        ReadMessage::SharedImage(_, _, _) => {
+            return Err("Shared memory images require the shared-memory feature".to_string());
+        }
+
         ReadMessage::Dict(id, updata, data) => match vals.dicts.get(&id) {
             Some(value) => {
-                value.update_dict(data)?;
+                if !value.update_dict(data)? {
+                    resync(ui_state, id, "Dict");
+                }
+                // the changes made offline are applied again over the synchronized dict
+                for data in offline.map(|queue| queue.replay(id)).unwrap_or_default() {
+                    value.update_dict(data)?;
+                }
                 updata
             }
             None => return Err(format!("Dict with id {} not found", id)),
@@ -69,7 +136,12 @@ fn handle_message(
 
         ReadMessage::List(id, updata, data) => match vals.lists.get(&id) {
             Some(value) => {
-                value.update_list(data)?;
+                if !value.update_list(data)? {
+                    resync(ui_state, id, "List");
+                }
+                for data in offline.map(|queue| queue.replay(id)).unwrap_or_default() {
+                    value.update_list(data)?;
+                }
                 updata
             }
             None => return Err(format!("List with id {} not found", id)),
@@ -77,19 +149,30 @@ fn handle_message(
 
         ReadMessage::Graph(id, updata, data) => match vals.graphs.get(&id) {
             Some(value) => {
-                match data {
-                    MessageData::Stack(data) => value.update_graph(&data),
-                    MessageData::Heap(data) => value.update_graph(&data),
-                }?;
+                let res = value.update_graph(data.as_slice());
+                data.recycle();
+                res?;
                 updata
             }
             None => return Err(format!("Graph with id {} not found", id)),
         },
 
+        ReadMessage::Bitset(id, updata, data) => match vals.bitsets.get(&id) {
+            Some(value) => {
+                value.update_bitset(data)?;
+                updata
+            }
+            None => return Err(format!("Bitset with id {} not found", id)),
+        },
+
         ReadMessage::Signal(_, _) => {
             return Err("Signal message should not be handled in the client".to_string());
         }
 
+        ReadMessage::CompareSwap(_, _, _) => {
+            return Err("CompareSwap message should not be handled in the client".to_string());
+        }
+
         ReadMessage::Command(_) => unreachable!("should not parse Command message"),
     };
 
@@ -100,55 +183,249 @@ fn handle_message(
     Ok(())
 }
 
+type Connector = Arc<dyn Fn(&Address) -> io::Result<TcpStream> + Send + Sync>;
+
+// how the socket to the server is opened
+#[derive(Clone)]
+enum Connect {
+    Direct,
+    Socks5(Socks5Proxy),
+    Custom(Connector),
+}
+
+impl Connect {
+    fn connect(&self, addr: &Address) -> io::Result<Stream> {
+        match self {
+            Self::Direct => Stream::connect(addr),
+            Self::Socks5(proxy) => Stream::connect_socks5(proxy, addr),
+            Self::Custom(connector) => connector(addr).map(Stream::from_tcp),
+        }
+    }
+}
+
+/// Retrying of the failed connection attempts, see [`ClientBuilder::set_reconnect`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reconnect {
+    /// Delay before the first retry.
+    pub initial_delay: Duration,
+    /// The delay is multiplied by the factor after every retry.
+    pub backoff: f64,
+    /// The longest delay between two retries.
+    pub max_delay: Duration,
+    /// Number of the retries after the failed attempt, None retries until the client connects.
+    pub max_retries: Option<u32>,
+    /// Random part of the delay from 0 to 1, so more clients do not retry at the same time.
+    pub jitter: f64,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            backoff: 2.0,
+            max_delay: Duration::from_secs(5),
+            max_retries: None,
+            jitter: 0.1,
+        }
+    }
+}
+
+impl Reconnect {
+    fn allowed(&self, retry: u32) -> bool {
+        self.max_retries.is_none_or(|max| retry < max)
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.initial_delay.as_secs_f64() * self.backoff.max(1.0).powi(retry as i32);
+        let delay = delay.min(self.max_delay.as_secs_f64());
+
+        // the random number from the hasher with the random keys
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        Duration::from_secs_f64(delay * (1.0 - jitter * random))
+    }
+}
+
+// options of the connection set by the builder
+#[derive(Clone)]
+struct Options {
+    connect: Connect,
+    reconnect: Option<Reconnect>,
+    heartbeat: Option<Duration>,
+    checksum: bool,
+    tcp: TcpOptions,
+    max_message_size: usize,
+    auth_token: Option<String>,
+    offline: bool,
+    #[cfg(feature = "encryption")]
+    psk: Option<PresharedKey>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            connect: Connect::Direct,
+            reconnect: None,
+            heartbeat: None,
+            checksum: false,
+            tcp: TcpOptions::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            auth_token: None,
+            offline: false,
+            #[cfg(feature = "encryption")]
+            psk: None,
+        }
+    }
+}
+
 fn start_gui_client(
-    addr: SocketAddrV4,
+    addr: Address,
     vals: ValuesList,
-    version: u64,
     mut rx: Receiver<WriteMessage>,
     channel: Sender<WriteMessage>,
     ui_state: UIState,
-    handshake: u64,
+    handshake: CommandMessage,
+    options: Options,
 ) {
+    let Options {
+        connect,
+        reconnect,
+        heartbeat,
+        checksum,
+        tcp,
+        max_message_size,
+        auth_token: _, // already in the handshake
+        offline,
+        #[cfg(feature = "encryption")]
+        psk,
+    } = options;
+    let mut announced = Extensions::supported();
+    announced.checksum = checksum;
+    let offline = offline.then(|| OfflineQueue::new(channel.clone()));
+
     let client_thread = thread::Builder::new().name("Client".to_string());
     let _ = client_thread.spawn(move || loop {
         // wait for the connection signal
         ui_state.wait_connection();
         ui_state.set_state(ConnectionState::NotConnected);
 
-        // try to connect to the server
-        let res = TcpStream::connect(addr);
-        if res.is_err() {
+        // try to connect to the server, the failed attempts are retried by the reconnect policy
+        let mut retry = 0;
+        let res = loop {
+            match (connect.connect(&addr), reconnect) {
+                (Err(_), Some(policy)) if policy.allowed(retry) => {
+                    thread::sleep(policy.delay(retry));
+                    retry += 1;
+                }
+                (res, _) => break res,
+            }
+        };
+        if let Err(ref e) = res {
+            // the refused direct connection is expected while the server is not running,
+            // it is reported only after the retries
+            if retry > 0 {
+                let message = format!(
+                    "Error connecting to {} after {} retries: {:?}",
+                    addr, retry, e
+                );
+                ui_state.error(Severity::Error, ErrorSource::Connection, None, message);
+            } else if !matches!(connect, Connect::Direct) {
+                let message = format!("Error connecting to {}: {:?}", addr, e);
+                ui_state.error(Severity::Error, ErrorSource::Connection, None, message);
+            }
             continue;
         }
 
         // get the stream
         let mut stream_write = res.unwrap();
+        #[cfg(feature = "encryption")]
+        if let Some(ref key) = psk {
+            if let Err(e) = stream_write.secure(key, false) {
+                let message = format!("Error starting encryption: {:?}", e);
+                ui_state.error(Severity::Error, ErrorSource::Handshake, None, message);
+                continue;
+            }
+        }
         let mut stream_read = stream_write.try_clone().unwrap();
+        if let Err(e) = stream_write.set_tcp_options(&tcp) {
+            let message = format!("Error setting TCP options: {:?}", e);
+            ui_state.error(Severity::Warning, ErrorSource::Connection, None, message);
+        }
+        let timeout = heartbeat_timeout(heartbeat);
+        if let Err(e) = stream_read.set_read_timeout(timeout) {
+            let message = format!("Error setting heartbeat timeout: {:?}", e);
+            ui_state.error(Severity::Warning, ErrorSource::Connection, None, message);
+        }
 
-        // clean mesage queue before starting
-        for _v in rx.try_iter() {}
+        // clean mesage queue before starting, the offline mode keeps the writes
+        match offline {
+            Some(ref queue) => queue.queue(&rx),
+            None => for _v in rx.try_iter() {},
+        }
 
         // read thread -----------------------------------------
         let th_vals = vals.clone();
         let th_ui_state = ui_state.clone();
         let th_channel = channel.clone();
+        let th_offline = offline.clone();
 
         let read_thread = thread::Builder::new().name("Read".to_string());
         let recv_tread = read_thread
             .spawn(move || {
                 loop {
                     // read the message
-                    let res = read_message(&mut stream_read);
+                    let res = read_message(&mut stream_read, max_message_size, &th_ui_state.stats);
                     if let Err(e) = res {
-                        println!("Error reading message: {:?}", e); // TODO: log error
+                        // the oversized message was skipped, the server is told about it
+                        if let Some(error) = oversized(&e) {
+                            let error = format!("Message was rejected: {}", error);
+                            th_ui_state.error(
+                                Severity::Warning,
+                                ErrorSource::Transport,
+                                None,
+                                error.clone(),
+                            );
+                            th_channel
+                                .send(WriteMessage::Command(CommandMessage::Error(error)))
+                                .unwrap();
+                            continue;
+                        }
+                        let message = match timeout {
+                            Some(timeout) if is_timeout(&e) => {
+                                format!("Connection timed out, nothing received for {:?}", timeout)
+                            }
+                            _ => format!("Error reading message: {:?}", e),
+                        };
+                        th_ui_state.error(Severity::Error, ErrorSource::Transport, None, message);
                         break;
                     }
                     let message = res.unwrap();
 
+                    // the server closes the connection after the rejection
+                    if let ReadMessage::Command(CommandMessage::HandshakeRejected(reason)) = message
+                    {
+                        let message = format!("Handshake rejected: {}", reason);
+                        th_ui_state.error(Severity::Error, ErrorSource::Handshake, None, message);
+                        th_ui_state.set_rejection(reason);
+                        break;
+                    }
+
+                    #[cfg(feature = "message-dump")]
+                    let description = message.describe();
+                    let value_id = message.value_id();
+
                     // handle the message
-                    let res = handle_message(message, &th_vals, &th_ui_state);
+                    let res = handle_message(message, &th_vals, &th_ui_state, th_offline.as_ref());
                     if let Err(e) = res {
                         let error = format!("Error handling message: {:?}", e);
+                        #[cfg(feature = "message-dump")]
+                        let error = format!("{} {}", error, description);
+                        th_ui_state.error(
+                            Severity::Error,
+                            ErrorSource::Value,
+                            value_id,
+                            error.clone(),
+                        );
                         th_channel
                             .send(WriteMessage::Command(CommandMessage::Error(error)))
                             .unwrap();
@@ -159,21 +436,34 @@ fn start_gui_client(
             .unwrap();
 
         // send thread -----------------------------------------
+        let th_handshake = handshake.clone();
+        let th_ui_state = ui_state.clone();
+        let stats = ui_state.stats.clone();
         let write_thread = thread::Builder::new().name("Write".to_string());
         let send_thread = write_thread
             .spawn(move || {
                 // send handshake
-                let handshake = CommandMessage::Handshake(version, handshake);
-                let message = WriteMessage::Command(handshake);
-                let res = write_message(message, &mut stream_write);
+                let message = WriteMessage::Command(th_handshake);
+                let res = write_message(message, &mut stream_write, announced, &stats);
                 if let Err(e) = res {
-                    println!("Error for sending hadnskae: {:?}", e); // TODO: log error
+                    let message = format!("Error for sending hadnskae: {:?}", e);
+                    th_ui_state.error(Severity::Error, ErrorSource::Transport, None, message);
                     return rx;
                 }
 
+                // the server sends all values until it gets the subscription
+                if th_ui_state.is_subscribed() {
+                    let message = WriteMessage::Command(th_ui_state.subscribe_command());
+                    if let Err(e) = write_message(message, &mut stream_write, announced, &stats) {
+                        let message = format!("Error sending subscription: {:?}", e);
+                        th_ui_state.error(Severity::Error, ErrorSource::Transport, None, message);
+                        return rx;
+                    }
+                }
+
                 loop {
                     // wait for the message from the channel
-                    let message = rx.recv().unwrap();
+                    let message = recv_message(&rx, heartbeat, th_ui_state.round_trip());
 
                     // check if the message is terminate
                     if let WriteMessage::Terminate = message {
@@ -181,10 +471,28 @@ fn start_gui_client(
                         break;
                     }
 
-                    // write the message
-                    let res = write_message(message, &mut stream_write);
+                    // flush the stream and confirm that all previous messages were sent
+                    if let WriteMessage::Flush(confirm) = message {
+                        if let Err(e) = stream_write.flush() {
+                            let message = format!("Error for flushing stream: {:?}", e);
+                            th_ui_state.error(
+                                Severity::Error,
+                                ErrorSource::Transport,
+                                None,
+                                message,
+                            );
+                            break;
+                        }
+                        let _ = confirm.send(());
+                        continue;
+                    }
+
+                    // write the message, the extensions are known after the handshake reply
+                    let extensions = th_ui_state.extensions().unwrap_or_default();
+                    let res = write_message(message, &mut stream_write, extensions, &stats);
                     if let Err(e) = res {
-                        println!("Error for sending message: {:?}", e); // TODO: log error
+                        let message = format!("Error for sending message: {:?}", e);
+                        th_ui_state.error(Severity::Error, ErrorSource::Transport, None, message);
                         break;
                     }
                 }
@@ -193,6 +501,7 @@ fn start_gui_client(
             .unwrap();
 
         ui_state.set_state(ConnectionState::Connected);
+        ui_state.send_client_info();
 
         // wait for the read thread to finish
         recv_tread.join().unwrap();
@@ -209,6 +518,13 @@ pub struct ClientBuilder {
     creator: ValuesCreator,
     channel: Sender<WriteMessage>,
     rx: Receiver<WriteMessage>,
+    options: Options,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientBuilder {
@@ -220,6 +536,7 @@ impl ClientBuilder {
             creator,
             channel,
             rx,
+            options: Options::default(),
         }
     }
 
@@ -227,24 +544,144 @@ impl ClientBuilder {
         &mut self.creator
     }
 
-    pub fn build(self, context: Context, addr: Ipv4Addr, port: u16, handshake: u64) -> UIState {
+    /// Send the ping if nothing was sent for the interval. If nothing is received for three
+    /// intervals, the connection is closed, so the dead connection is detected without waiting
+    /// for the TCP timeout. The server answers the ping also without its own heartbeat.
+    pub fn set_heartbeat(&mut self, interval: Option<Duration>) {
+        self.options.heartbeat = interval;
+    }
+
+    /// Request the CRC32 checksum of every message except the commands. The corrupted message
+    /// closes the connection with the [`ChecksumError`](crate::ChecksumError). The messages
+    /// are checked only if the server accepts it in the handshake reply.
+    pub fn set_checksum(&mut self, enabled: bool) {
+        self.options.checksum = enabled;
+    }
+
+    /// Connect to the server through the SOCKS5 proxy, None connects directly. The host name of
+    /// the server is sent to the proxy unresolved.
+    pub fn set_socks5_proxy(&mut self, proxy: Option<Socks5Proxy>) {
+        self.options.connect = match proxy {
+            Some(proxy) => Connect::Socks5(proxy),
+            None => Connect::Direct,
+        };
+    }
+
+    /// Open the connection with the function instead of connecting to the address, for example
+    /// through the tunnel of the own SSH library. The function gets the address given to
+    /// the build and is called again for every reconnection. It replaces the SOCKS5 proxy.
+    pub fn set_connector(
+        &mut self,
+        connector: impl Fn(&Address) -> io::Result<TcpStream> + Send + Sync + 'static,
+    ) {
+        self.options.connect = Connect::Custom(Arc::new(connector));
+    }
+
+    /// Retry the failed connection attempts with the growing delay, for example while the server
+    /// is starting. None tries to connect only once for every [`UIState::connect`] call, which
+    /// is the default. The policy applies to every connect call, also after the lost connection.
+    pub fn set_reconnect(&mut self, reconnect: Option<Reconnect>) {
+        self.options.reconnect = reconnect;
+    }
+
+    /// Keep the values, dicts, lists and signals written while the client is not connected and
+    /// send them after the connection, otherwise they are dropped. Only the newest write of
+    /// the value or the signal is kept. The value written offline wins over the value from
+    /// the server, the changes of the dicts and lists are applied over the server content.
+    pub fn set_offline_writes(&mut self, enabled: bool) {
+        self.options.offline = enabled;
+    }
+
+    /// Set the options of the TCP socket, they are applied to every connection.
+    pub fn set_tcp_options(&mut self, options: TcpOptions) {
+        self.options.tcp = options;
+    }
+
+    /// Limit the data size of one received message. The larger messages are skipped and reported
+    /// to the server, the connection continues. The default is
+    /// [`DEFAULT_MAX_MESSAGE_SIZE`](crate::DEFAULT_MAX_MESSAGE_SIZE).
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.options.max_message_size = size;
+    }
+
+    /// Send the token in the handshake, the server can validate it with the authenticator
+    /// callback. The token is sent in plain, unless the connection is encrypted.
+    pub fn set_auth_token(&mut self, token: Option<String>) {
+        self.options.auth_token = token;
+    }
+
+    /// Encrypt the connection with the pre-shared key, the server has to use the same key.
+    #[cfg(feature = "encryption")]
+    pub fn set_psk(&mut self, key: Option<PresharedKey>) {
+        self.options.psk = key;
+    }
+
+    /// Validate the registered values without connecting, see [`ValuesCreator::validate`].
+    pub fn validate(&self) -> Result<u64, Vec<String>> {
+        self.creator.validate()
+    }
+
+    /// Get the wire type of the registered value, see [`ValuesCreator::wire_type`].
+    pub fn wire_type(&self, id: u32) -> Option<WireType> {
+        self.creator.wire_type(id)
+    }
+
+    /// Build the client connecting to the server on the IPv4 or IPv6 address.
+    pub fn build(
+        self,
+        context: Context,
+        addr: impl Into<IpAddr>,
+        port: u16,
+        handshake: u64,
+    ) -> UIState {
+        self.build_address(context, SocketAddr::new(addr.into(), port), handshake)
+    }
+
+    /// Build the client connecting to the server with the host name, for example
+    /// `my-instrument.local`. The name is resolved again on every connection.
+    pub fn build_host(self, context: Context, host: &str, port: u16, handshake: u64) -> UIState {
+        self.build_address(context, Address::host(host, port), handshake)
+    }
+
+    /// Build the client connecting to the server on the Unix domain socket with given path.
+    #[cfg(unix)]
+    pub fn build_unix(self, context: Context, path: impl AsRef<Path>, handshake: u64) -> UIState {
+        let addr = Address::Unix(path.as_ref().to_path_buf());
+        self.build_address(context, addr, handshake)
+    }
+
+    /// Build the client connecting to any kind of the address.
+    pub fn build_address(
+        self,
+        context: Context,
+        addr: impl Into<Address>,
+        handshake: u64,
+    ) -> UIState {
+        let addr = addr.into();
         let Self {
             creator,
             channel,
             rx,
+            options,
         } = self;
 
-        let addr = SocketAddrV4::new(addr, port);
-        let (values, version) = creator.get_values();
-        let ui_state = UIState::new(context, channel.clone());
+        let (values, version, reserved, layout) = creator.get_values();
+        let token = options.auth_token.clone();
+        let handshake = CommandMessage::Handshake(version, handshake, reserved, layout, token);
+        let ui_state = UIState::new(context.clone(), channel.clone());
+        let info_state = ui_state.clone();
+        context.on_begin_pass(
+            "egui_pysync_client_info",
+            Arc::new(move |_| info_state.send_client_info()),
+        );
         start_gui_client(
             addr,
             values,
-            version,
             rx,
             channel,
             ui_state.clone(),
             handshake,
+            options,
         );
 
         ui_state
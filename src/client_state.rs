@@ -1,25 +1,62 @@
-use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
 use std::time::Duration;
 
 use egui::{mutex::RwLock, Context};
 
+use crate::commands::{ClientInfo, CommandMessage, RoundTrip};
+use crate::errors::{ErrorCallback, ErrorLog, ErrorRecord, ErrorSource, Severity};
 use crate::event::Event;
-use crate::transport::WriteMessage;
+use crate::stats::{MessageStats, Stats};
+use crate::transport::{Extensions, WriteMessage};
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ConnectionState {
     NotConnected,
     Connected,
     Disconnected,
 }
 
+/// Change of the connection state, see [`UIState::set_state_callback`] and
+/// [`UIState::state_changes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateChange {
+    pub previous: ConnectionState,
+    pub state: ConnectionState,
+    /// Message of the last error reported in the previous state, for example why the connection
+    /// was lost.
+    pub error: Option<String>,
+}
+
+/// Called with every change of the connection state.
+pub type StateCallback = Box<dyn Fn(&StateChange) + Send + Sync>;
+
+/// Values received by the client, see [`UIState::subscribe`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Subscription {
+    /// Ids of the values.
+    pub ids: Vec<u32>,
+    /// Names of the groups of the server, all values of the group are received.
+    pub groups: Vec<String>,
+}
+
 #[derive(Clone)]
 pub struct UIState {
     context: Context,
     connect_signal: Event,
     state: Arc<RwLock<ConnectionState>>,
     channel: Sender<WriteMessage>,
+    round_trip: RoundTrip,
+    client_info: ClientInfo, // last info sent to the server
+    extensions: Arc<RwLock<Option<Extensions>>>,
+    rejection: Arc<RwLock<Option<String>>>,
+    subscription: Arc<RwLock<Option<Subscription>>>,
+    errors: ErrorLog,
+    state_callback: Arc<RwLock<Option<StateCallback>>>,
+    state_channels: Arc<RwLock<Vec<Sender<StateChange>>>>,
+    state_errors: Arc<AtomicU64>, // sequence of the first error in the actual state
+    pub(crate) stats: Stats,
 }
 
 impl UIState {
@@ -29,6 +66,16 @@ impl UIState {
             connect_signal: Event::new(),
             state: Arc::new(RwLock::new(ConnectionState::NotConnected)),
             channel,
+            round_trip: RoundTrip::new(),
+            client_info: ClientInfo::new(),
+            extensions: Arc::new(RwLock::new(None)),
+            rejection: Arc::new(RwLock::new(None)),
+            subscription: Arc::new(RwLock::new(None)),
+            errors: ErrorLog::new(),
+            state_callback: Arc::new(RwLock::new(None)),
+            state_channels: Arc::new(RwLock::new(Vec::new())),
+            state_errors: Arc::new(AtomicU64::new(0)),
+            stats: Stats::new(false),
         }
     }
 
@@ -54,9 +101,199 @@ impl UIState {
         self.channel.send(WriteMessage::Terminate).unwrap();
     }
 
+    /// Block until all messages sent before this call are written and the stream is flushed.
+    ///
+    /// Returns `false` if the client is not connected, the connection is lost or the timeout expires.
+    pub fn flush_and_wait(&self, timeout: Duration) -> bool {
+        if self.get_state() != ConnectionState::Connected {
+            return false;
+        }
+
+        let (confirm, rx) = mpsc::channel();
+        self.channel.send(WriteMessage::Flush(confirm)).unwrap();
+        rx.recv_timeout(timeout).is_ok()
+    }
+
+    /// Send the ping to the server. The round trip time is updated when the pong is received.
+    pub fn ping(&self) {
+        if self.get_state() == ConnectionState::Connected {
+            let message = WriteMessage::Command(self.round_trip.ping());
+            self.channel.send(message).unwrap();
+        }
+    }
+
+    /// Get the round trip time of the last ping. It is `None` until the first pong is received.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.round_trip.last_rtt()
+    }
+
+    /// Get the counters of the messages sent and received by the client since it was built.
+    pub fn stats(&self) -> MessageStats {
+        self.stats.snapshot()
+    }
+
+    /// Get the average round trip time of the last 8 pings, so it does not jump with every ping.
+    /// It is `None` until the first pong is received. The pings are sent by [`UIState::ping`]
+    /// or by the heartbeat.
+    pub fn latency(&self) -> Option<Duration> {
+        self.round_trip.latency()
+    }
+
+    /// Get the extensions used for the connection. It is `None` until the server accepts
+    /// the handshake, also for the servers which do not reply to the handshake.
+    pub fn extensions(&self) -> Option<Extensions> {
+        *self.extensions.read()
+    }
+
+    /// Get the reason why the server rejected the last handshake, for example a different version.
+    pub fn rejection(&self) -> Option<String> {
+        self.rejection.read().clone()
+    }
+
+    /// Receive only the subscribed values from the server, for example a dashboard without
+    /// the image stream. None receives all values, which is the default. The newly subscribed
+    /// values are sent again by the server, the subscription is kept after the reconnection.
+    pub fn subscribe(&self, subscription: Option<Subscription>) {
+        *self.subscription.write() = subscription;
+        if self.get_state() == ConnectionState::Connected {
+            let command = self.subscribe_command();
+            self.channel.send(WriteMessage::Command(command)).unwrap();
+        }
+    }
+
+    /// Ask the server to send the actual content of the value again, for example when a message
+    /// could be lost. The server stops waiting for the acknowledges of the value, so the writes
+    /// of the client pass again. It is ignored when the client is not connected.
+    pub fn resync(&self, id: u32) {
+        if self.get_state() == ConnectionState::Connected {
+            let command = CommandMessage::Resync(id);
+            self.channel.send(WriteMessage::Command(command)).unwrap();
+        }
+    }
+
+    /// Get the last errors of the client with the sequence number from the given one, the oldest
+    /// first. Only the last 100 errors are kept.
+    pub fn errors(&self, since: u64) -> Vec<ErrorRecord> {
+        self.errors.since(since)
+    }
+
+    /// Get the last error of the client, it is kept also after the reconnection.
+    pub fn last_error(&self) -> Option<ErrorRecord> {
+        self.errors.last()
+    }
+
+    /// Set the callback which is called with every new error from the thread which reported it.
+    /// Without the callback the errors are only kept for [`Self::errors`] and [`Self::last_error`].
+    pub fn set_error_callback(&self, callback: Option<ErrorCallback>) {
+        self.errors.set_callback(callback);
+    }
+
+    /// Set the callback which is called with every change of the connection state from the thread
+    /// of the connection, for example to show the notification when the connection is lost.
+    pub fn set_state_callback(&self, callback: Option<StateCallback>) {
+        *self.state_callback.write() = callback;
+    }
+
+    /// Get the receiver of all following changes of the connection state. The changes are sent
+    /// until the receiver is dropped, so they can be read with `try_iter` in every frame.
+    pub fn state_changes(&self) -> Receiver<StateChange> {
+        let (channel, rx) = mpsc::channel();
+        self.state_channels.write().push(channel);
+        rx
+    }
+
+    pub(crate) fn error(
+        &self,
+        severity: Severity,
+        source: ErrorSource,
+        value_id: Option<u32>,
+        message: String,
+    ) {
+        self.errors.push(severity, source, value_id, message);
+        self.context.request_repaint();
+    }
+
+    pub(crate) fn subscribe_command(&self) -> CommandMessage {
+        let subscription = self.subscription.read().clone();
+        CommandMessage::Subscribe(subscription.map(|s| (s.ids, s.groups)))
+    }
+
+    pub(crate) fn is_subscribed(&self) -> bool {
+        self.subscription.read().is_some()
+    }
+
+    pub(crate) fn set_extensions(&self, extensions: Extensions) {
+        *self.extensions.write() = Some(extensions);
+    }
+
+    pub(crate) fn set_rejection(&self, reason: String) {
+        *self.rejection.write() = Some(reason);
+    }
+
+    pub(crate) fn round_trip(&self) -> &RoundTrip {
+        &self.round_trip
+    }
+
+    pub(crate) fn pong(&self, token: u64) {
+        self.round_trip.pong(token);
+    }
+
+    pub(crate) fn send_pong(&self, token: u64) {
+        let message = WriteMessage::Command(CommandMessage::Pong(token));
+        self.channel.send(message).unwrap();
+    }
+
+    // Send the viewport size and the pixels per point to the server if it changed since
+    // the last time. It is checked at the beginning of every frame.
+    pub(crate) fn send_client_info(&self) {
+        if self.get_state() != ConnectionState::Connected {
+            return;
+        }
+
+        let rect = self.context.screen_rect();
+        let ppp = self.context.pixels_per_point();
+        if self.client_info.set([rect.width(), rect.height(), ppp]) {
+            let info = CommandMessage::ClientInfo(rect.width(), rect.height(), ppp);
+            self.channel.send(WriteMessage::Command(info)).unwrap();
+        }
+    }
+
     pub(crate) fn set_state(&self, state: ConnectionState) {
-        *self.state.write() = state;
+        // round trip time and client info of the previous connection are not valid anymore
+        if state == ConnectionState::Connected {
+            self.round_trip.clear();
+            self.client_info.clear();
+        }
+        // the reply to the handshake can come before the connected state is set
+        if state == ConnectionState::NotConnected {
+            *self.extensions.write() = None;
+            *self.rejection.write() = None;
+        }
+        let previous = std::mem::replace(&mut *self.state.write(), state);
         self.context.request_repaint();
+        if previous == state {
+            return;
+        }
+
+        let errors_from = self
+            .state_errors
+            .swap(self.errors.next(), Ordering::Relaxed);
+        let error = self
+            .errors
+            .last()
+            .filter(|record| record.sequence >= errors_from)
+            .map(|record| record.message);
+        let change = StateChange {
+            previous,
+            state,
+            error,
+        };
+        self.state_channels
+            .write()
+            .retain(|channel| channel.send(change.clone()).is_ok());
+        if let Some(ref callback) = *self.state_callback.read() {
+            callback(&change);
+        }
     }
 
     pub fn get_state(&self) -> ConnectionState {
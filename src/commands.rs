@@ -1,21 +1,167 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) enum CommandMessage {
     Error(String),
     Ack(u32),
-    Handshake(u64, u64),
+    // version, hash, reserved ids, layout hash and auth token
+    Handshake(u64, u64, u32, u64, Option<String>),
     Update(f32),
+    Ping(u64),                 // token of the ping, only the sender knows its meaning
+    Pong(u64),                 // echoed token from the ping
+    ClientInfo(f32, f32, f32), // viewport width, height in points and pixels per point
+    SwapResult(u32, bool),     // value id and if the compare and swap was successful
+    HandshakeAccepted(u8),     // flag of the extensions used for the connection
+    HandshakeRejected(String), // reason of the rejection
+    // value ids and groups, None for all values
+    Subscribe(Option<(Vec<u32>, Vec<String>)>),
+    Resync(u32), // value id, the client asks for the actual content of the value
+}
+
+// Viewport of the client. The client sends it after the handshake and when it changes.
+#[derive(Clone)]
+pub(crate) struct ClientInfo {
+    info: Arc<RwLock<Option<[f32; 3]>>>,
 }
 
-#[cfg(feature = "server")]
+impl ClientInfo {
+    pub(crate) fn new() -> Self {
+        Self {
+            info: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    // returns if the info was changed
+    pub(crate) fn set(&self, info: [f32; 3]) -> bool {
+        let mut w = self.info.write().unwrap();
+        let changed = *w != Some(info);
+        *w = Some(info);
+        changed
+    }
+
+    pub(crate) fn clear(&self) {
+        *self.info.write().unwrap() = None;
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn get(&self) -> Option<[f32; 3]> {
+        *self.info.read().unwrap()
+    }
+}
+
+const LATENCY_SAMPLES: usize = 8; // round trips in the rolling latency
+
+// Round trip time measured by the ping and pong commands. The ping carries only the token,
+// the sender keeps the monotonic time of the sent pings, so the wall clock changes do not
+// matter. The pong of an unknown token, a duplicate or a late one, is ignored.
+#[derive(Clone)]
+pub(crate) struct RoundTrip {
+    samples: Arc<RwLock<VecDeque<Duration>>>, // the last round trips, the newest is at the back
+    sent: Arc<Mutex<VecDeque<(u64, Instant)>>>, // pings waiting for the pong, the newest at the back
+    token: Arc<AtomicU64>,
+}
+
+impl RoundTrip {
+    pub(crate) fn new() -> Self {
+        Self {
+            samples: Arc::new(RwLock::new(VecDeque::with_capacity(LATENCY_SAMPLES))),
+            sent: Arc::new(Mutex::new(VecDeque::with_capacity(LATENCY_SAMPLES))),
+            token: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub(crate) fn ping(&self) -> CommandMessage {
+        let token = self.token.fetch_add(1, Ordering::Relaxed);
+        let mut sent = self.sent.lock().unwrap();
+        if sent.len() == LATENCY_SAMPLES {
+            sent.pop_front();
+        }
+        sent.push_back((token, Instant::now()));
+        CommandMessage::Ping(token)
+    }
+
+    pub(crate) fn pong(&self, token: u64) {
+        let mut sent = self.sent.lock().unwrap();
+        let Some(position) = sent.iter().position(|(t, _)| *t == token) else {
+            return;
+        };
+        let (_, time) = sent.remove(position).unwrap();
+        drop(sent);
+
+        let mut samples = self.samples.write().unwrap();
+        if samples.len() == LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(time.elapsed());
+    }
+
+    pub(crate) fn clear(&self) {
+        self.samples.write().unwrap().clear();
+        self.sent.lock().unwrap().clear();
+    }
+
+    pub(crate) fn last_rtt(&self) -> Option<Duration> {
+        self.samples.read().unwrap().back().copied()
+    }
+
+    // average of the last round trips
+    pub(crate) fn latency(&self) -> Option<Duration> {
+        let samples = self.samples.read().unwrap();
+        match samples.len() {
+            0 => None,
+            n => Some(samples.iter().sum::<Duration>() / n as u32),
+        }
+    }
+}
+
+#[cfg(any(feature = "server", feature = "message-dump"))]
 impl CommandMessage {
     pub fn as_str(&self) -> &str {
         match self {
             CommandMessage::Error(_) => "ErrorCommand",
             CommandMessage::Ack(_) => "AckCommand",
-            CommandMessage::Handshake(_, _) => "HandshakeCommand",
+            CommandMessage::Handshake(_, _, _, _, _) => "HandshakeCommand",
             CommandMessage::Update(_) => "UpdateCommand",
+            CommandMessage::Ping(_) => "PingCommand",
+            CommandMessage::Pong(_) => "PongCommand",
+            CommandMessage::ClientInfo(_, _, _) => "ClientInfoCommand",
+            CommandMessage::SwapResult(_, _) => "SwapResultCommand",
+            CommandMessage::HandshakeAccepted(_) => "HandshakeAcceptedCommand",
+            CommandMessage::HandshakeRejected(_) => "HandshakeRejectedCommand",
+            CommandMessage::Subscribe(_) => "SubscribeCommand",
+            CommandMessage::Resync(_) => "ResyncCommand",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_known_pong_is_measured() {
+        let round_trip = RoundTrip::new();
+        let CommandMessage::Ping(token) = round_trip.ping() else {
+            panic!("Ping expected");
+        };
+
+        round_trip.pong(token + 1);
+        assert!(round_trip.last_rtt().is_none());
+
+        round_trip.pong(token);
+        round_trip.pong(token); // the duplicate from the other client
+        assert_eq!(round_trip.samples.read().unwrap().len(), 1);
+
+        let CommandMessage::Ping(token) = round_trip.ping() else {
+            panic!("Ping expected");
+        };
+        round_trip.clear();
+        round_trip.pong(token);
+        assert!(round_trip.last_rtt().is_none());
+    }
+}
@@ -1,10 +1,12 @@
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, RwLock};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::transport::{deserialize, MessageData};
+use crate::transport::{deserialize, serialize, MessageData, WriteMessage};
 
 #[derive(Deserialize)]
 enum DictMessage<K, V>
@@ -14,15 +16,56 @@ where
     All(HashMap<K, V>),
     Set(K, V),
     Remove(K),
+    Rename(K, K), // old key, new key
+}
+
+impl<K: Eq + Hash, V> DictMessage<K, V> {
+    // The same for the messages from the server and from the client. Returns false if
+    // the renamed key is missing, so the dict differs from the sender.
+    fn apply(self, dict: &mut HashMap<K, V>) -> bool {
+        match self {
+            DictMessage::All(new_dict) => {
+                *dict = new_dict;
+            }
+            DictMessage::Set(key, value) => {
+                dict.insert(key, value);
+            }
+            DictMessage::Remove(key) => {
+                dict.remove(&key);
+            }
+            DictMessage::Rename(old, new) => match dict.remove(&old) {
+                Some(value) => {
+                    dict.insert(new, value);
+                }
+                None => return false,
+            },
+        }
+        true
+    }
+}
+
+#[derive(Serialize)]
+enum DictMessageRef<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    All(&'a HashMap<K, V>),
+    Set(&'a K, &'a V),
+    Remove(&'a K),
+    Rename(&'a K, &'a K),
 }
 
 pub(crate) trait DictUpdate: Sync + Send {
-    fn update_dict(&self, data: MessageData) -> Result<(), String>;
+    // returns false if the change did not match the local dict
+    fn update_dict(&self, data: MessageData) -> Result<bool, String>;
 }
 
 pub struct ValueDict<K, V> {
-    _id: u32,
+    id: u32,
     dict: RwLock<HashMap<K, V>>,
+    channel: Sender<WriteMessage>,
+    changed: AtomicBool,
+    ack: bool, // the server waits for the acknowledges of the writable dict
 }
 
 impl<K, V> ValueDict<K, V>
@@ -30,10 +73,13 @@ where
     K: Clone + Hash + Eq,
     V: Clone,
 {
-    pub(crate) fn new(id: u32) -> Arc<Self> {
+    pub(crate) fn new(id: u32, channel: Sender<WriteMessage>, ack: bool) -> Arc<Self> {
         Arc::new(Self {
-            _id: id,
+            id,
             dict: RwLock::new(HashMap::new()),
+            channel,
+            changed: AtomicBool::new(false),
+            ack,
         })
     }
 
@@ -47,10 +93,64 @@ where
         self.dict.read().unwrap().get(key).cloned()
     }
 
-    pub fn process<R>(&self, op: impl Fn(&HashMap<K, V>) -> R) -> R {
+    pub fn process<R>(&self, op: impl FnOnce(&HashMap<K, V>) -> R) -> R {
         let d = self.dict.read().unwrap();
         op(&*d)
     }
+
+    /// If the dict was changed by the server since the last call. The flag is shared by all
+    /// callers, so only one place in the UI should take it.
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+}
+
+// The changes are applied to the local dict and sent to the server, which relays them to
+// the other clients. The change does not set the changed flag. Only the dicts added
+// by `add_dict_writable` can be changed, the server sends the other ones back.
+impl<K, V> ValueDict<K, V>
+where
+    K: Serialize + Hash + Eq,
+    V: Serialize,
+{
+    pub fn set(&self, dict: HashMap<K, V>, signal: bool) {
+        let data = serialize(DictMessageRef::All(&dict));
+        let mut d = self.dict.write().unwrap();
+        self.send(data, signal);
+        *d = dict;
+    }
+
+    pub fn set_item(&self, key: K, value: V, signal: bool) {
+        let data = serialize(DictMessageRef::Set(&key, &value));
+        let mut d = self.dict.write().unwrap();
+        self.send(data, signal);
+        d.insert(key, value);
+    }
+
+    /// Returns the removed value, nothing is sent if the key is not in the dict.
+    pub fn remove_item(&self, key: &K, signal: bool) -> Option<V> {
+        let mut d = self.dict.write().unwrap();
+        let value = d.remove(key)?;
+        self.send(serialize(DictMessageRef::Remove::<K, V>(key)), signal);
+        Some(value)
+    }
+
+    /// Rename the key and keep its value, the item with the new key is replaced. Returns false
+    /// and nothing is sent if the old key is not in the dict.
+    pub fn rename_item(&self, old: &K, new: K, signal: bool) -> bool {
+        let mut d = self.dict.write().unwrap();
+        let Some(value) = d.remove(old) else {
+            return false;
+        };
+        self.send(serialize(DictMessageRef::Rename::<K, V>(old, &new)), signal);
+        d.insert(new, value);
+        true
+    }
+
+    fn send(&self, data: MessageData, signal: bool) {
+        let message = WriteMessage::Dict(self.id, signal, data);
+        self.channel.send(message).unwrap();
+    }
 }
 
 impl<K, V> DictUpdate for ValueDict<K, V>
@@ -58,20 +158,14 @@ where
     K: for<'a> Deserialize<'a> + Eq + Hash + Send + Sync,
     V: for<'a> Deserialize<'a> + Send + Sync,
 {
-    fn update_dict(&self, data: MessageData) -> Result<(), String> {
+    fn update_dict(&self, data: MessageData) -> Result<bool, String> {
         let message: DictMessage<K, V> = deserialize(data).map_err(|e| e.to_string())?;
-        match message {
-            DictMessage::All(dict) => {
-                *self.dict.write().unwrap() = dict;
-            }
-            DictMessage::Set(key, value) => {
-                self.dict.write().unwrap().insert(key, value);
-            }
-            DictMessage::Remove(key) => {
-                self.dict.write().unwrap().remove(&key);
-            }
+        let matched = message.apply(&mut self.dict.write().unwrap());
+        self.changed.store(true, Ordering::Relaxed);
+        if self.ack {
+            self.channel.send(WriteMessage::ack(self.id)).unwrap();
         }
-        Ok(())
+        Ok(matched)
     }
 }
 
@@ -80,26 +174,51 @@ where
 #[cfg(feature = "server")]
 pub(crate) mod server {
     use super::*;
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::sync::mpsc::Sender;
 
-    use pyo3::exceptions::PyKeyError;
+    use std::sync::Mutex;
+
+    use pyo3::exceptions::{PyKeyError, PyValueError};
     use pyo3::prelude::*;
-    use pyo3::types::PyDict;
-    use serde::Serialize;
+    use pyo3::types::{PyDict, PyString, PyTuple};
 
     use crate::python_convert::ToPython;
-    use crate::server::SyncTrait;
-    use crate::transport::{serialize, WriteMessage};
+    use crate::server::{Acknowledge, SyncTarget, SyncTrait};
+    use crate::signals::ChangedValues;
+    use crate::values::server::{Pending, UpdateValueServer};
+
+    // change made by the client, the signal is the tuple of the action and the key
+    enum DictChange<K> {
+        All,
+        Set(K),
+        Remove(K),
+        Rename(K, K),
+    }
 
-    #[derive(Serialize)]
-    enum DictMessageRef<'a, K, V>
-    where
-        K: Eq + Hash,
-    {
-        All(&'a HashMap<K, V>),
-        Set(&'a K, &'a V),
-        Remove(&'a K),
+    impl<K: Clone + Eq + Hash> DictChange<K> {
+        fn new<V>(message: &DictMessage<K, V>) -> Self {
+            match message {
+                DictMessage::All(_) => Self::All,
+                DictMessage::Set(key, _) => Self::Set(key.clone()),
+                DictMessage::Remove(key) => Self::Remove(key.clone()),
+                DictMessage::Rename(old, new) => Self::Rename(old.clone(), new.clone()),
+            }
+        }
+    }
+
+    impl<K: ToPython> ToPython for DictChange<K> {
+        fn to_python<'py>(&self, py: Python<'py>) -> Bound<'py, PyAny> {
+            let (action, key) = match self {
+                Self::All => ("all", ().to_python(py)),
+                Self::Set(key) => ("set", key.to_python(py)),
+                Self::Remove(key) => ("remove", key.to_python(py)),
+                Self::Rename(old, new) => {
+                    let keys = PyTuple::new(py, [old.to_python(py), new.to_python(py)]).unwrap();
+                    ("rename", keys.into_any())
+                }
+            };
+            let action = PyString::new(py, action).into_any();
+            PyTuple::new(py, [action, key]).unwrap().into_any()
+        }
     }
 
     pub(crate) trait PyDictTrait: Send + Sync {
@@ -113,14 +232,27 @@ pub(crate) mod server {
             update: bool,
         ) -> PyResult<()>;
         fn del_item_py(&self, key: &Bound<PyAny>, update: bool) -> PyResult<()>;
+        fn rename_item_py(
+            &self,
+            old: &Bound<PyAny>,
+            new: &Bound<PyAny>,
+            overwrite: bool,
+            update: bool,
+        ) -> PyResult<()>;
         fn len_py(&self) -> usize;
+        // serialized content for the state file
+        fn save(&self) -> Vec<u8>;
+        fn load_py(&self, py: Python, data: &[u8]) -> PyResult<()>;
     }
 
     pub(crate) struct PyValueDict<K, V> {
         id: u32,
         dict: RwLock<HashMap<K, V>>,
+        pending: Mutex<Pending>, // checked with the dict lock
+        ack: bool,               // the clients can write the dict
         channel: Sender<WriteMessage>,
         connected: Arc<AtomicBool>,
+        signals: ChangedValues,
     }
 
     impl<K, V> PyValueDict<K, V> {
@@ -128,20 +260,70 @@ pub(crate) mod server {
             id: u32,
             channel: Sender<WriteMessage>,
             connected: Arc<AtomicBool>,
+            signals: ChangedValues,
+            ack: bool,
         ) -> Arc<Self> {
             Arc::new(Self {
                 id,
                 dict: RwLock::new(HashMap::new()),
+                pending: Mutex::new(Pending::default()),
+                ack,
                 channel,
                 connected,
+                signals,
             })
         }
+
+        // every change sent to the clients waits for their acknowledges, if they can write
+        fn send(&self, message: WriteMessage) {
+            if self.ack {
+                self.pending.lock().unwrap().expect(None);
+            }
+            self.channel.send(message).unwrap();
+        }
+    }
+
+    impl<K, V> UpdateValueServer for PyValueDict<K, V>
+    where
+        K: Serialize + for<'a> Deserialize<'a> + ToPython + Clone + Eq + Hash + 'static,
+        V: Serialize + for<'a> Deserialize<'a> + Send + Sync,
+    {
+        // The applied change is relayed as it was sent, the client with the different dict gets
+        // the whole dict. The change of the client which did not acknowledge the latest changes
+        // of the server would overwrite them, so it is dropped and the client gets the whole dict.
+        fn update_value(
+            &self,
+            data: MessageData,
+            signal: bool,
+            client: usize,
+        ) -> Result<bool, String> {
+            let message: DictMessage<K, V> = deserialize(data)
+                .map_err(|e| format!("Parse error: {} for dict id: {}", e, self.id))?;
+            let change = signal.then(|| DictChange::new(&message));
+            let mut dict = self.dict.write().unwrap();
+            if self.ack && self.pending.lock().unwrap().count(client) > 0 {
+                self.pending.lock().unwrap().reset(client);
+                drop(dict);
+                self.sync(SyncTarget::Client(client));
+                return Ok(false);
+            }
+            let matched = message.apply(&mut dict);
+            drop(dict);
+            if !matched {
+                self.sync(SyncTarget::All);
+                return Err(format!("Change did not match the dict id: {}", self.id));
+            }
+            if let Some(change) = change {
+                self.signals.set(self.id, change);
+            }
+            Ok(true)
+        }
     }
 
     impl<K, V> PyDictTrait for PyValueDict<K, V>
     where
-        K: Serialize + ToPython + for<'py> FromPyObject<'py> + Eq + Hash,
-        V: Serialize + ToPython + for<'py> FromPyObject<'py>,
+        K: Serialize + for<'a> Deserialize<'a> + ToPython + for<'py> FromPyObject<'py> + Eq + Hash,
+        V: Serialize + for<'a> Deserialize<'a> + ToPython + for<'py> FromPyObject<'py>,
     {
         fn get_py<'py>(&self, py: Python<'py>) -> Bound<'py, PyDict> {
             let dict = self.dict.read().unwrap();
@@ -172,13 +354,45 @@ pub(crate) mod server {
             if self.connected.load(Ordering::Relaxed) {
                 let data = serialize(DictMessageRef::Remove::<K, V>(&dict_key));
                 let message = WriteMessage::Dict(self.id, update, data);
-                self.channel.send(message).unwrap();
+                self.send(message);
             }
             d.remove(&dict_key);
 
             Ok(())
         }
 
+        fn rename_item_py(
+            &self,
+            old: &Bound<PyAny>,
+            new: &Bound<PyAny>,
+            overwrite: bool,
+            update: bool,
+        ) -> PyResult<()> {
+            let old_key: K = old.extract()?;
+            let new_key: K = new.extract()?;
+
+            let mut d = self.dict.write().unwrap();
+            if !d.contains_key(&old_key) {
+                return Err(PyKeyError::new_err("Key not found."));
+            }
+            if old_key == new_key {
+                return Ok(());
+            }
+            if !overwrite && d.contains_key(&new_key) {
+                return Err(PyKeyError::new_err("New key already exists."));
+            }
+
+            if self.connected.load(Ordering::Relaxed) {
+                let data = serialize(DictMessageRef::Rename::<K, V>(&old_key, &new_key));
+                let message = WriteMessage::Dict(self.id, update, data);
+                self.send(message);
+            }
+
+            let value = d.remove(&old_key).unwrap();
+            d.insert(new_key, value);
+            Ok(())
+        }
+
         fn set_item_py(
             &self,
             key: &Bound<PyAny>,
@@ -193,7 +407,7 @@ pub(crate) mod server {
             if self.connected.load(Ordering::Relaxed) {
                 let data = serialize(DictMessageRef::Set::<K, V>(&dict_key, &dict_value));
                 let message = WriteMessage::Dict(self.id, update, data);
-                self.channel.send(message).unwrap();
+                self.send(message);
             }
 
             d.insert(dict_key, dict_value);
@@ -216,7 +430,7 @@ pub(crate) mod server {
                 dict.py().allow_threads(|| {
                     let data = serialize(DictMessageRef::All(&new_dict));
                     let message = WriteMessage::Dict(self.id, update, data);
-                    self.channel.send(message).unwrap();
+                    self.send(message);
                 });
             }
 
@@ -228,6 +442,28 @@ pub(crate) mod server {
         fn len_py(&self) -> usize {
             self.dict.read().unwrap().len()
         }
+
+        fn save(&self) -> Vec<u8> {
+            postcard::to_stdvec(&*self.dict.read().unwrap()).unwrap()
+        }
+
+        fn load_py(&self, py: Python, data: &[u8]) -> PyResult<()> {
+            let new_dict: HashMap<K, V> = postcard::from_bytes(data).map_err(|e| {
+                PyValueError::new_err(format!("Parse error: {} for dict id: {}", e, self.id))
+            })?;
+
+            let mut d = self.dict.write().unwrap();
+            if self.connected.load(Ordering::Relaxed) {
+                py.allow_threads(|| {
+                    let data = serialize(DictMessageRef::All(&new_dict));
+                    let message = WriteMessage::Dict(self.id, false, data);
+                    self.send(message);
+                });
+            }
+            *d = new_dict;
+
+            Ok(())
+        }
     }
 
     impl<K, V> SyncTrait for PyValueDict<K, V>
@@ -235,11 +471,67 @@ pub(crate) mod server {
         K: Serialize + Send + Sync + Eq + Hash,
         V: Serialize + Send + Sync,
     {
-        fn sync(&self) {
+        fn sync(&self, target: SyncTarget) {
             let dict = self.dict.read().unwrap();
+            if self.ack {
+                match target {
+                    SyncTarget::All => self.pending.lock().unwrap().expect(None),
+                    SyncTarget::Client(client) => {
+                        self.pending.lock().unwrap().expect_client(client)
+                    }
+                }
+            }
             let data = serialize(DictMessageRef::All(&dict));
             let message = WriteMessage::Dict(self.id, false, data);
-            self.channel.send(message).unwrap();
+            self.channel.send(target.message(message)).unwrap();
         }
     }
+    impl<K: Send + Sync, V: Send + Sync> Acknowledge for PyValueDict<K, V> {
+        fn acknowledge(&self, client: usize) {
+            self.pending.lock().unwrap().acknowledge(client);
+        }
+
+        fn reset(&self, client: usize) {
+            self.pending.lock().unwrap().reset(client);
+        }
+
+        fn remove(&self, client: usize) {
+            self.pending.lock().unwrap().remove(client);
+        }
+
+        fn relayed(&self, client: usize) {
+            self.pending.lock().unwrap().expect(Some(client));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::server::PyValueDict;
+    use super::*;
+    use std::sync::mpsc;
+
+    use crate::server::{Acknowledge, SyncTarget, SyncTrait};
+    use crate::signals::ChangedValues;
+    use crate::values::server::UpdateValueServer;
+
+    #[test]
+    fn change_crossing_server_change_is_dropped() {
+        let (channel, rx) = mpsc::channel();
+        let connected = Arc::new(AtomicBool::new(true));
+        let dict = PyValueDict::<u32, u32>::new(1, channel, connected, ChangedValues::new(), true);
+        dict.reset(0);
+
+        // the client changed the dict before it got the change of the server
+        dict.sync(SyncTarget::All);
+        let change = || serialize(DictMessageRef::Set(&1u32, &2u32));
+        assert!(!dict.update_value(change(), false, 0).unwrap());
+
+        // the client gets the whole dict, its changes pass after the acknowledge
+        let resync = rx.try_iter().last().unwrap();
+        assert!(matches!(resync, WriteMessage::Direct(0, message)
+            if matches!(*message, WriteMessage::Dict(1, false, _))));
+        dict.acknowledge(0);
+        assert!(dict.update_value(change(), false, 0).unwrap());
+    }
 }
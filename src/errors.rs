@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+#[cfg(feature = "server")]
+use pyo3::prelude::*;
+#[cfg(feature = "server")]
+use pyo3::types::PyDict;
+
+// the last records kept for the queries, the older ones are dropped
+const ERROR_HISTORY: usize = 100;
+
+/// Severity of the error record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// The connection continues, for example the value was rejected.
+    Warning,
+    /// The connection was closed or it could not be established.
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Part of the library which reported the error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorSource {
+    /// Binding, accepting and connecting of the socket and its options.
+    Connection,
+    /// Handshake and the rejected clients.
+    Handshake,
+    /// Reading and writing of the messages.
+    Transport,
+    /// Processing of the values, including the validators, transforms and properties.
+    Value,
+    /// Callbacks set by the user.
+    Callback,
+    /// Error reported by the other side of the connection.
+    Remote,
+}
+
+impl ErrorSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Connection => "connection",
+            Self::Handshake => "handshake",
+            Self::Transport => "transport",
+            Self::Value => "value",
+            Self::Callback => "callback",
+            Self::Remote => "remote",
+        }
+    }
+}
+
+/// One reported error of the client or the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorRecord {
+    pub severity: Severity,
+    pub source: ErrorSource,
+    pub message: String,
+    /// Id of the value which caused the error, if it is known.
+    pub value_id: Option<u32>,
+    /// Number of the record, it increases by one for every record.
+    pub sequence: u64,
+    pub time: SystemTime,
+}
+
+#[cfg(feature = "server")]
+impl ErrorRecord {
+    pub(crate) fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let time = self
+            .time
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0.0, |t| t.as_secs_f64());
+
+        let dict = PyDict::new(py);
+        dict.set_item("severity", self.severity.as_str())?;
+        dict.set_item("source", self.source.as_str())?;
+        dict.set_item("message", &self.message)?;
+        dict.set_item("value_id", self.value_id)?;
+        dict.set_item("sequence", self.sequence)?;
+        dict.set_item("time", time)?;
+        Ok(dict)
+    }
+}
+
+/// Called with every new error record.
+pub type ErrorCallback = Box<dyn Fn(&ErrorRecord) + Send + Sync>;
+
+struct History {
+    records: VecDeque<ErrorRecord>,
+    next: u64,
+}
+
+// The last errors and the callback, shared by all threads of the client or the server.
+#[derive(Clone)]
+pub(crate) struct ErrorLog {
+    history: Arc<Mutex<History>>,
+    callback: Arc<RwLock<Option<ErrorCallback>>>,
+}
+
+impl ErrorLog {
+    pub(crate) fn new() -> Self {
+        Self {
+            history: Arc::new(Mutex::new(History {
+                records: VecDeque::with_capacity(ERROR_HISTORY),
+                next: 0,
+            })),
+            callback: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub(crate) fn push(
+        &self,
+        severity: Severity,
+        source: ErrorSource,
+        value_id: Option<u32>,
+        message: String,
+    ) {
+        let record = {
+            let mut history = self.history.lock().unwrap();
+            let record = ErrorRecord {
+                severity,
+                source,
+                message,
+                value_id,
+                sequence: history.next,
+                time: SystemTime::now(),
+            };
+            history.next += 1;
+            if history.records.len() == ERROR_HISTORY {
+                history.records.pop_front();
+            }
+            history.records.push_back(record.clone());
+            record
+        };
+
+        if let Some(ref callback) = *self.callback.read().unwrap() {
+            callback(&record);
+        }
+    }
+
+    // the kept records with the sequence number from the given one
+    pub(crate) fn since(&self, sequence: u64) -> Vec<ErrorRecord> {
+        let history = self.history.lock().unwrap();
+        history
+            .records
+            .iter()
+            .filter(|record| record.sequence >= sequence)
+            .cloned()
+            .collect()
+    }
+
+    // sequence number of the next record
+    pub(crate) fn next(&self) -> u64 {
+        self.history.lock().unwrap().next
+    }
+
+    pub(crate) fn last(&self) -> Option<ErrorRecord> {
+        self.history.lock().unwrap().records.back().cloned()
+    }
+
+    pub(crate) fn set_callback(&self, callback: Option<ErrorCallback>) {
+        *self.callback.write().unwrap() = callback;
+    }
+}
@@ -1,9 +1,12 @@
+use std::borrow::Cow;
 use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 
 use serde::{Deserialize, Serialize};
 
 use crate::nohash::NoHashMap;
+use crate::transport::from_wire_order;
 
 pub trait WriteGraphMessage: Send + Sync {
     fn write_message(self: Box<Self>, head: &mut [u8]) -> Option<Vec<u8>>;
@@ -18,55 +21,44 @@ pub struct Graph<T> {
     pub x: Option<Vec<T>>,
 }
 
+// Native bytes of the elements, the wire byte order is applied by the caller.
+#[cfg(feature = "server")]
+fn as_bytes<T: GraphElement>(values: &[T]) -> &[u8] {
+    let ptr = values.as_ptr() as *const u8;
+    unsafe { std::slice::from_raw_parts(ptr, std::mem::size_of_val(values)) }
+}
+
+// Append the elements from their native bytes. The data do not have to be aligned.
+fn extend_from_bytes<T: GraphElement>(values: &mut Vec<T>, data: &[u8], points: usize) {
+    let old_size = values.len();
+    values.resize(old_size + points, T::zero());
+    let ptr = values[old_size..].as_mut_ptr() as *mut u8;
+    unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, points * size_of::<T>()) };
+}
+
 impl<T: GraphElement> Graph<T> {
     #[cfg(feature = "server")]
     fn to_graph_data(&self) -> (GraphDataInfo<T>, Vec<u8>) {
+        use crate::pool::POOL;
+        use crate::transport::to_wire_order;
+
         let bytes_size = std::mem::size_of::<T>() * self.y.len();
         let points = self.y.len();
 
         match self.x {
             Some(ref x) => {
-                let mut data = vec![0u8; bytes_size * 2];
-                #[cfg(target_endian = "little")]
-                {
-                    let dat_slice = unsafe {
-                        let ptr = x.as_ptr() as *const u8;
-                        std::slice::from_raw_parts(ptr, bytes_size)
-                    };
-                    data[..bytes_size].copy_from_slice(dat_slice);
-
-                    let dat_slice = unsafe {
-                        let ptr = self.y.as_ptr() as *const u8;
-                        std::slice::from_raw_parts(ptr, bytes_size)
-                    };
-                    data[bytes_size..].copy_from_slice(dat_slice);
-                }
-
-                // TODO: implement big endian
-                #[cfg(target_endian = "big")]
-                {
-                    unimplemented!("Big endian not implemented yet.");
-                }
+                let mut data = POOL.take(bytes_size * 2);
+                data.extend_from_slice(as_bytes(&x[..points]));
+                data.extend_from_slice(as_bytes(&self.y));
+                to_wire_order(&mut data, size_of::<T>());
 
                 (GraphDataInfo::new(points, false), data)
             }
 
             None => {
-                let mut data = vec![0u8; bytes_size];
-                #[cfg(target_endian = "little")]
-                {
-                    let dat_slice = unsafe {
-                        let ptr = self.y.as_ptr() as *const u8;
-                        std::slice::from_raw_parts(ptr, bytes_size)
-                    };
-                    data.copy_from_slice(dat_slice);
-                }
-
-                // TODO: implement big endian
-                #[cfg(target_endian = "big")]
-                {
-                    unimplemented!("Big endian not implemented yet.");
-                }
+                let mut data = POOL.take(bytes_size);
+                data.extend_from_slice(as_bytes(&self.y));
+                to_wire_order(&mut data, size_of::<T>());
 
                 (GraphDataInfo::new(points, true), data)
             }
@@ -74,94 +66,49 @@ impl<T: GraphElement> Graph<T> {
     }
 
     fn add_points_from_data(&mut self, info: GraphDataInfo<T>, data: &[u8]) -> Result<(), String> {
+        let decoded = info.decode(data)?;
         let GraphDataInfo {
             points, is_linear, ..
         } = info;
-
-        #[cfg(target_endian = "little")]
-        {
-            match (&mut self.x, is_linear) {
-                (Some(ref mut x), false) => {
-                    let old_size = x.len();
-                    x.resize(old_size + points, T::zero());
-                    let mut ptr = data.as_ptr() as *const T;
-                    let data_slice = unsafe { std::slice::from_raw_parts(ptr, points) };
-                    x[old_size..].copy_from_slice(data_slice);
-
-                    self.y.resize(old_size + points, T::zero());
-                    let data_slice = unsafe {
-                        ptr = ptr.add(points);
-                        std::slice::from_raw_parts(ptr, points)
-                    };
-                    self.y[old_size..].copy_from_slice(data_slice);
-
-                    Ok(())
-                }
-                (None, true) => {
-                    let old_size = self.y.len();
-                    self.y.resize(old_size + points, T::zero());
-                    let data_slice = unsafe {
-                        let ptr = data.as_ptr() as *const T;
-                        std::slice::from_raw_parts(ptr, points)
-                    };
-                    self.y[old_size..].copy_from_slice(data_slice);
-
-                    Ok(())
-                }
-                _ => return Err("Incoming Graph data and graph are not compatible.".to_string()),
+        let data = from_wire_order(&decoded, size_of::<T>());
+
+        match (&mut self.x, is_linear) {
+            (Some(ref mut x), false) => {
+                // x is written first
+                let bytes = points * size_of::<T>();
+                extend_from_bytes(x, &data[..bytes], points);
+                extend_from_bytes(&mut self.y, &data[bytes..], points);
+                Ok(())
             }
-        }
-
-        #[cfg(target_endian = "big")]
-        {
-            unimplemented!("Big endian not implemented yet.");
+            (None, true) => {
+                extend_from_bytes(&mut self.y, &data, points);
+                Ok(())
+            }
+            _ => Err("Incoming Graph data and graph are not compatible.".to_string()),
         }
     }
 
-    fn from_graph_data(info: GraphDataInfo<T>, data: &[u8]) -> Self {
+    fn from_graph_data(info: GraphDataInfo<T>, data: &[u8]) -> Result<Self, String> {
+        let decoded = info.decode(data)?;
         let GraphDataInfo {
             is_linear, points, ..
         } = info;
+        let data = from_wire_order(&decoded, size_of::<T>());
 
-        #[cfg(target_endian = "little")]
-        {
-            match is_linear {
-                true => {
-                    let mut y: Vec<T> = Vec::with_capacity(points);
-                    let y_ptr = y.as_mut_ptr() as *mut u8;
-                    let bytes = points * size_of::<T>();
-                    unsafe {
-                        std::ptr::copy_nonoverlapping(data.as_ptr(), y_ptr, bytes);
-                        y.set_len(points);
-                    }
-
-                    Graph { x: None, y }
-                }
-                false => {
-                    let bytes = points * size_of::<T>();
-                    let mut y: Vec<T> = Vec::with_capacity(points);
-                    let ptr = y.as_mut_ptr() as *mut u8;
-                    let mut data_ptr = data.as_ptr();
-                    unsafe {
-                        std::ptr::copy_nonoverlapping(data_ptr, ptr, bytes);
-                        y.set_len(points);
-                    }
-                    let mut x: Vec<T> = Vec::with_capacity(points);
-                    let ptr = x.as_mut_ptr() as *mut u8;
-                    unsafe {
-                        data_ptr = data_ptr.add(bytes);
-                        std::ptr::copy_nonoverlapping(data_ptr, ptr, bytes);
-                        x.set_len(points);
-                    }
-
-                    Graph { x: Some(x), y }
-                }
+        let mut y: Vec<T> = Vec::with_capacity(points);
+        match is_linear {
+            true => {
+                extend_from_bytes(&mut y, &data, points);
+                Ok(Graph { x: None, y })
+            }
+            false => {
+                // x is written first, same as in the AddPoints message
+                let bytes = points * size_of::<T>();
+                let mut x: Vec<T> = Vec::with_capacity(points);
+                extend_from_bytes(&mut x, &data[..bytes], points);
+                extend_from_bytes(&mut y, &data[bytes..], points);
+                Ok(Graph { x: Some(x), y })
             }
-        }
-
-        #[cfg(target_endian = "big")]
-        {
-            unimplemented!("Big endian not implemented yet.");
         }
     }
 }
@@ -171,17 +118,117 @@ struct GraphDataInfo<T> {
     phantom: std::marker::PhantomData<T>,
     is_linear: bool,
     points: usize,
+    compressed: bool,
 }
 
-#[cfg(feature = "server")]
 impl<T> GraphDataInfo<T> {
+    #[cfg(feature = "server")]
     fn new(points: usize, is_linear: bool) -> Self {
         Self {
             phantom: std::marker::PhantomData,
             is_linear,
             points,
+            compressed: false,
+        }
+    }
+
+    #[cfg(feature = "server")]
+    fn compress(mut self, data: Vec<u8>) -> (Self, Vec<u8>) {
+        let data = encode_floats(&data, size_of::<T>());
+        self.compressed = true;
+        (self, data)
+    }
+
+    // the number of points comes from the other side, it is checked before any allocation
+    fn decode<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, String> {
+        let mismatch = || "Graph data size does not match the number of points.".to_string();
+        let values = match self.is_linear {
+            true => Some(self.points),
+            false => self.points.checked_mul(2),
+        };
+        let values = values.ok_or_else(mismatch)?;
+        let size = values.checked_mul(size_of::<T>()).ok_or_else(mismatch)?;
+
+        let data = match self.compressed {
+            // every compressed value has at least the byte with the counts
+            true if values > data.len() => return Err(mismatch()),
+            true => Cow::Owned(decode_floats(data, size_of::<T>(), values)?),
+            false => Cow::Borrowed(data),
+        };
+
+        if data.len() != size {
+            return Err(mismatch());
+        }
+        Ok(data)
+    }
+}
+
+/*
+    Float codec for the graph data. Every value is XORed with the previous one and only the bytes
+    between the leading and the trailing zero bytes are stored, preceded by one byte with both
+    counts (leading in the high nibble). Slowly varying data have the sign, exponent and high
+    mantissa bytes equal, so most of the XOR is zero. It works on bytes, so the byte order of
+    the values does not matter.
+*/
+#[cfg(feature = "server")]
+fn encode_floats(data: &[u8], size: usize) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() / 2);
+    let mut previous = [0u8; 8];
+
+    for value in data.chunks_exact(size) {
+        let mut xor = [0u8; 8];
+        for i in 0..size {
+            xor[i] = value[i] ^ previous[i];
+        }
+        previous[..size].copy_from_slice(value);
+
+        let xor = &xor[..size];
+        let leading = xor.iter().take_while(|b| **b == 0).count();
+        let trailing = match leading {
+            l if l == size => 0,
+            _ => xor.iter().rev().take_while(|b| **b == 0).count(),
+        };
+        encoded.push(((leading as u8) << 4) | trailing as u8);
+        encoded.extend_from_slice(&xor[leading..size - trailing]);
+    }
+
+    encoded
+}
+
+fn decode_floats(data: &[u8], size: usize, values: usize) -> Result<Vec<u8>, String> {
+    // the count is not trusted, the data are too short for the larger count anyway
+    let mut decoded = Vec::with_capacity(values.min(data.len()).saturating_mul(size));
+    let mut previous = [0u8; 8];
+    let mut pos = 0;
+
+    for _ in 0..values {
+        let head = *data
+            .get(pos)
+            .ok_or("Compressed graph data are too short.")?;
+        pos += 1;
+
+        let leading = (head >> 4) as usize;
+        let trailing = (head & 0x0f) as usize;
+        if leading + trailing > size {
+            return Err("Compressed graph data are corrupted.".to_string());
         }
+
+        let end = pos + size - leading - trailing;
+        let bytes = data
+            .get(pos..end)
+            .ok_or("Compressed graph data are too short.")?;
+        pos = end;
+
+        for (i, b) in bytes.iter().enumerate() {
+            previous[leading + i] ^= b;
+        }
+        decoded.extend_from_slice(&previous[..size]);
     }
+
+    if pos != data.len() {
+        return Err("Compressed graph data are too long.".to_string());
+    }
+    Ok(decoded)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -190,6 +237,44 @@ enum GraphMessage<T> {
     AddPoints(u16, GraphDataInfo<T>),
     Remove(u16),
     Reset,
+    SetAxis(u16, GraphDataInfo<T>),
+    AddAxisPoints(u16, GraphDataInfo<T>),
+    RemoveAxis(u16),
+    SetShared(u16, u16, GraphDataInfo<T>),
+}
+
+// Shared x axes. Graphs with the shared axis keep only the y values and the x values are
+// taken from the axis. If the lengths of the axis and the graph differ, only the common
+// part is used, so the longer one waits for the shorter one.
+struct SharedAxes<T> {
+    axes: NoHashMap<u16, Vec<T>>,
+    graphs: NoHashMap<u16, u16>, // graph idx -> axis idx
+}
+
+impl<T> SharedAxes<T> {
+    fn new() -> Self {
+        Self {
+            axes: NoHashMap::default(),
+            graphs: NoHashMap::default(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.axes.clear();
+        self.graphs.clear();
+    }
+}
+
+impl<T: Clone> SharedAxes<T> {
+    fn resolve(&self, idx: u16, graph: &Graph<T>) -> Option<Graph<T>> {
+        let axis = self.axes.get(self.graphs.get(&idx)?)?;
+        let points = axis.len().min(graph.y.len());
+
+        Some(Graph {
+            y: graph.y[..points].to_vec(),
+            x: Some(axis[..points].to_vec()),
+        })
+    }
 }
 
 // CLIENT --------------------------------------------------------------------
@@ -201,6 +286,8 @@ pub(crate) trait GraphUpdate: Sync + Send {
 pub struct ValueGraphs<T> {
     _id: u32,
     graphs: RwLock<NoHashMap<u16, (Graph<T>, bool)>>,
+    axes: RwLock<SharedAxes<T>>,
+    changed: AtomicBool, // any graph or axis changed
 }
 
 impl<T: Clone + Copy> ValueGraphs<T> {
@@ -208,24 +295,78 @@ impl<T: Clone + Copy> ValueGraphs<T> {
         Arc::new(Self {
             _id: id,
             graphs: RwLock::new(NoHashMap::default()),
+            axes: RwLock::new(SharedAxes::new()),
+            changed: AtomicBool::new(false),
         })
     }
 
+    /// Get the graph. Graph with the shared x axis is returned with the x values from the axis.
     pub fn get(&self, idx: u16) -> Option<Graph<T>> {
-        self.graphs.read().unwrap().get(&idx).map(|g| g.0.clone())
+        let g = self.graphs.read().unwrap();
+        let graph = &g.get(&idx)?.0;
+        let axes = self.axes.read().unwrap();
+        Some(axes.resolve(idx, graph).unwrap_or_else(|| graph.clone()))
+    }
+
+    pub fn is_shared(&self, idx: u16) -> bool {
+        self.axes.read().unwrap().graphs.contains_key(&idx)
     }
 
     pub fn len(&self) -> usize {
         self.graphs.read().unwrap().len()
     }
 
-    pub fn process<R>(&self, idx: u16, op: impl Fn(Option<&Graph<T>>, bool) -> R) -> R {
+    pub fn is_empty(&self) -> bool {
+        self.graphs.read().unwrap().is_empty()
+    }
+
+    /// If any of the graphs was changed by the server since the last call. The flag is shared by
+    /// all callers, so only one place in the UI should take it. It is independent of the flags of
+    /// the single graphs used by the [`ValueGraphs::process`].
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn process<R>(&self, idx: u16, op: impl FnOnce(Option<&Graph<T>>, bool) -> R) -> R {
         let mut g = self.graphs.write().unwrap();
         let graph = g.get_mut(&idx);
 
         match graph {
             Some((graph, changed)) => {
-                let r = op(Some(graph), *changed);
+                let r = match self.axes.read().unwrap().resolve(idx, graph) {
+                    Some(resolved) => op(Some(&resolved), *changed),
+                    None => op(Some(graph), *changed),
+                };
+                *changed = false;
+                r
+            }
+            None => op(None, false),
+        }
+    }
+}
+
+impl<T> ValueGraphs<T> {
+    // The same as the process, but the shared axis is borrowed instead of copied. The x values
+    // are None for the linear graph.
+    #[cfg(feature = "plot")]
+    pub(crate) fn process_series<R>(
+        &self,
+        idx: u16,
+        op: impl FnOnce(Option<(Option<&[T]>, &[T])>, bool) -> R,
+    ) -> R {
+        let mut g = self.graphs.write().unwrap();
+        let axes = self.axes.read().unwrap();
+        match g.get_mut(&idx) {
+            Some((graph, changed)) => {
+                let shared = axes.graphs.get(&idx).and_then(|axis| axes.axes.get(axis));
+                let r = match (shared, graph.x.as_deref()) {
+                    (Some(axis), _) => {
+                        let points = axis.len().min(graph.y.len());
+                        op(Some((Some(&axis[..points]), &graph.y[..points])), *changed)
+                    }
+                    (None, Some(x)) => op(Some((Some(x), &graph.y)), *changed),
+                    (None, None) => op(Some((None, &graph.y)), *changed),
+                };
                 *changed = false;
                 r
             }
@@ -244,8 +385,10 @@ where
 
         match message {
             GraphMessage::Set(idx, info) => {
-                let graph = Graph::from_graph_data(info, data);
-                self.graphs.write().unwrap().insert(idx, (graph, true));
+                let graph = Graph::from_graph_data(info, data)?;
+                let mut g = self.graphs.write().unwrap();
+                self.axes.write().unwrap().graphs.remove(&idx);
+                g.insert(idx, (graph, true));
             }
             GraphMessage::AddPoints(idx, info) => {
                 if let Some((graph, changed)) = self.graphs.write().unwrap().get_mut(&idx) {
@@ -254,17 +397,68 @@ where
                 }
             }
             GraphMessage::Remove(idx) => {
-                self.graphs.write().unwrap().remove(&idx);
+                let mut g = self.graphs.write().unwrap();
+                self.axes.write().unwrap().graphs.remove(&idx);
+                g.remove(&idx);
             }
             GraphMessage::Reset => {
-                self.graphs.write().unwrap().clear();
+                let mut g = self.graphs.write().unwrap();
+                self.axes.write().unwrap().clear();
+                g.clear();
+            }
+            GraphMessage::SetAxis(axis_idx, info) => {
+                let axis = Graph::from_graph_data(info, data)?.y;
+                let mut g = self.graphs.write().unwrap();
+                let mut axes = self.axes.write().unwrap();
+                axes.axes.insert(axis_idx, axis);
+                Self::axis_changed(&mut g, &axes, axis_idx);
+            }
+            GraphMessage::AddAxisPoints(axis_idx, info) => {
+                let mut g = self.graphs.write().unwrap();
+                let mut axes = self.axes.write().unwrap();
+                if let Some(axis) = axes.axes.get_mut(&axis_idx) {
+                    let mut graph = Graph {
+                        y: std::mem::take(axis),
+                        x: None,
+                    };
+                    let res = graph.add_points_from_data(info, data);
+                    *axis = graph.y;
+                    res?;
+                    Self::axis_changed(&mut g, &axes, axis_idx);
+                }
+            }
+            GraphMessage::RemoveAxis(axis_idx) => {
+                self.axes.write().unwrap().axes.remove(&axis_idx);
+            }
+            GraphMessage::SetShared(idx, axis_idx, info) => {
+                let graph = Graph::from_graph_data(info, data)?;
+                let mut g = self.graphs.write().unwrap();
+                self.axes.write().unwrap().graphs.insert(idx, axis_idx);
+                g.insert(idx, (graph, true));
             }
         }
 
+        self.changed.store(true, Ordering::Relaxed);
         Ok(())
     }
 }
 
+impl<T> ValueGraphs<T> {
+    fn axis_changed(
+        graphs: &mut NoHashMap<u16, (Graph<T>, bool)>,
+        axes: &SharedAxes<T>,
+        axis_idx: u16,
+    ) {
+        for (idx, axis) in axes.graphs.iter() {
+            if *axis == axis_idx {
+                if let Some((_, changed)) = graphs.get_mut(idx) {
+                    *changed = true;
+                }
+            }
+        }
+    }
+}
+
 // SERVER --------------------------------------------------------------------
 // ---------------------------------------------------------------------------
 #[cfg(feature = "server")]
@@ -280,8 +474,9 @@ pub(crate) mod server {
     use pyo3::prelude::*;
     use pyo3::types::{PyByteArray, PyTuple};
 
+    use crate::nohash::NoHashSet;
     use crate::python_convert::ToPython;
-    use crate::server::SyncTrait;
+    use crate::server::{SyncTarget, SyncTrait};
     use crate::transport::{serialize, WriteMessage};
 
     pub(crate) trait PyGraphTrait: Send + Sync {
@@ -291,13 +486,32 @@ pub(crate) mod server {
         fn len_py(&self, idx: u16) -> PyResult<usize>;
         fn remove_py(&self, idx: u16, update: bool);
         fn count_py(&self) -> u16;
+        fn lengths_py(&self) -> Vec<(u16, usize)>;
         fn is_linear_py(&self, idx: u16) -> PyResult<bool>;
         fn clear_py(&self, update: bool);
+        fn set_compressed_py(&self, idx: u16, compressed: bool);
+        fn set_axis_py(&self, x_idx: u16, object: &Bound<PyAny>, update: bool) -> PyResult<()>;
+        fn add_axis_points_py(
+            &self,
+            x_idx: u16,
+            object: &Bound<PyAny>,
+            update: bool,
+        ) -> PyResult<()>;
+        fn remove_axis_py(&self, x_idx: u16, update: bool) -> PyResult<()>;
+        fn set_shared_py(
+            &self,
+            idx: u16,
+            x_idx: u16,
+            object: &Bound<PyAny>,
+            update: bool,
+        ) -> PyResult<()>;
     }
 
     pub(crate) struct PyValueGraphs<T> {
         id: u32,
         graphs: RwLock<NoHashMap<u16, Graph<T>>>,
+        axes: RwLock<SharedAxes<T>>,
+        compressed: RwLock<NoHashSet<u16>>, // graphs sent with the float codec
 
         channel: Sender<WriteMessage>,
         connected: Arc<AtomicBool>,
@@ -314,12 +528,34 @@ pub(crate) mod server {
             Arc::new(Self {
                 id,
                 graphs,
+                axes: RwLock::new(SharedAxes::new()),
+                compressed: RwLock::new(NoHashSet::default()),
                 channel,
                 connected,
             })
         }
     }
 
+    impl<T: GraphElement> PyValueGraphs<T>
+    where
+        T: Serialize,
+    {
+        fn graph_data(&self, idx: u16, graph: &Graph<T>) -> (GraphDataInfo<T>, Vec<u8>) {
+            let (info, data) = graph.to_graph_data();
+            match self.compressed.read().unwrap().contains(&idx) {
+                true => info.compress(data),
+                false => (info, data),
+            }
+        }
+
+        fn send_graph(&self, message: GraphMessage<T>, data: Option<Vec<u8>>, update: bool) {
+            let message = serialize(message);
+            self.channel
+                .send(WriteMessage::Graph(self.id, update, message, data))
+                .unwrap();
+        }
+    }
+
     impl<T> PyGraphTrait for PyValueGraphs<T>
     where
         T: GraphElement + Element + for<'py> FromPyObject<'py> + ToPython + Serialize,
@@ -330,12 +566,13 @@ pub(crate) mod server {
 
             let mut w = self.graphs.write().unwrap();
             if self.connected.load(Ordering::Relaxed) {
-                let (info, data) = graph.to_graph_data();
+                let (info, data) = self.graph_data(idx, &graph);
                 let message = serialize(GraphMessage::Set(idx, info));
                 self.channel
                     .send(WriteMessage::Graph(self.id, update, message, Some(data)))
                     .unwrap();
             }
+            self.axes.write().unwrap().graphs.remove(&idx);
             w.insert(idx, graph);
             Ok(())
         }
@@ -347,10 +584,16 @@ pub(crate) mod server {
             let graph = w
                 .get_mut(&idx)
                 .ok_or_else(|| PyValueError::new_err("Graph not found"))?;
+            let original_len = graph.y.len();
             buffer_to_graph_add(&buffer, graph)?;
 
+            // only the new points are sent, the client appends them to its graph
             if self.connected.load(Ordering::Relaxed) {
-                let (info, data) = graph.to_graph_data();
+                let added = Graph {
+                    y: graph.y[original_len..].to_vec(),
+                    x: graph.x.as_ref().map(|x| x[original_len..].to_vec()),
+                };
+                let (info, data) = self.graph_data(idx, &added);
                 let message = serialize(GraphMessage::AddPoints(idx, info));
                 self.channel
                     .send(WriteMessage::Graph(self.id, update, message, Some(data)))
@@ -365,6 +608,8 @@ pub(crate) mod server {
             let graph = w
                 .get(&idx)
                 .ok_or_else(|| PyValueError::new_err(format!("Graph with id {} not found", idx)))?;
+            let resolved = self.axes.read().unwrap().resolve(idx, graph);
+            let graph = resolved.as_ref().unwrap_or(graph);
 
             match graph.x {
                 Some(ref x) => {
@@ -416,6 +661,7 @@ pub(crate) mod server {
                     .send(WriteMessage::Graph(self.id, update, message, None))
                     .unwrap();
             }
+            self.axes.write().unwrap().graphs.remove(&idx);
             w.remove(&idx);
         }
 
@@ -423,13 +669,23 @@ pub(crate) mod server {
             self.graphs.read().unwrap().len() as u16
         }
 
+        fn lengths_py(&self) -> Vec<(u16, usize)> {
+            self.graphs
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(idx, graph)| (*idx, graph.y.len()))
+                .collect()
+        }
+
         fn is_linear_py(&self, idx: u16) -> PyResult<bool> {
+            let shared = self.axes.read().unwrap().graphs.contains_key(&idx);
             self.graphs.read().unwrap().get(&idx).map_or(
                 Err(PyValueError::new_err(format!(
                     "Graph with id {} not found",
                     idx
                 ))),
-                |graph| Ok(graph.x.is_none()),
+                |graph| Ok(graph.x.is_none() && !shared),
             )
         }
 
@@ -442,28 +698,154 @@ pub(crate) mod server {
                     .send(WriteMessage::Graph(self.id, update, message, None))
                     .unwrap();
             }
+            self.axes.write().unwrap().clear();
             w.clear();
         }
+
+        fn set_compressed_py(&self, idx: u16, compressed: bool) {
+            let mut c = self.compressed.write().unwrap();
+            match compressed {
+                true => c.insert(idx),
+                false => c.remove(&idx),
+            };
+        }
+
+        fn set_axis_py(&self, x_idx: u16, object: &Bound<PyAny>, update: bool) -> PyResult<()> {
+            let buffer = PyBuffer::<T>::extract_bound(object)?;
+            let axis = buffer_to_graph(&buffer)?;
+            if axis.x.is_some() {
+                return Err(PyValueError::new_err(
+                    "Shared x axis data must have 1 dimension.",
+                ));
+            }
+
+            let mut axes = self.axes.write().unwrap();
+            if self.connected.load(Ordering::Relaxed) {
+                let (info, data) = axis.to_graph_data();
+                self.send_graph(GraphMessage::SetAxis(x_idx, info), Some(data), update);
+            }
+            axes.axes.insert(x_idx, axis.y);
+            Ok(())
+        }
+
+        fn add_axis_points_py(
+            &self,
+            x_idx: u16,
+            object: &Bound<PyAny>,
+            update: bool,
+        ) -> PyResult<()> {
+            let buffer = PyBuffer::<T>::extract_bound(object)?;
+
+            let mut axes = self.axes.write().unwrap();
+            let axis = axes.axes.get_mut(&x_idx).ok_or_else(|| {
+                PyValueError::new_err(format!("Shared x axis with id {} not found", x_idx))
+            })?;
+
+            let original_len = axis.len();
+            let mut graph = Graph {
+                y: std::mem::take(axis),
+                x: None,
+            };
+            let res = buffer_to_graph_add(&buffer, &mut graph);
+            *axis = graph.y;
+            res?;
+
+            if self.connected.load(Ordering::Relaxed) {
+                let added = Graph {
+                    y: axis[original_len..].to_vec(),
+                    x: None,
+                };
+                let (info, data) = added.to_graph_data();
+                self.send_graph(GraphMessage::AddAxisPoints(x_idx, info), Some(data), update);
+            }
+
+            Ok(())
+        }
+
+        fn remove_axis_py(&self, x_idx: u16, update: bool) -> PyResult<()> {
+            let mut axes = self.axes.write().unwrap();
+            if axes.graphs.values().any(|axis| *axis == x_idx) {
+                return Err(PyValueError::new_err(format!(
+                    "Shared x axis with id {} is used by some graphs.",
+                    x_idx
+                )));
+            }
+
+            if self.connected.load(Ordering::Relaxed) {
+                self.send_graph(GraphMessage::RemoveAxis(x_idx), None, update);
+            }
+            axes.axes.remove(&x_idx);
+            Ok(())
+        }
+
+        fn set_shared_py(
+            &self,
+            idx: u16,
+            x_idx: u16,
+            object: &Bound<PyAny>,
+            update: bool,
+        ) -> PyResult<()> {
+            let buffer = PyBuffer::<T>::extract_bound(object)?;
+            let graph = buffer_to_graph(&buffer)?;
+            if graph.x.is_some() {
+                return Err(PyValueError::new_err(
+                    "Graph data with shared x axis must have 1 dimension.",
+                ));
+            }
+
+            let mut w = self.graphs.write().unwrap();
+            let mut axes = self.axes.write().unwrap();
+            if !axes.axes.contains_key(&x_idx) {
+                return Err(PyValueError::new_err(format!(
+                    "Shared x axis with id {} not found",
+                    x_idx
+                )));
+            }
+
+            if self.connected.load(Ordering::Relaxed) {
+                let (info, data) = self.graph_data(idx, &graph);
+                self.send_graph(
+                    GraphMessage::SetShared(idx, x_idx, info),
+                    Some(data),
+                    update,
+                );
+            }
+            axes.graphs.insert(idx, x_idx);
+            w.insert(idx, graph);
+            Ok(())
+        }
     }
 
     impl<T: GraphElement> SyncTrait for PyValueGraphs<T>
     where
         T: Serialize,
     {
-        fn sync(&self) {
+        fn sync(&self, target: SyncTarget) {
             let w = self.graphs.read().unwrap();
+            let send = |message: GraphMessage<T>, data: Option<Vec<u8>>| {
+                let message = WriteMessage::Graph(self.id, false, serialize(message), data);
+                self.channel.send(target.message(message)).unwrap();
+            };
 
-            let message = serialize(GraphMessage::<T>::Reset);
-            self.channel
-                .send(WriteMessage::Graph(self.id, false, message, None))
-                .unwrap();
+            send(GraphMessage::Reset, None);
+
+            let axes = self.axes.read().unwrap();
+            for (x_idx, axis) in axes.axes.iter() {
+                let axis = Graph {
+                    y: axis.clone(),
+                    x: None,
+                };
+                let (info, data) = axis.to_graph_data();
+                send(GraphMessage::SetAxis(*x_idx, info), Some(data));
+            }
 
             for (idx, graph) in w.iter() {
-                let (info, data) = graph.to_graph_data();
-                let message = serialize(GraphMessage::Set(*idx, info));
-                self.channel
-                    .send(WriteMessage::Graph(self.id, false, message, Some(data)))
-                    .unwrap();
+                let (info, data) = self.graph_data(*idx, graph);
+                let message = match axes.graphs.get(idx) {
+                    Some(x_idx) => GraphMessage::SetShared(*idx, *x_idx, info),
+                    None => GraphMessage::Set(*idx, info),
+                };
+                send(message, Some(data));
             }
         }
     }
@@ -502,6 +884,11 @@ pub(crate) mod server {
                     "Graph data to add must have the same x axis type.",
                 ));
             }
+            if shape[0] != 2 {
+                return Err(PyValueError::new_err(
+                    "Graph data must have 2 lines (x, y).",
+                ));
+            }
 
             let points = shape[1];
 
@@ -608,3 +995,111 @@ impl GraphElement for f64 {
         0.0
     }
 }
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    fn bytes<T: Copy>(values: &[T]) -> Vec<u8> {
+        let size = std::mem::size_of_val(values);
+        unsafe { std::slice::from_raw_parts(values.as_ptr() as *const u8, size) }.to_vec()
+    }
+
+    fn round_trip<T: Copy>(values: &[T]) -> Vec<u8> {
+        let data = bytes(values);
+        let encoded = encode_floats(&data, size_of::<T>());
+        let decoded = decode_floats(&encoded, size_of::<T>(), values.len()).unwrap();
+        assert_eq!(decoded, data);
+        encoded
+    }
+
+    #[test]
+    fn smooth_data_round_trip() {
+        let sine: Vec<f64> = (0..1000).map(|i| (i as f64 * 0.01).sin()).collect();
+        let encoded = round_trip(&sine);
+        assert!(encoded.len() < sine.len() * size_of::<f64>());
+
+        let sine: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.01).sin()).collect();
+        round_trip(&sine);
+    }
+
+    #[test]
+    fn constant_data_are_only_heads() {
+        // only the first value has some bytes, the others are equal to the previous
+        fn stored(head: u8, size: usize) -> usize {
+            size - (head >> 4) as usize - (head & 0x0f) as usize
+        }
+
+        let encoded = round_trip(&[1.5f64; 100]);
+        let first = 1 + stored(encoded[0], 8);
+        assert_eq!(encoded.len(), first + 99);
+        assert!(encoded[first..].iter().all(|head| *head == 8 << 4));
+
+        let encoded = round_trip(&[-2.25f32; 100]);
+        let first = 1 + stored(encoded[0], 4);
+        assert_eq!(encoded.len(), first + 99);
+        assert!(encoded[first..].iter().all(|head| *head == 4 << 4));
+    }
+
+    #[test]
+    fn special_values_round_trip() {
+        round_trip(&[
+            f64::NAN,
+            0.0,
+            -0.0,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NAN,
+        ]);
+        round_trip(&[f32::NAN, f32::MIN_POSITIVE, f32::MAX, -f32::MAX, f32::NAN]);
+        round_trip::<f64>(&[]);
+    }
+
+    #[test]
+    fn alternating_signs_round_trip() {
+        let values: Vec<f64> = (0..100)
+            .map(|i| if i % 2 == 0 { 1.0 } else { -1.0 })
+            .collect();
+        round_trip(&values);
+        let values: Vec<f32> = (0..100).map(|i| (-1.0f32).powi(i) * i as f32).collect();
+        round_trip(&values);
+    }
+
+    #[test]
+    fn invalid_data_are_rejected() {
+        let data = bytes(&[1.0f64, 2.0, 3.0]);
+        let encoded = encode_floats(&data, 8);
+
+        let error = decode_floats(&encoded[..encoded.len() - 1], 8, 3).unwrap_err();
+        assert!(error.contains("too short"));
+        assert!(decode_floats(&encoded, 8, 4)
+            .unwrap_err()
+            .contains("too short"));
+        assert!(decode_floats(&encoded, 8, 2)
+            .unwrap_err()
+            .contains("too long"));
+
+        let mut corrupted = encoded.clone();
+        corrupted[0] = 0x5f; // more zero bytes than the size of the value
+        assert!(decode_floats(&corrupted, 8, 3)
+            .unwrap_err()
+            .contains("corrupted"));
+    }
+
+    #[test]
+    fn bogus_number_of_points_is_rejected() {
+        let data = bytes(&[1.0f64, 2.0]);
+        for points in [usize::MAX, usize::MAX / 2 + 1, usize::MAX / 8 + 1, 3] {
+            for is_linear in [true, false] {
+                let info = GraphDataInfo::<f64>::new(points, is_linear);
+                assert!(info.decode(&data).is_err());
+                let (info, encoded) =
+                    GraphDataInfo::<f64>::new(points, is_linear).compress(data.clone());
+                assert!(info.decode(&encoded).is_err());
+            }
+        }
+
+        let info = GraphDataInfo::<f64>::new(1, false);
+        assert_eq!(info.decode(&data).unwrap().len(), data.len());
+    }
+}
@@ -1,10 +1,17 @@
 use std::ptr::copy_nonoverlapping;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "shared-memory")]
+use std::sync::Mutex;
 use std::sync::{Arc, RwLock};
 
+use egui::load::SizedTexture;
 use egui::{ColorImage, ImageData, TextureHandle};
 use postcard;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "shared-memory")]
+use crate::shared::{SharedFrame, SharedReader};
+
 #[derive(Clone, Copy, Serialize, Deserialize)]
 enum ImageType {
     Color,
@@ -18,10 +25,14 @@ struct ImageInfo {
     pub image_size: [usize; 2],   // [y, x]
     pub rect: Option<[usize; 4]>, // [y, x, h, w]
     pub image_type: ImageType,
+    pub preview: Option<[usize; 2]>, // [y, x] size of the preview data
 }
 
+// returns if the full image has to be sent again
 pub(crate) trait ImageUpdate: Send + Sync {
-    fn update_image(&self, data: &[u8]) -> Result<(), String>;
+    fn update_image(&self, data: &[u8]) -> Result<bool, String>;
+    #[cfg(feature = "shared-memory")]
+    fn update_shared_image(&self, data: &[u8]) -> Result<bool, String>;
 }
 
 const TEXTURE_OPTIONS: egui::TextureOptions = egui::TextureOptions {
@@ -34,6 +45,11 @@ const TEXTURE_OPTIONS: egui::TextureOptions = egui::TextureOptions {
 pub struct ValueImage {
     id: u32,
     texture_handle: RwLock<Option<(TextureHandle, [usize; 2])>>,
+    pending: RwLock<Option<(ColorImage, [usize; 2])>>, // received before the texture was created
+    preview: AtomicBool,
+    changed: AtomicBool,
+    #[cfg(feature = "shared-memory")]
+    shared: Mutex<SharedReader>,
 }
 
 impl ValueImage {
@@ -41,9 +57,25 @@ impl ValueImage {
         Arc::new(Self {
             id,
             texture_handle: RwLock::new(None),
+            pending: RwLock::new(None),
+            preview: AtomicBool::new(false),
+            changed: AtomicBool::new(false),
+            #[cfg(feature = "shared-memory")]
+            shared: Mutex::new(SharedReader::new()),
         })
     }
 
+    /// If the texture holds only the preview and the full image was not received yet.
+    pub fn is_preview(&self) -> bool {
+        self.preview.load(Ordering::Relaxed)
+    }
+
+    /// If the image was changed by the server since the last call. The flag is shared by all
+    /// callers, so only one place in the UI should take it.
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+
     pub fn get_id(&self) -> egui::TextureId {
         self.texture_handle
             .read()
@@ -63,50 +95,82 @@ impl ValueImage {
             .1
     }
 
+    /// Create the texture with the image. If the image was already received from the server,
+    /// the received image is used instead.
     pub fn initialize(&self, ctx: &egui::Context, image: ColorImage) {
+        let mut w = self.texture_handle.write().unwrap();
+        if w.is_some() {
+            return;
+        }
+
+        let (image, size) = self.pending.write().unwrap().take().unwrap_or_else(|| {
+            let size = image.size;
+            (image, size)
+        });
         let image_data = ImageData::Color(Arc::new(image));
         let name = format!("image_{}", self.id);
         let texture_handle = ctx.load_texture(name, image_data, TEXTURE_OPTIONS);
+        *w = Some((texture_handle, size));
+    }
 
-        let mut w = self.texture_handle.write().unwrap();
-        let size = texture_handle.size();
-        match *w {
-            None => {
-                *w = Some((texture_handle, size));
-            }
-            _ => {}
+    /// Get the texture with the size of the full image. If the image is not initialized, it is
+    /// initialized with the received image or with the empty one.
+    pub fn texture(&self, ctx: &egui::Context) -> SizedTexture {
+        if self.texture_handle.read().unwrap().is_none() {
+            self.initialize(ctx, ColorImage::new([1, 1], egui::Color32::TRANSPARENT));
         }
+
+        let r = self.texture_handle.read().unwrap();
+        let (texture_handle, size) = r.as_ref().expect("image is initialized");
+        SizedTexture::new(texture_handle.id(), [size[0] as f32, size[1] as f32])
     }
 }
 
-impl ImageUpdate for ValueImage {
-    fn update_image(&self, data: &[u8]) -> Result<(), String> {
-        let (info, image_data) = postcard::take_from_bytes(data).map_err(|e| {
-            format!(
-                "Failed to deserialize image message: {} for image of id {}",
-                e, self.id
-            )
-        })?;
-
+impl ValueImage {
+    // check the size of the data and convert the pixels to the color image
+    fn convert(&self, info: &ImageInfo, image_data: &[u8]) -> Result<ColorImage, String> {
         let ImageInfo {
             image_size,
             rect,
             image_type,
-        } = info;
+            preview,
+        } = *info;
 
-        let size = match rect {
-            Some(r) => {
+        let size = match (rect, preview) {
+            (Some(_), Some(_)) => {
+                return Err("Preview can not be set for the rectangle".to_string());
+            }
+            (Some(r), None) => {
                 if r[0] + r[2] > image_size[0] || r[1] + r[3] > image_size[1] {
                     return Err("Rectangle is out of bounds".to_string());
                 }
                 [r[3], r[2]]
             }
-            None => [image_size[1], image_size[0]],
+            (None, Some(p)) => [p[1], p[0]],
+            (None, None) => [image_size[1], image_size[0]],
+        };
+
+        // Rows are always packed, the server removes the row padding of the python buffer
+        // before sending. The size is checked, so the conversion can not read out of the data.
+        let pixel_count = size[0] * size[1];
+        let channels = match image_type {
+            ImageType::Color => 3,
+            ImageType::ColorAlpha => 4,
+            ImageType::Gray => 1,
+            ImageType::GrayAlpha => 2,
         };
+        if image_data.len() != pixel_count * channels {
+            return Err(format!(
+                "Image data size {} does not match the image size {:?} with {} channels for image of id {}",
+                image_data.len(),
+                [size[1], size[0]],
+                channels,
+                self.id
+            ));
+        }
 
         // TODO: cache the color image
         let mut c_image = egui::ColorImage::new(size, egui::Color32::WHITE);
-        let pixel_count = size[0] * size[1];
 
         let data_ptr = image_data.as_ptr();
         let image_ptr = c_image.pixels.as_mut_ptr() as *mut u8;
@@ -156,26 +220,121 @@ impl ImageUpdate for ValueImage {
             }
         }
 
+        Ok(c_image)
+    }
+
+    fn set_texture(&self, info: ImageInfo, c_image: ColorImage) -> Result<bool, String> {
+        let ImageInfo {
+            image_size,
+            rect,
+            preview,
+            ..
+        } = info;
+
         let mut w = self.texture_handle.write().unwrap();
-        if let Some((ref mut texture_handle, ref mut save_size)) = *w {
-            match rect {
-                Some(rec) => {
-                    if save_size[0] != image_size[1] || save_size[1] != image_size[0] {
-                        return Err(
-                            "Rectangle is set but the image size is different from texture"
-                                .to_string(),
-                        );
-                    }
-                    texture_handle.set_partial([rec[1], rec[0]], c_image, TEXTURE_OPTIONS);
+        let Some((ref mut texture_handle, ref mut save_size)) = *w else {
+            return self.set_pending(info, c_image);
+        };
+        match rect {
+            // the rectangle is in the coordinates of the full image, so the full image is asked for
+            Some(_) if self.preview.load(Ordering::Relaxed) => return Ok(true),
+            Some(rec) => {
+                if save_size[0] != image_size[1] || save_size[1] != image_size[0] {
+                    return Err(
+                        "Rectangle is set but the image size is different from texture".to_string(),
+                    );
                 }
-                None => {
-                    texture_handle.set(c_image, TEXTURE_OPTIONS);
-                    *save_size = size;
+                texture_handle.set_partial([rec[1], rec[0]], c_image, TEXTURE_OPTIONS);
+            }
+            None => {
+                // the size of the full image is kept also for the preview
+                texture_handle.set(c_image, TEXTURE_OPTIONS);
+                *save_size = [image_size[1], image_size[0]];
+                self.preview.store(preview.is_some(), Ordering::Relaxed);
+            }
+        }
+
+        self.changed.store(true, Ordering::Relaxed);
+        Ok(false)
+    }
+
+    // the image is kept until the texture is created, the rectangles are copied into it
+    fn set_pending(&self, info: ImageInfo, c_image: ColorImage) -> Result<bool, String> {
+        let ImageInfo {
+            image_size,
+            rect,
+            preview,
+            ..
+        } = info;
+
+        let mut pending = self.pending.write().unwrap();
+        match (rect, pending.as_mut()) {
+            (Some(_), Some(_)) if self.preview.load(Ordering::Relaxed) => return Ok(true),
+            (Some(rec), Some((image, size))) => {
+                if size[0] != image_size[1] || size[1] != image_size[0] || image.size != *size {
+                    return Err(
+                        "Rectangle is set but the image size is different from the received image"
+                            .to_string(),
+                    );
                 }
+                let width = rec[3];
+                for row in 0..rec[2] {
+                    let start = (rec[0] + row) * size[0] + rec[1];
+                    image.pixels[start..start + width]
+                        .copy_from_slice(&c_image.pixels[row * width..(row + 1) * width]);
+                }
+            }
+            (Some(_), None) => {
+                return Err("Rectangle is set but the full image was not received".to_string());
+            }
+            (None, _) => {
+                *pending = Some((c_image, [image_size[1], image_size[0]]));
+                self.preview.store(preview.is_some(), Ordering::Relaxed);
             }
         }
 
-        Ok(())
+        self.changed.store(true, Ordering::Relaxed);
+        Ok(false)
+    }
+}
+
+impl ImageUpdate for ValueImage {
+    fn update_image(&self, data: &[u8]) -> Result<bool, String> {
+        let (info, image_data): (ImageInfo, _) = postcard::take_from_bytes(data).map_err(|e| {
+            format!(
+                "Failed to deserialize image message: {} for image of id {}",
+                e, self.id
+            )
+        })?;
+
+        let c_image = self.convert(&info, image_data)?;
+        self.set_texture(info, c_image)
+    }
+
+    #[cfg(feature = "shared-memory")]
+    fn update_shared_image(&self, data: &[u8]) -> Result<bool, String> {
+        let (info, frame): (ImageInfo, _) = postcard::take_from_bytes(data).map_err(|e| {
+            format!(
+                "Failed to deserialize image message: {} for image of id {}",
+                e, self.id
+            )
+        })?;
+        let frame: SharedFrame = postcard::from_bytes(frame).map_err(|e| {
+            format!(
+                "Failed to deserialize shared frame: {} for image of id {}",
+                e, self.id
+            )
+        })?;
+
+        let converted = self
+            .shared
+            .lock()
+            .unwrap()
+            .read(&frame, |image_data| self.convert(&info, image_data))?;
+        match converted {
+            Some(c_image) => self.set_texture(info, c_image?),
+            None => Ok(false), // overwritten by the newer frame
+        }
     }
 }
 
@@ -185,16 +344,21 @@ impl ImageUpdate for ValueImage {
 pub(crate) mod server {
     use super::*;
 
-    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::atomic::AtomicU64;
     use std::sync::mpsc::Sender;
+    use std::sync::Weak;
+    use std::time::Duration;
 
     use pyo3::buffer::PyBuffer;
     use pyo3::exceptions::PyValueError;
     use pyo3::prelude::*;
     use pyo3::types::PyByteArray;
 
-    use crate::server::SyncTrait;
-    use crate::transport::{serialize, WriteMessage};
+    use crate::pool::POOL;
+    use crate::server::{SyncTarget, SyncTrait};
+    use crate::transport::{
+        send_later, serialize, ImageGeneration, SendRate, Throttle, WriteMessage,
+    };
 
     struct ImageDataInner {
         data: Vec<u8>,
@@ -204,6 +368,10 @@ pub(crate) mod server {
     pub(crate) struct PyValueImage {
         id: u32,
         image: RwLock<ImageDataInner>,
+        preview: RwLock<Option<usize>>, // maximal size of the preview
+        generation: Arc<AtomicU64>,     // generation of the last full frame
+        frame_rate: RwLock<SendRate>,   // the last dropped frame is sent as the whole image
+        this: Weak<Self>,
         channel: Sender<WriteMessage>,
         connected: Arc<AtomicBool>,
     }
@@ -214,12 +382,16 @@ pub(crate) mod server {
             channel: Sender<WriteMessage>,
             connected: Arc<AtomicBool>,
         ) -> Arc<Self> {
-            Arc::new(Self {
+            Arc::new_cyclic(|this| Self {
                 id,
                 image: RwLock::new(ImageDataInner {
                     data: Vec::with_capacity(0),
                     size: [0, 0],
                 }),
+                preview: RwLock::new(None),
+                generation: Arc::new(AtomicU64::new(0)),
+                frame_rate: RwLock::new(SendRate::new()),
+                this: this.clone(),
                 channel,
                 connected,
             })
@@ -229,6 +401,89 @@ pub(crate) mod server {
             self.image.read().unwrap().size
         }
 
+        pub(crate) fn set_preview_py(&self, max_size: Option<usize>) -> PyResult<()> {
+            if max_size == Some(0) {
+                return Err(PyValueError::new_err(
+                    "Preview size must be greater than 0.",
+                ));
+            }
+            *self.preview.write().unwrap() = max_size;
+            Ok(())
+        }
+
+        pub(crate) fn set_max_fps_py(&self, fps: Option<f64>) -> PyResult<()> {
+            let interval = match fps {
+                Some(fps) if fps.is_nan() || fps <= 0.0 => {
+                    return Err(PyValueError::new_err("Max fps must be greater than 0."));
+                }
+                Some(fps) => Some(Duration::from_secs_f64(1.0 / fps)),
+                None => None,
+            };
+
+            let pending = self.frame_rate.write().unwrap().set_interval(interval);
+            if let Some(update) = pending {
+                if self.connected.load(Ordering::Relaxed) {
+                    self.send_image(update, SyncTarget::All);
+                }
+            }
+            Ok(())
+        }
+
+        // The dropped frame is sent as the whole image, because it can consist of
+        // several rectangles.
+        fn send_pending(&self) {
+            let pending = self.frame_rate.write().unwrap().take_pending();
+            if let Some(update) = pending {
+                if self.connected.load(Ordering::Relaxed) {
+                    self.send_image(update, SyncTarget::All);
+                }
+            }
+        }
+
+        // The new generation has to be created under the image lock, so the generations
+        // are in the same order as the messages in the queue.
+        fn generation(&self, new_frame: bool) -> ImageGeneration {
+            let generation = match new_frame {
+                true => self.generation.fetch_add(1, Ordering::Relaxed) + 1,
+                false => self.generation.load(Ordering::Relaxed),
+            };
+            ImageGeneration::new(generation, self.generation.clone())
+        }
+
+        // Preview is downscaled from the stored RGBA data by the nearest neighbor. It is created
+        // only if the image is bigger than the preview size.
+        fn preview_message(&self, image: &ImageDataInner, update: bool) -> Option<WriteMessage> {
+            let max_size = (*self.preview.read().unwrap())?;
+            let size = image.size;
+            let max_dim = size[0].max(size[1]);
+            if max_dim <= max_size {
+                return None;
+            }
+
+            let preview_size = [
+                (size[0] * max_size / max_dim).max(1),
+                (size[1] * max_size / max_dim).max(1),
+            ];
+            let mut data = Vec::with_capacity(preview_size[0] * preview_size[1] * 4);
+            for y in 0..preview_size[0] {
+                let line = y * size[0] / preview_size[0] * size[1];
+                for x in 0..preview_size[1] {
+                    let idx = (line + x * size[1] / preview_size[1]) * 4;
+                    data.extend_from_slice(&image.data[idx..idx + 4]);
+                }
+            }
+
+            let image_info = ImageInfo {
+                image_size: size,
+                rect: None,
+                image_type: ImageType::ColorAlpha,
+                preview: Some(preview_size),
+            };
+            let info = serialize(&image_info);
+            let generation = self.generation(false);
+            Some(WriteMessage::Image(self.id, update, info, data, generation))
+        }
+
         pub(crate) fn get_image_py<'py>(
             &self,
             py: Python<'py>,
@@ -263,11 +518,25 @@ pub(crate) mod server {
                 strides[0] as usize
             };
 
+            // check if the frame is not dropped because of the max fps
+            let mut send = self.connected.load(Ordering::Relaxed);
+            if send {
+                let throttle = self.frame_rate.write().unwrap().throttle(update);
+                match throttle {
+                    Throttle::Send => {}
+                    Throttle::Drop => send = false,
+                    Throttle::Schedule(wait) => {
+                        send_later(self.this.clone(), wait, Self::send_pending);
+                        send = false;
+                    }
+                }
+            }
+
             // get data pointer and prepare data
             let data_ptr;
-            let data = if self.connected.load(Ordering::Relaxed) {
+            let data = if send {
                 let data_size = image.item_count();
-                let mut data = Vec::with_capacity(data_size);
+                let mut data = POOL.take(data_size);
                 if contiguous {
                     let buffer = image.buf_ptr() as *const u8;
                     unsafe {
@@ -336,14 +605,22 @@ pub(crate) mod server {
 
             // send the image to the server
             if let Some(data) = data {
+                let generation = self.generation(origin.is_none());
+                if origin.is_none() {
+                    if let Some(message) = self.preview_message(&w, update) {
+                        self.channel.send(message).unwrap();
+                    }
+                }
+
                 let rect = origin.map(|o| [o[0], o[1], size[0], size[1]]);
                 let image_info = ImageInfo {
                     image_size: new_size,
                     rect,
                     image_type,
+                    preview: None,
                 };
                 let info = serialize(&image_info);
-                let message = WriteMessage::Image(self.id, update, info, data);
+                let message = WriteMessage::Image(self.id, update, info, data, generation);
                 self.channel.send(message).unwrap();
             }
 
@@ -351,24 +628,47 @@ pub(crate) mod server {
         }
     }
 
-    impl SyncTrait for PyValueImage {
-        fn sync(&self) {
+    impl PyValueImage {
+        // The last full frame is kept also without a client, so it is sent by the sync after
+        // the reconnection and it can be resent at any time.
+        pub(crate) fn resend_py(&self, update: bool) {
+            if self.connected.load(Ordering::Relaxed) {
+                self.frame_rate.write().unwrap().clear();
+                self.send_image(update, SyncTarget::All);
+            }
+        }
+
+        fn send_image(&self, update: bool, target: SyncTarget) {
             let w = self.image.read().unwrap();
             if w.size[0] == 0 || w.size[1] == 0 {
                 return;
             }
 
+            // the frame for one client can not make the queued frames of the others stale
+            let generation = self.generation(matches!(target, SyncTarget::All));
+            if let Some(message) = self.preview_message(&w, update) {
+                self.channel.send(target.message(message)).unwrap();
+            }
+
             let image_info = ImageInfo {
                 image_size: w.size,
                 rect: None,
                 image_type: ImageType::ColorAlpha,
+                preview: None,
             };
             let info = serialize(&image_info);
             let image_data = w.data.clone();
             drop(w);
 
-            let message = WriteMessage::Image(self.id, false, info, image_data);
-            self.channel.send(message).unwrap();
+            let message = WriteMessage::Image(self.id, update, info, image_data, generation);
+            self.channel.send(target.message(message)).unwrap();
+        }
+    }
+
+    impl SyncTrait for PyValueImage {
+        fn sync(&self, target: SyncTarget) {
+            self.frame_rate.write().unwrap().clear();
+            self.send_image(false, target);
         }
     }
 
@@ -590,3 +890,103 @@ pub(crate) mod server {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(image_size: [usize; 2], image_type: ImageType) -> ImageInfo {
+        ImageInfo {
+            image_size,
+            rect: None,
+            image_type,
+            preview: None,
+        }
+    }
+
+    fn pixels(image: &ColorImage) -> Vec<[u8; 4]> {
+        image.pixels.iter().map(|p| p.to_array()).collect()
+    }
+
+    #[test]
+    fn all_image_types_are_converted() {
+        let image = ValueImage::new(1);
+
+        let color = image
+            .convert(&info([1, 2], ImageType::Color), &[1, 2, 3, 4, 5, 6])
+            .unwrap();
+        assert_eq!(pixels(&color), vec![[1, 2, 3, 255], [4, 5, 6, 255]]);
+
+        let data = [1, 2, 3, 4, 5, 6, 7, 8];
+        let color_alpha = image
+            .convert(&info([2, 1], ImageType::ColorAlpha), &data)
+            .unwrap();
+        assert_eq!(color_alpha.size, [1, 2]);
+        assert_eq!(pixels(&color_alpha), vec![[1, 2, 3, 4], [5, 6, 7, 8]]);
+
+        let gray = image
+            .convert(&info([1, 2], ImageType::Gray), &[10, 20])
+            .unwrap();
+        assert_eq!(pixels(&gray), vec![[10, 10, 10, 255], [20, 20, 20, 255]]);
+
+        let gray_alpha = image
+            .convert(&info([1, 2], ImageType::GrayAlpha), &[10, 1, 20, 2])
+            .unwrap();
+        assert_eq!(pixels(&gray_alpha), vec![[10, 10, 10, 1], [20, 20, 20, 2]]);
+    }
+
+    #[test]
+    fn odd_width_gray_rows_are_aligned() {
+        let image = ValueImage::new(1);
+        let data: Vec<u8> = (0..3 * 5).collect();
+        let gray = image
+            .convert(&info([3, 5], ImageType::Gray), &data)
+            .unwrap();
+
+        assert_eq!(gray.size, [5, 3]);
+        for y in 0..3 {
+            for x in 0..5 {
+                let value = (y * 5 + x) as u8;
+                assert_eq!(gray[(x, y)].to_array(), [value, value, value, 255]);
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_data_size_is_rejected() {
+        let image = ValueImage::new(1);
+        // the padded rows of the odd width gray image
+        let data = vec![0u8; 3 * 8];
+        assert!(image
+            .convert(&info([3, 5], ImageType::Gray), &data)
+            .is_err());
+        assert!(image
+            .convert(&info([3, 5], ImageType::GrayAlpha), &[0; 3 * 5])
+            .is_err());
+    }
+
+    #[test]
+    fn rectangle_over_preview_asks_for_full_image() {
+        let image = ValueImage::new(1);
+        let preview = ImageInfo {
+            preview: Some([2, 2]),
+            ..info([4, 4], ImageType::Gray)
+        };
+        let c_image = image.convert(&preview, &[0; 4]).unwrap();
+        assert!(!image.set_texture(preview, c_image).unwrap());
+        assert!(image.is_preview());
+
+        let rect = ImageInfo {
+            rect: Some([1, 1, 2, 2]),
+            ..info([4, 4], ImageType::Gray)
+        };
+        let c_image = image.convert(&rect, &[255; 4]).unwrap();
+        assert!(image.set_texture(rect, c_image).unwrap());
+
+        // the preview is kept unchanged
+        let pending = image.pending.read().unwrap();
+        let (kept, _) = pending.as_ref().unwrap();
+        assert_eq!(kept.size, [2, 2]);
+        assert!(kept.pixels.iter().all(|p| p.r() == 0));
+    }
+}
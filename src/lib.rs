@@ -1,19 +1,39 @@
 pub mod build;
 
+pub mod bitset;
 pub mod client;
 pub mod client_state;
 pub mod dict;
 pub mod graphs;
 pub mod image;
 pub mod list;
+pub mod trace;
 pub mod values;
+pub mod widgets;
 
 mod commands;
+mod errors;
 mod event;
 mod nohash;
+mod offline;
+mod pool;
+mod schema;
+mod socket;
+mod stats;
 mod states_creator;
 mod transport;
 
+#[cfg(feature = "chaos")]
+pub mod chaos;
+#[cfg(feature = "plot")]
+pub mod plot;
+#[cfg(feature = "encryption")]
+mod secure;
+#[cfg(feature = "shared-memory")]
+mod shared;
+
+#[cfg(feature = "server")]
+mod audit;
 #[cfg(feature = "server")]
 mod py_server;
 #[cfg(feature = "server")]
@@ -25,12 +45,22 @@ mod signals;
 #[cfg(feature = "server")]
 mod states_server;
 
+pub use bitset::ValueBitset;
 pub use dict::ValueDict;
+pub use errors::{ErrorCallback, ErrorRecord, ErrorSource, Severity};
 pub use graphs::ValueGraphs;
 pub use image::ValueImage;
 pub use list::ValueList;
+pub use schema::WireType;
+#[cfg(feature = "encryption")]
+pub use secure::PresharedKey;
+pub use socket::{Address, Socks5Proxy, TcpOptions};
+pub use stats::{MessageStats, TypeStats};
 pub use states_creator::ValuesCreator;
-pub use values::{Diff, Empty, Signal, Value, ValueStatic};
+pub use transport::{ChecksumError, Extensions, MessageSizeError, DEFAULT_MAX_MESSAGE_SIZE};
+pub use values::{Diff, Empty, EnumNames, Signal, Value, ValueStatic};
+
+pub use egui_pysync_macros::EnumNames;
 
 pub use serde;
 
@@ -38,9 +68,15 @@ pub use serde;
 #[cfg(feature = "server")]
 pub use egui_pysync_macros::{pyenum, pystruct};
 
+#[cfg(feature = "server")]
+pub use server::RateLimit;
+
 #[cfg(feature = "server")]
 pub use states_server::ServerValuesCreator;
 
+#[cfg(feature = "server")]
+pub use signals::{ChangedValues, SignalValue};
+
 #[cfg(feature = "server")]
 pub use python_convert::ToPython;
 
@@ -62,11 +98,31 @@ pub fn init_module(
     m: &pyo3::Bound<pyo3::types::PyModule>,
     create_function: fn(&mut states_server::ServerValuesCreator),
 ) -> pyo3::PyResult<()> {
-    py_server::CREATE_HOOK.set(create_function).map_err(|_| {
-        pyo3::exceptions::PyRuntimeError::new_err("Failed to inicialize state server module.")
-    })?;
+    if !py_server::register_creator(None, create_function) {
+        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+            "Failed to inicialize state server module.",
+        ));
+    }
 
     m.add_class::<py_server::StateServerCore>()?;
 
     Ok(())
 }
+
+/// Register another function creating the values, which is used by the servers created with its name.
+///
+/// Every server has its own values, so one Python process can serve more different UIs on different
+/// ports. It has to be called before the servers are created, usually from the module initialization.
+#[cfg(feature = "server")]
+pub fn add_creator(
+    name: &str,
+    create_function: fn(&mut states_server::ServerValuesCreator),
+) -> pyo3::PyResult<()> {
+    if !py_server::register_creator(Some(name), create_function) {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Creator {} is already registered.",
+            name
+        )));
+    }
+    Ok(())
+}
@@ -1,8 +1,10 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, RwLock};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::transport::{deserialize, MessageData};
+use crate::transport::{deserialize, serialize, MessageData, WriteMessage};
 
 #[derive(Deserialize)]
 enum ListMessage<T> {
@@ -10,22 +12,72 @@ enum ListMessage<T> {
     Set(usize, T),
     Add(T),
     Remove(usize),
+    Move(usize, usize),
+    Swap(usize, usize),
+}
+
+impl<T> ListMessage<T> {
+    // The same for the messages from the server and from the client. The indexes out of
+    // the range are ignored and false is returned, so the list differs from the sender.
+    fn apply(self, list: &mut Vec<T>) -> bool {
+        let len = list.len();
+        match self {
+            ListMessage::All(new_list) => {
+                *list = new_list;
+            }
+            ListMessage::Set(idx, value) if idx < len => {
+                list[idx] = value;
+            }
+            ListMessage::Add(value) => {
+                list.push(value);
+            }
+            ListMessage::Remove(idx) if idx < len => {
+                list.remove(idx);
+            }
+            ListMessage::Move(from, to) if from < len && to < len => {
+                let value = list.remove(from);
+                list.insert(to, value);
+            }
+            ListMessage::Swap(i, j) if i < len && j < len => {
+                list.swap(i, j);
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[derive(Serialize)]
+enum ListMessageRef<'a, T> {
+    All(&'a Vec<T>),
+    Set(usize, &'a T),
+    Add(&'a T),
+    Remove(usize),
+    Move(usize, usize),
+    Swap(usize, usize),
 }
 
 pub(crate) trait ListUpdate: Sync + Send {
-    fn update_list(&self, data: MessageData) -> Result<(), String>;
+    // returns false if the change did not match the local list
+    fn update_list(&self, data: MessageData) -> Result<bool, String>;
 }
 
 pub struct ValueList<T> {
     id: u32,
     list: RwLock<Vec<T>>,
+    channel: Sender<WriteMessage>,
+    changed: AtomicBool,
+    ack: bool, // the server waits for the acknowledges of the writable list
 }
 
 impl<T: Clone> ValueList<T> {
-    pub(crate) fn new(id: u32) -> Arc<Self> {
+    pub(crate) fn new(id: u32, channel: Sender<WriteMessage>, ack: bool) -> Arc<Self> {
         Arc::new(Self {
             id,
             list: RwLock::new(Vec::new()),
+            channel,
+            changed: AtomicBool::new(false),
+            ack,
         })
     }
 
@@ -37,38 +89,98 @@ impl<T: Clone> ValueList<T> {
         self.list.read().unwrap().get(idx).cloned()
     }
 
-    pub fn process<R>(&self, op: impl Fn(&Vec<T>) -> R) -> R {
+    pub fn process<R>(&self, op: impl FnOnce(&Vec<T>) -> R) -> R {
         let l = self.list.read().unwrap();
         op(&*l)
     }
+
+    /// If the list was changed by the server since the last call. The flag is shared by all
+    /// callers, so only one place in the UI should take it.
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+}
+
+// The changes are applied to the local list and sent to the server, which relays them to
+// the other clients. The change does not set the changed flag. Nothing is sent if the index is
+// out of the range. Only the lists added by `add_list_writable` can be changed, the server sends
+// the other ones back.
+impl<T: Serialize> ValueList<T> {
+    pub fn set(&self, list: Vec<T>, signal: bool) {
+        let data = serialize(ListMessageRef::All(&list));
+        let mut l = self.list.write().unwrap();
+        self.send(data, signal);
+        *l = list;
+    }
+
+    /// Returns false if the index is out of the range.
+    pub fn set_item(&self, idx: usize, value: T, signal: bool) -> bool {
+        let mut l = self.list.write().unwrap();
+        if idx >= l.len() {
+            return false;
+        }
+        self.send(serialize(ListMessageRef::Set(idx, &value)), signal);
+        l[idx] = value;
+        true
+    }
+
+    pub fn add_item(&self, value: T, signal: bool) {
+        let data = serialize(ListMessageRef::Add(&value));
+        let mut l = self.list.write().unwrap();
+        self.send(data, signal);
+        l.push(value);
+    }
+
+    /// Returns the removed item, None if the index is out of the range.
+    pub fn remove_item(&self, idx: usize, signal: bool) -> Option<T> {
+        let mut l = self.list.write().unwrap();
+        if idx >= l.len() {
+            return None;
+        }
+        self.send(serialize(ListMessageRef::Remove::<T>(idx)), signal);
+        Some(l.remove(idx))
+    }
+
+    /// Returns false if any of the indexes is out of the range.
+    pub fn move_item(&self, from: usize, to: usize, signal: bool) -> bool {
+        let mut l = self.list.write().unwrap();
+        if from >= l.len() || to >= l.len() {
+            return false;
+        }
+        self.send(serialize(ListMessageRef::Move::<T>(from, to)), signal);
+        let value = l.remove(from);
+        l.insert(to, value);
+        true
+    }
+
+    /// Returns false if any of the indexes is out of the range.
+    pub fn swap(&self, i: usize, j: usize, signal: bool) -> bool {
+        let mut l = self.list.write().unwrap();
+        if i >= l.len() || j >= l.len() {
+            return false;
+        }
+        self.send(serialize(ListMessageRef::Swap::<T>(i, j)), signal);
+        l.swap(i, j);
+        true
+    }
+
+    fn send(&self, data: MessageData, signal: bool) {
+        let message = WriteMessage::List(self.id, signal, data);
+        self.channel.send(message).unwrap();
+    }
 }
 
 impl<T: for<'a> Deserialize<'a> + Send + Sync> ListUpdate for ValueList<T> {
-    fn update_list(&self, data: MessageData) -> Result<(), String> {
-        let message = deserialize(data)
+    fn update_list(&self, data: MessageData) -> Result<bool, String> {
+        let message: ListMessage<T> = deserialize(data)
             .map_err(|e| format!("Error deserializing message {} with id {}", e, self.id))?;
 
-        match message {
-            ListMessage::All(list) => {
-                *self.list.write().unwrap() = list;
-            }
-            ListMessage::Set(idx, value) => {
-                let mut list = self.list.write().unwrap();
-                if idx < list.len() {
-                    list[idx] = value;
-                }
-            }
-            ListMessage::Add(value) => {
-                self.list.write().unwrap().push(value);
-            }
-            ListMessage::Remove(idx) => {
-                let mut list = self.list.write().unwrap();
-                if idx < list.len() {
-                    list.remove(idx);
-                }
-            }
+        let matched = message.apply(&mut self.list.write().unwrap());
+        self.changed.store(true, Ordering::Relaxed);
+        if self.ack {
+            self.channel.send(WriteMessage::ack(self.id)).unwrap();
         }
-        Ok(())
+        Ok(matched)
     }
 }
 
@@ -78,24 +190,48 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> ListUpdate for ValueList<T> {
 pub(crate) mod server {
     use super::*;
 
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::sync::mpsc::Sender;
+    use std::sync::Mutex;
 
-    use pyo3::exceptions::PyIndexError;
+    use pyo3::exceptions::{PyIndexError, PyValueError};
     use pyo3::prelude::*;
-    use pyo3::types::PyList;
-    use serde::Serialize;
+    use pyo3::types::{PyList, PyString, PyTuple};
 
     use crate::python_convert::ToPython;
-    use crate::server::SyncTrait;
-    use crate::transport::{serialize, WriteMessage};
+    use crate::server::{Acknowledge, SyncTarget, SyncTrait};
+    use crate::signals::ChangedValues;
+    use crate::values::server::{Pending, UpdateValueServer};
+
+    // change made by the client, the signal is the tuple of the action and the index
+    enum ListChange {
+        All,
+        Index(&'static str, usize),
+        Pair(&'static str, usize, usize),
+    }
 
-    #[derive(Serialize)]
-    enum ListMessageRef<'a, T> {
-        All(&'a Vec<T>),
-        Set(usize, &'a T),
-        Add(&'a T),
-        Remove(usize),
+    impl ListChange {
+        // the index of the added item is the length of the list before the change
+        fn new<T>(message: &ListMessage<T>, len: usize) -> Self {
+            match *message {
+                ListMessage::All(_) => Self::All,
+                ListMessage::Set(idx, _) => Self::Index("set", idx),
+                ListMessage::Add(_) => Self::Index("add", len),
+                ListMessage::Remove(idx) => Self::Index("remove", idx),
+                ListMessage::Move(from, to) => Self::Pair("move", from, to),
+                ListMessage::Swap(i, j) => Self::Pair("swap", i, j),
+            }
+        }
+    }
+
+    impl ToPython for ListChange {
+        fn to_python<'py>(&self, py: Python<'py>) -> Bound<'py, PyAny> {
+            let (action, idx) = match *self {
+                Self::All => ("all", ().to_python(py)),
+                Self::Index(action, idx) => (action, (idx as u64).to_python(py)),
+                Self::Pair(action, i, j) => (action, (i as u64, j as u64).to_python(py)),
+            };
+            let action = PyString::new(py, action).into_any();
+            PyTuple::new(py, [action, idx]).unwrap().into_any()
+        }
     }
 
     pub(crate) trait PyListTrait: Send + Sync {
@@ -105,14 +241,22 @@ pub(crate) mod server {
         fn set_item_py(&self, idx: usize, value: &Bound<PyAny>, update: bool) -> PyResult<()>;
         fn add_item_py(&self, value: &Bound<PyAny>, update: bool) -> PyResult<()>;
         fn del_item_py(&self, idx: usize, update: bool) -> PyResult<()>;
+        fn move_item_py(&self, from: usize, to: usize, update: bool) -> PyResult<()>;
+        fn swap_py(&self, i: usize, j: usize, update: bool) -> PyResult<()>;
         fn len_py(&self) -> usize;
+        // serialized content for the state file
+        fn save(&self) -> Vec<u8>;
+        fn load(&self, data: &[u8]) -> PyResult<()>;
     }
 
     pub(crate) struct PyValueList<T> {
         id: u32,
         list: RwLock<Vec<T>>,
+        pending: Mutex<Pending>, // checked with the list lock
+        ack: bool,               // the clients can write the list
         channel: Sender<WriteMessage>,
         connected: Arc<AtomicBool>,
+        signals: ChangedValues,
     }
 
     impl<T> PyValueList<T> {
@@ -120,19 +264,69 @@ pub(crate) mod server {
             id: u32,
             channel: Sender<WriteMessage>,
             connected: Arc<AtomicBool>,
+            signals: ChangedValues,
+            ack: bool,
         ) -> Arc<Self> {
             Arc::new(Self {
                 id,
                 list: RwLock::new(Vec::new()),
+                pending: Mutex::new(Pending::default()),
+                ack,
                 channel,
                 connected,
+                signals,
             })
         }
+
+        // every change sent to the clients waits for their acknowledges, if they can write
+        fn send(&self, message: WriteMessage) {
+            if self.ack {
+                self.pending.lock().unwrap().expect(None);
+            }
+            self.channel.send(message).unwrap();
+        }
+    }
+
+    impl<T> UpdateValueServer for PyValueList<T>
+    where
+        T: Serialize + for<'a> Deserialize<'a> + Send + Sync,
+    {
+        // The applied change is relayed as it was sent, the client with the different list gets
+        // the whole list. The change of the client which did not acknowledge the latest changes
+        // of the server would be applied to the other items, so it is dropped and the client
+        // gets the whole list.
+        fn update_value(
+            &self,
+            data: MessageData,
+            signal: bool,
+            client: usize,
+        ) -> Result<bool, String> {
+            let message: ListMessage<T> = deserialize(data)
+                .map_err(|e| format!("Parse error: {} for list id: {}", e, self.id))?;
+            let mut list = self.list.write().unwrap();
+            if self.ack && self.pending.lock().unwrap().count(client) > 0 {
+                self.pending.lock().unwrap().reset(client);
+                drop(list);
+                self.sync(SyncTarget::Client(client));
+                return Ok(false);
+            }
+            let change = signal.then(|| ListChange::new(&message, list.len()));
+            let matched = message.apply(&mut list);
+            drop(list);
+            if !matched {
+                self.sync(SyncTarget::All);
+                return Err(format!("Change did not match the list id: {}", self.id));
+            }
+            if let Some(change) = change {
+                self.signals.set(self.id, change);
+            }
+            Ok(true)
+        }
     }
 
     impl<T> PyListTrait for PyValueList<T>
     where
-        T: Serialize + ToPython + for<'py> FromPyObject<'py> + Clone,
+        T: Serialize + for<'a> Deserialize<'a> + ToPython + for<'py> FromPyObject<'py> + Clone,
     {
         fn get_py<'py>(&self, py: Python<'py>) -> Bound<'py, PyList> {
             let list = self.list.read().unwrap().clone();
@@ -166,7 +360,7 @@ pub(crate) mod server {
                 let data = serialize(ListMessageRef::All(&data));
                 let message = WriteMessage::List(self.id, update, data);
 
-                self.channel.send(message).unwrap();
+                self.send(message);
             }
 
             *l = data;
@@ -185,7 +379,7 @@ pub(crate) mod server {
                 list.py().allow_threads(|| {
                     let data = serialize(ListMessageRef::Set(idx, &value));
                     let message = WriteMessage::List(self.id, update, data);
-                    self.channel.send(message).unwrap();
+                    self.send(message);
                 });
             }
 
@@ -203,7 +397,7 @@ pub(crate) mod server {
             if self.connected.load(Ordering::Relaxed) {
                 let data = serialize(ListMessageRef::Remove::<T>(idx));
                 let message = WriteMessage::List(self.id, update, data);
-                self.channel.send(message).unwrap();
+                self.send(message);
             }
 
             list.remove(idx);
@@ -219,7 +413,7 @@ pub(crate) mod server {
                 let data = serialize(ListMessageRef::Add(&value));
                 let message = WriteMessage::List(self.id, update, data);
 
-                self.channel.send(message).unwrap();
+                self.send(message);
             }
 
             list.push(value);
@@ -227,17 +421,97 @@ pub(crate) mod server {
             Ok(())
         }
 
+        fn move_item_py(&self, from: usize, to: usize, update: bool) -> PyResult<()> {
+            let mut list = self.list.write().unwrap();
+            if from >= list.len() || to >= list.len() {
+                return Err(PyIndexError::new_err("list index out of range"));
+            }
+
+            if self.connected.load(Ordering::Relaxed) {
+                let data = serialize(ListMessageRef::Move::<T>(from, to));
+                let message = WriteMessage::List(self.id, update, data);
+                self.send(message);
+            }
+
+            let value = list.remove(from);
+            list.insert(to, value);
+
+            Ok(())
+        }
+
+        fn swap_py(&self, i: usize, j: usize, update: bool) -> PyResult<()> {
+            let mut list = self.list.write().unwrap();
+            if i >= list.len() || j >= list.len() {
+                return Err(PyIndexError::new_err("list index out of range"));
+            }
+
+            if self.connected.load(Ordering::Relaxed) {
+                let data = serialize(ListMessageRef::Swap::<T>(i, j));
+                let message = WriteMessage::List(self.id, update, data);
+                self.send(message);
+            }
+
+            list.swap(i, j);
+
+            Ok(())
+        }
+
         fn len_py(&self) -> usize {
             self.list.read().unwrap().len()
         }
+
+        fn save(&self) -> Vec<u8> {
+            postcard::to_stdvec(&*self.list.read().unwrap()).unwrap()
+        }
+
+        fn load(&self, data: &[u8]) -> PyResult<()> {
+            let data: Vec<T> = postcard::from_bytes(data).map_err(|e| {
+                PyValueError::new_err(format!("Parse error: {} for list id: {}", e, self.id))
+            })?;
+
+            let mut l = self.list.write().unwrap();
+            if self.connected.load(Ordering::Relaxed) {
+                let data = serialize(ListMessageRef::All(&data));
+                let message = WriteMessage::List(self.id, false, data);
+                self.send(message);
+            }
+            *l = data;
+
+            Ok(())
+        }
     }
 
     impl<T: Serialize + Send + Sync> SyncTrait for PyValueList<T> {
-        fn sync(&self) {
+        fn sync(&self, target: SyncTarget) {
             let list = self.list.read().unwrap();
+            if self.ack {
+                match target {
+                    SyncTarget::All => self.pending.lock().unwrap().expect(None),
+                    SyncTarget::Client(client) => {
+                        self.pending.lock().unwrap().expect_client(client)
+                    }
+                }
+            }
             let data = serialize(ListMessageRef::All(&list));
             let message = WriteMessage::List(self.id, false, data);
-            self.channel.send(message).unwrap();
+            self.channel.send(target.message(message)).unwrap();
+        }
+    }
+    impl<T: Send + Sync> Acknowledge for PyValueList<T> {
+        fn acknowledge(&self, client: usize) {
+            self.pending.lock().unwrap().acknowledge(client);
+        }
+
+        fn reset(&self, client: usize) {
+            self.pending.lock().unwrap().reset(client);
+        }
+
+        fn remove(&self, client: usize) {
+            self.pending.lock().unwrap().remove(client);
+        }
+
+        fn relayed(&self, client: usize) {
+            self.pending.lock().unwrap().expect(Some(client));
         }
     }
 }
@@ -0,0 +1,66 @@
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::transport::{MessageData, WriteMessage};
+use crate::NoHashMap;
+
+// Writes made while the client was not connected. The values keep only the newest write,
+// the changes of the dicts and lists are kept in the order. They are sent after the server
+// synchronized the value, so the synchronization does not overwrite them.
+#[derive(Clone)]
+pub(crate) struct OfflineQueue {
+    queued: Arc<Mutex<NoHashMap<u32, Vec<WriteMessage>>>>,
+    channel: Sender<WriteMessage>,
+}
+
+impl OfflineQueue {
+    pub(crate) fn new(channel: Sender<WriteMessage>) -> Self {
+        Self {
+            queued: Arc::new(Mutex::new(NoHashMap::default())),
+            channel,
+        }
+    }
+
+    // Called before the connection starts. The signals have nothing to synchronize, so they
+    // are sent right after the handshake. The commands belong to the previous connection.
+    pub(crate) fn queue(&self, rx: &Receiver<WriteMessage>) {
+        let mut queued = self.queued.lock().unwrap();
+        let mut signals = NoHashMap::default();
+        for message in rx.try_iter() {
+            match message {
+                WriteMessage::Value(id, _, _) => {
+                    queued.insert(id, vec![message]);
+                }
+                WriteMessage::Signal(id, _) => {
+                    signals.insert(id, message);
+                }
+                WriteMessage::Dict(id, _, _) | WriteMessage::List(id, _, _) => {
+                    queued.entry(id).or_default().push(message);
+                }
+                _ => {}
+            }
+        }
+        for (_, message) in signals {
+            self.channel.send(message).unwrap();
+        }
+    }
+
+    pub(crate) fn contains(&self, id: u32) -> bool {
+        self.queued.lock().unwrap().contains_key(&id)
+    }
+
+    // sends the queued writes of the value, their data is returned to apply them again
+    // over the synchronized content
+    pub(crate) fn replay(&self, id: u32) -> Vec<MessageData> {
+        let messages = self.queued.lock().unwrap().remove(&id).unwrap_or_default();
+        let mut replayed = Vec::with_capacity(messages.len());
+        for message in messages {
+            if let WriteMessage::Dict(_, _, ref data) | WriteMessage::List(_, _, ref data) = message
+            {
+                replayed.push(data.clone());
+            }
+            self.channel.send(message).unwrap();
+        }
+        replayed
+    }
+}
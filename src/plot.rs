@@ -0,0 +1,60 @@
+use egui_plot::{Line, PlotPoint, PlotPoints};
+
+use crate::graphs::{GraphElement, ValueGraphs};
+
+/// Points of one graph for the egui_plot.
+///
+/// The points are converted only when the graph changed, it uses the same changed flag as
+/// [`ValueGraphs::process`], so only one of them should be used for the graph. The linear graph
+/// has the index of the point as the x value.
+pub struct GraphPoints {
+    idx: u16,
+    points: Vec<PlotPoint>,
+}
+
+impl GraphPoints {
+    pub fn new(idx: u16) -> Self {
+        Self {
+            idx,
+            points: Vec::new(),
+        }
+    }
+
+    /// Convert the points again if the graph changed since the last update, returns if it
+    /// changed. The removed graph has no points.
+    pub fn update<T>(&mut self, graphs: &ValueGraphs<T>) -> bool
+    where
+        T: GraphElement + Into<f64>,
+    {
+        let points = &mut self.points;
+        graphs.process_series(self.idx, |series, changed| match series {
+            Some((x, y)) if changed || points.is_empty() => {
+                points.clear();
+                match x {
+                    Some(x) => points.extend(x.iter().zip(y).map(|(x, y)| PlotPoint::new(*x, *y))),
+                    None => points.extend(
+                        y.iter()
+                            .enumerate()
+                            .map(|(i, y)| PlotPoint::new(i as f64, *y)),
+                    ),
+                }
+                true
+            }
+            Some(_) => false,
+            None => {
+                let removed = !points.is_empty();
+                points.clear();
+                removed
+            }
+        })
+    }
+
+    pub fn points(&self) -> &[PlotPoint] {
+        &self.points
+    }
+
+    /// Line with the points, the plot needs its own copy of the points for every frame.
+    pub fn line(&self) -> Line {
+        Line::new(PlotPoints::Owned(self.points.clone()))
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::Mutex;
+
+/*
+    Buffers of the large messages, images and graphs, are returned to the pool after they are
+    written to the socket or processed by the client and the next message takes them instead of
+    a new allocation. The pool is shared by all threads of the process. Small buffers are not
+    kept, the allocator is fast enough for them, and the pool keeps only limited memory.
+*/
+const MIN_SIZE: usize = 4096;
+const MAX_BUFFERS: usize = 16;
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+
+pub(crate) static POOL: BufferPool = BufferPool::new();
+
+pub(crate) struct BufferPool {
+    buffers: Mutex<(Vec<Vec<u8>>, usize)>, // buffers, sum of their capacities
+}
+
+impl BufferPool {
+    const fn new() -> Self {
+        Self {
+            buffers: Mutex::new((Vec::new(), 0)),
+        }
+    }
+
+    /// Empty buffer with at least the capacity.
+    pub(crate) fn take(&self, capacity: usize) -> Vec<u8> {
+        if capacity >= MIN_SIZE {
+            let mut b = self.buffers.lock().unwrap();
+            // the smallest buffer which is large enough
+            let best =
+                b.0.iter()
+                    .enumerate()
+                    .filter(|(_, buffer)| buffer.capacity() >= capacity)
+                    .min_by_key(|(_, buffer)| buffer.capacity())
+                    .map(|(i, _)| i);
+            if let Some(i) = best {
+                let buffer = b.0.swap_remove(i);
+                b.1 -= buffer.capacity();
+                return buffer;
+            }
+        }
+        Vec::with_capacity(capacity)
+    }
+
+    /// Buffer with the length filled with zeros.
+    pub(crate) fn take_zeroed(&self, len: usize) -> Vec<u8> {
+        let mut buffer = self.take(len);
+        buffer.resize(len, 0);
+        buffer
+    }
+
+    /// Return the buffer to the pool. It is dropped if it is small or the pool is full.
+    pub(crate) fn give(&self, mut buffer: Vec<u8>) {
+        let capacity = buffer.capacity();
+        if capacity < MIN_SIZE {
+            return;
+        }
+
+        let mut b = self.buffers.lock().unwrap();
+        if b.0.len() < MAX_BUFFERS && b.1 + capacity <= MAX_BYTES {
+            buffer.clear();
+            b.0.push(buffer);
+            b.1 += capacity;
+        }
+    }
+}
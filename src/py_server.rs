@@ -1,33 +1,102 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::{
     atomic,
     mpsc::{self, Sender},
-    Arc, OnceLock, RwLock,
+    Arc, Mutex, RwLock,
 };
+use std::thread;
+use std::time::Duration;
 
 use pyo3::buffer::PyBuffer;
 use pyo3::prelude::*;
 use pyo3::types::{PyByteArray, PyDict, PyList, PyTuple};
+use serde::{Deserialize, Serialize};
 
 use crate::commands::CommandMessage;
-use crate::server::Server;
+use crate::errors::{ErrorCallback, ErrorSource, Severity};
+use crate::server::{
+    AuthCallback, DisconnectCallback, HandshakeRules, Server, ServerConfig, SyncTarget, SyncTrait,
+};
 use crate::signals::ChangedValues;
+use crate::socket::{Address, TcpOptions};
 use crate::states_server::{PyValuesList, ServerValuesCreator};
 use crate::transport::WriteMessage;
-use crate::NoHashSet;
+use crate::{NoHashMap, NoHashSet};
+
+type CreateFunction = fn(&mut ServerValuesCreator);
+
+// seconds for writing of the queued messages when the server is closed
+const CLOSE_TIMEOUT: f64 = 5.0;
+
+// To be able to create all values outside this crate. The creator without the name is the default
+// one, the named creators allow more servers with different values in one process.
+static CREATE_HOOKS: Mutex<Vec<(Option<String>, CreateFunction)>> = Mutex::new(Vec::new());
+
+pub(crate) fn register_creator(name: Option<&str>, create_function: CreateFunction) -> bool {
+    let mut hooks = CREATE_HOOKS.lock().unwrap();
+    if hooks.iter().any(|(n, _)| n.as_deref() == name) {
+        return false;
+    }
+    hooks.push((name.map(str::to_string), create_function));
+    true
+}
 
-// To be able to create all values outside this crate
-pub(crate) static CREATE_HOOK: OnceLock<fn(&mut ServerValuesCreator)> = OnceLock::new();
+fn get_creator(name: Option<&str>) -> Option<CreateFunction> {
+    let hooks = CREATE_HOOKS.lock().unwrap();
+    hooks
+        .iter()
+        .find(|(n, _)| n.as_deref() == name)
+        .map(|(_, create_function)| *create_function)
+}
+
+// content of the state file, the values are serialized by their own types
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    layout: u64,
+    values: Vec<(u32, Vec<u8>)>,
+    dicts: Vec<(u32, Vec<u8>)>,
+    lists: Vec<(u32, Vec<u8>)>,
+}
+
+// the ip address as the tuple or the IPv4, IPv6 address or the host name as the string
+#[derive(FromPyObject)]
+enum IpAddress {
+    Tuple([u8; 4]),
+    Host(String),
+}
+
+// the socket path is used instead of the port and the ip address, localhost is the default ip
+fn server_address(
+    port: u16,
+    ip_addr: Option<IpAddress>,
+    socket_path: Option<PathBuf>,
+) -> PyResult<Address> {
+    match (socket_path, ip_addr) {
+        #[cfg(unix)]
+        (Some(path), _) => Ok(Address::Unix(path)),
+        #[cfg(not(unix))]
+        (Some(_), _) => Err(pyo3::exceptions::PyValueError::new_err(
+            "Unix domain sockets are not supported on this platform.",
+        )),
+        (None, Some(IpAddress::Tuple(addr))) => Ok(SocketAddr::new(addr.into(), port).into()),
+        (None, Some(IpAddress::Host(host))) => Ok(Address::host(&host, port)),
+        (None, None) => Ok(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port).into()),
+    }
+}
 
 #[pyclass]
 pub(crate) struct StateServerCore {
     changed_values: ChangedValues,
     values: PyValuesList,
+    sync: NoHashMap<u32, Arc<dyn SyncTrait>>,
 
     channel: Sender<WriteMessage>,
     connected: Arc<atomic::AtomicBool>,
     server: RwLock<Server>,
     registed_values: RwLock<NoHashSet<u32>>,
+    reserved: u32,
+    layout: u64, // hash of the registered values, it is checked when the state is loaded
 }
 
 impl Drop for StateServerCore {
@@ -39,8 +108,16 @@ impl Drop for StateServerCore {
 #[pymethods]
 impl StateServerCore {
     #[new]
-    #[pyo3(signature = (port, ip_addr=None, handshake=None))]
-    fn new(port: u16, ip_addr: Option<[u8; 4]>, handshake: Option<Vec<u64>>) -> PyResult<Self> {
+    #[pyo3(signature = (port, ip_addr=None, handshake=None, read_only=None, socket_path=None, psk=None, creator=None))]
+    fn new(
+        port: u16,
+        ip_addr: Option<IpAddress>,
+        handshake: Option<Vec<u64>>,
+        read_only: Option<Vec<u64>>,
+        socket_path: Option<PathBuf>,
+        psk: Option<Vec<u8>>,
+        creator: Option<String>,
+    ) -> PyResult<Self> {
         let (channel, rx) = mpsc::channel();
         let connected = Arc::new(atomic::AtomicBool::new(false));
 
@@ -48,49 +125,221 @@ impl StateServerCore {
         let mut values_creator =
             ServerValuesCreator::new(channel.clone(), connected.clone(), signals.clone());
 
-        let creator = CREATE_HOOK.get();
-        match creator {
+        match get_creator(creator.as_deref()) {
             Some(c) => {
                 c(&mut values_creator);
             }
-            None => {
-                return Err(pyo3::exceptions::PyRuntimeError::new_err(
-                    "Failed to inicialize state server object.",
-                ))
-            }
+            None => match creator {
+                Some(name) => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                        "Creator {} is not registered.",
+                        name
+                    )))
+                }
+                None => {
+                    return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                        "Failed to inicialize state server object.",
+                    ))
+                }
+            },
         }
 
-        let (values, py_values, version) = values_creator.get_values();
+        let (values, py_values, version, reserved) = values_creator.get_values();
+        let sync = values.sync.clone();
 
-        let addr = match ip_addr {
-            Some(addr) => {
-                SocketAddrV4::new(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]), port)
-            }
-            None => SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port),
-        };
+        let layout = py_values.schema.hash(version, reserved);
+        let addr = server_address(port, ip_addr, socket_path)?;
         let server = Server::new(
             channel.clone(),
             rx,
             connected.clone(),
             values,
             signals.clone(),
-            addr,
-            version,
-            handshake,
+            ServerConfig {
+                addr,
+                version,
+                reserved,
+                handshake: HandshakeRules {
+                    hashes: handshake,
+                    read_only: read_only.unwrap_or_default(),
+                    layout,
+                },
+            },
         );
 
+        if let Some(psk) = psk {
+            #[cfg(feature = "encryption")]
+            {
+                let key = psk.try_into().map_err(|_| {
+                    pyo3::exceptions::PyValueError::new_err("Pre-shared key must be 32 bytes long.")
+                })?;
+                server.set_psk(Some(key));
+            }
+            #[cfg(not(feature = "encryption"))]
+            {
+                let _ = psk;
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "Pre-shared key requires the encryption feature.",
+                ));
+            }
+        }
+
         let obj = Self {
             changed_values: signals,
             values: py_values,
+            sync,
             channel,
             connected,
             server: RwLock::new(server),
             registed_values: RwLock::new(NoHashSet::default()),
+            reserved,
+            layout,
         };
 
         Ok(obj)
     }
 
+    // The file is written next to the path and renamed, so the previous state is kept if
+    // the process crashes while writing.
+    fn save_state(&self, py: Python, path: PathBuf) -> PyResult<()> {
+        let mut state = StateFile {
+            layout: self.layout,
+            values: Vec::with_capacity(self.values.values.len()),
+            dicts: Vec::with_capacity(self.values.dicts.len()),
+            lists: Vec::with_capacity(self.values.lists.len()),
+        };
+        for (id, value) in self.values.values.iter() {
+            state.values.push((*id, value.save_py(py)?));
+        }
+        for (id, dict) in self.values.dicts.iter() {
+            state.dicts.push((*id, dict.save()));
+        }
+        for (id, list) in self.values.lists.iter() {
+            state.lists.push((*id, list.save()));
+        }
+
+        let data = postcard::to_stdvec(&state).unwrap();
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        py.allow_threads(|| {
+            std::fs::write(&temp, data)?;
+            std::fs::rename(&temp, &path)
+        })?;
+        Ok(())
+    }
+
+    // The layout hash of the file has to be the same, so all ids exist and have the same types.
+    fn load_state(&self, py: Python, path: PathBuf) -> PyResult<()> {
+        let data = py.allow_threads(|| std::fs::read(&path))?;
+        let state: StateFile = postcard::from_bytes(&data).map_err(|e| {
+            pyo3::exceptions::PyValueError::new_err(format!("Failed to parse state file: {}", e))
+        })?;
+        if state.layout != self.layout {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "State file was saved with different values.",
+            ));
+        }
+
+        for (id, data) in state.values.iter() {
+            if let Some(value) = self.values.values.get(id) {
+                value.load_py(py, data)?;
+            }
+        }
+        for (id, data) in state.dicts.iter() {
+            if let Some(dict) = self.values.dicts.get(id) {
+                dict.load_py(py, data)?;
+            }
+        }
+        for (id, data) in state.lists.iter() {
+            if let Some(list) = self.values.lists.get(id) {
+                list.load(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reserved_ids(&self) -> u32 {
+        self.reserved
+    }
+
+    // type byte, kind, element type and encoding of the data
+    fn value_wire_type(&self, value_id: u32) -> PyResult<(u8, &'static str, String, &'static str)> {
+        match self.values.schema.wire_type(value_id) {
+            Some(wire) => Ok((wire.type_byte, wire.kind, wire.element, wire.encoding)),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    // Values are dumped by their ids. Signals do not hold any state so they are not included.
+    // Values which are not serializable to json are dumped by their repr.
+    fn dump_state_json(&self, py: Python) -> PyResult<String> {
+        let values = PyDict::new(py);
+        for (id, value) in self.values.values.iter() {
+            values.set_item(id, value.get_py(py)?)?;
+        }
+
+        let static_values = PyDict::new(py);
+        for (id, value) in self.values.static_values.iter() {
+            static_values.set_item(id, value.get_py(py))?;
+        }
+
+        let images = PyDict::new(py);
+        for (id, image) in self.values.images.iter() {
+            let info = PyDict::new(py);
+            info.set_item("size", image.get_size_py())?;
+            images.set_item(id, info)?;
+        }
+
+        // json keys has to be strings
+        let dicts = PyDict::new(py);
+        for (id, dict) in self.values.dicts.iter() {
+            let content = PyDict::new(py);
+            for (key, value) in dict.get_py(py).iter() {
+                content.set_item(key.str()?, value)?;
+            }
+            dicts.set_item(id, content)?;
+        }
+
+        let lists = PyDict::new(py);
+        for (id, list) in self.values.lists.iter() {
+            lists.set_item(id, list.get_py(py))?;
+        }
+
+        let graphs = PyDict::new(py);
+        for (id, graph) in self.values.graphs.iter() {
+            let points = PyDict::new(py);
+            for (idx, len) in graph.lengths_py() {
+                points.set_item(idx, len)?;
+            }
+            graphs.set_item(id, points)?;
+        }
+
+        let bitsets = PyDict::new(py);
+        for (id, bitset) in self.values.bitsets.iter() {
+            bitsets.set_item(id, bitset.get_py(py))?;
+        }
+
+        let state = PyDict::new(py);
+        state.set_item("values", values)?;
+        state.set_item("static_values", static_values)?;
+        state.set_item("images", images)?;
+        state.set_item("dicts", dicts)?;
+        state.set_item("lists", lists)?;
+        state.set_item("graphs", graphs)?;
+        state.set_item("bitsets", bitsets)?;
+
+        let kwargs = PyDict::new(py);
+        kwargs.set_item("default", py.import("builtins")?.getattr("repr")?)?;
+        kwargs.set_item("sort_keys", true)?;
+        kwargs.set_item("indent", 2)?;
+        py.import("json")?
+            .call_method("dumps", (state,), Some(&kwargs))?
+            .extract()
+    }
+
     fn is_connected(&self) -> bool {
         self.connected.load(atomic::Ordering::Relaxed)
     }
@@ -99,6 +348,282 @@ impl StateServerCore {
         self.server.read().unwrap().is_running()
     }
 
+    fn ping(&self) {
+        self.server.read().unwrap().ping();
+    }
+
+    fn last_rtt(&self) -> Option<f64> {
+        self.server
+            .read()
+            .unwrap()
+            .last_rtt()
+            .map(|rtt| rtt.as_secs_f64())
+    }
+
+    fn latency_ms(&self) -> Option<f64> {
+        self.server
+            .read()
+            .unwrap()
+            .latency()
+            .map(|latency| latency.as_secs_f64() * 1000.0)
+    }
+
+    #[pyo3(signature = (interval=None))]
+    fn set_heartbeat(&self, interval: Option<f64>) -> PyResult<()> {
+        let interval = match interval {
+            Some(interval) if interval.is_nan() || interval <= 0.0 => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "Heartbeat interval must be greater than 0.",
+                ));
+            }
+            Some(interval) => Some(Duration::from_secs_f64(interval)),
+            None => None,
+        };
+        self.server.read().unwrap().set_heartbeat(interval);
+        Ok(())
+    }
+
+    #[pyo3(signature = (timeout=None))]
+    fn set_ack_timeout(&self, timeout: Option<f64>) -> PyResult<()> {
+        let timeout = match timeout {
+            Some(timeout) if timeout.is_nan() || timeout <= 0.0 => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "Acknowledge timeout must be greater than 0.",
+                ));
+            }
+            Some(timeout) => Some(Duration::from_secs_f64(timeout)),
+            None => None,
+        };
+        self.changed_values.set_ack_timeout(timeout);
+        Ok(())
+    }
+
+    #[pyo3(signature = (path, payload=false))]
+    fn start_trace(&self, path: PathBuf, payload: bool) -> PyResult<()> {
+        crate::trace::start(&path, payload).map_err(|e| {
+            pyo3::exceptions::PyIOError::new_err(format!(
+                "Failed to create trace file {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    fn stop_trace(&self) -> PyResult<()> {
+        crate::trace::stop().map_err(|e| {
+            pyo3::exceptions::PyIOError::new_err(format!("Failed to write trace file: {}", e))
+        })
+    }
+
+    #[pyo3(signature = (nodelay=false, send_buffer=None, recv_buffer=None, keepalive=None))]
+    fn set_tcp_options(
+        &self,
+        nodelay: bool,
+        send_buffer: Option<usize>,
+        recv_buffer: Option<usize>,
+        keepalive: Option<f64>,
+    ) -> PyResult<()> {
+        let keepalive = match keepalive {
+            Some(keepalive) if keepalive.is_nan() || keepalive <= 0.0 => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "Keepalive time must be greater than 0.",
+                ));
+            }
+            Some(keepalive) => Some(Duration::from_secs_f64(keepalive)),
+            None => None,
+        };
+        let options = TcpOptions {
+            nodelay,
+            send_buffer,
+            recv_buffer,
+            keepalive,
+        };
+        self.server.read().unwrap().set_tcp_options(options);
+        Ok(())
+    }
+
+    fn set_max_message_size(&self, size: usize) -> PyResult<()> {
+        if size == 0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Maximal message size must be greater than 0.",
+            ));
+        }
+        self.server.read().unwrap().set_max_message_size(size);
+        Ok(())
+    }
+
+    fn set_queue_limit(&self, size: usize) {
+        self.server.read().unwrap().set_queue_limit(size);
+    }
+
+    fn set_broadcast(&self, enabled: bool) {
+        self.server.read().unwrap().set_broadcast(enabled);
+    }
+
+    // The callback is called with the address of the client and the last error, the error is
+    // None if the client was disconnected cleanly.
+    #[pyo3(signature = (callback=None))]
+    fn on_disconnect(&self, callback: Option<PyObject>) {
+        let signals = self.changed_values.clone();
+        let callback = callback.map(|callback| -> DisconnectCallback {
+            Box::new(move |addr, error| {
+                let addr = addr.map(|addr| addr.to_string());
+                Python::with_gil(|py| {
+                    if let Err(e) = callback.call1(py, (addr, error)) {
+                        let error = format!("Error in disconnect callback: {}", e);
+                        signals.error(Severity::Warning, ErrorSource::Callback, None, error);
+                    }
+                });
+            })
+        });
+        self.server.read().unwrap().set_on_disconnect(callback);
+    }
+
+    // The callback is called with the hash, the auth token and the address of the client and
+    // returns if the client is accepted. The raised exception rejects the client too.
+    #[pyo3(signature = (callback=None))]
+    fn set_authenticator(&self, callback: Option<PyObject>) {
+        let callback = callback.map(|callback| -> AuthCallback {
+            Box::new(move |hash, token, addr| {
+                let addr = addr.map(|addr| addr.to_string());
+                Python::with_gil(|py| {
+                    let accepted = callback
+                        .call1(py, (hash, token, addr))
+                        .and_then(|res| res.extract::<bool>(py))
+                        .map_err(|e| format!("Error in authenticator: {}", e))?;
+                    match accepted {
+                        true => Ok(()),
+                        false => {
+                            Err("Attempted to connect with rejected authentication".to_string())
+                        }
+                    }
+                })
+            })
+        });
+        self.server.read().unwrap().set_authenticator(callback);
+    }
+
+    // None allows all hashes, the read only hashes are allowed also if they are not in the hashes
+    #[pyo3(signature = (hashes=None, read_only=None))]
+    fn set_handshake(&self, hashes: Option<Vec<u64>>, read_only: Option<Vec<u64>>) {
+        let server = self.server.read().unwrap();
+        server.set_handshake(hashes, read_only.unwrap_or_default());
+    }
+
+    #[pyo3(signature = (hash, read_only=false))]
+    fn handshake_add(&self, hash: u64, read_only: bool) {
+        self.server.read().unwrap().allow_hash(hash, read_only);
+    }
+
+    fn handshake_remove(&self, hash: u64) {
+        self.server.read().unwrap().remove_hash(hash);
+    }
+
+    fn handshake(&self) -> (Option<Vec<u64>>, Vec<u64>) {
+        self.server.read().unwrap().handshake()
+    }
+
+    // counters by the message type and the number of messages waiting in the writer
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let stats = self.server.read().unwrap().stats();
+
+        let types = PyDict::new(py);
+        for (name, counters) in stats.types.iter() {
+            let item = PyDict::new(py);
+            item.set_item("messages_sent", counters.messages_sent)?;
+            item.set_item("bytes_sent", counters.bytes_sent)?;
+            item.set_item("messages_received", counters.messages_received)?;
+            item.set_item("bytes_received", counters.bytes_received)?;
+            types.set_item(name, item)?;
+        }
+
+        let result = PyDict::new(py);
+        result.set_item("types", types)?;
+        result.set_item("queue_depth", stats.queue_depth.unwrap_or_default())?;
+        result.set_item("dropped", stats.dropped.unwrap_or_default())?;
+        Ok(result)
+    }
+
+    // the kept records with the sequence number from the given one, the oldest first
+    #[pyo3(signature = (since=0))]
+    fn errors<'py>(&self, py: Python<'py>, since: u64) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        let records = self.changed_values.errors(since);
+        records.iter().map(|record| record.to_dict(py)).collect()
+    }
+
+    // The callback is called in the observers thread with every new record. The exception of
+    // the callback is only printed, it can not be reported as another error.
+    #[pyo3(signature = (callback=None))]
+    fn on_error(&self, callback: Option<PyObject>) {
+        let observers = self.changed_values.observers();
+        let callback = callback.map(|callback| -> ErrorCallback {
+            let callback = Arc::new(callback);
+            Box::new(move |record| {
+                let callback = callback.clone();
+                let record = record.clone();
+                observers.dispatch(move |py| {
+                    let res = record
+                        .to_dict(py)
+                        .and_then(|record| callback.call1(py, (record,)));
+                    if let Err(e) = res {
+                        e.print(py);
+                    }
+                });
+            })
+        });
+        self.changed_values.set_error_callback(callback);
+    }
+
+    // None disables the log, the values written by python and by the clients are recorded
+    #[pyo3(signature = (capacity=None))]
+    fn set_audit(&self, capacity: Option<usize>) {
+        self.changed_values.audit().set_capacity(capacity);
+    }
+
+    #[pyo3(signature = (since=0))]
+    fn audit_log<'py>(&self, py: Python<'py>, since: u64) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        self.changed_values.audit().to_py(py, since)
+    }
+
+    // the rates are measured since the previous call, the first one since the server was created
+    fn health<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let health = self.server.read().unwrap().health();
+
+        let result = PyDict::new(py);
+        result.set_item("running", health.running)?;
+        result.set_item("connected", health.connected)?;
+        result.set_item("queue_depth", health.queue_depth)?;
+        let last_error = self.changed_values.last_error();
+        result.set_item("last_error", last_error.map(|record| record.message))?;
+        result.set_item("messages_sent_per_sec", health.sent_rate)?;
+        result.set_item("messages_received_per_sec", health.received_rate)?;
+        result.set_item("uptime", health.uptime.map(|uptime| uptime.as_secs_f64()))?;
+        Ok(result)
+    }
+
+    // viewport width and height in points and pixels per point reported by the client
+    fn client_info(&self) -> Option<(f32, f32, f32)> {
+        if !self.connected.load(atomic::Ordering::Relaxed) {
+            return None;
+        }
+        let info = self.server.read().unwrap().client_info();
+        info.map(|[w, h, ppp]| (w, h, ppp))
+    }
+
+    // The flush message is handled by the writer after all previous messages, so the queue is
+    // empty at the time of the confirmation, unless other messages were sent in the meantime.
+    #[pyo3(signature = (timeout=None))]
+    fn wait_idle(&self, py: Python, timeout: Option<f64>) -> bool {
+        if !self.connected.load(atomic::Ordering::Relaxed) {
+            return true;
+        }
+
+        let timeout = timeout.unwrap_or(f64::MAX).clamp(0.0, u32::MAX as f64);
+        let (confirm, rx) = mpsc::channel();
+        self.channel.send(WriteMessage::Flush(confirm)).unwrap();
+        py.allow_threads(move || rx.recv_timeout(Duration::from_secs_f64(timeout)).is_ok())
+    }
+
     fn start(&self) {
         self.server.write().unwrap().start();
     }
@@ -111,6 +636,52 @@ impl StateServerCore {
         self.server.write().unwrap().disconnect_client();
     }
 
+    #[pyo3(signature = (ip_addr, port, socket_path=None))]
+    fn set_address(
+        &self,
+        ip_addr: Option<IpAddress>,
+        port: u16,
+        socket_path: Option<PathBuf>,
+    ) -> PyResult<()> {
+        let addr = server_address(port, ip_addr, socket_path)?;
+        self.server
+            .read()
+            .unwrap()
+            .set_address(addr)
+            .map_err(pyo3::exceptions::PyValueError::new_err)
+    }
+
+    // the reading threads can call python, so the GIL is released while they are joined
+    #[pyo3(signature = (timeout=None))]
+    fn shutdown(&self, py: Python, timeout: Option<f64>) -> bool {
+        let timeout =
+            timeout.map(|timeout| Duration::from_secs_f64(timeout.clamp(0.0, u32::MAX as f64)));
+        py.allow_threads(|| self.server.read().unwrap().shutdown(timeout))
+    }
+
+    // the same as the shutdown, but the messages are written only for the limited time
+    #[pyo3(signature = (timeout=Some(CLOSE_TIMEOUT)))]
+    fn close(&self, py: Python, timeout: Option<f64>) -> bool {
+        self.shutdown(py, timeout)
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf.start();
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &self,
+        py: Python,
+        _exc_type: Option<PyObject>,
+        _exc_value: Option<PyObject>,
+        _traceback: Option<PyObject>,
+    ) -> bool {
+        self.close(py, Some(CLOSE_TIMEOUT));
+        false
+    }
+
     #[pyo3(signature=(duration=None))]
     fn update(&self, duration: Option<f32>) {
         if self.connected.load(atomic::Ordering::Relaxed) {
@@ -119,6 +690,42 @@ impl StateServerCore {
         }
     }
 
+    // groups -----------------------------------------------------------------
+    fn group_ids(&self, group: &str) -> PyResult<Vec<u32>> {
+        match self.values.groups.get(group) {
+            Some(ids) => Ok(ids.clone()),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Group {} is not available.",
+                group
+            ))),
+        }
+    }
+
+    // Only values and static values have the initial value, other members are skipped.
+    fn group_reset(&self, py: Python, group: &str, update: bool) -> PyResult<()> {
+        for id in self.group_ids(group)? {
+            if let Some(value) = self.values.values.get(&id) {
+                value.reset_py(py, update)?;
+            } else if let Some(value) = self.values.static_values.get(&id) {
+                value.reset_py(py, update)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Values are synced after the connection, so there is nothing to do without a client.
+    fn group_sync(&self, group: &str) -> PyResult<()> {
+        let ids = self.group_ids(group)?;
+        if self.connected.load(atomic::Ordering::Relaxed) {
+            for id in ids {
+                if let Some(value) = self.sync.get(&id) {
+                    value.sync(SyncTarget::All);
+                }
+            }
+        }
+        Ok(())
+    }
+
     // signals ----------------------------------------------------------------
     fn value_set_register(&self, value_id: u32, register: bool) {
         if register {
@@ -128,16 +735,25 @@ impl StateServerCore {
         }
     }
 
-    fn value_get_signal<'py>(&self, py: Python<'py>, thread_id: u32) -> (u32, Bound<'py, PyAny>) {
+    // None is returned when the worker was retired
+    fn value_get_signal<'py>(
+        &self,
+        py: Python<'py>,
+        thread_id: u32,
+    ) -> Option<(u32, Bound<'py, PyAny>)> {
         let (value_id, value) = py.allow_threads(|| loop {
-            let res = self.changed_values.wait_changed_value(thread_id);
+            let res = self.changed_values.wait_worker_value(thread_id)?;
             if self.registed_values.read().unwrap().contains(&res.0) {
-                break res;
+                break Some(res);
             }
-        });
+        })?;
         let arg = value.to_python(py);
 
-        (value_id, arg)
+        Some((value_id, arg))
+    }
+
+    fn signals_retire_worker(&self, thread_id: u32) {
+        self.changed_values.retire_worker(thread_id);
     }
 
     fn signal_set(&self, value_id: u32, value: &Bound<PyAny>) -> PyResult<()> {
@@ -167,9 +783,121 @@ impl StateServerCore {
         }
     }
 
+    #[pyo3(signature = (value_id, allowed=None))]
+    fn value_set_allowed(&self, value_id: u32, allowed: Option<&Bound<PyAny>>) -> PyResult<()> {
+        match self.values.values.get(&value_id) {
+            Some(value) => value.set_allowed_py(allowed),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, rate=None))]
+    fn value_set_send_rate(&self, value_id: u32, rate: Option<f64>) -> PyResult<()> {
+        match self.values.values.get(&value_id) {
+            Some(value) => value.set_send_rate_py(rate),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, thresholds=None, hysteresis=0.0))]
+    fn value_set_thresholds(
+        &self,
+        py: Python,
+        value_id: u32,
+        thresholds: Option<Vec<f64>>,
+        hysteresis: f64,
+    ) -> PyResult<()> {
+        match self.values.values.get(&value_id) {
+            Some(value) => value.set_thresholds_py(py, thresholds, hysteresis),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, transform=None))]
+    fn value_set_transform(&self, value_id: u32, transform: Option<PyObject>) -> PyResult<()> {
+        match self.values.values.get(&value_id) {
+            Some(value) => {
+                value.set_transform_py(transform);
+                Ok(())
+            }
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, callback=None))]
+    fn value_on_change(&self, value_id: u32, callback: Option<PyObject>) -> PyResult<()> {
+        match self.values.values.get(&value_id) {
+            Some(value) => {
+                value.set_on_change_py(callback);
+                Ok(())
+            }
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, validator=None))]
+    fn value_set_validator(&self, value_id: u32, validator: Option<PyObject>) -> PyResult<()> {
+        match self.values.values.get(&value_id) {
+            Some(value) => {
+                value.set_validator_py(validator);
+                Ok(())
+            }
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, getter=None, setter=None))]
+    fn value_set_property(
+        &self,
+        value_id: u32,
+        getter: Option<PyObject>,
+        setter: Option<PyObject>,
+    ) -> PyResult<()> {
+        match self.values.values.get(&value_id) {
+            Some(value) => {
+                value.set_property_py(getter, setter);
+                Ok(())
+            }
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
     fn value_get<'py>(&self, py: Python<'py>, value_id: u32) -> PyResult<Bound<'py, PyAny>> {
         match self.values.values.get(&value_id) {
-            Some(getter) => Ok(getter.get_py(py)),
+            Some(getter) => getter.get_py(py),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    // seconds since the value was last sent to the client
+    #[cfg(feature = "sync-time")]
+    fn value_last_sync(&self, value_id: u32) -> PyResult<Option<f64>> {
+        match self.values.values.get(&value_id) {
+            Some(value) => Ok(value.last_sync_py().map(|t| t.elapsed().as_secs_f64())),
             None => Err(pyo3::exceptions::PyValueError::new_err(format!(
                 "Value with id {} is not available.",
                 value_id
@@ -198,6 +926,18 @@ impl StateServerCore {
         }
     }
 
+    // seconds since the static value was last sent to the client
+    #[cfg(feature = "sync-time")]
+    fn static_last_sync(&self, value_id: u32) -> PyResult<Option<f64>> {
+        match self.values.static_values.get(&value_id) {
+            Some(value) => Ok(value.last_sync_py().map(|t| t.elapsed().as_secs_f64())),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Static value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
     // images -----------------------------------------------------------------
     #[pyo3(signature = (value_id, image, update, origin=None))]
     fn image_set(
@@ -231,6 +971,41 @@ impl StateServerCore {
         }
     }
 
+    #[pyo3(signature = (value_id, max_size=None))]
+    fn image_set_preview(&self, value_id: u32, max_size: Option<usize>) -> PyResult<()> {
+        match self.values.images.get(&value_id) {
+            Some(image) => image.set_preview_py(max_size),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Image with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    fn image_resend(&self, value_id: u32, update: bool) -> PyResult<()> {
+        match self.values.images.get(&value_id) {
+            Some(image) => {
+                image.resend_py(update);
+                Ok(())
+            }
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Image with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, fps=None))]
+    fn image_set_max_fps(&self, value_id: u32, fps: Option<f64>) -> PyResult<()> {
+        match self.values.images.get(&value_id) {
+            Some(image) => image.set_max_fps_py(fps),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Image with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
     fn image_size(&self, value_id: u32) -> PyResult<[usize; 2]> {
         match self.values.images.get(&value_id) {
             Some(image) => Ok(image.get_size_py()),
@@ -302,6 +1077,24 @@ impl StateServerCore {
         }
     }
 
+    #[pyo3(signature = (value_id, old, new, update, overwrite=false))]
+    fn dict_item_rename(
+        &self,
+        value_id: u32,
+        old: &Bound<PyAny>,
+        new: &Bound<PyAny>,
+        update: bool,
+        overwrite: bool,
+    ) -> PyResult<()> {
+        match self.values.dicts.get(&value_id) {
+            Some(dict) => dict.rename_item_py(old, new, overwrite, update),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Dict value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
     fn dict_len(&self, value_id: u32) -> PyResult<usize> {
         match self.values.dicts.get(&value_id) {
             Some(dict) => Ok(dict.len_py()),
@@ -384,6 +1177,26 @@ impl StateServerCore {
         }
     }
 
+    fn list_item_move(&self, value_id: u32, from: usize, to: usize, update: bool) -> PyResult<()> {
+        match self.values.lists.get(&value_id) {
+            Some(list) => list.move_item_py(from, to, update),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "List value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    fn list_swap(&self, value_id: u32, i: usize, j: usize, update: bool) -> PyResult<()> {
+        match self.values.lists.get(&value_id) {
+            Some(list) => list.swap_py(i, j, update),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "List value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
     fn list_len(&self, value_id: u32) -> PyResult<usize> {
         match self.values.lists.get(&value_id) {
             Some(list) => Ok(list.len_py()),
@@ -444,6 +1257,82 @@ impl StateServerCore {
         }
     }
 
+    // The source is polled in its own thread. Every interval the next item is taken from the
+    // iterator and added to the graph. The thread stops when the iterator is exhausted, when
+    // the graph is removed, when the server is stopped or closed or when an error occurs.
+    #[pyo3(signature = (value_id, idx, source, interval, update=false))]
+    fn graphs_attach_source(
+        &self,
+        value_id: u32,
+        idx: u16,
+        source: &Bound<PyAny>,
+        interval: f32,
+        update: bool,
+    ) -> PyResult<()> {
+        let graph = match self.values.graphs.get(&value_id) {
+            Some(graph) => graph.clone(),
+            None => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Graph value with id {} is not available.",
+                    value_id
+                )))
+            }
+        };
+        graph.len_py(idx)?;
+
+        if interval.is_nan() || interval <= 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Interval must be a positive number.",
+            ));
+        }
+
+        let source = source.try_iter()?.unbind();
+        let signals = self.changed_values.clone();
+        let interval = Duration::from_secs_f32(interval);
+        let stopped = self.server.read().unwrap().stop_check();
+
+        let source_thread = thread::Builder::new().name(format!("GraphSource_{}", value_id));
+        let spawned = source_thread.spawn(move || loop {
+            thread::sleep(interval);
+
+            // the server was stopped or the graph was removed
+            if stopped() || graph.len_py(idx).is_err() {
+                break;
+            }
+
+            let res = Python::with_gil(|py| -> PyResult<bool> {
+                match source.bind(py).clone().next() {
+                    Some(points) => {
+                        graph.add_points_py(idx, &points?, update)?;
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            });
+
+            match res {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    let error = format!(
+                        "Error in source of graph {} for value id {}: {}",
+                        idx, value_id, e
+                    );
+                    let source = ErrorSource::Callback;
+                    signals.error(Severity::Warning, source, Some(value_id), error);
+                    break;
+                }
+            }
+        });
+
+        spawned.map(|_| ()).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to start the source of graph {} for value id {}: {}",
+                idx, value_id, e
+            ))
+        })
+    }
+
     fn graphs_len(&self, value_id: u32, idx: u16) -> PyResult<usize> {
         match self.values.graphs.get(&value_id) {
             Some(graph) => graph.len_py(idx),
@@ -499,4 +1388,130 @@ impl StateServerCore {
             ))),
         }
     }
+
+    fn graphs_set_compressed(&self, value_id: u32, idx: u16, compressed: bool) -> PyResult<()> {
+        match self.values.graphs.get(&value_id) {
+            Some(graph) => {
+                graph.set_compressed_py(idx, compressed);
+                Ok(())
+            }
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Graph value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, x_idx, axis, update))]
+    fn graphs_set_axis(
+        &self,
+        value_id: u32,
+        x_idx: u16,
+        axis: &Bound<PyAny>,
+        update: bool,
+    ) -> PyResult<()> {
+        match self.values.graphs.get(&value_id) {
+            Some(graph) => graph.set_axis_py(x_idx, axis, update),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Graph value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, x_idx, points, update))]
+    fn graphs_add_axis_points(
+        &self,
+        value_id: u32,
+        x_idx: u16,
+        points: &Bound<PyAny>,
+        update: bool,
+    ) -> PyResult<()> {
+        match self.values.graphs.get(&value_id) {
+            Some(graph) => graph.add_axis_points_py(x_idx, points, update),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Graph value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    fn graphs_remove_axis(&self, value_id: u32, x_idx: u16, update: bool) -> PyResult<()> {
+        match self.values.graphs.get(&value_id) {
+            Some(graph) => graph.remove_axis_py(x_idx, update),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Graph value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    #[pyo3(signature = (value_id, idx, x_idx, graph, update))]
+    fn graphs_set_shared(
+        &self,
+        value_id: u32,
+        idx: u16,
+        x_idx: u16,
+        graph: &Bound<PyAny>,
+        update: bool,
+    ) -> PyResult<()> {
+        match self.values.graphs.get(&value_id) {
+            Some(graph_) => graph_.set_shared_py(idx, x_idx, graph, update),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Graph value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    // bitsets ----------------------------------------------------------------
+    fn bitset_get<'py>(&self, py: Python<'py>, value_id: u32) -> PyResult<Bound<'py, PyList>> {
+        match self.values.bitsets.get(&value_id) {
+            Some(bitset) => Ok(bitset.get_py(py)),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Bitset value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    fn bitset_set(&self, value_id: u32, values: &Bound<PyAny>, update: bool) -> PyResult<()> {
+        match self.values.bitsets.get(&value_id) {
+            Some(bitset) => bitset.set_py(values, update),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Bitset value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    fn bitset_bit_get(&self, value_id: u32, idx: usize) -> PyResult<bool> {
+        match self.values.bitsets.get(&value_id) {
+            Some(bitset) => bitset.get_bit_py(idx),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Bitset value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    fn bitset_bit_set(&self, value_id: u32, idx: usize, value: bool, update: bool) -> PyResult<()> {
+        match self.values.bitsets.get(&value_id) {
+            Some(bitset) => bitset.set_bit_py(idx, value, update),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Bitset value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
+
+    fn bitset_len(&self, value_id: u32) -> PyResult<usize> {
+        match self.values.bitsets.get(&value_id) {
+            Some(bitset) => Ok(bitset.len_py()),
+            None => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Bitset value with id {} is not available.",
+                value_id
+            ))),
+        }
+    }
 }
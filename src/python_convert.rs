@@ -31,12 +31,12 @@ macro_rules! impl_topython_basic {
 
 impl_topython_basic!(i8, i16, i32, i64, u8, u16, u32, u64, f32, f64, bool);
 
-// // None ---------------------------------------------------
-// impl ToPython for () {
-//     fn to_python<'py>(&self, py: Python<'py>) -> Bound<'py, PyAny> {
-//         PyNone::get(py).to_owned().into_any()
-//     }
-// }
+// None ---------------------------------------------------
+impl ToPython for () {
+    fn to_python<'py>(&self, py: Python<'py>) -> Bound<'py, PyAny> {
+        PyNone::get(py).to_owned().into_any()
+    }
+}
 
 // strings ---------------------------------------------------
 impl ToPython for String {
@@ -0,0 +1,133 @@
+use std::any::type_name;
+
+use crate::transport::{
+    TYPE_BITSET, TYPE_DICT, TYPE_GRAPH, TYPE_IMAGE, TYPE_LIST, TYPE_SIGNAL, TYPE_STATIC, TYPE_VALUE,
+};
+use crate::NoHashSet;
+
+// FNV-1a, the layout hash has to be the same for all builds of the client and the server
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// Type name without module paths, because the client and the server can define the same type
+// in different modules. For example "alloc::vec::Vec<my_crate::Data>" is "Vec<Data>".
+// Unit has the same layout as Empty.
+fn short_type_name<T>() -> String {
+    let name = type_name::<T>();
+    if name == "()" {
+        return "Empty".to_string();
+    }
+    let mut parts = name.split("::").peekable();
+    let mut short = String::new();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            short.push_str(part);
+        } else {
+            let end = part
+                .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .map_or(0, |i| i + 1);
+            short.push_str(&part[..end]);
+        }
+    }
+    short
+}
+
+/// How the value with given id is sent on the wire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WireType {
+    /// Type byte in the head of the message.
+    pub type_byte: u8,
+    /// Kind of the value, for example "value" or "graphs".
+    pub kind: &'static str,
+    /// Type of the value or of the elements, empty for images and bitsets.
+    pub element: String,
+    /// Encoding of the data, "postcard", "raw" for the native numbers or "bits" for packed bits.
+    pub encoding: &'static str,
+}
+
+// Registered values in the order of registration, used to compare the client and the server
+// layout without connecting.
+#[derive(Clone)]
+pub(crate) struct Schema {
+    entries: Vec<(u32, &'static str, String)>, // id, kind, type
+}
+
+impl Schema {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add<T>(&mut self, id: u32, kind: &'static str) {
+        self.entries.push((id, kind, short_type_name::<T>()));
+    }
+
+    // types without any parameter, like images and bitsets
+    pub(crate) fn add_untyped(&mut self, id: u32, kind: &'static str) {
+        self.entries.push((id, kind, String::new()));
+    }
+
+    pub(crate) fn wire_type(&self, id: u32) -> Option<WireType> {
+        let (_, kind, element) = self.entries.iter().find(|(i, _, _)| *i == id)?;
+        let (type_byte, encoding) = match *kind {
+            "value" | "value_without_ack" => (TYPE_VALUE, "postcard"),
+            "static" => (TYPE_STATIC, "postcard"),
+            "signal" => (TYPE_SIGNAL, "postcard"),
+            "image" => (TYPE_IMAGE, "raw"),
+            "dict" | "dict_writable" => (TYPE_DICT, "postcard"),
+            "list" | "list_writable" => (TYPE_LIST, "postcard"),
+            "graphs" => (TYPE_GRAPH, "raw"),
+            "bitset" => (TYPE_BITSET, "bits"),
+            _ => unreachable!("unknown value kind {}", kind),
+        };
+
+        Some(WireType {
+            type_byte,
+            kind,
+            element: element.clone(),
+            encoding,
+        })
+    }
+
+    pub(crate) fn validate(&self, version: u64, reserved: u32) -> Result<u64, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut ids = NoHashSet::default();
+        for (id, kind, _) in self.entries.iter() {
+            if !ids.insert(*id) {
+                errors.push(format!("Duplicate id {} of {}", id, kind));
+            }
+            if *id < reserved {
+                errors.push(format!("Id {} of {} is in the reserved range", id, kind));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(self.hash(version, reserved)),
+            false => Err(errors),
+        }
+    }
+
+    // layout hash, the client sends it in the handshake and the server compares it with its own
+    pub(crate) fn hash(&self, version: u64, reserved: u32) -> u64 {
+        let mut hash = FNV_OFFSET;
+        let mut write = |bytes: &[u8]| {
+            for b in bytes {
+                hash ^= *b as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
+
+        write(&version.to_le_bytes());
+        write(&reserved.to_le_bytes());
+        for (id, kind, ty) in self.entries.iter() {
+            write(&id.to_le_bytes());
+            write(kind.as_bytes());
+            write(&[0]);
+            write(ty.as_bytes());
+            write(&[0]);
+        }
+
+        hash
+    }
+}
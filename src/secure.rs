@@ -0,0 +1,320 @@
+use std::io::{self, IoSlice, Read, Write};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::transport::{from_wire_u32, to_wire_u32};
+
+/*
+    Both sides know the same 32 bytes key. After the connection the client sends the magic and
+    its random bytes and the server replies with its random bytes, all in plain. Both sides then
+    confirm the key, the server first, by the sealed empty record with the number which is never
+    used by the data, so the side with a wrong key fails already on the handshake. Then every
+    write to the socket is one or more sealed records: size, random nonce and the encrypted data
+    with the tag. The random bytes of both sides, the direction and the number of the record are
+    authenticated with every record, so the records of other connections or directions and the
+    reordered or dropped records are rejected. The size is read before the record can be
+    authenticated, so the records are limited to a small size and the longer writes are split.
+*/
+const MAGIC: [u8; 4] = *b"EPSK";
+const RANDOM_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+const MAX_PLAIN: usize = 1 << 16;
+const MAX_RECORD: usize = MAX_PLAIN + NONCE_SIZE + TAG_SIZE;
+const CONFIRM: u64 = u64::MAX; // number of the key confirmation record
+
+/// Pre-shared key of the encrypted connection.
+pub type PresharedKey = [u8; 32];
+
+pub(crate) struct Secure {
+    cipher: XChaCha20Poly1305,
+    session: [u8; 2 * RANDOM_SIZE], // random bytes of the client and the server
+    server: bool,
+    written: u64,
+    read: u64,
+    plain: Vec<u8>, // decrypted data of the last record, which were not read yet
+    position: usize,
+}
+
+impl Secure {
+    /// Exchange the random bytes of the connection and confirm the key before any message.
+    pub(crate) fn handshake<S: Read + Write>(
+        stream: &mut S,
+        key: &PresharedKey,
+        server: bool,
+    ) -> io::Result<Self> {
+        let mut session = [0u8; 2 * RANDOM_SIZE];
+        let (client_random, server_random) = session.split_at_mut(RANDOM_SIZE);
+
+        if server {
+            let mut hello = [0u8; MAGIC.len() + RANDOM_SIZE];
+            stream.read_exact(&mut hello)?;
+            if hello[..MAGIC.len()] != MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Client does not use the encrypted connection.",
+                ));
+            }
+            client_random.copy_from_slice(&hello[MAGIC.len()..]);
+            OsRng.fill_bytes(server_random);
+            stream.write_all(server_random)?;
+        } else {
+            OsRng.fill_bytes(client_random);
+            let mut hello = [0u8; MAGIC.len() + RANDOM_SIZE];
+            hello[..MAGIC.len()].copy_from_slice(&MAGIC);
+            hello[MAGIC.len()..].copy_from_slice(client_random);
+            stream.write_all(&hello)?;
+            stream.read_exact(server_random)?;
+        }
+
+        let secure = Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+            session,
+            server,
+            written: 0,
+            read: 0,
+            plain: Vec::new(),
+            position: 0,
+        };
+
+        // the client does not reveal its confirmation to the server with a wrong key
+        if server {
+            stream.write_all(&secure.confirmation()?)?;
+            secure.check_confirmation(stream)?;
+        } else {
+            secure.check_confirmation(stream)?;
+            stream.write_all(&secure.confirmation()?)?;
+        }
+        Ok(secure)
+    }
+
+    fn confirmation(&self) -> io::Result<[u8; NONCE_SIZE + TAG_SIZE]> {
+        let aad = self.associated(self.server, CONFIRM);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let tag = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &[],
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| io::Error::other("Failed to encrypt the data."))?;
+
+        let mut confirmation = [0u8; NONCE_SIZE + TAG_SIZE];
+        confirmation[..NONCE_SIZE].copy_from_slice(&nonce);
+        confirmation[NONCE_SIZE..].copy_from_slice(&tag);
+        Ok(confirmation)
+    }
+
+    fn check_confirmation<R: Read>(&self, stream: &mut R) -> io::Result<()> {
+        let mut confirmation = [0u8; NONCE_SIZE + TAG_SIZE];
+        stream.read_exact(&mut confirmation)?;
+        let (nonce, tag) = confirmation.split_at(NONCE_SIZE);
+        let aad = self.associated(!self.server, CONFIRM);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: tag,
+                    aad: &aad,
+                },
+            )
+            .map(|_| ())
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The other side uses a different key of the encrypted connection.",
+                )
+            })
+    }
+
+    /// Copy of the state for the other half of the cloned stream, it continues with the same
+    /// numbers of the records. Only one of the copies can write and one can read.
+    pub(crate) fn split(&self) -> Self {
+        Self {
+            cipher: self.cipher.clone(),
+            session: self.session,
+            server: self.server,
+            written: self.written,
+            read: self.read,
+            plain: Vec::new(),
+            position: 0,
+        }
+    }
+
+    fn associated(&self, from_server: bool, number: u64) -> [u8; 2 * RANDOM_SIZE + 9] {
+        let mut aad = [0u8; 2 * RANDOM_SIZE + 9];
+        aad[..2 * RANDOM_SIZE].copy_from_slice(&self.session);
+        aad[2 * RANDOM_SIZE] = from_server as u8;
+        aad[2 * RANDOM_SIZE + 1..].copy_from_slice(&number.to_le_bytes());
+        aad
+    }
+
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        stream: &mut W,
+        bufs: &[IoSlice<'_>],
+    ) -> io::Result<usize> {
+        // the rest of the longer data is written by the next call
+        let msg: Vec<u8> = bufs
+            .iter()
+            .flat_map(|buf| buf.iter().copied())
+            .take(MAX_PLAIN)
+            .collect();
+        let aad = self.associated(self.server, self.written);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &msg,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| io::Error::other("Failed to encrypt the data."))?;
+        self.written += 1;
+
+        let size = (NONCE_SIZE + sealed.len()) as u32;
+        let head = to_wire_u32(size);
+        let mut record = [
+            IoSlice::new(&head),
+            IoSlice::new(&nonce),
+            IoSlice::new(&sealed),
+        ];
+        let mut slices = &mut record[..];
+        while !slices.is_empty() {
+            match stream.write_vectored(slices) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut slices, n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg.len())
+    }
+
+    pub(crate) fn read<R: Read>(&mut self, stream: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        // empty records are skipped, zero is returned only for the closed connection
+        while self.position == self.plain.len() {
+            let mut head = [0u8; 4];
+            match stream.read(&mut head[..1])? {
+                0 => return Ok(0),
+                _ => stream.read_exact(&mut head[1..])?,
+            }
+            let size = from_wire_u32(head) as usize;
+            if !(NONCE_SIZE + TAG_SIZE..=MAX_RECORD).contains(&size) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid size of the encrypted record: {}", size),
+                ));
+            }
+
+            let mut record = vec![0u8; size];
+            stream.read_exact(&mut record)?;
+            let (nonce, sealed) = record.split_at(NONCE_SIZE);
+            let aad = self.associated(!self.server, self.read);
+            self.plain = self
+                .cipher
+                .decrypt(
+                    XNonce::from_slice(nonce),
+                    Payload {
+                        msg: sealed,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Failed to decrypt the record, the key is wrong or the data are corrupted.",
+                    )
+                })?;
+            self.position = 0;
+            self.read += 1;
+        }
+
+        let n = buf.len().min(self.plain.len() - self.position);
+        buf[..n].copy_from_slice(&self.plain[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+
+    fn handshake(server_key: PresharedKey, client_key: PresharedKey) -> [io::Result<Secure>; 2] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            Secure::handshake(&mut stream, &client_key, false).map(|s| (s, stream))
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let server = Secure::handshake(&mut stream, &server_key, true);
+        drop(stream);
+        [server, client.join().unwrap().map(|(s, _)| s)]
+    }
+
+    #[test]
+    fn wrong_key_fails_on_handshake() {
+        let [server, client] = handshake([1; 32], [2; 32]);
+        assert_eq!(client.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert!(server.is_err());
+
+        let [server, client] = handshake([1; 32], [1; 32]);
+        assert!(server.is_ok() && client.is_ok());
+    }
+
+    #[test]
+    fn long_write_is_split_to_records() {
+        let mut sender = Secure::handshake_state([3; 32], true);
+        let mut receiver = Secure::handshake_state([3; 32], false);
+
+        let data: Vec<u8> = (0..3 * MAX_PLAIN + 5).map(|i| i as u8).collect();
+        let mut wire = Vec::new();
+        let mut written = 0;
+        while written < data.len() {
+            let n = sender
+                .write(&mut wire, &[IoSlice::new(&data[written..])])
+                .unwrap();
+            assert!(n <= MAX_PLAIN);
+            written += n;
+        }
+
+        let mut wire = &wire[..];
+        let mut received = vec![0u8; data.len()];
+        receiver.read_exact_from(&mut wire, &mut received);
+        assert_eq!(received, data);
+        assert!(wire.is_empty());
+    }
+
+    impl Secure {
+        fn handshake_state(key: PresharedKey, server: bool) -> Self {
+            Self {
+                cipher: XChaCha20Poly1305::new(&key.into()),
+                session: [7; 2 * RANDOM_SIZE],
+                server,
+                written: 0,
+                read: 0,
+                plain: Vec::new(),
+                position: 0,
+            }
+        }
+
+        fn read_exact_from(&mut self, stream: &mut &[u8], buf: &mut [u8]) {
+            let mut position = 0;
+            while position < buf.len() {
+                position += self.read(stream, &mut buf[position..]).unwrap();
+            }
+        }
+    }
+}
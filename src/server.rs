@@ -1,181 +1,1604 @@
-use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::AtomicBool;
 use std::sync::{
     atomic,
-    mpsc::{Receiver, Sender},
-    Arc,
+    mpsc::{self, Receiver, Sender},
+    Arc, Condvar, Mutex, RwLock,
 };
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::commands::CommandMessage;
+use crate::commands::{ClientInfo, CommandMessage, RoundTrip};
+use crate::errors::{ErrorSource, Severity};
 use crate::event::Event;
+#[cfg(feature = "encryption")]
+use crate::secure::PresharedKey;
+#[cfg(feature = "shared-memory")]
+use crate::shared::server::SharedImages;
 use crate::signals::ChangedValues;
+use crate::socket::{Address, Listener, Stream, TcpOptions};
 use crate::states_server::ValuesList;
-use crate::transport::{read_message, write_message, ReadMessage, WriteMessage};
+use crate::stats::{MessageStats, Stats};
+use crate::transport::{
+    heartbeat_timeout, is_timeout, oversized, read_handshake, read_message, reply_rejection,
+    write_message, Extensions, ReadMessage, WriteMessage, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use crate::{NoHashMap, NoHashSet};
+
+// Hashes of clients allowed to connect. Clients with read only hashes can not write any values.
+// The layout hash of the registered values has to be the same for all clients.
+#[derive(Clone)]
+pub(crate) struct HandshakeRules {
+    pub(crate) hashes: Option<Vec<u64>>,
+    pub(crate) read_only: Vec<u64>,
+    pub(crate) layout: u64,
+}
+
+impl HandshakeRules {
+    // returns if the client is allowed to write values
+    fn check(&self, hash: u64) -> Result<bool, String> {
+        if self.read_only.contains(&hash) {
+            return Ok(false);
+        }
+
+        match self.hashes {
+            Some(ref hashes) if !hashes.contains(&hash) => {
+                Err("Attempted to connect with wrong hash".to_string())
+            }
+            _ => Ok(true),
+        }
+    }
+
+    // the callback replaces the allowed hashes, the read only hashes still can not write values
+    fn authenticate(
+        &self,
+        callback: &AuthCallback,
+        hash: u64,
+        token: Option<&str>,
+        addr: Option<SocketAddr>,
+    ) -> Result<bool, String> {
+        callback(hash, token, addr)?;
+        Ok(!self.read_only.contains(&hash))
+    }
+}
+
+// the version is checked first, the client is authenticated only with the matching values
+fn check_handshake(
+    rules: &HandshakeRules,
+    reserved: u32,
+    client_reserved: u32,
+    layout: u64,
+) -> Result<(), String> {
+    if client_reserved != reserved {
+        return Err(format!("Attempted to connect with different number of reserved ids: {}, {} reserved ids are required.", client_reserved, reserved));
+    }
+
+    if layout != rules.layout {
+        return Err(format!("Attempted to connect with different values: layout hash {:#018x}, {:#018x} is required. The client and the server register different ids or types.", layout, rules.layout));
+    }
+
+    Ok(())
+}
+
+fn reject(stream: &mut Stream, error: &str, stats: &Stats) {
+    let rejected = WriteMessage::Command(CommandMessage::HandshakeRejected(error.to_string()));
+    let _ = write_message(rejected, stream, Extensions::default(), stats);
+}
+
+// The client has to send the handshake in this time after the connection.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Called with the hash, the auth token and the address of the client, the error is the reason
+// of the rejection.
+pub(crate) type AuthCallback =
+    Box<dyn Fn(u64, Option<&str>, Option<SocketAddr>) -> Result<(), String> + Send + Sync>;
+
+// Called with the address of the client and the last error, if the connection was not
+// closed cleanly.
+pub(crate) type DisconnectCallback = Box<dyn Fn(Option<SocketAddr>, Option<String>) + Send + Sync>;
+
+// State of the connected client shared by the server and the transfer threads.
+#[derive(Clone)]
+struct PeerState {
+    round_trip: RoundTrip,
+    client_info: ClientInfo,
+    on_disconnect: Arc<RwLock<Option<DisconnectCallback>>>,
+    authenticator: Arc<RwLock<Option<AuthCallback>>>,
+    heartbeat: Arc<RwLock<Option<Duration>>>, // applied to the next connection
+    tcp_options: Arc<RwLock<TcpOptions>>,     // applied to the next connection
+    max_message_size: Arc<RwLock<usize>>,     // applied to the next connection
+    queue_limit: Arc<RwLock<usize>>,          // applied to the next connection
+    broadcast: Arc<AtomicBool>,               // applied to the next connection
+    stats: Stats,
+    #[cfg(feature = "encryption")]
+    psk: Arc<RwLock<Option<PresharedKey>>>, // applied to the next connection
+}
+
+impl PeerState {
+    fn new() -> Self {
+        Self {
+            round_trip: RoundTrip::new(),
+            client_info: ClientInfo::new(),
+            on_disconnect: Arc::new(RwLock::new(None)),
+            authenticator: Arc::new(RwLock::new(None)),
+            heartbeat: Arc::new(RwLock::new(None)),
+            tcp_options: Arc::new(RwLock::new(TcpOptions::default())),
+            max_message_size: Arc::new(RwLock::new(DEFAULT_MAX_MESSAGE_SIZE)),
+            queue_limit: Arc::new(RwLock::new(DEFAULT_QUEUE_LIMIT)),
+            broadcast: Arc::new(AtomicBool::new(false)),
+            stats: Stats::new(true),
+            #[cfg(feature = "encryption")]
+            psk: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    // values of the previous client are not valid anymore
+    fn clear(&self) {
+        self.round_trip.clear();
+        self.client_info.clear();
+    }
+
+    // The callback runs in its own thread, so it can not block the accepting of the next client.
+    fn disconnected(
+        &self,
+        addr: Option<SocketAddr>,
+        error: Option<String>,
+        signals: &ChangedValues,
+    ) {
+        if self.on_disconnect.read().unwrap().is_none() {
+            return;
+        }
+
+        let on_disconnect = self.on_disconnect.clone();
+        let res = thread::Builder::new()
+            .name("Disconnect".to_string())
+            .spawn(move || {
+                if let Some(ref callback) = *on_disconnect.read().unwrap() {
+                    callback(addr, error);
+                }
+            });
+        if let Err(e) = res {
+            let error = format!("Error starting disconnect callback: {:?}", e);
+            signals.error(Severity::Error, ErrorSource::Callback, None, error);
+        }
+    }
+}
+
+/// Limit of the messages of one value written to the client, per second. The messages above
+/// the limit wait in the writer, they are not dropped, and one second of the traffic can be
+/// written at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimit {
+    /// Maximal number of messages per second.
+    Messages(f64),
+    /// Maximal size of the message data in bytes per second.
+    Bytes(f64),
+}
+
+impl RateLimit {
+    pub(crate) fn rate(&self) -> f64 {
+        match *self {
+            RateLimit::Messages(rate) | RateLimit::Bytes(rate) => rate,
+        }
+    }
+}
+
+// Token bucket of one value. The tokens can go negative, so also the messages larger than
+// the limit are written, the next one waits until the debt is paid.
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.rate(),
+            last: Instant::now(),
+        }
+    }
+
+    // time until the next message can be written
+    fn wait(&mut self, now: Instant) -> Duration {
+        let rate = self.limit.rate();
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last = now;
+        match self.tokens > 0.0 {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64((f64::EPSILON - self.tokens) / rate),
+        }
+    }
+
+    fn take(&mut self, size: usize) {
+        self.tokens -= match self.limit {
+            RateLimit::Messages(_) => 1.0,
+            RateLimit::Bytes(_) => size as f64,
+        };
+    }
+}
+
+// messages of the value with the rate limit, which wait for the tokens
+struct Limited {
+    bucket: Bucket,
+    held: VecDeque<WriteMessage>,
+}
+
+// Messages with at least this size of the data are queued to the bulk lane.
+const BULK_SIZE: usize = 16 * 1024;
+// Default limit of the queued data of the images and graphs.
+const DEFAULT_QUEUE_LIMIT: usize = 64 * 1024 * 1024;
+// Number of control messages written in a row while the bulk messages are waiting.
+const CONTROL_RUN: usize = 32;
+
+// id and size of the data of the messages with the value data
+fn data_size(message: &WriteMessage) -> Option<(u32, usize)> {
+    match message {
+        WriteMessage::Value(id, _, data)
+        | WriteMessage::Static(id, _, data)
+        | WriteMessage::Dict(id, _, data)
+        | WriteMessage::List(id, _, data)
+        | WriteMessage::Bitset(id, _, data) => Some((*id, data.as_slice().len())),
+        WriteMessage::Image(id, _, info, data, _) => {
+            Some((*id, info.as_slice().len() + data.len()))
+        }
+        WriteMessage::Graph(id, _, data, graph_data) => {
+            let graph_size = graph_data.as_ref().map_or(0, |data| data.len());
+            Some((*id, data.as_slice().len() + graph_size))
+        }
+        _ => None,
+    }
+}
+
+// the message left the bulk lane
+fn unbulk(bulk_ids: &mut NoHashMap<u32, usize>, message: &WriteMessage) {
+    if let Some((id, _)) = data_size(message) {
+        if let Some(count) = bulk_ids.get_mut(&id) {
+            *count -= 1;
+            if *count == 0 {
+                bulk_ids.remove(&id);
+            }
+        }
+    }
+}
+
+// size of the data of the messages, which can be dropped from the full queue
+fn droppable_size(message: &WriteMessage) -> Option<usize> {
+    match message {
+        WriteMessage::Image(..) | WriteMessage::Graph(..) => {
+            data_size(message).map(|(_, size)| size)
+        }
+        _ => None,
+    }
+}
+
+// The writer takes all waiting messages from the channel and prefers the small ones, so the value
+// updates are not waiting for the images queued before them. The messages of each lane keep
+// their order and the messages of the value with a queued bulk message follow it to the bulk
+// lane, so a small change is not applied before the large data it builds on. Flush and
+// Terminate wait until everything received before them is written.
+// Messages of the values with the rate limit wait for the tokens in their own queue.
+// If the data of the queued images and graphs exceed the limit, the replaced image frames are
+// dropped first, then all queued messages of the oldest image or graph. The client can not apply
+// the later changes of such value, so they are dropped too until the value is synchronized again.
+struct Lanes {
+    control: VecDeque<WriteMessage>,
+    bulk: VecDeque<WriteMessage>,
+    bulk_ids: NoHashMap<u32, usize>, // number of the queued bulk messages of the values
+    barrier: Option<WriteMessage>,
+    control_run: usize,
+    limited: NoHashMap<u32, Limited>,
+    heartbeat: Option<Duration>,
+    limit: usize,
+    queued: usize,         // data size of the queued images and graphs
+    dirty: NoHashSet<u32>, // values with the dropped messages, which wait for the resync
+    closed: bool,          // the writer stopped, the messages are not queued anymore
+}
+
+impl Lanes {
+    fn new(limits: &NoHashMap<u32, RateLimit>, heartbeat: Option<Duration>, limit: usize) -> Self {
+        let limited = limits
+            .iter()
+            .map(|(id, limit)| {
+                let limited = Limited {
+                    bucket: Bucket::new(*limit),
+                    held: VecDeque::new(),
+                };
+                (*id, limited)
+            })
+            .collect();
+
+        Self {
+            control: VecDeque::new(),
+            bulk: VecDeque::new(),
+            bulk_ids: NoHashMap::default(),
+            barrier: None,
+            control_run: 0,
+            limited,
+            heartbeat,
+            limit,
+            queued: 0,
+            dirty: NoHashSet::default(),
+            closed: false,
+        }
+    }
+
+    // messages taken from the channel, which were not written yet
+    fn len(&self) -> usize {
+        let held: usize = self
+            .limited
+            .values()
+            .map(|limited| limited.held.len())
+            .sum();
+        self.control.len() + self.bulk.len() + held + self.barrier.is_some() as usize
+    }
+
+    // Returns the number of the dropped messages. The newest message is queued also over
+    // the limit, so the image larger than the limit is written, unless its value waits
+    // for the resync.
+    fn push(&mut self, message: WriteMessage) -> usize {
+        let data = data_size(&message);
+        let dirty = |lanes: &Self| data.is_some_and(|(id, _)| lanes.dirty.contains(&id));
+        if dirty(self) {
+            message.recycle();
+            return 1;
+        }
+
+        let mut dropped = 0;
+        if let Some(size) = droppable_size(&message) {
+            while self.queued + size > self.limit {
+                match self.drop_oldest() {
+                    0 => break,
+                    n => dropped += n,
+                }
+            }
+            if dirty(self) {
+                message.recycle();
+                return dropped + 1;
+            }
+            self.queued += size;
+        }
+
+        let bulk = match message {
+            WriteMessage::Flush(_) | WriteMessage::Terminate => {
+                self.barrier = Some(message);
+                return dropped;
+            }
+            // the client repaints after the data queued before the update
+            WriteMessage::Command(CommandMessage::Update(_)) => !self.bulk.is_empty(),
+            _ if data.is_some_and(|(id, _)| self.bulk_ids.contains_key(&id)) => true,
+            WriteMessage::Image(..)
+            | WriteMessage::Graph(..)
+            | WriteMessage::Dict(..)
+            | WriteMessage::List(..) => data.unwrap().1 >= BULK_SIZE,
+            _ => false,
+        };
+
+        match bulk {
+            true => {
+                if let Some((id, _)) = data {
+                    *self.bulk_ids.entry(id).or_default() += 1;
+                }
+                self.bulk.push_back(message);
+            }
+            false => self.control.push_back(message),
+        }
+        dropped
+    }
+
+    // Returns the number of the dropped messages. The large messages are in the bulk lane,
+    // so it is searched first.
+    fn drop_oldest(&mut self) -> usize {
+        if self.drop_stale() {
+            return 1;
+        }
+
+        let held = self.limited.values().map(|limited| &limited.held);
+        let oldest = [&self.bulk, &self.control]
+            .into_iter()
+            .chain(held)
+            .find_map(|lane| lane.iter().find(|m| droppable_size(m).is_some()));
+        match oldest.and_then(data_size) {
+            Some((id, _)) => {
+                self.dirty.insert(id);
+                self.discard(id)
+            }
+            None => 0,
+        }
+    }
+
+    // the image frame replaced by the newer frame is dropped without the resync
+    fn drop_stale(&mut self) -> bool {
+        let stale = |m: &WriteMessage| matches!(m, WriteMessage::Image(_, _, _, _, generation) if generation.is_stale());
+        let held = self.limited.values_mut().map(|limited| &mut limited.held);
+        for (n, lane) in [&mut self.bulk, &mut self.control]
+            .into_iter()
+            .chain(held)
+            .enumerate()
+        {
+            if let Some(i) = lane.iter().position(stale) {
+                let message = lane.remove(i).unwrap();
+                self.queued -= droppable_size(&message).unwrap();
+                if n == 0 {
+                    unbulk(&mut self.bulk_ids, &message);
+                }
+                message.recycle();
+                return true;
+            }
+        }
+        false
+    }
+
+    // drops all queued messages of the value, returns their number
+    fn discard(&mut self, id: u32) -> usize {
+        let mut dropped = 0;
+        let held = self.limited.values_mut().map(|limited| &mut limited.held);
+        for lane in [&mut self.bulk, &mut self.control].into_iter().chain(held) {
+            let mut kept = VecDeque::with_capacity(lane.len());
+            for message in lane.drain(..) {
+                match data_size(&message) {
+                    Some((message_id, _)) if message_id == id => {
+                        self.queued -= droppable_size(&message).unwrap_or_default();
+                        message.recycle();
+                        dropped += 1;
+                    }
+                    _ => kept.push_back(message),
+                }
+            }
+            *lane = kept;
+        }
+        self.bulk_ids.remove(&id);
+        dropped
+    }
+
+    // The values with the dropped messages are synchronized again, when the queue has enough
+    // space for them.
+    fn take_resync(&mut self) -> Vec<u32> {
+        if self.closed || self.queued > self.limit / 2 {
+            return Vec::new();
+        }
+        self.dirty.drain().collect()
+    }
+
+    // the message leaves the queue
+    fn taken(&mut self, message: WriteMessage) -> WriteMessage {
+        if let Some(size) = droppable_size(&message) {
+            self.queued -= size;
+        }
+        message
+    }
+
+    // the writer stopped, the rest of the queue is not written
+    fn close(&mut self) {
+        self.closed = true;
+        self.barrier = None;
+        let held = self
+            .limited
+            .values_mut()
+            .flat_map(|limited| limited.held.drain(..));
+        for message in self
+            .control
+            .drain(..)
+            .chain(self.bulk.drain(..))
+            .chain(held)
+        {
+            message.recycle();
+        }
+        self.bulk_ids.clear();
+        self.dirty.clear();
+        self.queued = 0;
+    }
+
+    fn pop(&mut self) -> Option<WriteMessage> {
+        let bulk_first = self.control_run >= CONTROL_RUN || self.control.is_empty();
+        if !bulk_first {
+            self.control_run += 1;
+            return self.control.pop_front();
+        }
+        if let Some(message) = self.bulk.pop_front() {
+            self.control_run = 0;
+            unbulk(&mut self.bulk_ids, &message);
+            return Some(message);
+        }
+        self.control.pop_front()
+    }
 
+    // Returns the message if it can be written now, otherwise it waits for the tokens.
+    fn admit(&mut self, message: WriteMessage, now: Instant) -> Option<WriteMessage> {
+        let (id, size) = match data_size(&message) {
+            Some(data) => data,
+            None => return Some(message), // commands are never limited
+        };
+        let limited = match self.limited.get_mut(&id) {
+            Some(limited) => limited,
+            None => return Some(message),
+        };
+
+        if limited.held.is_empty() && limited.bucket.wait(now).is_zero() {
+            limited.bucket.take(size);
+            Some(message)
+        } else {
+            limited.held.push_back(message);
+            None
+        }
+    }
+
+    // The first waiting message which can be written now, otherwise the time until the next one.
+    fn release(&mut self, now: Instant) -> Result<WriteMessage, Option<Duration>> {
+        let mut wait: Option<Duration> = None;
+        for limited in self.limited.values_mut() {
+            // the stale image frames would only waste the tokens
+            while let Some(WriteMessage::Image(_, _, _, _, ref generation)) = limited.held.front() {
+                if !generation.is_stale() {
+                    break;
+                }
+                let message = limited.held.pop_front().unwrap();
+                self.queued -= droppable_size(&message).unwrap();
+                message.recycle();
+            }
+
+            let size = match limited.held.front().and_then(data_size) {
+                Some((_, size)) => size,
+                None => continue,
+            };
+            let until = limited.bucket.wait(now);
+            if until.is_zero() {
+                limited.bucket.take(size);
+                return Ok(limited.held.pop_front().unwrap());
+            }
+            wait = Some(wait.map_or(until, |wait| wait.min(until)));
+        }
+        Err(wait)
+    }
+}
+
+// The intake thread moves the messages from the channel to the lanes also while the writer is
+// blocked by the slow client, so the full queue drops the images and graphs instead of growing
+// without the limit. Values and commands are never dropped. The writer requests the resync of
+// the values with the dropped messages.
+struct Queue {
+    lanes: Mutex<Lanes>,
+    changed: Condvar,
+    round_trip: RoundTrip, // the heartbeat pings are measured too
+    resync: Box<dyn Fn(u32) + Send + Sync>,
+}
+
+impl Queue {
+    fn new(
+        lanes: Lanes,
+        round_trip: RoundTrip,
+        resync: Box<dyn Fn(u32) + Send + Sync>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            lanes: Mutex::new(lanes),
+            changed: Condvar::new(),
+            round_trip,
+            resync,
+        })
+    }
+
+    // The reader sends the terminate at the end of every connection, so the intake stops also
+    // if the writer stopped before.
+    fn intake(self: &Arc<Self>, rx: Receiver<WriteMessage>, stats: Stats) -> JoinHandle<()> {
+        let queue = self.clone();
+        let thread = thread::Builder::new().name("Intake".to_string());
+        thread
+            .spawn(move || {
+                while let Ok(message) = rx.recv() {
+                    let terminate = matches!(message, WriteMessage::Terminate);
+                    let mut lanes = queue.lanes.lock().unwrap();
+                    // the messages after the flush wait until everything before it is written
+                    while lanes.barrier.is_some() && !lanes.closed {
+                        lanes = queue.changed.wait(lanes).unwrap();
+                    }
+                    if !lanes.closed {
+                        stats.dropped(lanes.push(message));
+                        queue.changed.notify_all();
+                    }
+                    drop(lanes);
+
+                    if terminate {
+                        break;
+                    }
+                }
+            })
+            .unwrap()
+    }
+
+    fn len(&self) -> usize {
+        self.lanes.lock().unwrap().len()
+    }
+
+    fn close(&self) {
+        self.lanes.lock().unwrap().close();
+        self.changed.notify_all();
+    }
+
+    fn next(&self) -> WriteMessage {
+        let mut lanes = self.lanes.lock().unwrap();
+        let heartbeat = lanes.heartbeat;
+        let idle = Instant::now();
+        loop {
+            // the resync goes through the hub, so the lanes are not locked
+            let resync = lanes.take_resync();
+            if !resync.is_empty() {
+                drop(lanes);
+                for id in resync {
+                    (self.resync)(id);
+                }
+                lanes = self.lanes.lock().unwrap();
+                continue;
+            }
+
+            let now = Instant::now();
+            let held = match lanes.release(now) {
+                Ok(message) => return lanes.taken(message),
+                Err(held) => held,
+            };
+            if let Some(message) = lanes.pop() {
+                match lanes.admit(message, now) {
+                    Some(message) => return lanes.taken(message),
+                    None => continue,
+                }
+            }
+            if held.is_none() {
+                if let Some(message) = lanes.barrier.take() {
+                    self.changed.notify_all();
+                    return message;
+                }
+            }
+
+            // nothing to write, wait for the next message, the held message or the heartbeat
+            lanes.control_run = 0;
+            let ping = heartbeat.map(|interval| interval.saturating_sub(idle.elapsed()));
+            let timeout = match (held, ping) {
+                (Some(held), Some(ping)) => Some(held.min(ping)),
+                (held, ping) => held.or(ping),
+            };
+            lanes = match timeout {
+                Some(timeout) => {
+                    let (lanes, res) = self.changed.wait_timeout(lanes, timeout).unwrap();
+                    if res.timed_out() && heartbeat.is_some_and(|i| idle.elapsed() >= i) {
+                        return WriteMessage::Command(self.round_trip.ping());
+                    }
+                    lanes
+                }
+                None => self.changed.wait(lanes).unwrap(),
+            };
+        }
+    }
+}
+
+// channel of one connected client
+struct ClientChannel {
+    id: usize,
+    channel: Sender<WriteMessage>,
+    subscription: Option<NoHashSet<u32>>, // None receives all values
+}
+
+fn subscribed(subscription: &Option<NoHashSet<u32>>, id: u32) -> bool {
+    subscription.as_ref().is_none_or(|ids| ids.contains(&id))
+}
+
+/*
+    All messages of the values go to the hub, which sends them to every connected client, so
+    several UIs can watch the same server. The value set by one client is applied and relayed
+    to the other clients through the hub, so it keeps the order with the messages of the server.
+    The acknowledges are counted for every client, so the writes of one client are ignored until
+    it acknowledged the latest value of the server, whatever the other clients do.
+*/
+#[derive(Clone)]
+struct Clients {
+    list: Arc<Mutex<Vec<ClientChannel>>>,
+    connected: Arc<AtomicBool>, // at least one client is connected
+    hub: Sender<WriteMessage>,
+}
+
+impl Clients {
+    fn start(
+        rx: Receiver<WriteMessage>,
+        hub: Sender<WriteMessage>,
+        connected: Arc<AtomicBool>,
+    ) -> Self {
+        let clients = Self {
+            list: Arc::new(Mutex::new(Vec::new())),
+            connected,
+            hub,
+        };
+
+        let hub_clients = clients.clone();
+        let thread = thread::Builder::new().name("Hub".to_string());
+        thread
+            .spawn(move || {
+                while let Ok(message) = rx.recv() {
+                    hub_clients.send(message);
+                }
+            })
+            .unwrap();
+        clients
+    }
+
+    fn add(&self, id: usize, channel: Sender<WriteMessage>) {
+        self.list.lock().unwrap().push(ClientChannel {
+            id,
+            channel,
+            subscription: None,
+        });
+        self.connected.store(true, atomic::Ordering::Relaxed);
+    }
+
+    fn remove(&self, id: usize) {
+        let mut list = self.list.lock().unwrap();
+        list.retain(|client| client.id != id);
+        if list.is_empty() {
+            self.connected.store(false, atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.list.lock().unwrap().len()
+    }
+
+    // returns the previous subscription of the client
+    fn subscribe(&self, id: usize, subscription: Option<NoHashSet<u32>>) -> Option<NoHashSet<u32>> {
+        let mut list = self.list.lock().unwrap();
+        match list.iter_mut().find(|client| client.id == id) {
+            Some(client) => std::mem::replace(&mut client.subscription, subscription),
+            None => subscription,
+        }
+    }
+
+    // the value set by the client is sent to all other clients
+    fn relay(&self, origin: usize, message: WriteMessage) {
+        let relay = WriteMessage::Relay(origin, Box::new(message));
+        self.hub.send(relay).unwrap();
+    }
+
+    fn send(&self, message: WriteMessage) {
+        let list = self.list.lock().unwrap();
+        let (origin, target, message) = match message {
+            WriteMessage::Relay(origin, message) => (Some(origin), None, *message),
+            WriteMessage::Direct(target, message) => (None, Some(target), *message),
+            message => (None, None, message),
+        };
+        // only the messages of the values are filtered, the commands go to all clients
+        let value_id = data_size(&message).map(|(id, _)| id);
+        let mut targets: Vec<&ClientChannel> = list
+            .iter()
+            .filter(|client| Some(client.id) != origin)
+            .filter(|client| target.is_none_or(|target| client.id == target))
+            .filter(|client| value_id.is_none_or(|id| subscribed(&client.subscription, id)))
+            .collect();
+
+        let last = match targets.pop() {
+            Some(last) => last,
+            None => {
+                message.recycle();
+                return;
+            }
+        };
+
+        // every client confirms the flush, the waiting side gets one confirmation for all
+        if let WriteMessage::Flush(confirm) = message {
+            if targets.is_empty() {
+                let _ = last.channel.send(WriteMessage::Flush(confirm));
+                return;
+            }
+            let (flushed, rx) = mpsc::channel();
+            for client in targets.iter().chain([&last]) {
+                let _ = client.channel.send(WriteMessage::Flush(flushed.clone()));
+            }
+            drop(flushed);
+            let count = targets.len() + 1;
+            let thread = thread::Builder::new().name("Flush".to_string());
+            let _ = thread.spawn(move || {
+                if rx.iter().take(count).count() == count {
+                    let _ = confirm.send(());
+                }
+            });
+            return;
+        }
+
+        for client in targets {
+            let _ = client.channel.send(message.duplicate());
+        }
+        let _ = last.channel.send(message);
+    }
+}
+
+// one connected client, its own channel gets the messages from the hub and the replies
+struct ClientLink {
+    id: usize,
+    alive: Arc<AtomicBool>,
+    channel: Sender<WriteMessage>,
+    clients: Clients,
+    relay: bool, // the values set by the client are relayed to the other clients
+    write_allowed: bool,
+    extensions: Extensions,
+}
+
+// the panic of the connection thread is reported, the server continues listening
+fn panic_error(thread: &str, panic: Box<dyn Any + Send>, signals: &ChangedValues) -> String {
+    let message = match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    };
+    let error = format!("{} thread panicked: {}", thread, message);
+    signals.error(Severity::Error, ErrorSource::Transport, None, error.clone());
+    error
+}
+
+/*
+    Every connected client has its own reader and writer thread, so each client adds two threads.
+    The loops are blocking on purpose. The values write into the std channels from python while
+    the GIL is held, and the encrypted, unix and shared memory streams are blocking too, so the
+    async loops would still need threads to forward them. The server is made for a few UIs of one
+    application, not for hundreds of clients, where the async runtime would pay off.
+*/
 struct StatesTransfer {
-    thread: JoinHandle<Receiver<WriteMessage>>,
+    thread: JoinHandle<()>,
+    alive: Arc<AtomicBool>,
+    channel: Sender<WriteMessage>,
+    stream: Stream, // to close the connection also if the writer is blocked
 }
 
 impl StatesTransfer {
     fn start(
-        connected: Arc<AtomicBool>,
+        link: ClientLink,
         values: ValuesList,
         signals: ChangedValues,
-        mut stream: TcpStream,
+        mut stream: Stream,
         rx: Receiver<WriteMessage>,
-        channel: Sender<WriteMessage>,
+        peer: PeerState,
     ) -> Self {
+        let ClientLink {
+            id: client_id,
+            alive: connected,
+            channel,
+            clients,
+            relay,
+            write_allowed,
+            extensions,
+        } = link;
+        let transfer_alive = connected.clone();
+        let transfer_channel = channel.clone();
+        let transfer_stream = stream.try_clone().unwrap();
+        let addr = stream.peer_addr();
+        let heartbeat = *peer.heartbeat.read().unwrap();
+        let max_message_size = *peer.max_message_size.read().unwrap();
+        let queue_limit = *peer.queue_limit.read().unwrap();
+        // also clears the timeout of the handshake
+        let timeout = heartbeat_timeout(heartbeat);
+        if let Err(e) = stream.set_read_timeout(timeout) {
+            let error = format!("Error setting heartbeat timeout: {:?}", e);
+            signals.error(Severity::Warning, ErrorSource::Connection, None, error);
+        }
+        let sync = values.sync.clone();
+        let resync = move |id| {
+            if let Some(value) = sync.get(&id) {
+                value.sync(SyncTarget::Client(client_id));
+            }
+        };
+        let lanes = Lanes::new(&values.limits, heartbeat, queue_limit);
         let writer = Self::writer(
             rx,
             connected.clone(),
             stream.try_clone().unwrap(),
             signals.clone(),
+            extensions,
+            Queue::new(lanes, peer.round_trip.clone(), Box::new(resync)),
+            peer.stats.clone(),
         );
 
         let read_thread = thread::Builder::new().name("Reader".to_string());
         let thread = read_thread
             .spawn(move || {
-                loop {
-                    // read the message
-                    let res = read_message(&mut stream);
+                // the panic of the reader closes the connection as any other error
+                let reading = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut last_error = None;
+                    loop {
+                        // read the message
+                        let res = read_message(&mut stream, max_message_size, &peer.stats);
 
-                    // check if not connected
-                    if !connected.load(atomic::Ordering::Relaxed) {
-                        let _ = stream.shutdown(std::net::Shutdown::Both);
-                        break;
-                    }
+                        // check if the client was disconnected
+                        if !connected.load(atomic::Ordering::Relaxed) {
+                            let _ = stream.shutdown(std::net::Shutdown::Both);
+                            break;
+                        }
 
-                    if let Err(e) = res {
-                        let error = format!("Error reading message: {:?}", e);
-                        signals.set(0, error);
-                        connected.store(false, atomic::Ordering::Relaxed);
-                        break;
-                    }
-                    let message = res.unwrap();
-
-                    // process posible command message
-                    if let ReadMessage::Command(command) = message {
-                        match command {
-                            CommandMessage::Ack(v) => {
-                                let val_res = values.ack.get(&v);
-                                match val_res {
-                                    Some(val) => val.acknowledge(),
+                        if let Err(e) = res {
+                            // the oversized message was skipped, the client is told about it
+                            if let Some(error) = oversized(&e) {
+                                let error = format!("Message was rejected: {}", error);
+                                signals.error(
+                                    Severity::Warning,
+                                    ErrorSource::Transport,
+                                    None,
+                                    error.clone(),
+                                );
+                                let command = WriteMessage::Command(CommandMessage::Error(error));
+                                let _ = channel.send(command);
+                                continue;
+                            }
+                            let error = match timeout {
+                                Some(timeout) if is_timeout(&e) => {
+                                    format!(
+                                        "Connection timed out, nothing received for {:?}",
+                                        timeout
+                                    )
+                                }
+                                _ => format!("Error reading message: {:?}", e),
+                            };
+                            signals.error(
+                                Severity::Error,
+                                ErrorSource::Transport,
+                                None,
+                                error.clone(),
+                            );
+                            connected.store(false, atomic::Ordering::Relaxed);
+                            last_error = Some(error);
+                            break;
+                        }
+                        let message = res.unwrap();
+
+                        // process posible command message
+                        if let ReadMessage::Command(command) = message {
+                            match command {
+                                CommandMessage::Ack(v) => {
+                                    let val_res = values.ack.get(&v);
+                                    match val_res {
+                                        Some(val) => val.acknowledge(client_id),
+                                        None => {
+                                            let error = format!(
+                                                "Value with id {} not found for Ack command",
+                                                v
+                                            );
+                                            signals.error(
+                                                Severity::Warning,
+                                                ErrorSource::Value,
+                                                Some(v),
+                                                error,
+                                            );
+                                        }
+                                    }
+                                }
+                                CommandMessage::Error(err) => {
+                                    let error = format!("Error message from UI client: {}", err);
+                                    signals.error(
+                                        Severity::Warning,
+                                        ErrorSource::Remote,
+                                        None,
+                                        error,
+                                    );
+                                }
+                                CommandMessage::Ping(t) => {
+                                    let pong = WriteMessage::Command(CommandMessage::Pong(t));
+                                    let _ = channel.send(pong);
+                                }
+                                CommandMessage::Pong(t) => peer.round_trip.pong(t),
+                                CommandMessage::ClientInfo(w, h, ppp) => {
+                                    peer.client_info.set([w, h, ppp]);
+                                }
+                                CommandMessage::Resync(id) => match values.sync.get(&id) {
+                                    Some(value) => {
+                                        if let Some(ack) = values.ack.get(&id) {
+                                            ack.reset(client_id);
+                                        }
+                                        value.sync(SyncTarget::Client(client_id));
+                                    }
                                     None => {
                                         let error = format!(
-                                            "Value with id {} not found for Ack command",
-                                            v
+                                            "Value with id {} not found for Resync command",
+                                            id
+                                        );
+                                        signals.error(
+                                            Severity::Warning,
+                                            ErrorSource::Remote,
+                                            Some(id),
+                                            error,
                                         );
-                                        signals.set(0, error);
+                                    }
+                                },
+                                CommandMessage::Subscribe(subscription) => {
+                                    let subscription = subscription.map(|(ids, groups)| {
+                                        let mut subscribed: NoHashSet<u32> =
+                                            ids.into_iter().collect();
+                                        for group in groups {
+                                            match values.groups.get(&group) {
+                                                Some(ids) => subscribed.extend(ids),
+                                                None => {
+                                                    let error = format!(
+                                                        "Client subscribed to unknown group {}",
+                                                        group
+                                                    );
+                                                    signals.error(
+                                                        Severity::Warning,
+                                                        ErrorSource::Remote,
+                                                        None,
+                                                        error,
+                                                    );
+                                                }
+                                            }
+                                        }
+                                        subscribed
+                                    });
+
+                                    // the newly subscribed values are sent to have the actual content
+                                    let visible: Vec<u32> = values
+                                        .sync
+                                        .keys()
+                                        .copied()
+                                        .filter(|id| subscribed(&subscription, *id))
+                                        .collect();
+                                    // the acknowledges of the hidden values will not come
+                                    for (id, ack) in values.ack.iter() {
+                                        if !subscribed(&subscription, *id) {
+                                            ack.remove(client_id);
+                                        }
+                                    }
+                                    let previous = clients.subscribe(client_id, subscription);
+                                    for id in visible {
+                                        if !subscribed(&previous, id) {
+                                            values.sync[&id].sync(SyncTarget::Client(client_id));
+                                        }
                                     }
                                 }
+                                _ => {
+                                    let err = format!(
+                                        "Command {} should not be processed here",
+                                        command.as_str()
+                                    );
+                                    signals.error(
+                                        Severity::Warning,
+                                        ErrorSource::Transport,
+                                        None,
+                                        err,
+                                    );
+                                }
                             }
-                            CommandMessage::Error(err) => {
-                                let error = format!("Error message from UI client: {}", err);
-                                signals.set(0, error);
-                            }
-                            _ => {
-                                let err = format!(
-                                    "Command {} should not be processed here",
-                                    command.as_str()
+                            continue;
+                        }
+
+                        // read only client can not change any values
+                        if !write_allowed {
+                            let error = format!(
+                                "Read only client attempted to write: {} message was rejected",
+                                message.to_str()
+                            );
+                            let value_id = message.value_id();
+                            signals.error(
+                                Severity::Warning,
+                                ErrorSource::Handshake,
+                                value_id,
+                                error,
+                            );
+                            continue;
+                        }
+
+                        #[cfg(feature = "message-dump")]
+                        let description = message.describe();
+                        let value_id = message.value_id();
+
+                        // read only values flow only to the client, its value is sent back
+                        if let ReadMessage::Value(id, _, _)
+                        | ReadMessage::CompareSwap(id, _, _)
+                        | ReadMessage::Dict(id, _, _)
+                        | ReadMessage::List(id, _, _) = message
+                        {
+                            if values.read_only.contains(&id) {
+                                let error =
+                                    format!("Value {} is read only, write was rejected", id);
+                                signals.error(
+                                    Severity::Warning,
+                                    ErrorSource::Value,
+                                    Some(id),
+                                    error.clone(),
                                 );
-                                signals.set(0, err);
+                                let command = WriteMessage::Command(CommandMessage::Error(error));
+                                let _ = channel.send(command);
+                                values.sync[&id].sync(SyncTarget::Client(client_id));
+                                continue;
                             }
                         }
-                        continue;
-                    }
 
-                    // process message
-                    let res = match message {
-                        ReadMessage::Value(id, signal, data) => match values.updated.get(&id) {
-                            Some(val) => val.update_value(data, signal),
-                            None => Err(format!("Value with id {} not found", id)),
-                        },
-
-                        ReadMessage::Signal(id, data) => match values.updated.get(&id) {
-                            Some(val) => val.update_value(data, true),
-                            None => Err(format!("Value with id {} not found", id)),
-                        },
-
-                        _ => Err(format!(
-                            "Message {} should not be processed here",
-                            message.to_str()
-                        )),
-                    };
-
-                    if let Err(e) = res {
-                        let text = format!("Error processing message: {}", e);
-                        signals.set(0, text);
+                        // process message
+                        let res = match message {
+                            ReadMessage::Value(id, signal, data) => match values.updated.get(&id) {
+                                Some(val) => {
+                                    // the copy is needed only if other clients are connected
+                                    let copy = (relay && clients.len() > 1).then(|| data.clone());
+                                    val.update_value(data, signal, client_id).map(|set| {
+                                        if let (true, Some(data)) = (set, copy) {
+                                            if let Some(ack) = values.ack.get(&id) {
+                                                ack.relayed(client_id);
+                                            }
+                                            let message = WriteMessage::Value(id, false, data);
+                                            clients.relay(client_id, message);
+                                        }
+                                    })
+                                }
+                                None => Err(format!("Value with id {} not found", id)),
+                            },
+
+                            ReadMessage::Dict(id, signal, data) => match values.dicts.get(&id) {
+                                Some(dict) => {
+                                    let copy = (relay && clients.len() > 1).then(|| data.clone());
+                                    dict.update_value(data, signal, client_id).map(|set| {
+                                        if let (true, Some(data)) = (set, copy) {
+                                            if let Some(ack) = values.ack.get(&id) {
+                                                ack.relayed(client_id);
+                                            }
+                                            let message = WriteMessage::Dict(id, false, data);
+                                            clients.relay(client_id, message);
+                                        }
+                                    })
+                                }
+                                None => Err(format!("Dict with id {} not found", id)),
+                            },
+
+                            ReadMessage::List(id, signal, data) => match values.lists.get(&id) {
+                                Some(list) => {
+                                    let copy = (relay && clients.len() > 1).then(|| data.clone());
+                                    list.update_value(data, signal, client_id).map(|set| {
+                                        if let (true, Some(data)) = (set, copy) {
+                                            if let Some(ack) = values.ack.get(&id) {
+                                                ack.relayed(client_id);
+                                            }
+                                            let message = WriteMessage::List(id, false, data);
+                                            clients.relay(client_id, message);
+                                        }
+                                    })
+                                }
+                                None => Err(format!("List with id {} not found", id)),
+                            },
+
+                            ReadMessage::Signal(id, data) => match values.updated.get(&id) {
+                                Some(val) => val.update_value(data, true, client_id).map(|_| ()),
+                                None => Err(format!("Value with id {} not found", id)),
+                            },
+
+                            ReadMessage::CompareSwap(id, signal, data) => {
+                                match values.updated.get(&id) {
+                                    Some(val) => val.compare_swap(data, signal).map(|success| {
+                                        let result = CommandMessage::SwapResult(id, success);
+                                        let _ = channel.send(WriteMessage::Command(result));
+                                    }),
+                                    None => Err(format!("Value with id {} not found", id)),
+                                }
+                            }
+
+                            _ => Err(format!(
+                                "Message {} should not be processed here",
+                                message.to_str()
+                            )),
+                        };
+
+                        if let Err(e) = res {
+                            let text = format!("Error processing message: {}", e);
+                            #[cfg(feature = "message-dump")]
+                            let text = format!("{} {}", text, description);
+                            signals.error(Severity::Warning, ErrorSource::Value, value_id, text);
+                        }
                     }
-                }
+                    last_error
+                }));
+                let last_error = reading.unwrap_or_else(|panic| {
+                    connected.store(false, atomic::Ordering::Relaxed);
+                    Some(panic_error("Reader", panic, &signals))
+                });
 
-                // send close signal to writing thread if reading fails
-                channel.send(WriteMessage::Terminate).unwrap();
+                // send close signal to writing thread if reading fails, the writer of
+                // the disconnected client can be already closed
+                clients.remove(client_id);
+                for (_, ack) in values.ack.iter() {
+                    ack.remove(client_id);
+                }
+                let _ = channel.send(WriteMessage::Terminate);
 
-                // wait for writing thread to finish and return the receiver
-                writer.join().unwrap()
+                // wait for writing thread to finish
+                let writer_error = writer
+                    .join()
+                    .unwrap_or_else(|panic| Some(panic_error("Writer", panic, &signals)));
+                peer.disconnected(addr, last_error.or(writer_error), &signals);
             })
             .unwrap();
 
-        Self { thread }
+        Self {
+            thread,
+            alive: transfer_alive,
+            channel: transfer_channel,
+            stream: transfer_stream,
+        }
     }
 
     fn writer(
         rx: Receiver<WriteMessage>,
         connected: Arc<AtomicBool>,
-        mut stream: TcpStream,
+        mut stream: Stream,
         signals: ChangedValues,
-    ) -> JoinHandle<Receiver<WriteMessage>> {
+        extensions: Extensions,
+        queue: Arc<Queue>,
+        stats: Stats,
+    ) -> JoinHandle<Option<String>> {
+        let intake = queue.intake(rx, stats.clone());
         let thread = thread::Builder::new().name("Writer".to_string());
         thread
             .spawn(move || {
-                loop {
-                    // get message from channel
-                    let message = rx.recv().unwrap();
+                // the queue is closed also after the panic, so the intake thread finishes
+                let writing = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut last_error = None;
+                    #[cfg(feature = "shared-memory")]
+                    let mut shared = extensions.shared_memory.then(SharedImages::new);
+                    loop {
+                        // get message from channel
+                        let message = queue.next();
+                        stats.set_queue_depth(queue.len());
 
-                    // check if message is terminate signal
-                    if let WriteMessage::Terminate = message {
-                        let _ = stream.shutdown(std::net::Shutdown::Both);
-                        break;
-                    }
+                        // check if message is terminate signal
+                        if let WriteMessage::Terminate = message {
+                            connected.store(false, atomic::Ordering::Relaxed);
+                            let _ = stream.shutdown(std::net::Shutdown::Both);
+                            break;
+                        }
 
-                    // if not connected, stop thread
-                    if !connected.load(atomic::Ordering::Relaxed) {
-                        let _ = stream.shutdown(std::net::Shutdown::Both);
-                        break;
-                    }
+                        // if not connected, stop thread
+                        if !connected.load(atomic::Ordering::Relaxed) {
+                            let _ = stream.shutdown(std::net::Shutdown::Both);
+                            break;
+                        }
 
-                    // send message
-                    let res = write_message(message, &mut stream);
-                    if let Err(e) = res {
-                        let error = format!("Error writing message: {:?}", e);
-                        signals.set(0, error);
-                        connected.store(false, atomic::Ordering::Relaxed);
-                        break;
+                        // flush the stream and confirm that all previous messages were sent
+                        if let WriteMessage::Flush(confirm) = message {
+                            if let Err(e) = stream.flush() {
+                                let error = format!("Error flushing stream: {:?}", e);
+                                signals.error(
+                                    Severity::Error,
+                                    ErrorSource::Transport,
+                                    None,
+                                    error.clone(),
+                                );
+                                connected.store(false, atomic::Ordering::Relaxed);
+                                last_error = Some(error);
+                                break;
+                            }
+                            let _ = confirm.send(());
+                            continue;
+                        }
+
+                        // skip the image frame if the newer one is already in the queue
+                        if let WriteMessage::Image(_, _, _, _, ref generation) = message {
+                            if generation.is_stale() {
+                                message.recycle();
+                                continue;
+                            }
+                        }
+
+                        // pass the pixels of the large images through the shared memory
+                        #[cfg(feature = "shared-memory")]
+                        let message = match shared {
+                            Some(ref mut shared) => match shared.convert(message) {
+                                Ok(message) => message,
+                                Err(e) => {
+                                    let error = format!("Error writing shared memory: {:?}", e);
+                                    signals.error(
+                                        Severity::Error,
+                                        ErrorSource::Transport,
+                                        None,
+                                        error.clone(),
+                                    );
+                                    connected.store(false, atomic::Ordering::Relaxed);
+                                    last_error = Some(error);
+                                    break;
+                                }
+                            },
+                            None => message,
+                        };
+
+                        // send message
+                        let res = write_message(message, &mut stream, extensions, &stats);
+                        if let Err(e) = res {
+                            let error = format!("Error writing message: {:?}", e);
+                            signals.error(
+                                Severity::Error,
+                                ErrorSource::Transport,
+                                None,
+                                error.clone(),
+                            );
+                            connected.store(false, atomic::Ordering::Relaxed);
+                            last_error = Some(error);
+                            break;
+                        }
                     }
-                }
-                rx
+                    last_error
+                }));
+                let last_error = writing.unwrap_or_else(|panic| {
+                    connected.store(false, atomic::Ordering::Relaxed);
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    Some(panic_error("Writer", panic, &signals))
+                });
+
+                queue.close();
+                intake.join().unwrap();
+                last_error
             })
             .unwrap()
     }
 
-    fn join(self) -> Receiver<WriteMessage> {
-        self.thread.join().unwrap()
+    // disconnect the client and wait until both threads finish
+    fn stop(self) {
+        self.alive.store(false, atomic::Ordering::Relaxed);
+        let _ = self.channel.send(WriteMessage::Terminate);
+        self.thread.join().unwrap();
+    }
+
+    // the messages waiting in the queue are dropped, the written data are still delivered
+    fn abort(self) {
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+        self.stop();
+    }
+}
+
+// Connected clients and the id of the next one, the handshake threads add the clients.
+struct Connections {
+    transfers: Vec<StatesTransfer>,
+    next_id: usize,
+}
+
+// Maximal number of the connections waiting for the handshake at once.
+const MAX_HANDSHAKES: usize = 16;
+
+/*
+    The handshake of every connection runs in its own thread, so the client which sends nothing,
+    the encryption hello or the authenticator waiting for python do not block the next
+    connections. The reading is limited by the handshake timeout and the client is rejected if
+    the authenticator answers after it. The accepted client is added only if the server was not
+    stopped in the meantime.
+*/
+#[derive(Clone)]
+struct Acceptor {
+    values: ValuesList,
+    signals: ChangedValues,
+    peer: PeerState,
+    rules: Arc<RwLock<HandshakeRules>>, // can be changed while the server runs
+    clients: Clients,
+    connections: Arc<Mutex<Connections>>,
+    waiting: Arc<Mutex<NoHashMap<u32, Stream>>>, // copies of the streams closed on the shutdown
+    version: u64,
+    reserved: u32,
+}
+
+impl Acceptor {
+    fn spawn(
+        &self,
+        key: u32,
+        mut stream: Stream,
+        running: impl Fn() -> bool + Send + 'static,
+    ) -> std::io::Result<JoinHandle<()>> {
+        self.waiting
+            .lock()
+            .unwrap()
+            .insert(key, stream.try_clone()?);
+        let acceptor = self.clone();
+        let thread = thread::Builder::new().name("Handshake".to_string());
+        let res = thread.spawn(move || {
+            let handshake = acceptor.handshake(&mut stream);
+            acceptor.waiting.lock().unwrap().remove(&key);
+            if let Some((write_allowed, extensions)) = handshake {
+                acceptor.add(stream, write_allowed, extensions, running);
+            }
+        });
+        if res.is_err() {
+            self.waiting.lock().unwrap().remove(&key);
+        }
+        res
+    }
+
+    // Returns if the client can write values and the extensions, None if it was rejected.
+    fn handshake(&self, stream: &mut Stream) -> Option<(bool, Extensions)> {
+        let (signals, peer) = (&self.signals, &self.peer);
+        let started = Instant::now();
+        let tcp_options = *peer.tcp_options.read().unwrap();
+        if let Err(e) = stream.set_tcp_options(&tcp_options) {
+            let error = format!("Error setting TCP options: {:?}", e);
+            signals.error(Severity::Warning, ErrorSource::Connection, None, error);
+        }
+        // the transfer replaces the timeout by the heartbeat timeout
+        if let Err(e) = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+            let error = format!("Error setting handshake timeout: {:?}", e);
+            signals.error(Severity::Error, ErrorSource::Connection, None, error);
+            return None;
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(key) = *peer.psk.read().unwrap() {
+            if let Err(e) = stream.secure(&key, true) {
+                let error = format!("Error starting encryption: {:?}", e);
+                signals.error(Severity::Error, ErrorSource::Handshake, None, error);
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                return None;
+            }
+        }
+
+        // read the message, the client of a different version can send the handshake
+        // which can not be deserialized
+        let (message, mut extensions, reply) = match read_handshake(stream, &peer.stats) {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                let error = format!("Error reading initial message: {}", e);
+                if reply_rejection(&e) {
+                    reject(stream, &error, &peer.stats);
+                }
+                signals.error(Severity::Error, ErrorSource::Handshake, None, error);
+                return None;
+            }
+        };
+
+        // check if message is handshake
+        let (v, h, r, l, t) = match message {
+            ReadMessage::Command(CommandMessage::Handshake(v, h, r, l, t)) => (v, h, r, l, t),
+            message => {
+                let error = format!("Expected handshake, {} message received", message.to_str());
+                signals.error(Severity::Warning, ErrorSource::Handshake, None, error);
+                return None;
+            }
+        };
+        // the shared memory is used only by the clients on the same host
+        extensions.shared_memory &= stream.peer_addr().is_none_or(|a| a.ip().is_loopback());
+
+        // the copy is not locked while the authenticator waits for python, the authenticator
+        // can not be interrupted, so its late answer rejects the client
+        let rules = self.rules.read().unwrap().clone();
+        let checked = match v == self.version {
+            true => check_handshake(&rules, self.reserved, r, l),
+            false => Err(format!(
+                "Attempted to connect with different version: {}, version {} is required.",
+                v, self.version
+            )),
+        };
+        let checked = checked.and_then(|_| match *peer.authenticator.read().unwrap() {
+            Some(ref callback) => {
+                let checked = rules.authenticate(callback, h, t.as_deref(), stream.peer_addr());
+                match started.elapsed() > HANDSHAKE_TIMEOUT {
+                    true => Err(format!(
+                        "Authentication took longer than the handshake timeout {:?}",
+                        HANDSHAKE_TIMEOUT
+                    )),
+                    false => checked,
+                }
+            }
+            None => rules.check(h),
+        });
+
+        // the client which understands the reply gets the reason of the rejection
+        let write_allowed = match checked {
+            Ok(write_allowed) => write_allowed,
+            Err(error) => {
+                if reply {
+                    reject(stream, &error, &peer.stats);
+                }
+                signals.error(Severity::Warning, ErrorSource::Handshake, None, error);
+                return None;
+            }
+        };
+
+        if reply {
+            let accepted = CommandMessage::HandshakeAccepted(extensions.to_flag());
+            let accepted = WriteMessage::Command(accepted);
+            if let Err(e) = write_message(accepted, stream, Extensions::default(), &peer.stats) {
+                let error = format!("Error writing handshake reply: {:?}", e);
+                signals.error(Severity::Error, ErrorSource::Handshake, None, error);
+                return None;
+            }
+        }
+        Some((write_allowed, extensions))
+    }
+
+    fn add(
+        &self,
+        stream: Stream,
+        write_allowed: bool,
+        extensions: Extensions,
+        running: impl Fn() -> bool,
+    ) {
+        let (values, peer, clients) = (&self.values, &self.peer, &self.clients);
+        let mut connections = self.connections.lock().unwrap();
+        if !running() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            return;
+        }
+
+        // disconnect previous clients, unless all clients get the messages
+        let broadcast = peer.broadcast.load(atomic::Ordering::Relaxed);
+        let transfers = &mut connections.transfers;
+        transfers.retain(|st| !st.thread.is_finished());
+        if !broadcast {
+            for st in transfers.drain(..) {
+                st.stop();
+            }
+        }
+        if transfers.is_empty() {
+            peer.clear();
+        }
+
+        // Reset pending acknowledges of the new client and send sync signals. Values keep
+        // their actual content also without a client, so the sync sends everything set
+        // before the connection (also before the server was started). The messages go
+        // through the hub only to the new client, so they keep the order with
+        // the later changes.
+        let (client_channel, client_rx) = mpsc::channel();
+        let id = connections.next_id;
+        connections.next_id += 1;
+        clients.add(id, client_channel.clone());
+        for (_, v) in values.ack.iter() {
+            v.reset(id);
+        }
+        for (_, v) in values.sync.iter() {
+            v.sync(SyncTarget::Client(id));
+        }
+
+        // start transfer thread
+        let link = ClientLink {
+            id,
+            alive: Arc::new(AtomicBool::new(true)),
+            channel: client_channel,
+            clients: clients.clone(),
+            relay: broadcast,
+            write_allowed,
+            extensions,
+        };
+        let st_transfer = StatesTransfer::start(
+            link,
+            values.clone(),
+            self.signals.clone(),
+            stream,
+            client_rx,
+            peer.clone(),
+        );
+        connections.transfers.push(st_transfer);
     }
 }
 
 // server -------------------------------------------------------
-enum ChannelHolder {
-    Transfer(StatesTransfer),
-    Rx(Receiver<WriteMessage>),
+
+// State of the server for the monitoring, the rates are measured since the previous health.
+pub(crate) struct Health {
+    pub(crate) running: bool,
+    pub(crate) connected: bool,
+    pub(crate) queue_depth: usize,
+    pub(crate) sent_rate: f64,     // messages per second
+    pub(crate) received_rate: f64, // messages per second
+    pub(crate) uptime: Option<Duration>,
+}
+
+// Address and the handshake of the server, the version and the reserved ids are fixed.
+pub(crate) struct ServerConfig {
+    pub(crate) addr: Address,
+    pub(crate) version: u64,
+    pub(crate) reserved: u32,
+    pub(crate) handshake: HandshakeRules,
 }
 
 pub(crate) struct Server {
     connected: Arc<atomic::AtomicBool>,
     enabled: Arc<atomic::AtomicBool>,
+    closed: Arc<atomic::AtomicBool>,
+    stops: Arc<atomic::AtomicU64>, // the listener of the previous run is closed after the stop
     channel: Sender<WriteMessage>,
     start_event: Event,
-    addr: SocketAddrV4,
+    thread: Mutex<Option<JoinHandle<Vec<StatesTransfer>>>>, // returns the connected clients
+    addr: Arc<RwLock<Address>>,                             // used by the next start
+    started: Option<Instant>,
+    rate_sample: Mutex<(Instant, u64, u64)>, // time and the sent and received messages
+    handshake: Arc<RwLock<HandshakeRules>>,  // can be changed while the server runs
+    peer: PeerState,
 }
 
 impl Server {
@@ -185,123 +1608,153 @@ impl Server {
         connected: Arc<atomic::AtomicBool>,
         values: ValuesList,
         signals: ChangedValues,
-        addr: SocketAddrV4,
-        version: u64,
-        handshake: Option<Vec<u64>>,
+        config: ServerConfig,
     ) -> Self {
+        let ServerConfig {
+            addr,
+            version,
+            reserved,
+            handshake,
+        } = config;
         let start_event = Event::new();
         let enabled = Arc::new(atomic::AtomicBool::new(false));
+        let stops = Arc::new(atomic::AtomicU64::new(0));
+        let closed = Arc::new(atomic::AtomicBool::new(false));
+        let addr = Arc::new(RwLock::new(addr));
+        let handshake = Arc::new(RwLock::new(handshake));
+        let peer = PeerState::new();
 
         let obj = Self {
             connected: connected.clone(),
             enabled: enabled.clone(),
+            stops: stops.clone(),
+            closed: closed.clone(),
             channel: channel.clone(),
             start_event: start_event.clone(),
-            addr,
+            thread: Mutex::new(None),
+            addr: addr.clone(),
+            started: None,
+            rate_sample: Mutex::new((Instant::now(), 0, 0)),
+            handshake: handshake.clone(),
+            peer: peer.clone(),
         };
 
         let server_thread = thread::Builder::new().name("Server".to_string());
-        let _ = server_thread.spawn(move || {
-            let mut holder = ChannelHolder::Rx(rx);
+        let thread = server_thread.spawn(move || {
+            let clients = Clients::start(rx, channel.clone(), connected.clone());
+            let acceptor = Acceptor {
+                values,
+                signals: signals.clone(),
+                peer,
+                rules: handshake,
+                clients,
+                connections: Arc::new(Mutex::new(Connections {
+                    transfers: Vec::new(),
+                    next_id: 0,
+                })),
+                waiting: Arc::new(Mutex::new(NoHashMap::default())),
+                version,
+                reserved,
+            };
+            let mut handshakes: Vec<JoinHandle<()>> = Vec::new();
+            let mut handshake_key: u32 = 0;
+            let mut listener: Option<(Listener, u64)> = None; // with the number of stops
 
             loop {
-                // wait for start control event
+                // wait for start control event, the shutdown ends the thread, the waiting
+                // handshakes are closed
                 start_event.wait();
-
-                // listen to incoming connections
-                let listener = TcpListener::bind(addr);
-                if let Err(e) = listener {
-                    let error = format!("Error binding: {:?}", e);
-                    signals.set(0, error);
-                    continue;
-                }
-                let listener = listener.unwrap();
-
-                // accept incoming connection
-                let stream = listener.accept();
-
-                // if server is disabled, go back and wait for start control event
-                if !enabled.load(atomic::Ordering::Relaxed) {
-                    if let Ok((stream, _)) = stream {
+                if closed.load(atomic::Ordering::Relaxed) {
+                    for (_, stream) in acceptor.waiting.lock().unwrap().drain() {
                         let _ = stream.shutdown(std::net::Shutdown::Both);
                     }
-                    continue;
+                    for handshake in handshakes {
+                        let _ = handshake.join();
+                    }
+                    let mut connections = acceptor.connections.lock().unwrap();
+                    break std::mem::take(&mut connections.transfers);
                 }
 
-                // check if error accepting connection
-                if let Err(e) = stream {
-                    let error = format!("Error accepting connection: {:?}", e);
-                    signals.set(0, error);
-                    continue;
+                // listen to incoming connections, the same listener is used for all connections
+                // while the server runs, the server is stopped if the address is not available,
+                // so it can be changed. The server can be started again before the accept
+                // notices the stop, so the listener is closed also if it was stopped in between.
+                let run = stops.load(atomic::Ordering::Relaxed);
+                if listener
+                    .as_ref()
+                    .is_some_and(|(_, bound_run)| *bound_run != run)
+                {
+                    listener = None;
                 }
-                let mut stream = stream.unwrap().0;
-
-                // read the message
-                let res = read_message(&mut stream);
-                if let Err(e) = res {
-                    let error = format!("Error reading initial message: {:?}", e);
-                    signals.set(0, error);
-                    connected.store(false, atomic::Ordering::Relaxed);
-                    continue;
+                if listener.is_none() {
+                    match Listener::bind(&addr.read().unwrap()) {
+                        Ok(bound) => listener = Some((bound, run)),
+                        Err(e) => {
+                            let error = format!("Error binding: {:?}", e);
+                            signals.error(Severity::Error, ErrorSource::Connection, None, error);
+                            start_event.clear();
+                            enabled.store(false, atomic::Ordering::Relaxed);
+                            continue;
+                        }
+                    }
                 }
 
-                // check if message is handshake
-                if let ReadMessage::Command(CommandMessage::Handshake(v, h)) = res.unwrap() {
-                    if v != version {
-                        let error = format!("Attempted to connect with different version: {}, version {} is required.", v, version);
-                        signals.set(0, error);
+                // accept incoming connection, if the server is stopped, the listener is closed
+                // and it goes back and waits for start control event
+                let running = {
+                    let (enabled, stops) = (enabled.clone(), stops.clone());
+                    move || {
+                        enabled.load(atomic::Ordering::Relaxed)
+                            && stops.load(atomic::Ordering::Relaxed) == run
+                    }
+                };
+                let accepted = listener.as_ref().unwrap().0.accept_while(&running);
+                let stream = match accepted {
+                    Ok(Some(stream)) => stream,
+                    Ok(None) => {
+                        listener = None;
                         continue;
                     }
-
-                    if let Some(ref hash) = handshake {
-                        if !hash.contains(&h) {
-                            let error = "Attempted to connect with wrong hash".to_string();
-                            signals.set(0, error);
-                            continue;
-                        }
+                    Err(e) => {
+                        let error = format!("Error accepting connection: {:?}", e);
+                        signals.error(Severity::Error, ErrorSource::Connection, None, error);
+                        continue;
                     }
+                };
 
-                    let rx = match holder {
-                        // disconnect previous client
-                        ChannelHolder::Transfer(st) => {
-                            connected.store(false, atomic::Ordering::Relaxed);
-                            channel.send(WriteMessage::Terminate).unwrap();
-                            st.join()
-                        }
-                        ChannelHolder::Rx(rx) => rx,
-                    };
-
-                    connected.store(true, atomic::Ordering::Relaxed);
-
-                    // clean mesage queue and send sync signals
-                    for _v in rx.try_iter() {}
-                    for (_, v) in values.sync.iter() {
-                        v.sync();
+                // the handshake runs in its own thread, the threads of the previous connections
+                // which already finished are removed
+                handshakes.retain(|handshake| !handshake.is_finished());
+                if handshakes.len() >= MAX_HANDSHAKES {
+                    let error = "Too many connections waiting for the handshake".to_string();
+                    signals.error(Severity::Warning, ErrorSource::Connection, None, error);
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    continue;
+                }
+                handshake_key = handshake_key.wrapping_add(1);
+                match acceptor.spawn(handshake_key, stream, running.clone()) {
+                    Ok(handshake) => handshakes.push(handshake),
+                    Err(e) => {
+                        let error = format!("Error starting handshake thread: {:?}", e);
+                        signals.error(Severity::Error, ErrorSource::Connection, None, error);
                     }
-
-                    // start transfer thread
-                    let st_transfer = StatesTransfer::start(
-                        connected.clone(),
-                        values.clone(),
-                        signals.clone(),
-                        stream,
-                        rx,
-                        channel.clone(),
-                    );
-                    holder = ChannelHolder::Transfer(st_transfer);
                 }
             }
         });
+        *obj.thread.lock().unwrap() = thread.ok();
 
         obj
     }
 
     pub(crate) fn start(&mut self) {
-        if self.enabled.load(atomic::Ordering::Relaxed) {
+        if self.enabled.load(atomic::Ordering::Relaxed)
+            || self.closed.load(atomic::Ordering::Relaxed)
+        {
             return;
         }
 
         self.enabled.store(true, atomic::Ordering::Relaxed);
+        self.started = Some(Instant::now());
         self.start_event.set();
     }
 
@@ -312,10 +1765,50 @@ impl Server {
 
         self.start_event.clear();
         self.enabled.store(false, atomic::Ordering::Relaxed);
+        self.stops.fetch_add(1, atomic::Ordering::Relaxed);
         self.disconnect_client();
+    }
+
+    /*
+        The listener is closed and the server thread ends. The messages in the queues are written
+        to the connected clients until the timeout, then the connections are closed and all
+        threads of the connections are joined. Returns if all messages were written in time.
+        The hub thread stays waiting, the values can still be set. The server can not be started
+        again after the shutdown.
+    */
+    pub(crate) fn shutdown(&self, timeout: Option<Duration>) -> bool {
+        let thread = match self.thread.lock().unwrap().take() {
+            Some(thread) => thread,
+            None => return true,
+        };
 
-        // try to connect to the server to unblock the accept call
-        let _ = TcpStream::connect(self.addr);
+        self.closed.store(true, atomic::Ordering::Relaxed);
+        self.enabled.store(false, atomic::Ordering::Relaxed);
+        self.start_event.set();
+        let transfers = thread.join().unwrap_or_default();
+
+        let flushed = !self.connected.load(atomic::Ordering::Relaxed) || {
+            let (confirm, rx) = mpsc::channel();
+            self.channel.send(WriteMessage::Flush(confirm)).unwrap();
+            match timeout {
+                Some(timeout) => rx.recv_timeout(timeout).is_ok(),
+                None => rx.recv().is_ok(),
+            }
+        };
+
+        for st in transfers {
+            st.abort();
+        }
+        self.peer.clear();
+        flushed
+    }
+
+    pub(crate) fn set_address(&self, addr: Address) -> Result<(), String> {
+        if self.enabled.load(atomic::Ordering::Relaxed) {
+            return Err("The address can be changed only while the server is stopped.".to_string());
+        }
+        *self.addr.write().unwrap() = addr;
+        Ok(())
     }
 
     pub(crate) fn disconnect_client(&mut self) {
@@ -325,16 +1818,326 @@ impl Server {
         }
     }
 
+    // Returns if the server was stopped or closed since this call, for the threads which should
+    // not outlive the current run of the server.
+    pub(crate) fn stop_check(&self) -> impl Fn() -> bool + Send + 'static {
+        let (stops, closed) = (self.stops.clone(), self.closed.clone());
+        let run = stops.load(atomic::Ordering::Relaxed);
+        move || {
+            stops.load(atomic::Ordering::Relaxed) != run || closed.load(atomic::Ordering::Relaxed)
+        }
+    }
+
     pub(crate) fn is_running(&self) -> bool {
         self.enabled.load(atomic::Ordering::Relaxed)
     }
+
+    pub(crate) fn ping(&self) {
+        if self.connected.load(atomic::Ordering::Relaxed) {
+            self.channel
+                .send(WriteMessage::Command(self.peer.round_trip.ping()))
+                .unwrap();
+        }
+    }
+
+    pub(crate) fn last_rtt(&self) -> Option<Duration> {
+        self.peer.round_trip.last_rtt()
+    }
+
+    pub(crate) fn latency(&self) -> Option<Duration> {
+        self.peer.round_trip.latency()
+    }
+
+    pub(crate) fn stats(&self) -> MessageStats {
+        self.peer.stats.snapshot()
+    }
+
+    pub(crate) fn health(&self) -> Health {
+        let stats = self.stats();
+        let total = stats.total();
+
+        let now = Instant::now();
+        let mut sample = self.rate_sample.lock().unwrap();
+        let elapsed = now.duration_since(sample.0).as_secs_f64().max(f64::EPSILON);
+        let sent_rate = total.messages_sent.saturating_sub(sample.1) as f64 / elapsed;
+        let received_rate = total.messages_received.saturating_sub(sample.2) as f64 / elapsed;
+        *sample = (now, total.messages_sent, total.messages_received);
+
+        let running = self.is_running();
+        Health {
+            running,
+            connected: self.connected.load(atomic::Ordering::Relaxed),
+            queue_depth: stats.queue_depth.unwrap_or_default(),
+            sent_rate,
+            received_rate,
+            uptime: self
+                .started
+                .filter(|_| running)
+                .map(|started| started.elapsed()),
+        }
+    }
+
+    pub(crate) fn client_info(&self) -> Option<[f32; 3]> {
+        self.peer.client_info.get()
+    }
+
+    pub(crate) fn set_on_disconnect(&self, callback: Option<DisconnectCallback>) {
+        *self.peer.on_disconnect.write().unwrap() = callback;
+    }
+
+    pub(crate) fn set_authenticator(&self, callback: Option<AuthCallback>) {
+        *self.peer.authenticator.write().unwrap() = callback;
+    }
+
+    // the connected clients are not affected, the rules are checked only in the handshake
+    pub(crate) fn set_handshake(&self, hashes: Option<Vec<u64>>, read_only: Vec<u64>) {
+        let mut handshake = self.handshake.write().unwrap();
+        handshake.hashes = hashes;
+        handshake.read_only = read_only;
+    }
+
+    // the allowed hashes are not changed if all hashes are allowed
+    pub(crate) fn allow_hash(&self, hash: u64, read_only: bool) {
+        let mut handshake = self.handshake.write().unwrap();
+        let list = match read_only {
+            true => Some(&mut handshake.read_only),
+            false => handshake.hashes.as_mut(),
+        };
+        if let Some(list) = list {
+            if !list.contains(&hash) {
+                list.push(hash);
+            }
+        }
+    }
+
+    pub(crate) fn remove_hash(&self, hash: u64) {
+        let mut handshake = self.handshake.write().unwrap();
+        if let Some(ref mut hashes) = handshake.hashes {
+            hashes.retain(|h| *h != hash);
+        }
+        handshake.read_only.retain(|h| *h != hash);
+    }
+
+    pub(crate) fn handshake(&self) -> (Option<Vec<u64>>, Vec<u64>) {
+        let handshake = self.handshake.read().unwrap();
+        (handshake.hashes.clone(), handshake.read_only.clone())
+    }
+
+    pub(crate) fn set_heartbeat(&self, interval: Option<Duration>) {
+        *self.peer.heartbeat.write().unwrap() = interval;
+    }
+
+    pub(crate) fn set_tcp_options(&self, options: TcpOptions) {
+        *self.peer.tcp_options.write().unwrap() = options;
+    }
+
+    pub(crate) fn set_max_message_size(&self, size: usize) {
+        *self.peer.max_message_size.write().unwrap() = size;
+    }
+
+    pub(crate) fn set_queue_limit(&self, size: usize) {
+        *self.peer.queue_limit.write().unwrap() = size;
+    }
+
+    pub(crate) fn set_broadcast(&self, enabled: bool) {
+        self.peer
+            .broadcast
+            .store(enabled, atomic::Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_psk(&self, key: Option<PresharedKey>) {
+        *self.peer.psk.write().unwrap() = key;
+    }
 }
 
 // server traits --------------------------------------------------------------
+
+// Receivers of the synchronized value, all connected clients or only the client which asked.
+#[derive(Clone, Copy)]
+pub(crate) enum SyncTarget {
+    All,
+    Client(usize),
+}
+
+impl SyncTarget {
+    pub(crate) fn message(self, message: WriteMessage) -> WriteMessage {
+        match self {
+            SyncTarget::All => message,
+            SyncTarget::Client(client) => WriteMessage::Direct(client, Box::new(message)),
+        }
+    }
+}
+
 pub(crate) trait SyncTrait: Sync + Send {
-    fn sync(&self);
+    fn sync(&self, target: SyncTarget);
 }
 
+// The acknowledges are counted for every client which gets the value.
 pub(crate) trait Acknowledge: Sync + Send {
-    fn acknowledge(&self);
+    fn acknowledge(&self, client: usize);
+    // The client gets the value, the messages sent to it before will never be acknowledged.
+    fn reset(&self, client: usize);
+    // The client does not get the value anymore, it was disconnected or unsubscribed.
+    fn remove(&self, client: usize);
+    // The value set by the client was sent to all other clients.
+    fn relayed(&self, client: usize);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{ImageGeneration, MessageData};
+    use std::sync::atomic::AtomicU64;
+
+    fn lanes() -> Lanes {
+        Lanes::new(&NoHashMap::default(), None, DEFAULT_QUEUE_LIMIT)
+    }
+
+    fn dict(id: u32, size: usize) -> WriteMessage {
+        WriteMessage::Dict(id, false, MessageData::Heap(vec![0; size]))
+    }
+
+    fn value(id: u32) -> WriteMessage {
+        WriteMessage::Value(id, false, MessageData::Heap(vec![0; 4]))
+    }
+
+    fn graph(id: u32, size: usize) -> WriteMessage {
+        WriteMessage::Graph(id, false, MessageData::Heap(vec![0; size]), None)
+    }
+
+    fn frame(id: u32, size: usize, latest: &Arc<AtomicU64>) -> WriteMessage {
+        let generation = latest.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+        let generation = ImageGeneration::new(generation, latest.clone());
+        let info = MessageData::Heap(Vec::new());
+        WriteMessage::Image(id, false, info, vec![0; size], generation)
+    }
+
+    fn pop(lanes: &mut Lanes) -> Option<(u32, usize)> {
+        lanes.pop().as_ref().and_then(data_size)
+    }
+
+    #[test]
+    fn small_change_follows_queued_bulk_message() {
+        let mut lanes = lanes();
+        lanes.push(dict(1, BULK_SIZE));
+        lanes.push(dict(1, 8));
+        lanes.push(value(2));
+
+        assert_eq!(pop(&mut lanes), Some((2, 4)));
+        assert_eq!(pop(&mut lanes), Some((1, BULK_SIZE)));
+        assert_eq!(pop(&mut lanes), Some((1, 8)));
+        assert_eq!(pop(&mut lanes), None);
+    }
+
+    #[test]
+    fn small_change_is_preferred_after_bulk_is_written() {
+        let mut lanes = lanes();
+        lanes.push(dict(1, BULK_SIZE));
+        assert_eq!(pop(&mut lanes), Some((1, BULK_SIZE)));
+
+        lanes.push(dict(3, BULK_SIZE));
+        lanes.push(dict(1, 8));
+        assert_eq!(pop(&mut lanes), Some((1, 8)));
+        assert_eq!(pop(&mut lanes), Some((3, BULK_SIZE)));
+    }
+
+    #[test]
+    fn replaced_frame_is_dropped_without_resync() {
+        let mut lanes = Lanes::new(&NoHashMap::default(), None, 100);
+        let latest = Arc::new(AtomicU64::new(0));
+        assert_eq!(lanes.push(frame(1, 60, &latest)), 0);
+        assert_eq!(lanes.push(frame(1, 60, &latest)), 1);
+
+        assert!(lanes.take_resync().is_empty());
+        assert_eq!(pop(&mut lanes), Some((1, 60)));
+        assert_eq!(pop(&mut lanes), None);
+    }
+
+    #[test]
+    fn dropped_changes_wait_for_resync() {
+        let mut lanes = Lanes::new(&NoHashMap::default(), None, 100);
+        lanes.push(graph(1, 60));
+        lanes.push(graph(1, 30));
+        // all messages of the oldest graph are dropped, also the later ones
+        assert_eq!(lanes.push(graph(2, 60)), 2);
+        assert_eq!(lanes.push(graph(1, 10)), 1);
+        assert!(lanes.take_resync().is_empty());
+
+        let message = lanes.pop().unwrap();
+        assert_eq!(data_size(&lanes.taken(message)), Some((2, 60)));
+        assert_eq!(lanes.take_resync(), vec![1]);
+        assert_eq!(lanes.push(graph(1, 60)), 0);
+        assert_eq!(pop(&mut lanes), Some((1, 60)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn value_set_before_start_is_synced_to_first_client() {
+        use pyo3::prelude::*;
+
+        use crate::states_server::ServerValuesCreator;
+        use crate::transport::deserialize;
+
+        pyo3::prepare_freethreaded_python();
+        let (channel, rx) = mpsc::channel();
+        let connected = Arc::new(AtomicBool::new(false));
+        let signals = ChangedValues::new();
+        let mut creator =
+            ServerValuesCreator::new(channel.clone(), connected.clone(), signals.clone());
+        creator.add_value(0i64);
+        let (values, py_values, version, reserved) = creator.get_values();
+        let layout = py_values.schema.hash(version, reserved);
+        let (&id, value) = py_values.values.iter().next().unwrap();
+
+        let path = std::env::temp_dir().join(format!("egui_pysync_sync_{}", std::process::id()));
+        let addr = Address::Unix(path.clone());
+        let rules = HandshakeRules {
+            hashes: None,
+            read_only: Vec::new(),
+            layout,
+        };
+        let config = ServerConfig {
+            addr: addr.clone(),
+            version,
+            reserved,
+            handshake: rules,
+        };
+        let mut server = Server::new(channel, rx, connected, values, signals, config);
+
+        Python::with_gil(|py| value.set_py(&42i64.into_pyobject(py).unwrap(), false, false))
+            .unwrap();
+        server.start();
+
+        let started = Instant::now();
+        let mut stream = loop {
+            match Stream::connect(&addr) {
+                Ok(stream) => break stream,
+                Err(_) if started.elapsed() < Duration::from_secs(5) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("Failed to connect: {}", e),
+            }
+        };
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let stats = Stats::new(false);
+        let handshake = CommandMessage::Handshake(version, 0, reserved, layout, None);
+        let handshake = WriteMessage::Command(handshake);
+        write_message(handshake, &mut stream, Extensions::default(), &stats).unwrap();
+        let synced = loop {
+            match read_message(&mut stream, DEFAULT_MAX_MESSAGE_SIZE, &stats).unwrap() {
+                ReadMessage::Value(value_id, _, data) if value_id == id => {
+                    break deserialize::<i64>(data).unwrap()
+                }
+                _ => {}
+            }
+        };
+        assert_eq!(synced, 42);
+
+        server.shutdown(Some(Duration::from_secs(1)));
+        let _ = std::fs::remove_file(path);
+    }
 }
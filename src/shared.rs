@@ -0,0 +1,269 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+/*
+    The image pixels are written to a memory mapped file and only the position of the frame is
+    sent over the socket. The file has two slots, so the client can read one while the server
+    writes the next frame to the other one. Every slot starts with the sequence number of the
+    frame (seqlock), which is odd while the server writes. If the sequence is changed during the
+    read, the frame was overwritten by a newer one and it is dropped, the newer one is coming.
+*/
+const SEQUENCE_SIZE: usize = 8;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SharedFrame {
+    path: String,
+    offset: usize, // offset of the sequence of the slot, the data follow
+    size: usize,
+    sequence: u64,
+}
+
+// The pointer has to be aligned to 8 bytes, the map is page aligned and the slots are aligned.
+#[inline]
+unsafe fn sequence<'a>(ptr: *const u8) -> &'a AtomicU64 {
+    &*(ptr as *const AtomicU64)
+}
+
+// client mapping of the last file of the image
+pub(crate) struct SharedReader {
+    map: Option<(String, Mmap)>,
+}
+
+impl SharedReader {
+    pub(crate) fn new() -> Self {
+        Self { map: None }
+    }
+
+    /// Process the data of the frame. Returns None if the frame was overwritten.
+    pub(crate) fn read<R>(
+        &mut self,
+        frame: &SharedFrame,
+        op: impl FnOnce(&[u8]) -> R,
+    ) -> Result<Option<R>, String> {
+        let mapped = matches!(self.map, Some((ref path, _)) if *path == frame.path);
+        if !mapped {
+            // the file is removed if the image was resized since the frame was sent
+            let file = match File::open(&frame.path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to open shared memory {}: {}",
+                        frame.path, e
+                    ))
+                }
+            };
+            let map = unsafe { Mmap::map(&file) }
+                .map_err(|e| format!("Failed to map shared memory {}: {}", frame.path, e))?;
+            self.map = Some((frame.path.clone(), map));
+        }
+        let map = &self.map.as_ref().unwrap().1;
+
+        // the frame comes from the other side, the overflowed position is out of bounds too
+        let start = frame.offset.checked_add(SEQUENCE_SIZE);
+        let end = start.and_then(|start| start.checked_add(frame.size));
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end))
+                if frame.offset.is_multiple_of(SEQUENCE_SIZE) && end <= map.len() =>
+            {
+                (start, end)
+            }
+            _ => {
+                return Err(format!(
+                    "Shared memory frame is out of bounds of {}",
+                    frame.path
+                ))
+            }
+        };
+
+        let seq = unsafe { sequence(map.as_ptr().add(frame.offset)) };
+        if seq.load(Ordering::Acquire) != frame.sequence {
+            return Ok(None);
+        }
+        let result = op(&map[start..end]);
+        fence(Ordering::Acquire);
+        if seq.load(Ordering::Relaxed) != frame.sequence {
+            return Ok(None);
+        }
+        Ok(Some(result))
+    }
+}
+
+// SERVER ---------------------------------------------------
+// ----------------------------------------------------------
+#[cfg(feature = "server")]
+pub(crate) mod server {
+    use super::*;
+
+    use std::fs::{self, OpenOptions};
+    use std::io;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicU32;
+
+    use memmap2::MmapMut;
+
+    use crate::pool::POOL;
+    use crate::transport::WriteMessage;
+    use crate::NoHashMap;
+
+    const SLOTS: usize = 2;
+    const SHARED_SIZE: usize = 64 * 1024; // smaller images are sent through the socket
+
+    static FILE_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    fn shared_dir() -> PathBuf {
+        let shm = PathBuf::from("/dev/shm");
+        match shm.is_dir() {
+            true => shm,
+            false => std::env::temp_dir(),
+        }
+    }
+
+    struct Region {
+        path: PathBuf,
+        map: MmapMut,
+        slot_size: usize,
+        capacity: usize,
+        slot: usize,
+        sequence: u64,
+    }
+
+    impl Region {
+        fn create(capacity: usize) -> io::Result<Self> {
+            let name = format!(
+                "egui_pysync_{}_{}",
+                std::process::id(),
+                FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = shared_dir().join(name);
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+
+            let slot_size = SEQUENCE_SIZE + capacity.div_ceil(SEQUENCE_SIZE) * SEQUENCE_SIZE;
+            let map = file
+                .set_len((slot_size * SLOTS) as u64)
+                .and_then(|_| unsafe { MmapMut::map_mut(&file) });
+            let map = match map {
+                Ok(map) => map,
+                Err(e) => {
+                    let _ = fs::remove_file(&path);
+                    return Err(e);
+                }
+            };
+
+            Ok(Self {
+                path,
+                map,
+                slot_size,
+                capacity,
+                slot: 0,
+                sequence: 0,
+            })
+        }
+
+        fn write(&mut self, data: &[u8]) -> SharedFrame {
+            self.slot = (self.slot + 1) % SLOTS;
+            self.sequence += 2;
+            let offset = self.slot * self.slot_size;
+
+            let seq = unsafe { sequence(self.map.as_mut_ptr().add(offset)) };
+            seq.store(self.sequence - 1, Ordering::Relaxed);
+            fence(Ordering::Release);
+            let start = offset + SEQUENCE_SIZE;
+            self.map[start..start + data.len()].copy_from_slice(data);
+            seq.store(self.sequence, Ordering::Release);
+
+            SharedFrame {
+                path: self.path.to_string_lossy().into_owned(),
+                offset,
+                size: data.len(),
+                sequence: self.sequence,
+            }
+        }
+    }
+
+    // The client keeps its mapping, the file is removed only from the file system. Files of the
+    // process which exits without closing the connection are left in the directory.
+    impl Drop for Region {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    /// Shared memory regions of the images of one connection.
+    pub(crate) struct SharedImages {
+        regions: NoHashMap<u32, Region>,
+    }
+
+    impl SharedImages {
+        pub(crate) fn new() -> Self {
+            Self {
+                regions: NoHashMap::default(),
+            }
+        }
+
+        // Large images are written to the shared memory, other messages are returned as they are.
+        pub(crate) fn convert(&mut self, message: WriteMessage) -> io::Result<WriteMessage> {
+            let (id, update, info, data) = match message {
+                WriteMessage::Image(id, update, info, data, _) if data.len() >= SHARED_SIZE => {
+                    (id, update, info, data)
+                }
+                message => return Ok(message),
+            };
+
+            let resize = match self.regions.get(&id) {
+                Some(region) => region.capacity < data.len(),
+                None => true,
+            };
+            if resize {
+                self.regions.insert(id, Region::create(data.len())?);
+            }
+
+            let frame = self.regions.get_mut(&id).unwrap().write(&data);
+            POOL.give(data);
+            let mut message = info.as_slice().to_vec();
+            message.extend(postcard::to_stdvec(&frame).unwrap());
+            Ok(WriteMessage::SharedImage(id, update, message))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflowed_frame_is_out_of_bounds() {
+        let path = std::env::temp_dir().join(format!("egui_pysync_shared_{}", std::process::id()));
+        std::fs::write(&path, [0u8; 64]).unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let mut reader = SharedReader::new();
+        let frames = [(usize::MAX - 7, 16), (8, usize::MAX - 8), (0, 57), (4, 8)];
+        for (offset, size) in frames {
+            let frame = SharedFrame {
+                path: path.clone(),
+                offset,
+                size,
+                sequence: 0,
+            };
+            assert!(reader.read(&frame, |data| data.len()).is_err());
+        }
+
+        let frame = SharedFrame {
+            path: path.clone(),
+            offset: 0,
+            size: 56,
+            sequence: 0,
+        };
+        assert_eq!(reader.read(&frame, |data| data.len()), Ok(Some(56)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,12 +1,33 @@
+use std::any::Any;
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
 
+use pyo3::Python;
+
+use crate::audit::AuditLog;
+use crate::errors::{ErrorCallback, ErrorLog, ErrorRecord, ErrorSource, Severity};
 use crate::event::Event;
 use crate::python_convert::ToPython;
 use crate::{NoHashMap, NoHashSet};
 
+// To be able to get the original value back from the signal.
+pub trait SignalValue: ToPython {
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: ToPython + 'static> SignalValue for T {
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+const WORKER_POLL: f32 = 0.1; // seconds between the checks of the retired workers
+
 struct OrderedMap {
-    values: NoHashMap<u32, Box<dyn ToPython + Sync + Send>>,
+    values: NoHashMap<u32, Box<dyn SignalValue>>,
     indexes: VecDeque<u32>,
 }
 
@@ -18,12 +39,16 @@ impl OrderedMap {
         }
     }
 
-    fn insert(&mut self, id: u32, value: Box<dyn ToPython + Sync + Send>) {
+    fn insert(&mut self, id: u32, value: Box<dyn SignalValue>) {
         self.values.insert(id, value);
         self.indexes.push_back(id);
     }
 
-    fn pop_first(&mut self) -> Option<(u32, Box<dyn ToPython + Sync + Send>)> {
+    fn remove(&mut self, id: u32) -> Option<Box<dyn SignalValue>> {
+        self.values.remove(&id)
+    }
+
+    fn pop_first(&mut self) -> Option<(u32, Box<dyn SignalValue>)> {
         for _ in 0..self.indexes.len() {
             let id = self.indexes.pop_front().unwrap();
             if let Some(value) = self.values.remove(&id) {
@@ -35,10 +60,11 @@ impl OrderedMap {
 }
 
 struct ChnegedInner {
-    values: OrderedMap,                                       // values not blocked
-    blocked: NoHashMap<u32, Box<dyn ToPython + Sync + Send>>, // values blocked by some thread
-    block_list: NoHashSet<u32>,                               // ids blocked by some thread
-    threads_last: NoHashMap<u32, u32>,                        // cache last id for each thread
+    values: OrderedMap,                            // values not blocked
+    blocked: NoHashMap<u32, Box<dyn SignalValue>>, // values blocked by some thread
+    block_list: NoHashSet<u32>,                    // ids blocked by some thread
+    threads_last: NoHashMap<u32, u32>,             // cache last id for each thread
+    retired: NoHashSet<u32>,                       // threads which should stop
 }
 
 /*
@@ -52,10 +78,11 @@ impl ChnegedInner {
             blocked: NoHashMap::default(),
             block_list: NoHashSet::default(),
             threads_last: NoHashMap::default(),
+            retired: NoHashSet::default(),
         }
     }
 
-    fn set(&mut self, id: u32, value: Box<dyn ToPython + Sync + Send>, event: &Event) {
+    fn set(&mut self, id: u32, value: Box<dyn SignalValue>, event: &Event) {
         if self.block_list.contains(&id) {
             self.blocked.insert(id, value);
         } else {
@@ -64,51 +91,63 @@ impl ChnegedInner {
         }
     }
 
-    fn get(&mut self, thread_id: u32) -> Option<(u32, Box<dyn ToPython + Send + Sync>)> {
-        match self.threads_last.get(&thread_id) {
-            // previous call was made
-            Some(last_id) => {
-                if self.block_list.contains(last_id) {
-                    let val = self.blocked.remove(last_id);
-                    match val {
-                        Some(v) => Some((*last_id, v)),
-                        None => {
-                            let val = self.values.pop_first();
-                            self.block_list.remove(last_id);
-
-                            if let Some(ref v) = val {
-                                self.threads_last.insert(thread_id, v.0);
-                                self.block_list.insert(v.0);
-                            }
-                            val
-                        }
-                    }
-                } else {
-                    let val = self.values.pop_first();
-                    if let Some(ref v) = val {
-                        self.threads_last.insert(thread_id, v.0);
-                        self.block_list.insert(v.0);
-                    }
-                    val
-                }
+    // Blocked value is newer than the not blocked one.
+    fn take(&mut self, id: u32) -> Vec<Box<dyn SignalValue>> {
+        let mut values = Vec::new();
+        if let Some(value) = self.values.remove(id) {
+            values.push(value);
+        }
+        if let Some(value) = self.blocked.remove(&id) {
+            values.push(value);
+        }
+        values
+    }
+
+    // The last id of the thread is kept only while the thread blocks it, otherwise the thread
+    // could take the value of the id blocked later by another thread.
+    fn get(&mut self, thread_id: u32) -> Option<(u32, Box<dyn SignalValue>)> {
+        if let Some(last_id) = self.threads_last.remove(&thread_id) {
+            if let Some(value) = self.blocked.remove(&last_id) {
+                self.threads_last.insert(thread_id, last_id);
+                return Some((last_id, value));
             }
-            // this is first time
-            None => {
-                let val = self.values.pop_first();
-                if let Some(ref v) = val {
-                    self.threads_last.insert(thread_id, v.0);
-                    self.block_list.insert(v.0);
-                }
-                val
+            self.block_list.remove(&last_id);
+        }
+
+        let val = self.values.pop_first();
+        if let Some(ref v) = val {
+            self.threads_last.insert(thread_id, v.0);
+            self.block_list.insert(v.0);
+        }
+        val
+    }
+
+    // the blocked value of the stopped thread is taken by any other thread
+    fn release(&mut self, thread_id: u32, event: &Event) {
+        if let Some(last_id) = self.threads_last.remove(&thread_id) {
+            self.block_list.remove(&last_id);
+            if let Some(value) = self.blocked.remove(&last_id) {
+                self.values.insert(last_id, value);
+                event.set_one();
             }
         }
     }
 }
 
 #[derive(Clone)]
-pub(crate) struct ChangedValues {
+pub struct ChangedValues {
     event: Event,
     values: Arc<Mutex<ChnegedInner>>,
+    errors: ErrorLog,
+    audit: AuditLog,
+    observers: Observers,
+    ack_timeout: Arc<RwLock<Option<Duration>>>, // the values waiting longer accept the client
+}
+
+impl Default for ChangedValues {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ChangedValues {
@@ -116,6 +155,10 @@ impl ChangedValues {
         Self {
             event: Event::new(),
             values: Arc::new(Mutex::new(ChnegedInner::new())),
+            errors: ErrorLog::new(),
+            audit: AuditLog::new(),
+            observers: Observers::new(),
+            ack_timeout: Arc::new(RwLock::new(None)),
         }
     }
 
@@ -124,7 +167,73 @@ impl ChangedValues {
         self.values.lock().unwrap().set(id, value, &self.event);
     }
 
-    pub fn wait_changed_value(&self, thread_id: u32) -> (u32, Box<dyn ToPython + Send + Sync>) {
+    /// Take the waiting values of the signal with given id without python.
+    ///
+    /// Only the latest value of every signal waits, the newer value replaces the older one. When
+    /// the value is handled by some thread, one newer value waits for it, so at most two values
+    /// are returned, the older one first. The data from the client which can not be deserialized
+    /// never become the signal values, they are recorded in the error log with the value id.
+    /// If some value has different type than T, the error is returned and all values are dropped.
+    pub fn drain_typed<T: 'static>(&self, id: u32) -> Result<Vec<T>, String> {
+        let values = self.values.lock().unwrap().take(id);
+
+        let mut typed = Vec::with_capacity(values.len());
+        for value in values {
+            match value.into_any().downcast::<T>() {
+                Ok(value) => typed.push(*value),
+                Err(_) => {
+                    return Err(format!(
+                        "Signal value with id {} has different type than {}",
+                        id,
+                        std::any::type_name::<T>()
+                    ))
+                }
+            }
+        }
+        Ok(typed)
+    }
+
+    /// Record the error, the message is also sent as the error signal with id 0.
+    pub fn error(
+        &self,
+        severity: Severity,
+        source: ErrorSource,
+        value_id: Option<u32>,
+        message: String,
+    ) {
+        self.set(0, message.clone());
+        self.errors.push(severity, source, value_id, message);
+    }
+
+    pub(crate) fn errors(&self, sequence: u64) -> Vec<ErrorRecord> {
+        self.errors.since(sequence)
+    }
+
+    pub(crate) fn last_error(&self) -> Option<ErrorRecord> {
+        self.errors.last()
+    }
+
+    pub(crate) fn set_error_callback(&self, callback: Option<ErrorCallback>) {
+        self.errors.set_callback(callback);
+    }
+
+    pub(crate) fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    pub(crate) fn observers(&self) -> Observers {
+        self.observers.clone()
+    }
+
+    pub(crate) fn ack_timeout(&self) -> Option<Duration> {
+        *self.ack_timeout.read().unwrap()
+    }
+
+    pub(crate) fn set_ack_timeout(&self, timeout: Option<Duration>) {
+        *self.ack_timeout.write().unwrap() = timeout;
+    }
+
+    pub fn wait_changed_value(&self, thread_id: u32) -> (u32, Box<dyn SignalValue>) {
         loop {
             if let Some(val) = self.values.lock().unwrap().get(thread_id) {
                 return val;
@@ -132,4 +241,72 @@ impl ChangedValues {
             self.event.wait_lock();
         }
     }
+
+    // Same as wait_changed_value, but None is returned when the thread was retired. The waiting
+    // threads are not woken by the retirement, so they check it periodically.
+    pub(crate) fn wait_worker_value(&self, thread_id: u32) -> Option<(u32, Box<dyn SignalValue>)> {
+        loop {
+            {
+                let mut values = self.values.lock().unwrap();
+                if values.retired.remove(&thread_id) {
+                    values.release(thread_id, &self.event);
+                    return None;
+                }
+                if let Some(val) = values.get(thread_id) {
+                    return Some(val);
+                }
+            }
+            self.event.wait_timeout_lock(WORKER_POLL);
+        }
+    }
+
+    // the thread stops after its current value, the values of the same id continue in order
+    pub(crate) fn retire_worker(&self, thread_id: u32) {
+        self.values.lock().unwrap().retired.insert(thread_id);
+    }
+}
+
+// Calls of the python callbacks. They run one after another in their own thread with the GIL
+// acquired for every call, so the thread which received the value is not blocked by python.
+type Observer = Box<dyn FnOnce(Python) + Send>;
+
+#[derive(Clone)]
+pub(crate) struct Observers {
+    channel: Sender<Observer>,
+}
+
+impl Observers {
+    pub(crate) fn new() -> Self {
+        let (channel, rx) = mpsc::channel::<Observer>();
+        let observers_thread = thread::Builder::new().name("Observers".to_string());
+        let _ = observers_thread.spawn(move || {
+            for observer in rx {
+                Python::with_gil(observer);
+            }
+        });
+
+        Self { channel }
+    }
+
+    pub(crate) fn dispatch(&self, observer: impl FnOnce(Python) + Send + 'static) {
+        let _ = self.channel.send(Box::new(observer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drain_typed_returns_latest_value() {
+        let signals = ChangedValues::new();
+        signals.set(3, 1i64);
+        signals.set(3, 2i64);
+        assert_eq!(signals.drain_typed::<i64>(3).unwrap(), vec![2]);
+        assert!(signals.drain_typed::<i64>(3).unwrap().is_empty());
+
+        signals.set(3, 1.5f64);
+        assert!(signals.drain_typed::<i64>(3).is_err());
+        assert!(signals.drain_typed::<f64>(3).unwrap().is_empty());
+    }
 }
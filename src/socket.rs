@@ -0,0 +1,544 @@
+use std::fmt;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+
+#[cfg(feature = "encryption")]
+use crate::secure::{PresharedKey, Secure};
+
+/// Address of the server, TCP address, host name with the port or path of the Unix domain socket.
+///
+/// The host name is resolved on every connection, all resolved addresses are tried in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Host(String, u16),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// Address from the IP address or the host name, the IP address is not resolved.
+    pub fn host(host: &str, port: u16) -> Self {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Self::Tcp(SocketAddr::new(ip, port)),
+            Err(_) => Self::Host(host.to_string(), port),
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+impl From<SocketAddrV4> for Address {
+    fn from(addr: SocketAddrV4) -> Self {
+        Self::Tcp(addr.into())
+    }
+}
+
+impl From<SocketAddrV6> for Address {
+    fn from(addr: SocketAddrV6) -> Self {
+        Self::Tcp(addr.into())
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Host(host, port) => write!(f, "{}:{}", host, port),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Options of the TCP socket, they are ignored for the Unix domain socket. None keeps
+/// the default of the system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Disable the Nagle algorithm, so the small messages are sent immediately.
+    pub nodelay: bool,
+    /// Size of the send buffer in bytes.
+    pub send_buffer: Option<usize>,
+    /// Size of the receive buffer in bytes.
+    pub recv_buffer: Option<usize>,
+    /// Idle time before the keepalive probes are sent, None disables the keepalive.
+    pub keepalive: Option<Duration>,
+}
+
+/// SOCKS5 proxy between the client and the server, for example the dynamic forwarding of ssh
+/// (`ssh -D`) on the jump host. The host name of the server is resolved by the proxy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    /// Address of the proxy.
+    pub addr: Address,
+    /// User name and password, None connects without the authentication.
+    pub credentials: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    pub fn new(addr: impl Into<Address>) -> Self {
+        Self {
+            addr: addr.into(),
+            credentials: None,
+        }
+    }
+}
+
+// Connection to the other side, the messages are the same for both kinds of sockets.
+pub(crate) struct Stream {
+    socket: Socket,
+    #[cfg(feature = "encryption")]
+    secure: Option<Secure>,
+}
+
+impl Stream {
+    fn new(socket: Socket) -> Self {
+        Self {
+            socket,
+            #[cfg(feature = "encryption")]
+            secure: None,
+        }
+    }
+
+    pub(crate) fn connect(addr: &Address) -> io::Result<Self> {
+        Socket::connect(addr).map(Self::new)
+    }
+
+    /// Connect to the address through the SOCKS5 proxy.
+    pub(crate) fn connect_socks5(proxy: &Socks5Proxy, addr: &Address) -> io::Result<Self> {
+        let mut socket = Socket::connect(&proxy.addr)?;
+        socks5_handshake(&mut socket, proxy.credentials.as_ref(), addr)?;
+        Ok(Self::new(socket))
+    }
+
+    /// Use the stream connected by the user.
+    pub(crate) fn from_tcp(stream: TcpStream) -> Self {
+        Self::new(Socket::Tcp(stream))
+    }
+
+    /// Encrypt all following data with the key, both sides has to call it right after
+    /// the connection.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn secure(&mut self, key: &PresharedKey, server: bool) -> io::Result<()> {
+        self.secure = Some(Secure::handshake(&mut self.socket, key, server)?);
+        Ok(())
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            socket: self.socket.try_clone()?,
+            #[cfg(feature = "encryption")]
+            secure: self.secure.as_ref().map(Secure::split),
+        })
+    }
+
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.shutdown(how)
+    }
+
+    pub(crate) fn set_tcp_options(&self, options: &TcpOptions) -> io::Result<()> {
+        self.socket.set_tcp_options(options)
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.socket.peer_addr()
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "encryption")]
+        if let Some(ref mut secure) = self.secure {
+            return secure.read(&mut self.socket, buf);
+        }
+        self.socket.read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    // with the encryption every call is written as one sealed record of the limited size
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        #[cfg(feature = "encryption")]
+        if let Some(ref mut secure) = self.secure {
+            return secure.write(&mut self.socket, bufs);
+        }
+        self.socket.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    fn connect(addr: &Address) -> io::Result<Self> {
+        match addr {
+            Address::Tcp(addr) => TcpStream::connect(addr).map(Self::Tcp),
+            Address::Host(host, port) => TcpStream::connect((host.as_str(), *port)).map(Self::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => UnixStream::connect(path).map(Self::Unix),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    fn set_tcp_options(&self, options: &TcpOptions) -> io::Result<()> {
+        let stream = match self {
+            Self::Tcp(stream) => stream,
+            #[cfg(unix)]
+            Self::Unix(_) => return Ok(()),
+        };
+
+        stream.set_nodelay(options.nodelay)?;
+        let socket = SockRef::from(stream);
+        if let Some(size) = options.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = options.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        match options.keepalive {
+            Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time)),
+            None => socket.set_keepalive(false),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    // clients of the Unix socket have no address
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write_vectored(bufs),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+impl Listener {
+    // The socket file left by the previous server is removed, otherwise the bind fails. Only
+    // the socket without any server listening on it is removed, never the other files.
+    pub(crate) fn bind(addr: &Address) -> io::Result<Self> {
+        match addr {
+            Address::Tcp(addr) => TcpListener::bind(addr).map(Self::Tcp),
+            Address::Host(host, port) => TcpListener::bind((host.as_str(), *port)).map(Self::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if !metadata.file_type().is_socket() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("Path {} exists and is not a socket.", path.display()),
+                        ));
+                    }
+                    if UnixStream::connect(path).is_ok() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("Socket {} is used by other server.", path.display()),
+                        ));
+                    }
+                    std::fs::remove_file(path)?;
+                }
+                UnixListener::bind(path).map(Self::Unix)
+            }
+        }
+    }
+
+    // The listener is polled, so the accept ends soon after the server is stopped without
+    // any connection. None is returned if the server is not running anymore.
+    pub(crate) fn accept_while(&self, running: impl Fn() -> bool) -> io::Result<Option<Stream>> {
+        self.set_nonblocking(true)?;
+        while running() {
+            match self.accept() {
+                Ok(stream) => return Ok(Some(stream)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    // the accepted stream can inherit the non blocking mode of the listener on some systems
+    fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                Ok(Stream::new(Socket::Tcp(stream)))
+            }),
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                Ok(Stream::new(Socket::Unix(stream)))
+            }),
+        }
+    }
+}
+
+/*
+    SOCKS5 (RFC 1928) with the username and password authentication (RFC 1929), only the CONNECT
+    command is used. After the reply of the proxy the stream continues directly to the server.
+*/
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const PASSWORD_AUTHENTICATION: u8 = 2;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+fn proxy_error(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message.into())
+}
+
+fn socks5_handshake<S: Read + Write>(
+    stream: &mut S,
+    credentials: Option<&(String, String)>,
+    target: &Address,
+) -> io::Result<()> {
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0];
+    let port = match target {
+        Address::Tcp(SocketAddr::V4(addr)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Address::Tcp(SocketAddr::V6(addr)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Address::Host(host, port) => {
+            let size = u8::try_from(host.len()).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Host name is too long for SOCKS5.",
+                )
+            })?;
+            request.push(ATYP_DOMAIN);
+            request.push(size);
+            request.extend_from_slice(host.as_bytes());
+            *port
+        }
+        #[cfg(unix)]
+        Address::Unix(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unix domain socket can not be reached through the proxy.",
+            ));
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+
+    // choose the authentication
+    let method = match credentials {
+        Some(_) => PASSWORD_AUTHENTICATION,
+        None => NO_AUTHENTICATION,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(proxy_error("Proxy is not the SOCKS5 proxy."));
+    }
+    if reply[1] != method {
+        return Err(proxy_error(
+            "Proxy does not accept the authentication method.",
+        ));
+    }
+
+    if let Some((user, password)) = credentials {
+        let (user_size, password_size) =
+            match (u8::try_from(user.len()), u8::try_from(password.len())) {
+                (Ok(user_size), Ok(password_size)) => (user_size, password_size),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "User name and password of the proxy have to be shorter than 256 bytes.",
+                    ))
+                }
+            };
+        let mut auth = vec![1, user_size];
+        auth.extend_from_slice(user.as_bytes());
+        auth.push(password_size);
+        auth.extend_from_slice(password.as_bytes());
+        stream.write_all(&auth)?;
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(proxy_error("Proxy rejected the user name or the password."));
+        }
+    }
+
+    // connect to the server
+    stream.write_all(&request)?;
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head)?;
+    if head[0] != SOCKS_VERSION {
+        return Err(proxy_error("Proxy is not the SOCKS5 proxy."));
+    }
+    if head[1] != 0 {
+        let reason = match head[1] {
+            1 => "general failure",
+            2 => "connection not allowed by the rules",
+            3 => "network unreachable",
+            4 => "host unreachable",
+            5 => "connection refused",
+            6 => "TTL expired",
+            7 => "command not supported",
+            8 => "address type not supported",
+            _ => "unknown error",
+        };
+        return Err(proxy_error(format!(
+            "Proxy failed to connect to {}: {}",
+            target, reason
+        )));
+    }
+
+    // the bound address of the proxy is not used
+    let size = match head[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut size = [0u8; 1];
+            stream.read_exact(&mut size)?;
+            size[0] as usize
+        }
+        _ => return Err(proxy_error("Invalid reply of the proxy.")),
+    };
+    let mut bound = vec![0u8; size + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_stale_socket_is_removed() {
+        let dir = std::env::temp_dir().join(format!("egui_pysync_bind_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let file = dir.join("file");
+        std::fs::write(&file, b"data").unwrap();
+        let error = Listener::bind(&Address::Unix(file.clone())).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&file).unwrap(), b"data");
+
+        let socket = dir.join("socket");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let error = Listener::bind(&Address::Unix(socket.clone()))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        drop(listener);
+        assert!(Listener::bind(&Address::Unix(socket)).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,12 +4,14 @@ use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
+use crate::bitset::{BitsetUpdate, ValueBitset};
 use crate::dict::{DictUpdate, ValueDict};
 use crate::graphs::GraphElement;
 use crate::graphs::{GraphUpdate, ValueGraphs};
 use crate::image::{ImageUpdate, ValueImage};
 use crate::list::{ListUpdate, ValueList};
-use crate::transport::WriteMessage;
+use crate::schema::{Schema, WireType};
+use crate::transport::{WriteMessage, MAX_VALUE_ID, NAMESPACE_SHIFT, RESERVED_IDS};
 use crate::values::{Signal, UpdateValueClient, Value, ValueStatic};
 use crate::NoHashMap;
 
@@ -21,6 +23,7 @@ pub(crate) struct ValuesList {
     pub(crate) dicts: NoHashMap<u32, Arc<dyn DictUpdate>>,
     pub(crate) lists: NoHashMap<u32, Arc<dyn ListUpdate>>,
     pub(crate) graphs: NoHashMap<u32, Arc<dyn GraphUpdate>>,
+    pub(crate) bitsets: NoHashMap<u32, Arc<dyn BitsetUpdate>>,
 }
 
 impl ValuesList {
@@ -32,6 +35,7 @@ impl ValuesList {
             dicts: NoHashMap::default(),
             lists: NoHashMap::default(),
             graphs: NoHashMap::default(),
+            bitsets: NoHashMap::default(),
         }
     }
 
@@ -42,50 +46,133 @@ impl ValuesList {
         self.dicts.shrink_to_fit();
         self.lists.shrink_to_fit();
         self.graphs.shrink_to_fit();
+        self.bitsets.shrink_to_fit();
     }
 }
 
 pub struct ValuesCreator {
     counter: u32,
+    reserved: u32,
+    namespace: u8,
+    namespaces: NoHashMap<u8, u32>, // counters of the other namespaces
     val: ValuesList,
     version: u64,
     channel: Sender<WriteMessage>,
+    schema: Schema,
 }
 
 impl ValuesCreator {
     pub(crate) fn new(channel: Sender<WriteMessage>) -> Self {
         Self {
-            counter: 9, // first 10 values are reserved for special values
+            counter: RESERVED_IDS - 1, // first ids are reserved for special values
+            reserved: RESERVED_IDS,
+            namespace: 0,
+            namespaces: NoHashMap::default(),
             val: ValuesList::new(),
             version: 0,
             channel,
+            schema: Schema::new(),
         }
     }
 
     fn get_id(&mut self) -> u32 {
-        if self.counter > 16777215 {
-            panic!("id counter overflow, id is 24bit long");
+        if self.counter >= MAX_VALUE_ID {
+            panic!(
+                "id counter overflow, id is 24bit long and the maximum id is {}",
+                MAX_VALUE_ID
+            );
         }
         self.counter += 1;
-        self.counter
+        (self.namespace as u32) << NAMESPACE_SHIFT | self.counter
     }
 
-    pub(crate) fn get_values(self) -> (ValuesList, u64) {
+    /// Set the number of ids reserved for special values. Default is 10.
+    ///
+    /// It has to be called before any value is added and the server has to use the same number,
+    /// otherwise the handshake fails.
+    pub fn set_reserved_ids(&mut self, reserved: u32) {
+        let unused = |counter: &u32| *counter == self.reserved - 1;
+        if !unused(&self.counter) || !self.namespaces.values().all(unused) {
+            panic!("reserved ids can be set only before any value is added");
+        }
+        if reserved < RESERVED_IDS {
+            panic!("at least {} ids have to be reserved", RESERVED_IDS);
+        }
+
+        self.reserved = reserved;
+        self.counter = reserved - 1;
+        self.namespaces
+            .values_mut()
+            .for_each(|counter| *counter = reserved - 1);
+    }
+
+    /// Add all following values to the namespace, 0 is the default namespace.
+    ///
+    /// Every namespace has its own ids, so independent modules can register their values
+    /// without knowing about each other and share one connection. The namespace is the highest
+    /// byte of the id. The server has to register the same values to the same namespaces.
+    pub fn set_namespace(&mut self, namespace: u8) {
+        if namespace == self.namespace {
+            return;
+        }
+        self.namespaces.insert(self.namespace, self.counter);
+        self.namespace = namespace;
+        self.counter = self
+            .namespaces
+            .remove(&namespace)
+            .unwrap_or(self.reserved - 1); // ids of every namespace start after the reserved ones
+    }
+
+    // values, version, reserved ids and the layout hash for the handshake
+    pub(crate) fn get_values(self) -> (ValuesList, u64, u32, u64) {
+        let layout = self.schema.hash(self.version, self.reserved);
         let mut val = self.val;
         val.shrink();
-        (val, self.version)
+        (val, self.version, self.reserved, layout)
     }
 
     pub fn set_version(&mut self, version: u64) {
         self.version = version;
     }
 
+    /// Validate the registered values without connecting to the server.
+    ///
+    /// Returns the layout hash, which is the same as the hash of the server values creator
+    /// if the values were registered in the same order with the same types. The layout hash is
+    /// sent in the handshake, so the server rejects the client with different values.
+    pub fn validate(&self) -> Result<u64, Vec<String>> {
+        self.schema.validate(self.version, self.reserved)
+    }
+
+    /// Get how the value with given id is sent on the wire, None if the id is not registered.
+    pub fn wire_type(&self, id: u32) -> Option<WireType> {
+        self.schema.wire_type(id)
+    }
+
     pub fn add_value<T>(&mut self, value: T) -> Arc<Value<T>>
     where
         T: for<'a> Deserialize<'a> + Serialize + Send + Sync + Clone + 'static,
     {
         let id = self.get_id();
-        let value = Value::new(id, value, self.channel.clone());
+        let value = Value::new(id, value, self.channel.clone(), true);
+        self.schema.add::<T>(id, "value");
+
+        self.val.values.insert(id, value.clone());
+        value
+    }
+
+    /// Add the value which does not acknowledge the updates from the server.
+    ///
+    /// It saves the return traffic for values which are not written by the client. The server
+    /// has to add the value by `ServerValuesCreator::add_value_without_ack`, otherwise it waits
+    /// for the acknowledges forever and ignores all client writes.
+    pub fn add_value_without_ack<T>(&mut self, value: T) -> Arc<Value<T>>
+    where
+        T: for<'a> Deserialize<'a> + Serialize + Send + Sync + Clone + 'static,
+    {
+        let id = self.get_id();
+        let value = Value::new(id, value, self.channel.clone(), false);
+        self.schema.add::<T>(id, "value_without_ack");
 
         self.val.values.insert(id, value.clone());
         value
@@ -97,6 +184,7 @@ impl ValuesCreator {
     {
         let id = self.get_id();
         let value = ValueStatic::new(id, value);
+        self.schema.add::<T>(id, "static");
 
         self.val.static_values.insert(id, value.clone());
         value
@@ -105,6 +193,7 @@ impl ValuesCreator {
     pub fn add_image(&mut self) -> Arc<ValueImage> {
         let id = self.get_id();
         let value = ValueImage::new(id);
+        self.schema.add_untyped(id, "image");
 
         self.val.images.insert(id, value.clone());
         value
@@ -115,29 +204,65 @@ impl ValuesCreator {
         T: Serialize + Clone + Send + Sync + 'static,
     {
         let id = self.get_id();
-        let signal = Signal::new(id, self.channel.clone());
-
-        signal
+        self.schema.add::<T>(id, "signal");
+        Signal::new(id, self.channel.clone())
     }
 
+    /// Add the dict which is changed only by the server, the server rejects the changes
+    /// of the client and sends its dict back.
     pub fn add_dict<K, V>(&mut self) -> Arc<ValueDict<K, V>>
     where
         K: Hash + Eq + Clone + for<'a> Deserialize<'a> + Send + Sync + 'static,
         V: Clone + for<'a> Deserialize<'a> + Send + Sync + 'static,
     {
         let id = self.get_id();
-        let value = ValueDict::new(id);
+        let value = ValueDict::new(id, self.channel.clone(), false);
+        self.schema.add::<(K, V)>(id, "dict");
+
+        self.val.dicts.insert(id, value.clone());
+        value
+    }
+
+    /// Add the dict which the client can change, it acknowledges the changes from the server.
+    ///
+    /// The server has to add the dict by `ServerValuesCreator::add_dict_writable`.
+    pub fn add_dict_writable<K, V>(&mut self) -> Arc<ValueDict<K, V>>
+    where
+        K: Hash + Eq + Clone + for<'a> Deserialize<'a> + Send + Sync + 'static,
+        V: Clone + for<'a> Deserialize<'a> + Send + Sync + 'static,
+    {
+        let id = self.get_id();
+        let value = ValueDict::new(id, self.channel.clone(), true);
+        self.schema.add::<(K, V)>(id, "dict_writable");
 
         self.val.dicts.insert(id, value.clone());
         value
     }
 
+    /// Add the list which is changed only by the server, the server rejects the changes
+    /// of the client and sends its list back.
     pub fn add_list<T>(&mut self) -> Arc<ValueList<T>>
     where
         T: Clone + for<'a> Deserialize<'a> + Send + Sync + 'static,
     {
         let id = self.get_id();
-        let value = ValueList::new(id);
+        let value = ValueList::new(id, self.channel.clone(), false);
+        self.schema.add::<T>(id, "list");
+
+        self.val.lists.insert(id, value.clone());
+        value
+    }
+
+    /// Add the list which the client can change, it acknowledges the changes from the server.
+    ///
+    /// The server has to add the list by `ServerValuesCreator::add_list_writable`.
+    pub fn add_list_writable<T>(&mut self) -> Arc<ValueList<T>>
+    where
+        T: Clone + for<'a> Deserialize<'a> + Send + Sync + 'static,
+    {
+        let id = self.get_id();
+        let value = ValueList::new(id, self.channel.clone(), true);
+        self.schema.add::<T>(id, "list_writable");
 
         self.val.lists.insert(id, value.clone());
         value
@@ -149,8 +274,18 @@ impl ValuesCreator {
     {
         let id = self.get_id();
         let value = ValueGraphs::new(id);
+        self.schema.add::<T>(id, "graphs");
 
         self.val.graphs.insert(id, value.clone());
         value
     }
+
+    pub fn add_bitset(&mut self) -> Arc<ValueBitset> {
+        let id = self.get_id();
+        let value = ValueBitset::new(id);
+        self.schema.add_untyped(id, "bitset");
+
+        self.val.bitsets.insert(id, value.clone());
+        value
+    }
 }
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::hash::Hash;
 use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::Sender;
@@ -7,18 +8,20 @@ use pyo3::buffer::Element;
 use pyo3::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::bitset::server::PyValueBitset;
 use crate::dict::server::{PyDictTrait, PyValueDict};
 use crate::graphs::server::{PyGraphTrait, PyValueGraphs};
 use crate::graphs::GraphElement;
 use crate::image::server::PyValueImage;
 use crate::list::server::{PyListTrait, PyValueList};
 use crate::python_convert::ToPython;
-use crate::server::{Acknowledge, SyncTrait};
-use crate::signals::ChangedValues;
-use crate::transport::WriteMessage;
+use crate::schema::{Schema, WireType};
+use crate::server::{Acknowledge, RateLimit, SyncTrait};
+use crate::signals::{ChangedValues, Observers};
+use crate::transport::{WriteMessage, MAX_VALUE_ID, NAMESPACE_SHIFT, RESERVED_IDS};
 use crate::values::server::{PySignal, PyValue, PyValueStatic};
 use crate::values::server::{PySignalTrait, PyValueStaticTrait, PyValueTrait, UpdateValueServer};
-use crate::NoHashMap;
+use crate::{NoHashMap, NoHashSet};
 
 #[derive(Clone)]
 pub(crate) struct PyValuesList {
@@ -29,6 +32,9 @@ pub(crate) struct PyValuesList {
     pub(crate) dicts: NoHashMap<u32, Arc<dyn PyDictTrait>>,
     pub(crate) lists: NoHashMap<u32, Arc<dyn PyListTrait>>,
     pub(crate) graphs: NoHashMap<u32, Arc<dyn PyGraphTrait>>,
+    pub(crate) bitsets: NoHashMap<u32, Arc<PyValueBitset>>,
+    pub(crate) groups: HashMap<String, Vec<u32>>, // ids of all values in the group
+    pub(crate) schema: Schema,
 }
 
 impl PyValuesList {
@@ -41,6 +47,9 @@ impl PyValuesList {
             dicts: NoHashMap::default(),
             lists: NoHashMap::default(),
             graphs: NoHashMap::default(),
+            bitsets: NoHashMap::default(),
+            groups: HashMap::new(),
+            schema: Schema::new(),
         }
     }
 
@@ -51,6 +60,8 @@ impl PyValuesList {
         self.dicts.shrink_to_fit();
         self.lists.shrink_to_fit();
         self.graphs.shrink_to_fit();
+        self.bitsets.shrink_to_fit();
+        self.groups.shrink_to_fit();
     }
 }
 
@@ -59,6 +70,11 @@ pub(crate) struct ValuesList {
     pub(crate) updated: NoHashMap<u32, Arc<dyn UpdateValueServer>>,
     pub(crate) ack: NoHashMap<u32, Arc<dyn Acknowledge>>,
     pub(crate) sync: NoHashMap<u32, Arc<dyn SyncTrait>>,
+    pub(crate) dicts: NoHashMap<u32, Arc<dyn UpdateValueServer>>, // changed by the clients
+    pub(crate) lists: NoHashMap<u32, Arc<dyn UpdateValueServer>>,
+    pub(crate) limits: NoHashMap<u32, RateLimit>,
+    pub(crate) groups: HashMap<String, Vec<u32>>, // for the subscriptions of the clients
+    pub(crate) read_only: NoHashSet<u32>, // values, dicts and lists which the clients can not write
 }
 
 impl ValuesList {
@@ -67,6 +83,11 @@ impl ValuesList {
             updated: NoHashMap::default(),
             ack: NoHashMap::default(),
             sync: NoHashMap::default(),
+            dicts: NoHashMap::default(),
+            lists: NoHashMap::default(),
+            limits: NoHashMap::default(),
+            groups: HashMap::new(),
+            read_only: NoHashSet::default(),
         }
     }
 
@@ -74,6 +95,10 @@ impl ValuesList {
         self.updated.shrink_to_fit();
         self.ack.shrink_to_fit();
         self.sync.shrink_to_fit();
+        self.dicts.shrink_to_fit();
+        self.lists.shrink_to_fit();
+        self.limits.shrink_to_fit();
+        self.read_only.shrink_to_fit();
     }
 }
 
@@ -81,11 +106,17 @@ pub struct ServerValuesCreator {
     channel: Sender<WriteMessage>,
     connected: Arc<AtomicBool>,
     signals: ChangedValues,
+    observers: Observers,
 
     version: u64,
     counter: u32,
+    reserved: u32,
+    namespace: u8,
+    namespaces: NoHashMap<u8, u32>, // counters of the other namespaces
     val: ValuesList,
     py_val: PyValuesList,
+    schema: Schema,
+    group: Option<String>,
 }
 
 impl ServerValuesCreator {
@@ -97,40 +128,223 @@ impl ServerValuesCreator {
         Self {
             channel,
             connected,
+            observers: signals.observers(),
             signals,
 
             version: 0,
-            counter: 9, // first 10 values are reserved for special values
+            counter: RESERVED_IDS - 1, // first ids are reserved for special values
+            reserved: RESERVED_IDS,
+            namespace: 0,
+            namespaces: NoHashMap::default(),
             val: ValuesList::new(),
             py_val: PyValuesList::new(),
+            schema: Schema::new(),
+            group: None,
         }
     }
 
+    /// Create the values creator which is not connected to any server.
+    ///
+    /// It can be used to register the values and validate them, for example in tests.
+    pub fn dry_run() -> Self {
+        let (channel, _) = std::sync::mpsc::channel();
+        Self::new(
+            channel,
+            Arc::new(AtomicBool::new(false)),
+            ChangedValues::new(),
+        )
+    }
+
     fn get_id(&mut self) -> u32 {
-        if self.counter > 16777215 {
-            panic!("id counter overflow, id is 24bit long");
+        if self.counter >= MAX_VALUE_ID {
+            panic!(
+                "id counter overflow, id is 24bit long and the maximum id is {}",
+                MAX_VALUE_ID
+            );
         }
         self.counter += 1;
-        self.counter
+        let id = (self.namespace as u32) << NAMESPACE_SHIFT | self.counter;
+        if let Some(ref group) = self.group {
+            let ids = self.py_val.groups.entry(group.clone()).or_default();
+            ids.push(id);
+        }
+        id
     }
 
-    pub(crate) fn get_values(self) -> (ValuesList, PyValuesList, u64) {
+    pub(crate) fn get_values(self) -> (ValuesList, PyValuesList, u64, u32) {
         let Self {
             mut val,
             mut py_val,
+            schema,
             ..
         } = self;
         val.shrink();
         py_val.shrink();
+        py_val.schema = schema;
+        val.groups = py_val.groups.clone();
 
-        (val, py_val, self.version)
+        (val, py_val, self.version, self.reserved)
+    }
+
+    /// Get the handle to the signals, so they can be read also from rust.
+    pub fn signals(&self) -> ChangedValues {
+        self.signals.clone()
     }
 
     pub fn set_version(&mut self, version: u64) {
         self.version = version;
     }
 
+    /// Validate the registered values without starting the server.
+    ///
+    /// Returns the layout hash, which is the same as the hash of the client values creator
+    /// if the values were registered in the same order with the same types. The client with
+    /// a different layout hash is rejected in the handshake.
+    pub fn validate(&self) -> Result<u64, Vec<String>> {
+        self.schema.validate(self.version, self.reserved)
+    }
+
+    /// Get how the value with given id is sent on the wire, None if the id is not registered.
+    pub fn wire_type(&self, id: u32) -> Option<WireType> {
+        self.schema.wire_type(id)
+    }
+
+    /// Set the group for all values added after this call. None stops adding values to a group.
+    ///
+    /// Values in the group can be reset or synced together from python.
+    pub fn set_group(&mut self, group: Option<&str>) {
+        self.group = group.map(|group| group.to_string());
+    }
+
+    /// Set the number of ids reserved for special values. Default is 10.
+    ///
+    /// It has to be called before any value is added and the client has to use the same number,
+    /// otherwise the handshake fails.
+    pub fn set_reserved_ids(&mut self, reserved: u32) {
+        let unused = |counter: &u32| *counter == self.reserved - 1;
+        if !unused(&self.counter) || !self.namespaces.values().all(unused) {
+            panic!("reserved ids can be set only before any value is added");
+        }
+        if reserved < RESERVED_IDS {
+            panic!("at least {} ids have to be reserved", RESERVED_IDS);
+        }
+
+        self.reserved = reserved;
+        self.counter = reserved - 1;
+        self.namespaces
+            .values_mut()
+            .for_each(|counter| *counter = reserved - 1);
+    }
+
+    /// Add all following values to the namespace, 0 is the default namespace.
+    ///
+    /// Every namespace has its own ids, so independent modules can register their values
+    /// without knowing about each other and share one connection. The namespace is the highest
+    /// byte of the id. The client has to register the same values to the same namespaces.
+    pub fn set_namespace(&mut self, namespace: u8) {
+        if namespace == self.namespace {
+            return;
+        }
+        self.namespaces.insert(self.namespace, self.counter);
+        self.namespace = namespace;
+        self.counter = self
+            .namespaces
+            .remove(&namespace)
+            .unwrap_or(self.reserved - 1); // ids of every namespace start after the reserved ones
+    }
+
+    /// Limit the messages of the value with given id written to the client, None removes
+    /// the limit. The messages above the limit are delayed, so the other values are not blocked
+    /// by the high rate values.
+    pub fn set_rate_limit(&mut self, id: u32, limit: Option<RateLimit>) {
+        if self.schema.wire_type(id).is_none() {
+            panic!("value with id {} is not registered", id);
+        }
+
+        match limit {
+            Some(limit) => {
+                let rate = limit.rate();
+                if rate.is_nan() || rate <= 0.0 {
+                    panic!("rate limit of the value {} has to be greater than 0", id);
+                }
+                self.val.limits.insert(id, limit);
+            }
+            None => {
+                self.val.limits.remove(&id);
+            }
+        }
+    }
+
     pub fn add_value<T>(&mut self, value: T)
+    where
+        T: ToPython
+            + for<'py> FromPyObject<'py>
+            + Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + 'static,
+    {
+        self.insert_value(value, true);
+    }
+
+    /// Add the value for which the client does not send the acknowledges.
+    ///
+    /// The client has to add the value by `ValuesCreator::add_value_without_ack`. The server
+    /// does not wait for the acknowledges, so the client writes are never ignored, also when
+    /// they cross with the values sent by the server.
+    pub fn add_value_without_ack<T>(&mut self, value: T)
+    where
+        T: ToPython
+            + for<'py> FromPyObject<'py>
+            + Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + 'static,
+    {
+        self.insert_value(value, false);
+    }
+
+    /// Add the value which is only sent to the client, for example the calibration constant.
+    ///
+    /// The server rejects all writes of the value from the clients, replies with the error
+    /// and sends its value back. The client adds the value by `ValuesCreator::add_value`.
+    pub fn add_value_read_only<T>(&mut self, value: T)
+    where
+        T: ToPython
+            + for<'py> FromPyObject<'py>
+            + Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + 'static,
+    {
+        let value = self.insert_value(value, true);
+        self.val.read_only.insert(value.id());
+    }
+
+    /// Add the value with the validator of the values written by the client.
+    ///
+    /// The validator returns `Ok(None)` to accept the value, `Ok(Some(value))` to apply
+    /// the corrected value instead, for example clamped to the range, or `Err` with the reason
+    /// to reject it. The corrected and the rejected values are not shown by the client, the server
+    /// sends its value back. It is called before the python transform and validator, without
+    /// the GIL.
+    pub fn add_value_with_validator<T>(
+        &mut self,
+        value: T,
+        validator: impl Fn(&T) -> Result<Option<T>, String> + Send + Sync + 'static,
+    ) where
+        T: ToPython
+            + for<'py> FromPyObject<'py>
+            + Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + 'static,
+    {
+        let value = self.insert_value(value, true);
+        value.set_validator(Box::new(validator));
+    }
+
+    fn insert_value<T>(&mut self, value: T, ack: bool) -> Arc<PyValue<T>>
     where
         T: ToPython
             + for<'py> FromPyObject<'py>
@@ -140,18 +354,28 @@ impl ServerValuesCreator {
             + 'static,
     {
         let id = self.get_id();
+        let kind = match ack {
+            true => "value",
+            false => "value_without_ack",
+        };
+        self.schema.add::<T>(id, kind);
         let value = PyValue::new(
             id,
             value,
             self.channel.clone(),
             self.connected.clone(),
             self.signals.clone(),
+            self.observers.clone(),
+            ack,
         );
 
         self.py_val.values.insert(id, value.clone());
         self.val.updated.insert(id, value.clone());
         self.val.sync.insert(id, value.clone());
-        self.val.ack.insert(id, value);
+        if ack {
+            self.val.ack.insert(id, value.clone());
+        }
+        value
     }
 
     pub fn add_static<T>(&mut self, value: T)
@@ -159,6 +383,7 @@ impl ServerValuesCreator {
         T: ToPython + for<'py> FromPyObject<'py> + Serialize + Clone + 'static,
     {
         let id = self.get_id();
+        self.schema.add::<T>(id, "static");
         let value = PyValueStatic::new(id, value, self.channel.clone(), self.connected.clone());
 
         self.py_val.static_values.insert(id, value.clone());
@@ -171,6 +396,7 @@ impl ServerValuesCreator {
         &mut self,
     ) {
         let id = self.get_id();
+        self.schema.add::<T>(id, "signal");
         let signal = PySignal::<T>::new(id, self.signals.clone());
 
         self.py_val.signals.insert(id, signal.clone());
@@ -179,33 +405,129 @@ impl ServerValuesCreator {
 
     pub fn add_image(&mut self) {
         let id = self.get_id();
+        self.schema.add_untyped(id, "image");
         let image = PyValueImage::new(id, self.channel.clone(), self.connected.clone());
 
         self.py_val.images.insert(id, image.clone());
         self.val.sync.insert(id, image);
     }
 
+    /// Add the dict which is only changed by the server, the server rejects the changes
+    /// from the clients and sends its dict back.
     pub fn add_dict<K, V>(&mut self)
     where
-        K: ToPython + for<'py> FromPyObject<'py> + Serialize + Eq + Hash + 'static,
-        V: ToPython + for<'py> FromPyObject<'py> + Serialize + 'static,
+        K: ToPython
+            + for<'py> FromPyObject<'py>
+            + Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + Eq
+            + Hash
+            + 'static,
+        V: ToPython + for<'py> FromPyObject<'py> + Serialize + for<'a> Deserialize<'a> + 'static,
     {
         let id = self.get_id();
-        let dict = PyValueDict::<K, V>::new(id, self.channel.clone(), self.connected.clone());
+        self.schema.add::<(K, V)>(id, "dict");
+        let dict = PyValueDict::<K, V>::new(
+            id,
+            self.channel.clone(),
+            self.connected.clone(),
+            self.signals.clone(),
+            false,
+        );
 
         self.py_val.dicts.insert(id, dict.clone());
         self.val.sync.insert(id, dict);
+        self.val.read_only.insert(id);
     }
 
+    /// Add the dict which the clients can change.
+    ///
+    /// The client has to add the dict by `ValuesCreator::add_dict_writable`, it acknowledges
+    /// the changes of the server. The change of the client which did not acknowledge all changes
+    /// of the server yet is dropped and the client gets the whole dict again.
+    pub fn add_dict_writable<K, V>(&mut self)
+    where
+        K: ToPython
+            + for<'py> FromPyObject<'py>
+            + Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + Eq
+            + Hash
+            + 'static,
+        V: ToPython + for<'py> FromPyObject<'py> + Serialize + for<'a> Deserialize<'a> + 'static,
+    {
+        let id = self.get_id();
+        self.schema.add::<(K, V)>(id, "dict_writable");
+        let dict = PyValueDict::<K, V>::new(
+            id,
+            self.channel.clone(),
+            self.connected.clone(),
+            self.signals.clone(),
+            true,
+        );
+
+        self.py_val.dicts.insert(id, dict.clone());
+        self.val.dicts.insert(id, dict.clone());
+        self.val.ack.insert(id, dict.clone());
+        self.val.sync.insert(id, dict);
+    }
+
+    /// Add the list which is only changed by the server, the server rejects the changes
+    /// from the clients and sends its list back.
     pub fn add_list<T>(&mut self)
     where
-        T: ToPython + for<'py> FromPyObject<'py> + Serialize + Clone + 'static,
+        T: ToPython
+            + for<'py> FromPyObject<'py>
+            + Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + 'static,
     {
         let id = self.get_id();
-        let list = PyValueList::<T>::new(id, self.channel.clone(), self.connected.clone());
+        self.schema.add::<T>(id, "list");
+        let list = PyValueList::<T>::new(
+            id,
+            self.channel.clone(),
+            self.connected.clone(),
+            self.signals.clone(),
+            false,
+        );
 
         self.py_val.lists.insert(id, list.clone());
         self.val.sync.insert(id, list);
+        self.val.read_only.insert(id);
+    }
+
+    /// Add the list which the clients can change.
+    ///
+    /// The client has to add the list by `ValuesCreator::add_list_writable`, it acknowledges
+    /// the changes of the server. The change of the client which did not acknowledge all changes
+    /// of the server yet is dropped and the client gets the whole list again.
+    pub fn add_list_writable<T>(&mut self)
+    where
+        T: ToPython
+            + for<'py> FromPyObject<'py>
+            + Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + 'static,
+    {
+        let id = self.get_id();
+        self.schema.add::<T>(id, "list_writable");
+        let list = PyValueList::<T>::new(
+            id,
+            self.channel.clone(),
+            self.connected.clone(),
+            self.signals.clone(),
+            true,
+        );
+
+        self.py_val.lists.insert(id, list.clone());
+        self.val.lists.insert(id, list.clone());
+        self.val.ack.insert(id, list.clone());
+        self.val.sync.insert(id, list);
     }
 
     pub fn add_graphs<
@@ -214,9 +536,19 @@ impl ServerValuesCreator {
         &mut self,
     ) {
         let id = self.get_id();
+        self.schema.add::<T>(id, "graphs");
         let graph = PyValueGraphs::<T>::new(id, self.channel.clone(), self.connected.clone());
 
         self.py_val.graphs.insert(id, graph.clone());
         self.val.sync.insert(id, graph);
     }
+
+    pub fn add_bitset(&mut self) {
+        let id = self.get_id();
+        self.schema.add_untyped(id, "bitset");
+        let bitset = PyValueBitset::new(id, self.channel.clone(), self.connected.clone());
+
+        self.py_val.bitsets.insert(id, bitset.clone());
+        self.val.sync.insert(id, bitset);
+    }
 }
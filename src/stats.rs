@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::transport::message_type_name;
+
+// message types are even numbers below 32
+const TYPES: usize = 16;
+
+/// Counters of the messages of one type. The bytes are counted as they are on the wire,
+/// with the head and compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TypeStats {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+/// Snapshot of the message counters of all connections since the start.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageStats {
+    /// Counters by the name of the message type, for example "value" or "image". Only types
+    /// which were sent or received are included.
+    pub types: BTreeMap<&'static str, TypeStats>,
+    /// Messages taken from the queue by the writer and waiting to be written. The client writes
+    /// the messages in the order of the queue, so it is always None for the client.
+    pub queue_depth: Option<usize>,
+    /// Images and graphs dropped from the full queue of the writer, None for the client.
+    pub dropped: Option<u64>,
+}
+
+impl MessageStats {
+    /// Sum of the counters of all types.
+    pub fn total(&self) -> TypeStats {
+        self.types
+            .values()
+            .fold(TypeStats::default(), |mut total, stats| {
+                total.messages_sent += stats.messages_sent;
+                total.bytes_sent += stats.bytes_sent;
+                total.messages_received += stats.messages_received;
+                total.bytes_received += stats.bytes_received;
+                total
+            })
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+// Counters shared by the reading and the writing thread.
+#[derive(Clone)]
+pub(crate) struct Stats {
+    counters: Arc<[Counters; TYPES]>,
+    queue_depth: Option<Arc<AtomicUsize>>,
+    dropped: Option<Arc<AtomicU64>>,
+}
+
+impl Stats {
+    pub(crate) fn new(queue: bool) -> Self {
+        Self {
+            counters: Arc::new(Default::default()),
+            queue_depth: queue.then(|| Arc::new(AtomicUsize::new(0))),
+            dropped: queue.then(|| Arc::new(AtomicU64::new(0))),
+        }
+    }
+
+    #[inline]
+    fn counters(&self, message_type: u8) -> &Counters {
+        &self.counters[(message_type as usize / 2) % TYPES]
+    }
+
+    pub(crate) fn sent(&self, message_type: u8, bytes: usize) {
+        let counters = self.counters(message_type);
+        counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes_sent
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, message_type: u8, bytes: usize) {
+        let counters = self.counters(message_type);
+        counters.messages_received.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn set_queue_depth(&self, depth: usize) {
+        if let Some(ref queue_depth) = self.queue_depth {
+            queue_depth.store(depth, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn dropped(&self, messages: usize) {
+        if let Some(ref dropped) = self.dropped {
+            if messages > 0 {
+                dropped.fetch_add(messages as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> MessageStats {
+        let mut types = BTreeMap::new();
+        for (i, counters) in self.counters.iter().enumerate() {
+            let stats = TypeStats {
+                messages_sent: counters.messages_sent.load(Ordering::Relaxed),
+                bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+                messages_received: counters.messages_received.load(Ordering::Relaxed),
+                bytes_received: counters.bytes_received.load(Ordering::Relaxed),
+            };
+            if stats != TypeStats::default() {
+                types.insert(message_type_name((i * 2) as u8), stats);
+            }
+        }
+
+        MessageStats {
+            types,
+            queue_depth: self
+                .queue_depth
+                .as_ref()
+                .map(|depth| depth.load(Ordering::Relaxed)),
+            dropped: self
+                .dropped
+                .as_ref()
+                .map(|dropped| dropped.load(Ordering::Relaxed)),
+        }
+    }
+}
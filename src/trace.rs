@@ -0,0 +1,77 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+    Every frame written or read by this process is one line of the trace file: unix time in
+    seconds, direction, type, flags, id and size of the data, optionally followed by the hex dump
+    of the data as they are on the wire, so the compressed data stay compressed. For example:
+    1700000000.123456 write type=4 flags=0x01 id=10 size=1 data=0e
+*/
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACER: Mutex<Option<Tracer>> = Mutex::new(None);
+
+struct Tracer {
+    file: BufWriter<File>,
+    payload: bool,
+}
+
+/// Start recording of all frames of the client and the server in this process to the file.
+///
+/// The file is truncated. With the payload, the data of every frame are written as hex.
+pub fn start(path: impl AsRef<Path>, payload: bool) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    *TRACER.lock().unwrap() = Some(Tracer { file, payload });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Stop the recording and flush the file. It does nothing if the recording is not running.
+pub fn stop() -> io::Result<()> {
+    ENABLED.store(false, Ordering::Relaxed);
+    match TRACER.lock().unwrap().take() {
+        Some(mut tracer) => tracer.file.flush(),
+        None => Ok(()),
+    }
+}
+
+#[inline]
+pub(crate) fn record(direction: &str, message_type: u8, flags: u8, id: u32, parts: &[&[u8]]) {
+    if ENABLED.load(Ordering::Relaxed) {
+        write_record(direction, message_type, flags, id, parts);
+    }
+}
+
+// errors of the trace file are ignored, the recording must not break the connection
+fn write_record(direction: &str, message_type: u8, flags: u8, id: u32, parts: &[&[u8]]) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs_f64())
+        .unwrap_or(0.0);
+    let size: usize = parts.iter().map(|part| part.len()).sum();
+
+    let mut line = format!(
+        "{:.6} {} type={} flags={:#04x} id={} size={}",
+        time, direction, message_type, flags, id, size
+    );
+
+    let mut tracer = TRACER.lock().unwrap();
+    let tracer = match tracer.as_mut() {
+        Some(tracer) => tracer,
+        None => return,
+    };
+
+    if tracer.payload {
+        line.reserve(size * 2 + 6);
+        line.push_str(" data=");
+        for b in parts.iter().flat_map(|part| part.iter()) {
+            let _ = write!(line, "{:02x}", b);
+        }
+    }
+    line.push('\n');
+    let _ = tracer.file.write_all(line.as_bytes());
+}
@@ -1,23 +1,135 @@
-use heapless;
 use heapless::Vec as HVec;
 use serde::{Deserialize, Serialize};
-use std::io::{self, Read, Write};
-use std::net::TcpStream;
+use std::borrow::Cow;
+use std::collections::BinaryHeap;
+use std::io::{self, IoSlice, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, OnceLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::commands::CommandMessage;
+use crate::commands::{CommandMessage, RoundTrip};
+use crate::pool::POOL;
+use crate::socket::Stream;
+use crate::stats::Stats;
+use crate::trace;
 
 pub(crate) const HEAPLESS_SIZE: usize = 32;
+pub(crate) const MAX_VALUE_ID: u32 = 0x00FF_FFFF; // value ids are 24bit long
+pub(crate) const NAMESPACE_SHIFT: u32 = 24; // the highest byte of the id is the namespace
+pub(crate) const RESERVED_IDS: u32 = 10; // default number of ids reserved for special values
 
 // message types
-const TYPE_VALUE: u8 = 4;
-const TYPE_STATIC: u8 = 8;
-const TYPE_SIGNAL: u8 = 10;
+pub(crate) const TYPE_VALUE: u8 = 4;
+pub(crate) const TYPE_STATIC: u8 = 8;
+pub(crate) const TYPE_SIGNAL: u8 = 10;
 const TYPE_COMMAND: u8 = 12;
-const TYPE_IMAGE: u8 = 14;
-const TYPE_DICT: u8 = 16;
-const TYPE_LIST: u8 = 18;
-const TYPE_GRAPH: u8 = 20;
+pub(crate) const TYPE_IMAGE: u8 = 14;
+pub(crate) const TYPE_DICT: u8 = 16;
+pub(crate) const TYPE_LIST: u8 = 18;
+pub(crate) const TYPE_GRAPH: u8 = 20;
+pub(crate) const TYPE_BITSET: u8 = 22;
+const TYPE_COMPARE_SWAP: u8 = 24;
+const TYPE_SHARED_IMAGE: u8 = 26;
 
+pub(crate) fn message_type_name(message_type: u8) -> &'static str {
+    match message_type {
+        TYPE_VALUE => "value",
+        TYPE_STATIC => "static",
+        TYPE_SIGNAL => "signal",
+        TYPE_COMMAND => "command",
+        TYPE_IMAGE => "image",
+        TYPE_DICT => "dict",
+        TYPE_LIST => "list",
+        TYPE_GRAPH => "graph",
+        TYPE_BITSET => "bitset",
+        TYPE_COMPARE_SWAP => "compare_swap",
+        TYPE_SHARED_IMAGE => "shared_image",
+        _ => "unknown",
+    }
+}
+
+fn known_type(message_type: u8) -> bool {
+    matches!(
+        message_type,
+        TYPE_VALUE
+            | TYPE_STATIC
+            | TYPE_SIGNAL
+            | TYPE_COMMAND
+            | TYPE_IMAGE
+            | TYPE_DICT
+            | TYPE_LIST
+            | TYPE_GRAPH
+            | TYPE_BITSET
+            | TYPE_COMPARE_SWAP
+            | TYPE_SHARED_IMAGE
+    )
+}
+
+// head flags, the first bit is the update flag of the message
+const FLAG_UPDATE: u8 = 0b01;
+// The data are compressed. The client sets it on the handshake if it can decompress the messages.
+const FLAG_COMPRESSED: u8 = 0b10;
+// The client sets it on the handshake if it can read the images from the shared memory.
+const FLAG_SHARED_MEMORY: u8 = 0b100;
+// The client sets it on the handshake if it understands the handshake reply of the server.
+const FLAG_REPLY: u8 = 0b1000;
+// The CRC32 of the data follows the head. The client sets it on the handshake to request it.
+const FLAG_CHECKSUM: u8 = 0b10000;
+#[cfg(feature = "compression")]
+const COMPRESS_SIZE: usize = 4096; // smaller messages are not compressed
+
+// byte order ---------------------------------------------------------------
+// Numbers on the wire are little endian by default. The big-endian feature forces the network
+// byte order, which costs a byte swap of every number on little endian hosts. Postcard payloads
+// are not affected, they are defined by the postcard format itself.
+pub(crate) const BIG_ENDIAN: bool = cfg!(feature = "big-endian");
+const SWAP_BYTES: bool = BIG_ENDIAN != cfg!(target_endian = "big");
+
+#[inline]
+pub(crate) fn to_wire_u32(value: u32) -> [u8; 4] {
+    match BIG_ENDIAN {
+        true => value.to_be_bytes(),
+        false => value.to_le_bytes(),
+    }
+}
+
+#[inline]
+pub(crate) fn from_wire_u32(bytes: [u8; 4]) -> u32 {
+    match BIG_ENDIAN {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes),
+    }
+}
+
+/// Swap the native data of elements with given size to the wire byte order.
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+#[inline]
+pub(crate) fn to_wire_order(data: &mut [u8], element_size: usize) {
+    if SWAP_BYTES {
+        for element in data.chunks_exact_mut(element_size) {
+            element.reverse();
+        }
+    }
+}
+
+/// Get the native data of elements with given size from the wire byte order.
+#[inline]
+pub(crate) fn from_wire_order(data: &[u8], element_size: usize) -> Cow<'_, [u8]> {
+    if SWAP_BYTES {
+        let mut data = data.to_vec();
+        for element in data.chunks_exact_mut(element_size) {
+            element.reverse();
+        }
+        Cow::Owned(data)
+    } else {
+        Cow::Borrowed(data)
+    }
+}
+
+#[derive(Clone)]
 pub(crate) enum MessageData {
     Heap(Vec<u8>),
     Stack(HVec<u8, HEAPLESS_SIZE>),
@@ -46,27 +158,294 @@ where
     }
 }
 
+// Generation of the image frame. Message of the older frame is not sent if the newer frame
+// is already waiting in the queue.
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+#[derive(Clone)]
+pub(crate) struct ImageGeneration {
+    generation: u64,
+    latest: Arc<AtomicU64>,
+}
+
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+impl ImageGeneration {
+    pub(crate) fn new(generation: u64, latest: Arc<AtomicU64>) -> Self {
+        Self { generation, latest }
+    }
+
+    pub(crate) fn is_stale(&self) -> bool {
+        self.latest.load(Ordering::Relaxed) != self.generation
+    }
+}
+
+// Messages set faster than the interval are not sent. The last dropped message is sent
+// after the interval elapses, so the client always ends with the latest content.
+pub(crate) struct SendRate {
+    interval: Option<Duration>,
+    last: Option<Instant>,
+    pending: Option<bool>, // update flag of the dropped messages
+}
+
+pub(crate) enum Throttle {
+    Send,
+    Drop,
+    Schedule(Duration), // drop and send the pending message after the duration
+}
+
+impl SendRate {
+    pub(crate) fn new() -> Self {
+        Self {
+            interval: None,
+            last: None,
+            pending: None,
+        }
+    }
+
+    // returns the update flag of the pending message if the limit was removed
+    pub(crate) fn set_interval(&mut self, interval: Option<Duration>) -> Option<bool> {
+        self.interval = interval;
+        match interval {
+            Some(_) => None,
+            None => self.pending.take(),
+        }
+    }
+
+    pub(crate) fn throttle(&mut self, update: bool) -> Throttle {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return Throttle::Send,
+        };
+
+        match self.last {
+            Some(last) if last.elapsed() < interval || self.pending.is_some() => {
+                let scheduled = self.pending.is_some();
+                self.pending = Some(self.pending.unwrap_or(false) || update);
+                match scheduled {
+                    true => Throttle::Drop,
+                    false => Throttle::Schedule(interval.saturating_sub(last.elapsed())),
+                }
+            }
+            _ => {
+                self.last = Some(Instant::now());
+                Throttle::Send
+            }
+        }
+    }
+
+    // returns the update flag if the pending message should be sent now
+    pub(crate) fn take_pending(&mut self) -> Option<bool> {
+        let pending = self.pending.take();
+        if pending.is_some() {
+            self.last = Some(Instant::now());
+        }
+        pending
+    }
+
+    // the pending message is not needed, when the whole content is sent
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn clear(&mut self) {
+        self.pending = None;
+    }
+}
+
+// Call the send function after the wait, if the object still exists. All scheduled sends wait
+// in one timer thread, so the throttled values do not start the thread for every send.
+pub(crate) fn send_later<S: Send + Sync + 'static>(this: Weak<S>, wait: Duration, send: fn(&S)) {
+    let task: Task = Box::new(move || {
+        if let Some(this) = this.upgrade() {
+            send(&this);
+        }
+    });
+    let _ = scheduler().send(Scheduled(Instant::now() + wait, task));
+}
+
+type Task = Box<dyn FnOnce() + Send>;
+
+struct Scheduled(Instant, Task);
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// reversed, so the heap gives the earliest task first
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other.0.cmp(&self.0)
+    }
+}
+
+static SCHEDULER: OnceLock<Sender<Scheduled>> = OnceLock::new();
+
+fn scheduler() -> &'static Sender<Scheduled> {
+    SCHEDULER.get_or_init(|| {
+        let (channel, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("SendLater".to_string())
+            .spawn(move || run_scheduler(rx))
+            .expect("failed to start the thread for the throttled sends");
+        channel
+    })
+}
+
+fn run_scheduler(rx: Receiver<Scheduled>) {
+    let mut tasks = BinaryHeap::new();
+    loop {
+        let received = match tasks.peek() {
+            Some(Scheduled(time, _)) => {
+                rx.recv_timeout(time.saturating_duration_since(Instant::now()))
+            }
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(task) => tasks.push(task),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        while tasks.peek().is_some_and(|Scheduled(time, _)| *time <= now) {
+            let Scheduled(_, task) = tasks.pop().unwrap();
+            // the panic of one send does not stop the other scheduled sends
+            let _ = panic::catch_unwind(AssertUnwindSafe(task));
+        }
+    }
+}
+
+// heartbeat ----------------------------------------------------------------
+// The connection is closed if nothing is received for this number of heartbeat intervals.
+const HEARTBEAT_MISSES: u32 = 3;
+
+pub(crate) fn heartbeat_timeout(heartbeat: Option<Duration>) -> Option<Duration> {
+    heartbeat.map(|interval| interval * HEARTBEAT_MISSES)
+}
+
+// Wait for the next message to write. If nothing is written for the heartbeat interval, the ping
+// is written, so the other side receives the pong or at least the ping also on an idle connection.
+pub(crate) fn recv_message(
+    rx: &Receiver<WriteMessage>,
+    heartbeat: Option<Duration>,
+    round_trip: &RoundTrip,
+) -> WriteMessage {
+    match heartbeat {
+        Some(interval) => match rx.recv_timeout(interval) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => WriteMessage::Command(round_trip.ping()),
+            Err(RecvTimeoutError::Disconnected) => panic!("message channel is closed"),
+        },
+        None => rx.recv().unwrap(),
+    }
+}
+
+// the read timeout is reported as WouldBlock on unix and as TimedOut on windows
+pub(crate) fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
 pub(crate) enum WriteMessage {
     Value(u32, bool, MessageData),
     #[cfg_attr(not(feature = "server"), allow(dead_code))]
     Static(u32, bool, MessageData),
     Signal(u32, MessageData),
     #[cfg_attr(not(feature = "server"), allow(dead_code))]
-    Image(u32, bool, MessageData, Vec<u8>),
-    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    Image(u32, bool, MessageData, Vec<u8>, ImageGeneration),
     Dict(u32, bool, MessageData),
-    #[cfg_attr(not(feature = "server"), allow(dead_code))]
     List(u32, bool, MessageData),
     #[cfg_attr(not(feature = "server"), allow(dead_code))]
     Graph(u32, bool, MessageData, Option<Vec<u8>>),
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    Bitset(u32, bool, MessageData),
+    CompareSwap(u32, bool, MessageData), // serialized (expected, new) value
+    #[cfg_attr(
+        not(all(feature = "server", feature = "shared-memory")),
+        allow(dead_code)
+    )]
+    SharedImage(u32, bool, Vec<u8>), // serialized image info and the position of the frame
     Command(CommandMessage),
+    Flush(Sender<()>), // flush the stream and confirm that all previous messages were written
     Terminate,
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    Relay(usize, Box<WriteMessage>), // value set by the client, for all other clients
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    Direct(usize, Box<WriteMessage>), // synchronized value, only for the client which asked
 }
 
 impl WriteMessage {
     pub fn ack(id: u32) -> Self {
         WriteMessage::Command(CommandMessage::Ack(id))
     }
+
+    // return the buffers of the written or dropped message to the pool
+    pub(crate) fn recycle(self) {
+        match self {
+            WriteMessage::Image(_, _, info, data, _) => {
+                info.recycle();
+                POOL.give(data);
+            }
+            WriteMessage::Graph(_, _, data, graph_data) => {
+                data.recycle();
+                if let Some(graph_data) = graph_data {
+                    POOL.give(graph_data);
+                }
+            }
+            WriteMessage::SharedImage(_, _, data) => POOL.give(data),
+            WriteMessage::Value(_, _, data)
+            | WriteMessage::Static(_, _, data)
+            | WriteMessage::Signal(_, data)
+            | WriteMessage::Dict(_, _, data)
+            | WriteMessage::List(_, _, data)
+            | WriteMessage::Bitset(_, _, data)
+            | WriteMessage::CompareSwap(_, _, data) => data.recycle(),
+            WriteMessage::Relay(_, message) | WriteMessage::Direct(_, message) => message.recycle(),
+            WriteMessage::Command(_) | WriteMessage::Flush(_) | WriteMessage::Terminate => {}
+        }
+    }
+
+    // copy of the message for the other client
+    #[cfg(feature = "server")]
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            WriteMessage::Value(id, f, data) => WriteMessage::Value(*id, *f, data.clone()),
+            WriteMessage::Static(id, f, data) => WriteMessage::Static(*id, *f, data.clone()),
+            WriteMessage::Signal(id, data) => WriteMessage::Signal(*id, data.clone()),
+            WriteMessage::Image(id, f, info, data, generation) => {
+                WriteMessage::Image(*id, *f, info.clone(), data.clone(), generation.clone())
+            }
+            WriteMessage::Dict(id, f, data) => WriteMessage::Dict(*id, *f, data.clone()),
+            WriteMessage::List(id, f, data) => WriteMessage::List(*id, *f, data.clone()),
+            WriteMessage::Graph(id, f, data, graph_data) => {
+                WriteMessage::Graph(*id, *f, data.clone(), graph_data.clone())
+            }
+            WriteMessage::Bitset(id, f, data) => WriteMessage::Bitset(*id, *f, data.clone()),
+            WriteMessage::CompareSwap(id, f, data) => {
+                WriteMessage::CompareSwap(*id, *f, data.clone())
+            }
+            WriteMessage::SharedImage(id, f, data) => {
+                WriteMessage::SharedImage(*id, *f, data.clone())
+            }
+            WriteMessage::Command(command) => WriteMessage::Command(command.clone()),
+            WriteMessage::Flush(confirm) => WriteMessage::Flush(confirm.clone()),
+            WriteMessage::Terminate => WriteMessage::Terminate,
+            WriteMessage::Relay(origin, message) => {
+                WriteMessage::Relay(*origin, Box::new(message.duplicate()))
+            }
+            WriteMessage::Direct(target, message) => {
+                WriteMessage::Direct(*target, Box::new(message.duplicate()))
+            }
+        }
+    }
 }
 
 pub(crate) enum ReadMessage {
@@ -78,10 +457,15 @@ pub(crate) enum ReadMessage {
     Dict(u32, bool, MessageData),
     List(u32, bool, MessageData),
     Graph(u32, bool, MessageData),
+    Bitset(u32, bool, MessageData),
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    CompareSwap(u32, bool, MessageData),
+    #[cfg_attr(not(feature = "shared-memory"), allow(dead_code))]
+    SharedImage(u32, bool, MessageData),
     Command(CommandMessage),
 }
 
-#[cfg(feature = "server")]
+#[cfg(any(feature = "server", feature = "message-dump"))]
 impl ReadMessage {
     pub fn to_str(&self) -> &'static str {
         match self {
@@ -92,122 +476,555 @@ impl ReadMessage {
             Self::Dict(_, _, _) => "Dict",
             Self::List(_, _, _) => "List",
             Self::Graph(_, _, _) => "Graph",
+            Self::Bitset(_, _, _) => "Bitset",
+            Self::CompareSwap(_, _, _) => "CompareSwap",
+            Self::SharedImage(_, _, _) => "SharedImage",
             Self::Command(_) => "Command",
         }
     }
 }
 
-fn write_data(
-    head: &mut [u8],
-    data: &MessageData,
-    stream: &mut TcpStream,
-    add_size: Option<usize>,
-) -> std::io::Result<()> {
-    match data {
-        MessageData::Heap(data) => {
-            match add_size {
-                Some(size) => {
-                    head[0..4].copy_from_slice(&((data.len() + size) as u32).to_le_bytes());
-                }
-                None => {
-                    head[0..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
-                }
-            }
-            stream.write_all(head)?;
-            stream.write_all(data)
+impl ReadMessage {
+    pub(crate) fn value_id(&self) -> Option<u32> {
+        match self {
+            Self::Value(id, _, _)
+            | Self::Static(id, _, _)
+            | Self::Image(id, _, _)
+            | Self::Dict(id, _, _)
+            | Self::List(id, _, _)
+            | Self::Graph(id, _, _)
+            | Self::Bitset(id, _, _)
+            | Self::CompareSwap(id, _, _)
+            | Self::SharedImage(id, _, _)
+            | Self::Signal(id, _) => Some(*id),
+            Self::Command(_) => None,
         }
-        MessageData::Stack(data) => {
-            match add_size {
-                Some(size) => {
-                    head[0..4].copy_from_slice(&((data.len() + size) as u32).to_le_bytes());
-                }
-                None => {
-                    head[0..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
-                }
-            }
-            stream.write_all(head)?;
-            stream.write_all(data)
+    }
+}
+
+// debug formatting ---------------------------------------------------------
+#[cfg(feature = "message-dump")]
+const DUMP_SIZE: usize = 32; // maximum number of dumped bytes
+
+#[cfg(feature = "message-dump")]
+pub(crate) fn hexdump(data: &[u8]) -> String {
+    let mut text = data[..data.len().min(DUMP_SIZE)]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if data.len() > DUMP_SIZE {
+        text.push_str(&format!(" ... ({} more bytes)", data.len() - DUMP_SIZE));
+    }
+    text
+}
+
+impl MessageData {
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        match self {
+            MessageData::Heap(data) => data,
+            MessageData::Stack(data) => data,
+        }
+    }
+
+    // return the heap buffer to the pool
+    pub(crate) fn recycle(self) {
+        if let MessageData::Heap(data) = self {
+            POOL.give(data);
         }
     }
 }
 
-pub(crate) fn write_message(message: WriteMessage, stream: &mut TcpStream) -> std::io::Result<()> {
-    let mut head = [0u8; 10];
-    match message {
-        WriteMessage::Value(id, flag, data) => {
-            head[4] = TYPE_VALUE;
-            head[5] = flag as u8;
-            head[6..10].copy_from_slice(&id.to_le_bytes());
-            write_data(&mut head, &data, stream, None)
-        }
-        WriteMessage::Signal(id, data) => {
-            head[4] = TYPE_SIGNAL;
-            head[6..10].copy_from_slice(&id.to_le_bytes());
-            write_data(&mut head, &data, stream, None)
-        }
-        WriteMessage::Static(id, flag, data) => {
-            head[4] = TYPE_STATIC;
-            head[5] = flag as u8;
-            head[6..10].copy_from_slice(&id.to_le_bytes());
-            write_data(&mut head, &data, stream, None)
-        }
-        WriteMessage::Dict(id, flag, data) => {
-            head[4] = TYPE_DICT;
-            head[5] = flag as u8;
-            head[6..10].copy_from_slice(&id.to_le_bytes());
-            write_data(&mut head, &data, stream, None)
-        }
-        WriteMessage::List(id, flag, data) => {
-            head[4] = TYPE_LIST;
-            head[5] = flag as u8;
-            head[6..10].copy_from_slice(&id.to_le_bytes());
-            write_data(&mut head, &data, stream, None)
-        }
-        WriteMessage::Image(id, flag, info, data) => {
-            head[4] = TYPE_IMAGE;
-            head[5] = flag as u8;
-            head[6..10].copy_from_slice(&id.to_le_bytes());
-            write_data(&mut head, &info, stream, Some(data.len()))?;
-            stream.write_all(&data)
-        }
-        WriteMessage::Graph(id, flag, data, graph_data) => {
-            head[4] = TYPE_GRAPH;
-            head[5] = flag as u8;
-            head[6..10].copy_from_slice(&id.to_le_bytes());
-            let size = match &graph_data {
-                Some(data) => Some(data.len()),
-                None => None,
+#[cfg(feature = "message-dump")]
+impl ReadMessage {
+    /// Readable description of the message for the error messages.
+    pub(crate) fn describe(&self) -> String {
+        let (id, flag, data) = match self {
+            Self::Value(id, flag, data)
+            | Self::Static(id, flag, data)
+            | Self::Image(id, flag, data)
+            | Self::Dict(id, flag, data)
+            | Self::List(id, flag, data)
+            | Self::Graph(id, flag, data)
+            | Self::Bitset(id, flag, data)
+            | Self::CompareSwap(id, flag, data)
+            | Self::SharedImage(id, flag, data) => (id, *flag, data),
+            Self::Signal(id, data) => (id, false, data),
+            Self::Command(command) => return format!("[Command {}]", command.as_str()),
+        };
+
+        let data = data.as_slice();
+        format!(
+            "[{} id: {}, flag: {}, size: {}, data: {}]",
+            self.to_str(),
+            id,
+            flag,
+            data.len(),
+            hexdump(data)
+        )
+    }
+}
+
+/// Optional extensions of the protocol. The client announces them in the flag of the handshake
+/// and the server answers with the extensions used for the connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// Large messages from the server are compressed.
+    pub compression: bool,
+    /// Pixels of the large images are passed through the shared memory.
+    pub shared_memory: bool,
+    /// The CRC32 of the data follows the head of every message except the commands.
+    pub checksum: bool,
+}
+
+impl Extensions {
+    // extensions enabled in this build
+    pub(crate) fn supported() -> Self {
+        Self {
+            compression: cfg!(feature = "compression"),
+            shared_memory: cfg!(feature = "shared-memory"),
+            checksum: true,
+        }
+    }
+
+    pub(crate) fn to_flag(self) -> u8 {
+        let mut flag = 0;
+        if self.compression {
+            flag |= FLAG_COMPRESSED;
+        }
+        if self.shared_memory {
+            flag |= FLAG_SHARED_MEMORY;
+        }
+        if self.checksum {
+            flag |= FLAG_CHECKSUM;
+        }
+        flag
+    }
+
+    pub(crate) fn from_flag(flag: u8) -> Self {
+        Self {
+            compression: flag & FLAG_COMPRESSED != 0,
+            shared_memory: flag & FLAG_SHARED_MEMORY != 0,
+            checksum: flag & FLAG_CHECKSUM != 0,
+        }
+    }
+}
+
+// checksum -----------------------------------------------------------------
+// CRC32 (IEEE 802.3), the same as zlib computes. The table is built at compile time.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => 0xEDB8_8320 ^ (crc >> 1),
+                _ => crc >> 1,
             };
-            write_data(&mut head, &data, stream, size)?;
-            if let Some(graph_data) = graph_data {
-                stream.write_all(&graph_data)
-            } else {
-                Ok(())
-            }
+            bit += 1;
         }
-        WriteMessage::Command(command) => {
-            head[4] = TYPE_COMMAND;
-            let data = serialize(&command);
-            write_data(&mut head, &data, stream, None)
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for part in parts {
+        for b in part.iter() {
+            crc = CRC_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8);
         }
-        WriteMessage::Terminate => {
-            unreachable!("Terminate message should not be written");
+    }
+    !crc
+}
+
+/// Error of the message with the checksum which does not match its data. It is the inner error
+/// of the [`io::Error`] with the [`io::ErrorKind::InvalidData`] kind.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChecksumError {
+    /// Type byte in the head of the message.
+    pub message_type: u8,
+    /// Id of the value.
+    pub id: u32,
+    /// Checksum sent in the head.
+    pub expected: u32,
+    /// Checksum of the received data.
+    pub actual: u32,
+}
+
+impl std::fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Checksum mismatch of message type {} with id {}: expected {:08x}, received {:08x}",
+            self.message_type, self.id, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+/// Default limit of the data size of one received message, see [`MessageSizeError`].
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+const HANDSHAKE_MAX_SIZE: usize = 16 * 1024; // the client is not checked yet, fits the auth token
+
+/// Error of the received message which is larger than the limit, also after the decompression.
+/// It is the inner error of the [`io::Error`] with the [`io::ErrorKind::InvalidData`] kind. The
+/// data of the message are skipped without the allocation, so the connection can continue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageSizeError {
+    /// Type byte in the head of the message.
+    pub message_type: u8,
+    /// Id of the value.
+    pub id: u32,
+    /// Size of the data claimed by the message.
+    pub size: usize,
+    /// Maximal allowed size.
+    pub limit: usize,
+}
+
+impl std::fmt::Display for MessageSizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Message type {} with id {} has {} bytes, the limit is {} bytes",
+            self.message_type, self.id, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MessageSizeError {}
+
+// the oversized message was skipped, so the next message can be read
+pub(crate) fn oversized(error: &io::Error) -> Option<&MessageSizeError> {
+    error.get_ref()?.downcast_ref()
+}
+
+// The command can not be deserialized, the other side can use a different version of the protocol.
+#[derive(Debug)]
+pub(crate) struct CommandError {
+    flags: u8,
+    error: postcard::Error,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid command message: {}", self.error)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+// the invalid handshake of the client which understands the reply
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub(crate) fn reply_rejection(error: &io::Error) -> bool {
+    let error = error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<CommandError>());
+    error.is_some_and(|error| error.flags & FLAG_REPLY != 0)
+}
+
+// Compress the data of the message if it is worth it.
+#[cfg(feature = "compression")]
+fn compress(parts: &[&[u8]]) -> Option<Vec<u8>> {
+    let size: usize = parts.iter().map(|part| part.len()).sum();
+    if size < COMPRESS_SIZE {
+        return None;
+    }
+
+    let compressed = lz4_flex::compress_prepend_size(&parts.concat());
+    match compressed.len() < size {
+        true => Some(compressed),
+        false => None,
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+#[inline]
+fn compress(_parts: &[&[u8]]) -> Option<Vec<u8>> {
+    None
+}
+
+// Vectored version of the write_all, which is not stable in std.
+fn write_all_vectored(stream: &mut Stream, mut slices: &mut [IoSlice<'_>]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match stream.write_vectored(slices) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole message",
+                ))
+            }
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
 }
 
-pub(crate) fn read_message(stream: &mut TcpStream) -> Result<ReadMessage, io::Error> {
+// Write the head with the size of all parts and the parts in one syscall. With the checksum flag
+// the CRC32 of the parts is written between the head and the data, the flag of the commands
+// announces it.
+fn write_parts(
+    head: &mut [u8; 10],
+    parts: [&[u8]; 2],
+    stream: &mut Stream,
+    stats: &Stats,
+) -> io::Result<()> {
+    let size = parts[0].len() + parts[1].len();
+    head[0..4].copy_from_slice(&to_wire_u32(size as u32));
+    let id = from_wire_u32([head[6], head[7], head[8], head[9]]);
+    trace::record("write", head[4], head[5], id, &parts);
+    let checksum = match head[5] & FLAG_CHECKSUM != 0 && head[4] != TYPE_COMMAND {
+        true => &to_wire_u32(crc32(&parts))[..],
+        false => &[],
+    };
+    stats.sent(head[4], head.len() + checksum.len() + size);
+
+    let mut slices = [
+        IoSlice::new(head),
+        IoSlice::new(checksum),
+        IoSlice::new(parts[0]),
+        IoSlice::new(parts[1]),
+    ];
+    write_all_vectored(stream, &mut slices)
+}
+
+// The extensions are the ones negotiated for the connection. The image, graph and dict messages
+// are compressed only with the compression and every message except the commands gets
+// the checksum. The handshake announces the extensions instead.
+pub(crate) fn write_message(
+    message: WriteMessage,
+    stream: &mut Stream,
+    extensions: Extensions,
+    stats: &Stats,
+) -> std::io::Result<()> {
+    let command_data;
+    let (message_type, flag, id, parts, compressible): (u8, u8, u32, [&[u8]; 2], bool) =
+        match message {
+            WriteMessage::Value(id, flag, ref data) => {
+                (TYPE_VALUE, flag as u8, id, [data.as_slice(), &[]], false)
+            }
+            WriteMessage::Signal(id, ref data) => {
+                (TYPE_SIGNAL, 0, id, [data.as_slice(), &[]], false)
+            }
+            WriteMessage::Static(id, flag, ref data) => {
+                (TYPE_STATIC, flag as u8, id, [data.as_slice(), &[]], false)
+            }
+            WriteMessage::Dict(id, flag, ref data) => {
+                (TYPE_DICT, flag as u8, id, [data.as_slice(), &[]], true)
+            }
+            WriteMessage::List(id, flag, ref data) => {
+                (TYPE_LIST, flag as u8, id, [data.as_slice(), &[]], false)
+            }
+            WriteMessage::Image(id, flag, ref info, ref data, _) => {
+                (TYPE_IMAGE, flag as u8, id, [info.as_slice(), data], true)
+            }
+            WriteMessage::Graph(id, flag, ref data, ref graph_data) => {
+                let graph_part = graph_data.as_deref().unwrap_or_default();
+                (
+                    TYPE_GRAPH,
+                    flag as u8,
+                    id,
+                    [data.as_slice(), graph_part],
+                    true,
+                )
+            }
+            WriteMessage::Bitset(id, flag, ref data) => {
+                (TYPE_BITSET, flag as u8, id, [data.as_slice(), &[]], false)
+            }
+            WriteMessage::CompareSwap(id, flag, ref data) => (
+                TYPE_COMPARE_SWAP,
+                flag as u8,
+                id,
+                [data.as_slice(), &[]],
+                false,
+            ),
+            WriteMessage::SharedImage(id, flag, ref data) => {
+                (TYPE_SHARED_IMAGE, flag as u8, id, [data, &[]], false)
+            }
+            WriteMessage::Command(ref command) => {
+                let flag = match command {
+                    CommandMessage::Handshake(..) => extensions.to_flag() | FLAG_REPLY,
+                    _ => 0,
+                };
+                command_data = serialize(command);
+                (TYPE_COMMAND, flag, 0, [command_data.as_slice(), &[]], false)
+            }
+            WriteMessage::Flush(_) => {
+                unreachable!("Flush message should not be written");
+            }
+            WriteMessage::Terminate => {
+                unreachable!("Terminate message should not be written");
+            }
+            WriteMessage::Relay(..) | WriteMessage::Direct(..) => {
+                unreachable!("Hub message should not be written");
+            }
+        };
+
+    let mut head = [0u8; 10];
+    head[4] = message_type;
+    head[5] = flag;
+    head[6..10].copy_from_slice(&to_wire_u32(id));
+
+    if extensions.checksum && message_type != TYPE_COMMAND {
+        head[5] |= FLAG_CHECKSUM;
+    }
+
+    let compressed = match extensions.compression && compressible {
+        true => compress(&parts),
+        false => None,
+    };
+    let res = match compressed {
+        Some(ref data) => {
+            head[5] |= FLAG_COMPRESSED;
+            write_parts(&mut head, [data, &[]], stream, stats)
+        }
+        None => write_parts(&mut head, parts, stream, stats),
+    };
+    message.recycle();
+    res
+}
+
+pub(crate) fn read_message(
+    stream: &mut Stream,
+    max_size: usize,
+    stats: &Stats,
+) -> Result<ReadMessage, io::Error> {
+    read_message_flags(stream, max_size, stats).map(|(message, _)| message)
+}
+
+/// Read the handshake message, the extensions supported by the client and by this build and if
+/// the client expects the reply. Older clients do not know the reply commands. The handshake is
+/// small, so the size in the head of the client with the other byte order is too large, but
+/// it fits the limit with the swapped bytes.
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub(crate) fn read_handshake(
+    stream: &mut Stream,
+    stats: &Stats,
+) -> Result<(ReadMessage, Extensions, bool), io::Error> {
+    let mut head = [0u8; 10];
+    stream.read_exact(&mut head)?;
+    let size = from_wire_u32([head[0], head[1], head[2], head[3]]);
+    if size as usize > HANDSHAKE_MAX_SIZE && size.swap_bytes() as usize <= HANDSHAKE_MAX_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Attempted to connect with different byte order",
+        ));
+    }
+
+    read_message_head(stream, head, HANDSHAKE_MAX_SIZE, stats).map(|(message, flags)| {
+        let client = Extensions::from_flag(flags);
+        let supported = Extensions::supported();
+        let extensions = Extensions {
+            compression: client.compression && supported.compression,
+            shared_memory: client.shared_memory && supported.shared_memory,
+            checksum: client.checksum && supported.checksum,
+        };
+        (message, extensions, flags & FLAG_REPLY != 0)
+    })
+}
+
+// size of the data prepended to the compressed data
+fn decompressed_size(data: &[u8]) -> usize {
+    match data.get(..4) {
+        Some(size) => u32::from_le_bytes(size.try_into().unwrap()) as usize,
+        None => 0,
+    }
+}
+
+#[cfg(feature = "compression")]
+fn decompress(data: MessageData) -> Result<MessageData, io::Error> {
+    let res = lz4_flex::decompress_size_prepended(data.as_slice());
+    data.recycle();
+    res.map(MessageData::Heap)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_data: MessageData) -> Result<MessageData, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Compressed message received, but the compression feature is disabled",
+    ))
+}
+
+#[cfg(feature = "message-dump")]
+fn unknown_type(head: &[u8; 10]) -> io::Error {
+    let error = format!("Unknown message type, head: [{}]", hexdump(head));
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(not(feature = "message-dump"))]
+fn unknown_type(_head: &[u8; 10]) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Unknown message type")
+}
+
+fn read_message_flags(
+    stream: &mut Stream,
+    max_size: usize,
+    stats: &Stats,
+) -> Result<(ReadMessage, u8), io::Error> {
     let mut head = [0u8; 10];
     stream.read_exact(&mut head)?;
+    read_message_head(stream, head, max_size, stats)
+}
 
-    let message_size = u32::from_le_bytes([head[0], head[1], head[2], head[3]]) as usize;
+fn read_message_head(
+    stream: &mut Stream,
+    head: [u8; 10],
+    max_size: usize,
+    stats: &Stats,
+) -> Result<(ReadMessage, u8), io::Error> {
+    let message_size = from_wire_u32([head[0], head[1], head[2], head[3]]) as usize;
     let message_type = head[4];
-    let flag = head[5] != 0;
-    let id = u32::from_le_bytes([head[6], head[7], head[8], head[9]]);
+    let flags = head[5];
+    let flag = flags & FLAG_UPDATE != 0;
+    let id = from_wire_u32([head[6], head[7], head[8], head[9]]);
+
+    // the head of unknown type is corrupted, the rest of the stream can not be trusted
+    if !known_type(message_type) {
+        return Err(unknown_type(&head));
+    }
+
+    // the checksum flag of the commands announces the checksum
+    let checksum = if flags & FLAG_CHECKSUM != 0 && message_type != TYPE_COMMAND {
+        let mut checksum = [0u8; 4];
+        stream.read_exact(&mut checksum)?;
+        Some(from_wire_u32(checksum))
+    } else {
+        None
+    };
+    let wire_size = head.len() + checksum.map_or(0, |_| 4) + message_size;
+
+    // nothing is allocated for the oversized message, its data are skipped
+    if message_size > max_size {
+        let skipped = io::copy(
+            &mut Read::by_ref(stream).take(message_size as u64),
+            &mut io::sink(),
+        )?;
+        if skipped != message_size as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        stats.received(message_type, wire_size);
+        let error = MessageSizeError {
+            message_type,
+            id,
+            size: message_size,
+            limit: max_size,
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+    }
 
     let data = if message_size > HEAPLESS_SIZE {
-        let mut data = Vec::with_capacity(message_size);
-        unsafe { data.set_len(message_size) };
+        let mut data = POOL.take_zeroed(message_size);
         stream.read_exact(&mut data)?;
         MessageData::Heap(data)
     } else {
@@ -217,7 +1034,41 @@ pub(crate) fn read_message(stream: &mut TcpStream) -> Result<ReadMessage, io::Er
         MessageData::Stack(data)
     };
 
-    match message_type {
+    trace::record("read", message_type, flags, id, &[data.as_slice()]);
+    stats.received(message_type, wire_size);
+
+    if let Some(expected) = checksum {
+        let actual = crc32(&[data.as_slice()]);
+        if actual != expected {
+            let error = ChecksumError {
+                message_type,
+                id,
+                expected,
+                actual,
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+        }
+    }
+
+    // the compressed flag of the commands announces the compression
+    let data = if flags & FLAG_COMPRESSED != 0 && message_type != TYPE_COMMAND {
+        let size = decompressed_size(data.as_slice());
+        if size > max_size {
+            data.recycle();
+            let error = MessageSizeError {
+                message_type,
+                id,
+                size,
+                limit: max_size,
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+        }
+        decompress(data)?
+    } else {
+        data
+    };
+
+    let message = match message_type {
         TYPE_VALUE => Ok(ReadMessage::Value(id, flag, data)),
         TYPE_STATIC => Ok(ReadMessage::Static(id, flag, data)),
         TYPE_SIGNAL => Ok(ReadMessage::Signal(id, data)),
@@ -225,13 +1076,124 @@ pub(crate) fn read_message(stream: &mut TcpStream) -> Result<ReadMessage, io::Er
         TYPE_DICT => Ok(ReadMessage::Dict(id, flag, data)),
         TYPE_GRAPH => Ok(ReadMessage::Graph(id, flag, data)),
         TYPE_IMAGE => Ok(ReadMessage::Image(id, flag, data)),
-        TYPE_COMMAND => {
-            let command = deserialize(data).unwrap(); // TODO: handle error
-            Ok(ReadMessage::Command(command))
+        TYPE_BITSET => Ok(ReadMessage::Bitset(id, flag, data)),
+        TYPE_COMPARE_SWAP => Ok(ReadMessage::CompareSwap(id, flag, data)),
+        TYPE_SHARED_IMAGE => Ok(ReadMessage::SharedImage(id, flag, data)),
+        TYPE_COMMAND => match deserialize(data) {
+            Ok(command) => Ok(ReadMessage::Command(command)),
+            Err(error) => {
+                let error = CommandError { flags, error };
+                Err(io::Error::new(io::ErrorKind::InvalidData, error))
+            }
+        },
+        _ => Err(unknown_type(&head)),
+    };
+    message.map(|message| (message, flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+
+    #[test]
+    fn later_sends_are_called_in_order() {
+        struct Sent(Mutex<Vec<u32>>, Sender<()>);
+        fn first(sent: &Sent) {
+            sent.0.lock().unwrap().push(1);
         }
-        _ => Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Unknown message type",
-        )),
+        fn second(sent: &Sent) {
+            sent.0.lock().unwrap().push(2);
+            sent.1.send(()).unwrap();
+        }
+
+        let (channel, rx) = mpsc::channel();
+        let sent = Arc::new(Sent(Mutex::new(Vec::new()), channel));
+        send_later(Arc::downgrade(&sent), Duration::from_millis(40), second);
+        send_later(Arc::downgrade(&sent), Duration::from_millis(10), first);
+        // the dropped object is not called
+        let dropped = Arc::new(Sent(Mutex::new(Vec::new()), mpsc::channel().0));
+        send_later(Arc::downgrade(&dropped), Duration::ZERO, |_| {
+            panic!("object was dropped")
+        });
+        drop(dropped);
+
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(*sent.0.lock().unwrap(), vec![1, 2]);
+    }
+
+    fn connected() -> (Stream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (Stream::from_tcp(stream), peer)
+    }
+
+    fn head(size: u32, message_type: u8, id: u32) -> [u8; 10] {
+        let mut head = [0u8; 10];
+        head[..4].copy_from_slice(&to_wire_u32(size));
+        head[4] = message_type;
+        head[6..10].copy_from_slice(&to_wire_u32(id));
+        head
+    }
+
+    #[test]
+    fn unknown_type_is_rejected_before_reading_data() {
+        let (mut stream, mut peer) = connected();
+        peer.write_all(&head(u32::MAX, 0xee, 1)).unwrap();
+
+        let error = read_message(&mut stream, 1024, &Stats::new(false))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(oversized(&error).is_none());
+    }
+
+    #[test]
+    fn oversized_message_is_skipped() {
+        let (mut stream, mut peer) = connected();
+        peer.write_all(&head(64, TYPE_VALUE, 1)).unwrap();
+        peer.write_all(&[0u8; 64]).unwrap();
+        peer.write_all(&head(2, TYPE_VALUE, 2)).unwrap();
+        peer.write_all(&[7, 8]).unwrap();
+
+        let stats = Stats::new(false);
+        let error = read_message(&mut stream, 16, &stats).err().unwrap();
+        let error = oversized(&error).unwrap();
+        assert_eq!((error.id, error.size, error.limit), (1, 64, 16));
+
+        match read_message(&mut stream, 16, &stats).unwrap() {
+            ReadMessage::Value(id, _, data) => assert_eq!((id, data.as_slice()), (2, &[7, 8][..])),
+            _ => panic!("Value message expected"),
+        }
+    }
+
+    #[test]
+    fn invalid_command_is_an_error() {
+        let (mut stream, mut peer) = connected();
+        let mut head = head(3, TYPE_COMMAND, 0);
+        head[5] = FLAG_REPLY;
+        peer.write_all(&head).unwrap();
+        peer.write_all(&[0xff, 0xff, 0xff]).unwrap();
+
+        let error = read_message(&mut stream, 1024, &Stats::new(false))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(reply_rejection(&error));
+    }
+
+    #[test]
+    fn handshake_with_other_byte_order_is_rejected() {
+        let (mut stream, mut peer) = connected();
+        peer.write_all(&head(40u32.swap_bytes(), TYPE_COMMAND, 0))
+            .unwrap();
+
+        let error = read_handshake(&mut stream, &Stats::new(false))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("byte order"));
     }
 }
@@ -1,10 +1,14 @@
 use postcard;
 use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+#[cfg(feature = "sync-time")]
+use std::time::Instant;
 
-use crate::transport::{serialize, WriteMessage};
+use crate::transport::{send_later, serialize, MessageData, SendRate, Throttle, WriteMessage};
 
 pub struct Diff<'a, T> {
     pub v: T,
@@ -30,36 +34,137 @@ impl<'a, T: Serialize + Clone + PartialEq> Diff<'a, T> {
     }
 }
 
+// time of the last sync, without the sync-time feature it is empty and does nothing
+#[derive(Default)]
+pub(crate) struct SyncTime {
+    #[cfg(feature = "sync-time")]
+    time: RwLock<Option<Instant>>,
+}
+
+impl SyncTime {
+    #[inline]
+    pub(crate) fn touch(&self) {
+        #[cfg(feature = "sync-time")]
+        {
+            *self.time.write().unwrap() = Some(Instant::now());
+        }
+    }
+
+    #[cfg(feature = "sync-time")]
+    #[inline]
+    pub(crate) fn get(&self) -> Option<Instant> {
+        *self.time.read().unwrap()
+    }
+}
+
 pub(crate) trait UpdateValueClient: Send + Sync {
     fn update_value(&self, data: &[u8]) -> Result<(), String>;
+    fn set_swap_result(&self, _success: bool) {}
+    // the update was not applied, but the server still waits for the acknowledge
+    fn acknowledge(&self) {}
 }
 
 #[derive(Clone, Copy, Deserialize, Serialize)]
 pub struct Empty;
 
-impl Into<Empty> for () {
+impl From<()> for Empty {
     #[inline]
-    fn into(self) -> Empty {
+    fn from(_: ()) -> Self {
         Empty
     }
 }
 
+/// Enum with the names of its variants, it can be derived by the `EnumNames` derive.
+pub trait EnumNames: Sized + Clone + PartialEq + 'static {
+    /// All variants with their names in the order of the declaration.
+    const VARIANTS: &'static [(Self, &'static str)];
+
+    /// Name of the variant.
+    fn name(&self) -> &'static str {
+        Self::VARIANTS
+            .iter()
+            .find(|(variant, _)| variant == self)
+            .map_or("", |(_, name)| name)
+    }
+}
+
+// Values set faster than the send rate are not sent. The latest one is kept serialized, so it
+// can be sent after the interval from another thread.
+struct ClientRate {
+    id: u32,
+    channel: Sender<WriteMessage>,
+    state: Mutex<(SendRate, Option<MessageData>)>,
+}
+
+impl ClientRate {
+    fn new(id: u32, channel: Sender<WriteMessage>) -> Arc<Self> {
+        Arc::new(Self {
+            id,
+            channel,
+            state: Mutex::new((SendRate::new(), None)),
+        })
+    }
+
+    fn send(self: &Arc<Self>, data: MessageData, signal: bool) {
+        let mut state = self.state.lock().unwrap();
+        match state.0.throttle(signal) {
+            Throttle::Send => {
+                let message = WriteMessage::Value(self.id, signal, data);
+                self.channel.send(message).unwrap();
+            }
+            Throttle::Drop => state.1 = Some(data),
+            Throttle::Schedule(wait) => {
+                state.1 = Some(data);
+                send_later(Arc::downgrade(self), wait, Self::send_pending);
+            }
+        }
+    }
+
+    fn send_pending(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let (Some(signal), Some(data)) = (state.0.take_pending(), state.1.take()) {
+            let _ = self
+                .channel
+                .send(WriteMessage::Value(self.id, signal, data));
+        }
+    }
+
+    fn set_interval(&self, interval: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        if let (Some(signal), Some(data)) = (state.0.set_interval(interval), state.1.take()) {
+            let _ = self
+                .channel
+                .send(WriteMessage::Value(self.id, signal, data));
+        }
+    }
+}
+
 // Value --------------------------------------------
 pub struct Value<T> {
     id: u32,
     value: RwLock<T>,
     channel: Sender<WriteMessage>,
+    send_rate: Arc<ClientRate>,
+    updated: SyncTime,
+    changed: AtomicBool, // set by the server updates, cleared by the take_changed
+    swapped: RwLock<Option<bool>>, // result of the last compare and set
+    ack: bool,           // if the updates from the server are acknowledged
 }
 
 impl<T> Value<T>
 where
     T: Serialize + Clone,
 {
-    pub(crate) fn new(id: u32, value: T, channel: Sender<WriteMessage>) -> Arc<Self> {
+    pub(crate) fn new(id: u32, value: T, channel: Sender<WriteMessage>, ack: bool) -> Arc<Self> {
         Arc::new(Self {
             id,
             value: RwLock::new(value),
+            send_rate: ClientRate::new(id, channel.clone()),
             channel,
+            updated: SyncTime::default(),
+            changed: AtomicBool::new(false),
+            swapped: RwLock::new(None),
+            ack,
         })
     }
 
@@ -67,12 +172,62 @@ where
         self.value.read().unwrap().clone()
     }
 
+    /// Process the value without cloning it. The value is locked for reading while the op runs,
+    /// so the op must not set the value.
+    pub fn process<R>(&self, op: impl FnOnce(&T) -> R) -> R {
+        let v = self.value.read().unwrap();
+        op(&*v)
+    }
+
+    /// Time of the last value received from the server.
+    #[cfg(feature = "sync-time")]
+    pub fn last_update_time(&self) -> Option<Instant> {
+        self.updated.get()
+    }
+
     pub fn set(&self, value: T, signal: bool) {
-        let message = WriteMessage::Value(self.id, signal, serialize(&value));
+        let data = serialize(&value);
         let mut w = self.value.write().unwrap();
-        self.channel.send(message).unwrap();
+        self.send_rate.send(data, signal);
         *w = value;
     }
+
+    /// If the value was changed by the server since the last call. The flag is shared by all
+    /// callers, so only one place in the UI should take it.
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+
+    /// Limit the number of the values sent to the server per second, for example while the slider
+    /// is dragged. The values set faster are not sent, the latest one is sent after the interval,
+    /// so the server always ends with the last value. None or not positive rate removes the limit.
+    pub fn set_send_rate(&self, rate: Option<f64>) {
+        let interval = rate
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f64(1.0 / rate));
+        self.send_rate.set_interval(interval);
+    }
+
+    /// Send the value held back by the send rate right away, for example when the drag ends.
+    pub fn send_pending(&self) {
+        self.send_rate.send_pending();
+    }
+
+    /// Set the value on the server only if the server value is equal to `expected`.
+    ///
+    /// The local value is not changed, the server sends its value back in both cases. The result
+    /// is available by [`Value::swap_result`] when the reply is received.
+    pub fn compare_and_set(&self, expected: T, value: T, signal: bool) {
+        *self.swapped.write().unwrap() = None;
+        let data = serialize((expected, value));
+        let message = WriteMessage::CompareSwap(self.id, signal, data);
+        self.channel.send(message).unwrap();
+    }
+
+    /// Result of the last [`Value::compare_and_set`]. It is `None` until the reply is received.
+    pub fn swap_result(&self) -> Option<bool> {
+        *self.swapped.read().unwrap()
+    }
 }
 
 impl<T: for<'a> Deserialize<'a> + Send + Sync> UpdateValueClient for Value<T> {
@@ -82,15 +237,29 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> UpdateValueClient for Value<T> {
 
         let mut w = self.value.write().unwrap();
         *w = value;
-        self.channel.send(WriteMessage::ack(self.id)).unwrap();
+        self.updated.touch();
+        self.changed.store(true, Ordering::Relaxed);
+        self.acknowledge();
         Ok(())
     }
+
+    fn set_swap_result(&self, success: bool) {
+        *self.swapped.write().unwrap() = Some(success);
+    }
+
+    fn acknowledge(&self) {
+        if self.ack {
+            self.channel.send(WriteMessage::ack(self.id)).unwrap();
+        }
+    }
 }
 
 // StaticValue --------------------------------------------
 pub struct ValueStatic<T> {
     id: u32,
     value: RwLock<T>,
+    updated: SyncTime,
+    changed: AtomicBool,
 }
 
 impl<T: Clone> ValueStatic<T> {
@@ -98,12 +267,32 @@ impl<T: Clone> ValueStatic<T> {
         Arc::new(Self {
             id,
             value: RwLock::new(value),
+            updated: SyncTime::default(),
+            changed: AtomicBool::new(false),
         })
     }
 
     pub fn get(&self) -> T {
         self.value.read().unwrap().clone()
     }
+
+    /// Process the value without cloning it. The value is locked while the op runs.
+    pub fn process<R>(&self, op: impl FnOnce(&T) -> R) -> R {
+        let v = self.value.read().unwrap();
+        op(&*v)
+    }
+
+    /// Time of the last value received from the server.
+    #[cfg(feature = "sync-time")]
+    pub fn last_update_time(&self) -> Option<Instant> {
+        self.updated.get()
+    }
+
+    /// If the value was changed by the server since the last call. The flag is shared by all
+    /// callers, so only one place in the UI should take it.
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
 }
 
 impl<T: for<'a> Deserialize<'a> + Send + Sync> UpdateValueClient for ValueStatic<T> {
@@ -111,7 +300,10 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> UpdateValueClient for ValueStatic
         let value = postcard::from_bytes(data)
             .map_err(|e| format!("Parse error: {} for value id: {}", e, self.id))?;
 
-        *self.value.write().unwrap() = value;
+        let mut w = self.value.write().unwrap();
+        *w = value;
+        self.updated.touch();
+        self.changed.store(true, Ordering::Relaxed);
         Ok(())
     }
 }
@@ -133,12 +325,28 @@ impl<T: Serialize + Clone> Signal<T> {
     }
 
     pub fn set(&self, value: impl Into<T>) {
-        let message = serialize(&value.into());
+        let message = serialize(value.into());
         let message = WriteMessage::Signal(self.id, message);
         self.channel.send(message).unwrap();
     }
 }
 
+impl Signal<Empty> {
+    /// Emit the signal without any payload.
+    pub fn emit(&self) {
+        self.set(Empty);
+    }
+}
+
+// Unit is serialized the same way as Empty, so it can be used on the client for the Empty
+// signals of the server.
+impl Signal<()> {
+    /// Emit the signal without any payload.
+    pub fn emit(&self) {
+        self.set(());
+    }
+}
+
 // SERVER ---------------------------------------------------
 // ----------------------------------------------------------
 #[cfg(feature = "server")]
@@ -146,82 +354,515 @@ pub(crate) mod server {
     use super::*;
 
     use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Weak;
+    use std::time::{Duration, Instant};
 
     use pyo3::prelude::*;
 
+    use crate::audit::ChangeSource;
+    use crate::errors::{ErrorSource, Severity};
     use crate::python_convert::ToPython;
-    use crate::server::{Acknowledge, SyncTrait};
-    use crate::signals::ChangedValues;
+    use crate::server::{Acknowledge, SyncTarget, SyncTrait};
+    use crate::signals::{ChangedValues, Observers};
     use crate::transport::MessageData;
-    use crate::transport::{deserialize, serialize, WriteMessage};
+    use crate::transport::{deserialize, send_later, serialize, SendRate, Throttle, WriteMessage};
 
     pub(crate) trait UpdateValueServer: Send + Sync {
-        fn update_value(&self, data: MessageData, signal: bool) -> Result<(), String>;
+        // returns if the value was set as it was sent, so the other clients can get the same data
+        fn update_value(
+            &self,
+            data: MessageData,
+            signal: bool,
+            client: usize,
+        ) -> Result<bool, String>;
+        // returns if the value was swapped
+        fn compare_swap(&self, _data: MessageData, _signal: bool) -> Result<bool, String> {
+            Err("Compare and swap is supported only for values".to_string())
+        }
     }
 
     pub(crate) trait PyValueTrait: Send + Sync {
-        fn get_py<'py>(&self, py: Python<'py>) -> Bound<'py, PyAny>;
+        fn get_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>>;
         fn set_py(&self, value: &Bound<PyAny>, set_signal: bool, update: bool) -> PyResult<()>;
+        fn set_allowed_py(&self, allowed: Option<&Bound<PyAny>>) -> PyResult<()>;
+        fn set_property_py(&self, getter: Option<PyObject>, setter: Option<PyObject>);
+        fn set_transform_py(&self, transform: Option<PyObject>);
+        fn set_validator_py(&self, validator: Option<PyObject>);
+        fn set_on_change_py(&self, callback: Option<PyObject>);
+        fn set_send_rate_py(&self, rate: Option<f64>) -> PyResult<()>;
+        fn set_thresholds_py(
+            &self,
+            py: Python,
+            thresholds: Option<Vec<f64>>,
+            hysteresis: f64,
+        ) -> PyResult<()>;
+        fn reset_py(&self, py: Python, update: bool) -> PyResult<()>;
+        #[cfg(feature = "sync-time")]
+        fn last_sync_py(&self) -> Option<Instant>;
+        // serialized value for the state file
+        fn save_py(&self, py: Python) -> PyResult<Vec<u8>>;
+        fn load_py(&self, py: Python, data: &[u8]) -> PyResult<()>;
     }
 
     pub(crate) trait PyValueStaticTrait: Send + Sync {
         fn get_py<'py>(&self, py: Python<'py>) -> Bound<'py, PyAny>;
         fn set_py(&self, value: &Bound<PyAny>, update: bool) -> PyResult<()>;
+        fn reset_py(&self, py: Python, update: bool) -> PyResult<()>;
+        #[cfg(feature = "sync-time")]
+        fn last_sync_py(&self) -> Option<Instant>;
     }
 
     pub(crate) trait PySignalTrait: Send + Sync {
         fn set_py(&self, value: &Bound<PyAny>) -> PyResult<()>;
     }
 
+    /*
+        The value is sent to the client only if it moves to another zone between the thresholds.
+        To move up, the value has to be over the threshold by the hysteresis, to move down it
+        has to be under the threshold by the hysteresis. The server mirror is updated always, so
+        the client value lags behind the server value between the thresholds.
+    */
+    struct Thresholds {
+        levels: Vec<f64>, // sorted
+        hysteresis: f64,
+        zone: Option<usize>, // zone of the last sent value, None if unknown
+    }
+
+    impl Thresholds {
+        fn update(&mut self, value: f64) -> bool {
+            let levels = &self.levels;
+            let zone = match self.zone {
+                Some(mut zone) => {
+                    while zone < levels.len() && value >= levels[zone] + self.hysteresis {
+                        zone += 1;
+                    }
+                    while zone > 0 && value < levels[zone - 1] - self.hysteresis {
+                        zone -= 1;
+                    }
+                    zone
+                }
+                None => levels.iter().filter(|level| value >= **level).count(),
+            };
+
+            let changed = self.zone != Some(zone);
+            self.zone = Some(zone);
+            changed
+        }
+    }
+
     // PyValue --------------------------------------------------
+    // accepts the value, corrects it or rejects it with the reason
+    type Validator<T> = Box<dyn Fn(&T) -> Result<Option<T>, String> + Send + Sync>;
+
+    // Values sent to the clients which were not acknowledged yet, counted for every client which
+    // gets the value. The writes of the client are ignored while some of its acknowledges are
+    // pending, the lost acknowledge is detected by the timeout.
+    #[derive(Default)]
+    pub(crate) struct Pending {
+        clients: Vec<Waiting>,
+    }
+
+    struct Waiting {
+        client: usize,
+        count: usize,
+        since: Option<Instant>, // the first expected acknowledge or the last received one
+    }
+
+    impl Waiting {
+        fn expect(&mut self) {
+            if self.count == 0 {
+                self.since = Some(Instant::now());
+            }
+            self.count += 1;
+        }
+
+        fn timed_out(&self, timeout: Option<Duration>) -> bool {
+            match (self.since, timeout) {
+                (Some(since), Some(timeout)) => self.count > 0 && since.elapsed() >= timeout,
+                _ => false,
+            }
+        }
+    }
+
+    impl Pending {
+        fn get(&mut self, client: usize) -> Option<&mut Waiting> {
+            self.clients.iter_mut().find(|w| w.client == client)
+        }
+
+        // the message goes to all clients which get the value, except the given one
+        pub(crate) fn expect(&mut self, except: Option<usize>) {
+            self.clients
+                .iter_mut()
+                .filter(|w| Some(w.client) != except)
+                .for_each(Waiting::expect);
+        }
+
+        pub(crate) fn expect_client(&mut self, client: usize) {
+            match self.get(client) {
+                Some(waiting) => waiting.expect(),
+                None => {
+                    self.reset(client);
+                    self.expect_client(client);
+                }
+            }
+        }
+
+        pub(crate) fn acknowledge(&mut self, client: usize) {
+            if let Some(waiting) = self.get(client).filter(|w| w.count > 0) {
+                waiting.count -= 1;
+                waiting.since = Some(Instant::now());
+            }
+        }
+
+        pub(crate) fn count(&self, client: usize) -> usize {
+            self.clients
+                .iter()
+                .find(|w| w.client == client)
+                .map_or(0, |w| w.count)
+        }
+
+        // returns the number of the lost acknowledges of the client
+        pub(crate) fn take_timed_out(&mut self, client: usize, timeout: Option<Duration>) -> usize {
+            match self.get(client).filter(|w| w.timed_out(timeout)) {
+                Some(waiting) => {
+                    waiting.since = None;
+                    std::mem::take(&mut waiting.count)
+                }
+                None => 0,
+            }
+        }
+
+        pub(crate) fn reset(&mut self, client: usize) {
+            self.remove(client);
+            self.clients.push(Waiting {
+                client,
+                count: 0,
+                since: None,
+            });
+        }
+
+        pub(crate) fn remove(&mut self, client: usize) {
+            self.clients.retain(|w| w.client != client);
+        }
+    }
+
     pub(crate) struct PyValue<T> {
         id: u32,
-        value: RwLock<(T, usize)>,
+        value: RwLock<(T, Pending)>,
+        default: T,                            // initial value used by the reset
+        allowed: RwLock<Option<Vec<Vec<u8>>>>, // serialized allowed values
+        thresholds: RwLock<Option<Thresholds>>,
+        synced: SyncTime,
+        property: RwLock<(Option<PyObject>, Option<PyObject>)>, // python getter and setter
+        transform: RwLock<Option<PyObject>>, // applied to the values from the client
+        validator: RwLock<Option<PyObject>>, // can reject the values from the client
+        checker: RwLock<Option<Validator<T>>>, // validator from the creator, it does not need the GIL
+        on_change: RwLock<Option<PyObject>>,   // called with the applied values from the client
+        send_rate: RwLock<SendRate>,           // the latest value is sent once per interval
+        ack: bool, // if the client acknowledges the updates, otherwise the client writes always pass
+        this: Weak<Self>,
         channel: Sender<WriteMessage>,
         connected: Arc<AtomicBool>,
         signals: ChangedValues,
+        observers: Observers,
     }
 
-    impl<T> PyValue<T> {
+    impl<T: Clone> PyValue<T> {
         pub(crate) fn new(
             id: u32,
             value: T,
             channel: Sender<WriteMessage>,
             connected: Arc<AtomicBool>,
             signals: ChangedValues,
+            observers: Observers,
+            ack: bool,
         ) -> Arc<Self> {
-            Arc::new(Self {
+            Arc::new_cyclic(|this| Self {
                 id,
-                value: RwLock::new((value, 0)),
+                default: value.clone(),
+                value: RwLock::new((value, Pending::default())),
+                allowed: RwLock::new(None),
+                thresholds: RwLock::new(None),
+                synced: SyncTime::default(),
+                property: RwLock::new((None, None)),
+                transform: RwLock::new(None),
+                validator: RwLock::new(None),
+                checker: RwLock::new(None),
+                on_change: RwLock::new(None),
+                send_rate: RwLock::new(SendRate::new()),
+                ack,
+                this: this.clone(),
                 channel,
                 connected,
                 signals,
+                observers,
             })
         }
     }
 
-    impl<T> PyValueTrait for PyValue<T>
+    impl<T> PyValue<T> {
+        pub(crate) fn id(&self) -> u32 {
+            self.id
+        }
+
+        pub(crate) fn set_validator(&self, validator: Validator<T>) {
+            *self.checker.write().unwrap() = Some(validator);
+        }
+
+        // returns the corrected value, None if the value is accepted as it is
+        fn check(&self, value: &T) -> Result<Option<T>, String> {
+            match *self.checker.read().unwrap() {
+                Some(ref validator) => validator(value),
+                None => Ok(None),
+            }
+        }
+    }
+
+    impl<T: Serialize> PyValue<T> {
+        // values are compared in the serialized form, so T does not need to implement PartialEq
+        fn is_allowed(&self, value: &T) -> bool {
+            match *self.allowed.read().unwrap() {
+                Some(ref allowed) => {
+                    let data = postcard::to_stdvec(value).unwrap();
+                    allowed.contains(&data)
+                }
+                None => true,
+            }
+        }
+    }
+
+    /*
+        If the value is bound to a python property, the python object is the source of truth.
+        The getter is called on every read from python and on every sync with the client, the
+        setter is called on every write from python and from the client. The GIL is always
+        acquired before the property lock to avoid deadlocks with python threads.
+    */
+    impl<T> PyValue<T>
     where
-        T: Serialize + Clone + ToPython + for<'py> FromPyObject<'py> + 'static,
+        T: ToPython + for<'py> FromPyObject<'py>,
     {
-        fn get_py<'py>(&self, py: Python<'py>) -> Bound<'py, PyAny> {
-            self.value.read().unwrap().0.to_python(py)
+        fn read_property(&self, py: Python) -> PyResult<Option<T>> {
+            let getter = match self.property.read().unwrap().0 {
+                Some(ref getter) => getter.clone_ref(py),
+                None => return Ok(None),
+            };
+
+            let value = getter.call0(py)?.extract(py)?;
+            Ok(Some(value))
         }
 
-        fn set_py(&self, value: &Bound<PyAny>, set_signal: bool, update: bool) -> PyResult<()> {
-            let value: T = value.extract()?;
+        fn write_property(&self, py: Python, value: &T) -> PyResult<()> {
+            let setter = match self.property.read().unwrap().1 {
+                Some(ref setter) => setter.clone_ref(py),
+                None => return Ok(()),
+            };
+
+            setter.call1(py, (value.to_python(py),))?;
+            Ok(())
+        }
+
+        fn has_property(&self) -> bool {
+            let p = self.property.read().unwrap();
+            p.0.is_some() || p.1.is_some()
+        }
+    }
+
+    impl<T> PyValue<T>
+    where
+        T: ToPython + for<'py> FromPyObject<'py> + Serialize,
+    {
+        // returns the transformed value and whether it differs from the original
+        fn apply_transform(&self, value: T) -> PyResult<(T, bool)> {
+            if self.transform.read().unwrap().is_none() {
+                return Ok((value, false));
+            }
+
+            Python::with_gil(|py| {
+                let transform = match *self.transform.read().unwrap() {
+                    Some(ref transform) => transform.clone_ref(py),
+                    None => return Ok((value, false)),
+                };
+
+                let new_value: T = transform.call1(py, (value.to_python(py),))?.extract(py)?;
+                let changed = postcard::to_stdvec(&new_value).unwrap()
+                    != postcard::to_stdvec(&value).unwrap();
+                Ok((new_value, changed))
+            })
+        }
+
+        // the validator rejects the value by raising an exception or by returning the error message
+        fn validate(&self, value: &T) -> Result<(), String> {
+            if self.validator.read().unwrap().is_none() {
+                return Ok(());
+            }
+
+            Python::with_gil(|py| {
+                let validator = match *self.validator.read().unwrap() {
+                    Some(ref validator) => validator.clone_ref(py),
+                    None => return Ok(()),
+                };
+
+                let result = validator
+                    .call1(py, (value.to_python(py),))
+                    .map_err(|e| e.to_string())?;
+                match result.extract::<Option<String>>(py) {
+                    Ok(None) => Ok(()),
+                    Ok(Some(error)) => Err(error),
+                    Err(_) => Err("Validator has to return None or the error message".to_string()),
+                }
+            })
+        }
+    }
+
+    impl<T> PyValue<T>
+    where
+        T: ToPython + for<'py> FromPyObject<'py> + Clone + 'static,
+    {
+        // the callback is called later in the observers thread, the value is already applied
+        fn notify_change(&self, value: &T) {
+            if self.on_change.read().unwrap().is_none() {
+                return;
+            }
+
+            let this = self.this.clone();
+            let value = value.clone();
+            self.observers.dispatch(move |py| {
+                if let Some(this) = this.upgrade() {
+                    this.call_on_change(py, &value);
+                }
+            });
+        }
+
+        fn call_on_change(&self, py: Python, value: &T) {
+            let callback = match *self.on_change.read().unwrap() {
+                Some(ref callback) => callback.clone_ref(py),
+                None => return,
+            };
+
+            if let Err(e) = callback.call1(py, (value.to_python(py),)) {
+                let error = format!("Change callback error: {} for value id: {}", e, self.id);
+                let source = ErrorSource::Callback;
+                self.signals
+                    .error(Severity::Warning, source, Some(self.id), error);
+            }
+        }
+    }
+
+    impl<T: Serialize> PyValue<T> {
+        // the dropped values are already in the mirror, only the latest one is sent
+        fn send_pending(&self) {
+            let pending = self.send_rate.write().unwrap().take_pending();
+            if let Some(update) = pending {
+                self.send_current(update);
+            }
+        }
+
+        // the client shows the rejected value, so the server value is sent back
+        fn reject(&self, error: String) -> Result<bool, String> {
             if self.connected.load(Ordering::Relaxed) {
+                let mut w = self.value.write().unwrap();
+                let message = WriteMessage::Value(self.id, false, serialize(&w.0));
+                self.expect_ack(&mut w.1);
+                self.channel.send(message).unwrap();
+            }
+            Err(error)
+        }
+
+        fn send_current(&self, update: bool) {
+            if self.connected.load(Ordering::Relaxed) {
+                let mut w = self.value.write().unwrap();
+                let message = WriteMessage::Value(self.id, update, serialize(&w.0));
+                self.expect_ack(&mut w.1);
+                self.synced.touch();
+                self.channel.send(message).unwrap();
+            }
+        }
+    }
+
+    impl<T> PyValue<T> {
+        // every sent value waits for the acknowledges of all clients, if the clients send them
+        #[inline]
+        fn expect_ack(&self, pending: &mut Pending) {
+            if self.ack {
+                pending.expect(None);
+            }
+        }
+
+        fn crosses_threshold(&self, value: &Bound<PyAny>) -> PyResult<bool> {
+            match *self.thresholds.write().unwrap() {
+                Some(ref mut thresholds) => Ok(thresholds.update(value.extract()?)),
+                None => Ok(true),
+            }
+        }
+
+        // the client value is not known anymore, so the next value is sent always
+        fn reset_thresholds(&self) {
+            if let Some(ref mut thresholds) = *self.thresholds.write().unwrap() {
+                thresholds.zone = None;
+            }
+        }
+    }
+
+    impl<T> PyValueTrait for PyValue<T>
+    where
+        T: Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + ToPython
+            + for<'py> FromPyObject<'py>
+            + 'static,
+    {
+        fn get_py<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+            if let Some(value) = self.read_property(py)? {
+                self.value.write().unwrap().0 = value;
+            }
+            Ok(self.value.read().unwrap().0.to_python(py))
+        }
+
+        fn set_py(&self, object: &Bound<PyAny>, set_signal: bool, update: bool) -> PyResult<()> {
+            let py = object.py();
+            let value: T = object.extract()?;
+            if !self.is_allowed(&value) {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Value is not one of the allowed values for value id: {}",
+                    self.id
+                )));
+            }
+            self.write_property(py, &value)?;
+
+            let mut send =
+                self.connected.load(Ordering::Relaxed) && self.crosses_threshold(object)?;
+            if send {
+                let throttle = self.send_rate.write().unwrap().throttle(update);
+                match throttle {
+                    Throttle::Send => {}
+                    Throttle::Drop => send = false,
+                    Throttle::Schedule(wait) => {
+                        send_later(self.this.clone(), wait, Self::send_pending);
+                        send = false;
+                    }
+                }
+            }
+
+            if send {
                 let data = serialize(&value);
                 let message = WriteMessage::Value(self.id, update, data);
                 let mut w = self.value.write().unwrap();
+                self.signals
+                    .audit()
+                    .record(self.id, ChangeSource::Server, &w.0, &value);
                 w.0 = value.clone();
-                w.1 += 1;
+                self.expect_ack(&mut w.1);
+                self.synced.touch();
                 self.channel.send(message).unwrap();
                 if set_signal {
                     self.signals.set(self.id, value);
                 }
             } else {
                 let mut w = self.value.write().unwrap();
+                self.signals
+                    .audit()
+                    .record(self.id, ChangeSource::Server, &w.0, &value);
                 w.0 = value.clone();
                 if set_signal {
                     self.signals.set(self.id, value);
@@ -230,49 +871,346 @@ pub(crate) mod server {
 
             Ok(())
         }
+
+        fn set_allowed_py(&self, allowed: Option<&Bound<PyAny>>) -> PyResult<()> {
+            let allowed = match allowed {
+                Some(allowed) if !allowed.is_none() => {
+                    let mut data = Vec::new();
+                    for value in allowed.try_iter()? {
+                        let value: T = value?.extract()?;
+                        data.push(postcard::to_stdvec(&value).unwrap());
+                    }
+                    Some(data)
+                }
+                _ => None,
+            };
+
+            *self.allowed.write().unwrap() = allowed;
+            Ok(())
+        }
+
+        fn set_property_py(&self, getter: Option<PyObject>, setter: Option<PyObject>) {
+            *self.property.write().unwrap() = (getter, setter);
+        }
+
+        fn set_transform_py(&self, transform: Option<PyObject>) {
+            *self.transform.write().unwrap() = transform;
+        }
+
+        fn set_validator_py(&self, validator: Option<PyObject>) {
+            *self.validator.write().unwrap() = validator;
+        }
+
+        fn set_on_change_py(&self, callback: Option<PyObject>) {
+            *self.on_change.write().unwrap() = callback;
+        }
+
+        fn set_send_rate_py(&self, rate: Option<f64>) -> PyResult<()> {
+            let interval = match rate {
+                Some(rate) if rate.is_nan() || rate <= 0.0 => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(
+                        "Send rate must be greater than 0.",
+                    ));
+                }
+                Some(rate) => Some(Duration::from_secs_f64(1.0 / rate)),
+                None => None,
+            };
+
+            let pending = self.send_rate.write().unwrap().set_interval(interval);
+            if let Some(update) = pending {
+                self.send_current(update);
+            }
+            Ok(())
+        }
+
+        fn set_thresholds_py(
+            &self,
+            py: Python,
+            thresholds: Option<Vec<f64>>,
+            hysteresis: f64,
+        ) -> PyResult<()> {
+            let thresholds = match thresholds {
+                Some(mut levels) => {
+                    if hysteresis.is_nan() || hysteresis < 0.0 || levels.iter().any(|l| l.is_nan())
+                    {
+                        return Err(pyo3::exceptions::PyValueError::new_err(
+                            "Thresholds must be numbers and hysteresis must be non-negative.",
+                        ));
+                    }
+                    self.value
+                        .read()
+                        .unwrap()
+                        .0
+                        .to_python(py)
+                        .extract::<f64>()
+                        .map_err(|_| {
+                            pyo3::exceptions::PyTypeError::new_err(format!(
+                                "Thresholds can be set only for numeric values, value id: {}",
+                                self.id
+                            ))
+                        })?;
+
+                    levels.sort_by(|a, b| a.total_cmp(b));
+                    Some(Thresholds {
+                        levels,
+                        hysteresis,
+                        zone: None,
+                    })
+                }
+                None => None,
+            };
+
+            *self.thresholds.write().unwrap() = thresholds;
+            Ok(())
+        }
+
+        // the initial value goes through the same path as the values set from python
+        fn reset_py(&self, py: Python, update: bool) -> PyResult<()> {
+            let default = self.default.to_python(py);
+            self.set_py(&default, false, update)
+        }
+
+        #[cfg(feature = "sync-time")]
+        fn last_sync_py(&self) -> Option<Instant> {
+            self.synced.get()
+        }
+
+        fn save_py(&self, py: Python) -> PyResult<Vec<u8>> {
+            if let Some(value) = self.read_property(py)? {
+                self.value.write().unwrap().0 = value;
+            }
+            Ok(postcard::to_stdvec(&self.value.read().unwrap().0).unwrap())
+        }
+
+        // the loaded value goes through the same path as the values set from python
+        fn load_py(&self, py: Python, data: &[u8]) -> PyResult<()> {
+            let value: T = postcard::from_bytes(data).map_err(|e| {
+                pyo3::exceptions::PyValueError::new_err(format!(
+                    "Parse error: {} for value id: {}",
+                    e, self.id
+                ))
+            })?;
+            self.set_py(&value.to_python(py), false, false)
+        }
     }
 
     impl<T> UpdateValueServer for PyValue<T>
     where
-        T: ToPython + for<'a> Deserialize<'a> + Clone + 'static,
+        T: ToPython
+            + for<'py> FromPyObject<'py>
+            + Serialize
+            + for<'a> Deserialize<'a>
+            + Clone
+            + 'static,
     {
-        fn update_value(&self, data: MessageData, signal: bool) -> Result<(), String> {
+        fn update_value(
+            &self,
+            data: MessageData,
+            signal: bool,
+            client: usize,
+        ) -> Result<bool, String> {
             let value: T = deserialize(data)
                 .map_err(|e| format!("Parse error: {} for value id: {}", e, self.id))?;
+            let (value, corrected) = match self.check(&value) {
+                Ok(Some(corrected)) => (corrected, true),
+                Ok(None) => (value, false),
+                Err(e) => {
+                    return self
+                        .reject(format!("Validation error: {} for value id: {}", e, self.id))
+                }
+            };
+            let (value, transformed) = self
+                .apply_transform(value)
+                .map_err(|e| format!("Transform error: {} for value id: {}", e, self.id))?;
+            let transformed = transformed || corrected;
 
+            // the validator is called before the lock, it needs the GIL
+            let error = match self.validate(&value) {
+                Err(e) => Some(format!("Validation error: {} for value id: {}", e, self.id)),
+                Ok(()) if !self.is_allowed(&value) => Some(format!(
+                    "Value is not one of the allowed values for value id: {}",
+                    self.id
+                )),
+                Ok(()) => None,
+            };
+            if let Some(error) = error {
+                return self.reject(error);
+            }
+
+            // Only the acknowledges of the writing client are checked, the other clients get
+            // the value after it is set. The lost acknowledge would block the client writes until
+            // the next connection, the accepted value is sent back, so both sides have it.
             let mut w = self.value.write().unwrap();
-            if w.1 == 0 {
+            let lost = w.1.take_timed_out(client, self.signals.ack_timeout());
+            let timed_out = lost > 0;
+            let updated = w.1.count(client) == 0;
+            if updated {
+                self.signals
+                    .audit()
+                    .record(self.id, ChangeSource::Client, &w.0, &value);
                 w.0 = value.clone();
+
+                // the client has to see the canonical form of the value
+                if (transformed || timed_out) && self.connected.load(Ordering::Relaxed) {
+                    let message = WriteMessage::Value(self.id, false, serialize(&w.0));
+                    self.expect_ack(&mut w.1);
+                    self.synced.touch();
+                    self.channel.send(message).unwrap();
+                }
+            }
+            drop(w);
+
+            if timed_out {
+                let error = format!(
+                    "{} acknowledges of value id: {} timed out, the client value was accepted",
+                    lost, self.id
+                );
+                let source = ErrorSource::Transport;
+                self.signals
+                    .error(Severity::Warning, source, Some(self.id), error);
+            }
+            if updated {
+                self.reset_thresholds();
             }
 
+            if updated && self.has_property() {
+                Python::with_gil(|py| self.write_property(py, &value))
+                    .map_err(|e| format!("Property error: {} for value id: {}", e, self.id))?;
+            }
+
+            if updated {
+                self.notify_change(&value);
+            }
             if signal {
                 self.signals.set(self.id, value);
             }
-            Ok(())
+            Ok(updated && !transformed)
+        }
+
+        // The value is compared in the serialized form under the value lock, so no other set can
+        // come in between. The server value is sent back in both cases, so the client has it.
+        fn compare_swap(&self, data: MessageData, signal: bool) -> Result<bool, String> {
+            let (expected, value): (T, T) = deserialize(data)
+                .map_err(|e| format!("Parse error: {} for value id: {}", e, self.id))?;
+
+            if self.has_property() {
+                let property = Python::with_gil(|py| self.read_property(py))
+                    .map_err(|e| format!("Property error: {} for value id: {}", e, self.id))?;
+                if let Some(current) = property {
+                    self.value.write().unwrap().0 = current;
+                }
+            }
+
+            // the same steps as for the plain write, the failed one fails the swap
+            let (value, valid) = match self.check(&value) {
+                Ok(corrected) => match self.apply_transform(corrected.unwrap_or(value)) {
+                    Ok((value, _)) => {
+                        let valid = self.validate(&value).is_ok();
+                        (Some(value), valid)
+                    }
+                    Err(e) => {
+                        let error = format!("Transform error: {} for value id: {}", e, self.id);
+                        self.signals.error(
+                            Severity::Warning,
+                            ErrorSource::Value,
+                            Some(self.id),
+                            error,
+                        );
+                        (None, false)
+                    }
+                },
+                Err(_) => (None, false),
+            };
+
+            let mut w = self.value.write().unwrap();
+            let value = value.filter(|value| {
+                valid
+                    && self.is_allowed(value)
+                    && postcard::to_stdvec(&w.0).unwrap() == postcard::to_stdvec(&expected).unwrap()
+            });
+            let success = value.is_some();
+            if let Some(ref value) = value {
+                self.signals
+                    .audit()
+                    .record(self.id, ChangeSource::Client, &w.0, value);
+                w.0 = value.clone();
+            }
+
+            // the client gets the canonical value, also the transformed one after the swap
+            if self.connected.load(Ordering::Relaxed) {
+                let message = WriteMessage::Value(self.id, false, serialize(&w.0));
+                self.expect_ack(&mut w.1);
+                self.synced.touch();
+                self.channel.send(message).unwrap();
+            }
+            drop(w);
+
+            if let Some(value) = value {
+                if self.has_property() {
+                    Python::with_gil(|py| self.write_property(py, &value))
+                        .map_err(|e| format!("Property error: {} for value id: {}", e, self.id))?;
+                }
+                self.notify_change(&value);
+                if signal {
+                    self.signals.set(self.id, value);
+                }
+            }
+            Ok(success)
         }
     }
 
     impl<T: Sync + Send> Acknowledge for PyValue<T> {
-        fn acknowledge(&self) {
-            let mut w = self.value.write().unwrap();
-            if w.1 > 0 {
-                w.1 -= 1;
+        fn acknowledge(&self, client: usize) {
+            self.value.write().unwrap().1.acknowledge(client);
+        }
+
+        fn reset(&self, client: usize) {
+            self.value.write().unwrap().1.reset(client);
+        }
+
+        fn remove(&self, client: usize) {
+            self.value.write().unwrap().1.remove(client);
+        }
+
+        fn relayed(&self, client: usize) {
+            if self.ack {
+                self.value.write().unwrap().1.expect(Some(client));
             }
         }
     }
 
     impl<T: Sync + Send> SyncTrait for PyValue<T>
     where
-        T: Serialize + Clone,
+        T: Serialize + Clone + ToPython + for<'py> FromPyObject<'py>,
     {
-        fn sync(&self) {
+        fn sync(&self, target: SyncTarget) {
+            if self.has_property() {
+                match Python::with_gil(|py| self.read_property(py)) {
+                    Ok(Some(value)) => self.value.write().unwrap().0 = value,
+                    Ok(None) => {}
+                    Err(e) => {
+                        let error = format!("Property error: {} for value id: {}", e, self.id);
+                        let source = ErrorSource::Value;
+                        self.signals
+                            .error(Severity::Warning, source, Some(self.id), error);
+                    }
+                }
+            }
+
             let mut w = self.value.write().unwrap();
-            w.1 = 1;
+            match target {
+                SyncTarget::All => self.expect_ack(&mut w.1),
+                SyncTarget::Client(client) if self.ack => w.1.expect_client(client),
+                SyncTarget::Client(_) => {}
+            }
             let data = serialize(&w.0);
+            self.synced.touch();
             drop(w);
+            self.reset_thresholds();
+            self.send_rate.write().unwrap().clear();
 
             let message = WriteMessage::Value(self.id, false, data);
-            self.channel.send(message).unwrap();
+            self.channel.send(target.message(message)).unwrap();
         }
     }
 
@@ -280,11 +1218,13 @@ pub(crate) mod server {
     pub(crate) struct PyValueStatic<T> {
         id: u32,
         value: RwLock<T>,
+        default: T, // initial value used by the reset
         channel: Sender<WriteMessage>,
         connected: Arc<AtomicBool>,
+        synced: SyncTime,
     }
 
-    impl<T> PyValueStatic<T> {
+    impl<T: Clone> PyValueStatic<T> {
         pub(crate) fn new(
             id: u32,
             value: T,
@@ -293,9 +1233,11 @@ pub(crate) mod server {
         ) -> Arc<Self> {
             Arc::new(Self {
                 id,
+                default: value.clone(),
                 value: RwLock::new(value),
                 channel,
                 connected,
+                synced: SyncTime::default(),
             })
         }
     }
@@ -315,6 +1257,7 @@ pub(crate) mod server {
                 let message = WriteMessage::Static(self.id, update, data);
                 let mut v = self.value.write().unwrap();
                 *v = value;
+                self.synced.touch();
                 self.channel.send(message).unwrap();
             } else {
                 *self.value.write().unwrap() = value;
@@ -322,17 +1265,28 @@ pub(crate) mod server {
 
             Ok(())
         }
+
+        fn reset_py(&self, py: Python, update: bool) -> PyResult<()> {
+            let default = self.default.to_python(py);
+            self.set_py(&default, update)
+        }
+
+        #[cfg(feature = "sync-time")]
+        fn last_sync_py(&self) -> Option<Instant> {
+            self.synced.get()
+        }
     }
 
     impl<T: Sync + Send> SyncTrait for PyValueStatic<T>
     where
         T: Serialize + Clone,
     {
-        fn sync(&self) {
+        fn sync(&self, target: SyncTarget) {
             let w = self.value.read().unwrap();
             let data = serialize(&(*w));
             let message = WriteMessage::Static(self.id, false, data);
-            self.channel.send(message).unwrap();
+            self.synced.touch();
+            self.channel.send(target.message(message)).unwrap();
         }
     }
 
@@ -357,11 +1311,11 @@ pub(crate) mod server {
     where
         T: for<'a> Deserialize<'a> + ToPython + 'static,
     {
-        fn update_value(&self, data: MessageData, _: bool) -> Result<(), String> {
+        fn update_value(&self, data: MessageData, _: bool, _: usize) -> Result<bool, String> {
             let value: T = deserialize(data)
                 .map_err(|e| format!("Parse error: {} for signal id: {}", e, self.id))?;
             self.signals.set(self.id, value);
-            Ok(())
+            Ok(false) // signals are events, they are not relayed
         }
     }
 
@@ -376,3 +1330,128 @@ pub(crate) mod server {
         }
     }
 }
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc::{self, Receiver};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use pyo3::prelude::*;
+
+    use super::server::{PySignal, PyValue, PyValueTrait, UpdateValueServer};
+    use super::{Empty, Signal};
+    use crate::python_convert::ToPython;
+    use crate::server::{Acknowledge, SyncTarget, SyncTrait};
+    use crate::signals::{ChangedValues, Observers};
+    use crate::transport::{deserialize, serialize, WriteMessage};
+
+    fn value(signals: ChangedValues) -> (Arc<PyValue<i64>>, Receiver<WriteMessage>) {
+        let (channel, rx) = mpsc::channel();
+        let connected = Arc::new(AtomicBool::new(true));
+        let value = PyValue::new(1, 0, channel, connected, signals, Observers::new(), true);
+        (value, rx)
+    }
+
+    #[test]
+    fn reconnect_resets_pending_acknowledges() {
+        let (value, _rx) = value(ChangedValues::new());
+
+        // the values sent to the client which disconnected before the acknowledges
+        value.reset(0);
+        value.sync(SyncTarget::All);
+        value.sync(SyncTarget::All);
+
+        // the connection of the next client does not wait for them
+        value.remove(0);
+        value.reset(1);
+        value.sync(SyncTarget::Client(1));
+        assert!(!value.update_value(serialize(5i64), false, 1).unwrap());
+        value.acknowledge(1);
+        assert!(value.update_value(serialize(6i64), false, 1).unwrap());
+    }
+
+    #[test]
+    fn acknowledges_are_counted_per_client() {
+        let (value, _rx) = value(ChangedValues::new());
+        value.reset(0);
+        value.reset(1);
+        value.sync(SyncTarget::All);
+
+        // the next client keeps the acknowledges of the connected ones
+        value.reset(2);
+        value.sync(SyncTarget::Client(2));
+
+        // the acknowledge of one client does not unblock the writes of the other one
+        value.acknowledge(0);
+        assert!(!value.update_value(serialize(5i64), false, 1).unwrap());
+        assert!(value.update_value(serialize(6i64), false, 0).unwrap());
+
+        // the value of the first client is relayed to the others
+        value.relayed(0);
+        value.acknowledge(1);
+        assert!(!value.update_value(serialize(7i64), false, 1).unwrap());
+        value.acknowledge(1);
+        assert!(value.update_value(serialize(7i64), false, 1).unwrap());
+    }
+
+    #[test]
+    fn lost_acknowledge_times_out() {
+        let signals = ChangedValues::new();
+        signals.set_ack_timeout(Some(Duration::ZERO));
+        let (value, rx) = value(signals);
+
+        value.reset(0);
+        value.sync(SyncTarget::All);
+        assert!(value.update_value(serialize(5i64), false, 0).unwrap());
+
+        // the accepted value is sent back to the client, which acknowledges it
+        let echoed = rx.try_iter().last().unwrap();
+        assert!(matches!(echoed, WriteMessage::Value(1, false, _)));
+        value.acknowledge(0);
+        assert!(value.update_value(serialize(6i64), false, 0).unwrap());
+    }
+
+    #[test]
+    fn unit_signal_is_emitted_and_received() {
+        let (channel, rx) = mpsc::channel();
+        let signals = ChangedValues::new();
+        let received = PySignal::<Empty>::new(7, signals.clone());
+
+        // the client unit signal is the payload free Empty signal of the server
+        Signal::<()>::new(7, channel).emit();
+        let data = match rx.try_recv().unwrap() {
+            WriteMessage::Signal(7, data) => data,
+            _ => panic!("Signal message expected"),
+        };
+        assert!(data.as_slice().is_empty());
+
+        received.update_value(data, true, 0).unwrap();
+        assert_eq!(signals.drain_typed::<Empty>(7).unwrap().len(), 1);
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| assert!(().to_python(py).is_none()));
+    }
+
+    #[test]
+    fn compare_swap_stores_transformed_value() {
+        let (value, rx) = value(ChangedValues::new());
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let transform = py.eval(c"lambda v: v * 2", None, None).unwrap();
+            value.set_transform_py(Some(transform.unbind()));
+        });
+
+        assert!(value.compare_swap(serialize((0i64, 5i64)), false).unwrap());
+        let echoed = match rx.try_iter().last().unwrap() {
+            WriteMessage::Value(1, false, data) => deserialize::<i64>(data).unwrap(),
+            _ => panic!("Value message expected"),
+        };
+        assert_eq!(echoed, 10);
+
+        // the stored value is the transformed one
+        assert!(!value.compare_swap(serialize((5i64, 1i64)), false).unwrap());
+        assert!(value.compare_swap(serialize((10i64, 1i64)), false).unwrap());
+    }
+}
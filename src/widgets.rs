@@ -0,0 +1,85 @@
+use std::ops::RangeInclusive;
+
+use egui::emath::Numeric;
+use egui::{ComboBox, DragValue, Image, Response, Slider, Ui, Vec2, WidgetText};
+use serde::Serialize;
+
+use crate::image::ValueImage;
+use crate::values::{Diff, EnumNames, Value};
+
+// Widgets bound to the values. The value is sent to the server only if the user changed it,
+// the change is sent with the signal, so the callbacks on the server are called. The dragged
+// widgets send the value held back by the send rate of the value when the drag ends.
+
+/// Slider changing the numeric value in the range.
+pub fn value_slider<T>(ui: &mut Ui, value: &Value<T>, range: RangeInclusive<T>) -> Response
+where
+    T: Numeric + Serialize,
+{
+    let mut diff = Diff::new(value);
+    let response = ui.add(Slider::new(&mut diff.v, range));
+    diff.set(true);
+    if response.drag_stopped() {
+        value.send_pending();
+    }
+    response
+}
+
+/// Dragged field changing the numeric value, the speed is the change per one point of the drag.
+pub fn value_drag<T>(ui: &mut Ui, value: &Value<T>, speed: f64) -> Response
+where
+    T: Numeric + Serialize,
+{
+    let mut diff = Diff::new(value);
+    let response = ui.add(DragValue::new(&mut diff.v).speed(speed));
+    diff.set(true);
+    if response.drag_stopped() {
+        value.send_pending();
+    }
+    response
+}
+
+/// Checkbox with the label changing the bool value.
+pub fn value_checkbox(ui: &mut Ui, value: &Value<bool>, text: impl Into<WidgetText>) -> Response {
+    let mut diff = Diff::new(value);
+    let response = ui.checkbox(&mut diff.v, text);
+    diff.set(true);
+    response
+}
+
+/// Single line text edit changing the string value, the value is sent with every typed character.
+pub fn value_text_edit(ui: &mut Ui, value: &Value<String>) -> Response {
+    let mut diff = Diff::new(value);
+    let response = ui.text_edit_singleline(&mut diff.v);
+    diff.set(true);
+    response
+}
+
+/// Combo box with the label listing the names of all variants of the enum value.
+pub fn value_combo<T>(ui: &mut Ui, value: &Value<T>, text: impl Into<WidgetText>) -> Response
+where
+    T: EnumNames + Serialize,
+{
+    let mut diff = Diff::new(value);
+    let response = ComboBox::from_label(text)
+        .selected_text(diff.v.name())
+        .show_ui(ui, |ui| {
+            for (variant, name) in T::VARIANTS {
+                ui.selectable_value(&mut diff.v, variant.clone(), *name);
+            }
+        })
+        .response;
+    diff.set(true);
+    response
+}
+
+/// Image scaled to fit the size with the kept aspect ratio. The preview is shown in the size of
+/// the full image, the texture is created on the first call if the image is not initialized.
+pub fn value_image(ui: &mut Ui, image: &ValueImage, max_size: Vec2) -> Response {
+    let mut texture = image.texture(ui.ctx());
+    let scale = (max_size.x / texture.size.x).min(max_size.y / texture.size.y);
+    if scale.is_finite() && scale > 0.0 {
+        texture.size *= scale;
+    }
+    ui.add(Image::from_texture(texture))
+}